@@ -0,0 +1,20 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generates the gRPC server from `proto/dispatch.proto`. The schema is parsed by protox, so that building doesn't
+/// require `protoc`.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    println!("cargo:rerun-if-changed=proto");
+
+    let descriptors = protox::compile(["dispatch.proto"], ["proto"])
+        .unwrap_or_else(|err| panic!("invalid gRPC schema: {:?}", err));
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+        .expect("failed to generate the gRPC server");
+}
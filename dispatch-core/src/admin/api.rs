@@ -0,0 +1,213 @@
+use std::{net::IpAddr, num::NonZeroUsize, str::FromStr};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{AdminState, Scope};
+use crate::{
+    connections::ConnectionId,
+    control::{self, AddressStats, AddressStatus, ClientStats, ConnectionInfo, Status},
+    dispatcher::{RawWeightedAddress, WeightedAddress},
+    rules::RuleInfo,
+};
+
+/// An error returned by the API, as a JSON object with an `error` message.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+
+        (self.0, Json(Body { error: self.1 })).into_response()
+    }
+}
+
+impl From<eyre::Report> for ApiError {
+    fn from(err: eyre::Report) -> ApiError {
+        ApiError(StatusCode::BAD_REQUEST, format!("{:#}", err))
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Routes of the API, which all require a token. Only the admin token allows changes.
+pub fn router(state: AdminState) -> Router<AdminState> {
+    Router::new()
+        .route("/status", get(status))
+        .route("/stats", get(stats))
+        .route("/addresses", get(addresses).post(add_address))
+        .route(
+            "/addresses/{address}",
+            patch(update_address).delete(remove_address),
+        )
+        .route("/clients", get(clients))
+        .route("/connections", get(connections))
+        .route("/connections/{id}", delete(kill_connection))
+        .route("/rules", get(rules).put(set_rules))
+        .route_layer(middleware::from_fn_with_state(state, authenticate))
+}
+
+/// Rejects requests that don't carry a valid bearer token, and changes that don't carry the admin token.
+pub(super) async fn authenticate(
+    State(state): State<AdminState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(scope) = bearer_token(&request).and_then(|provided| state.tokens.scope(provided))
+    else {
+        return ApiError(
+            StatusCode::UNAUTHORIZED,
+            "a valid `Authorization: Bearer <token>` header is required".to_string(),
+        )
+        .into_response();
+    };
+
+    let required = match *request.method() {
+        Method::GET => Scope::Read,
+        _ => Scope::Admin,
+    };
+    if scope < required {
+        return ApiError(
+            StatusCode::FORBIDDEN,
+            "the admin token is required to make changes".to_string(),
+        )
+        .into_response();
+    }
+
+    next.run(request).await
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+async fn status(State(state): State<AdminState>) -> ApiResult<Status> {
+    match control::handle(control::Request::Status, &state.control).await {
+        control::Response::Status(status) => Ok(Json(status)),
+        response => Err(unexpected(response)),
+    }
+}
+
+async fn stats(State(state): State<AdminState>) -> ApiResult<Vec<AddressStats>> {
+    match control::handle(control::Request::Stats, &state.control).await {
+        control::Response::Stats { addresses, .. } => Ok(Json(addresses)),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: control::Response) -> ApiError {
+    ApiError(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("unexpected response: {:?}", response),
+    )
+}
+
+async fn addresses(State(state): State<AdminState>) -> ApiResult<Vec<AddressStatus>> {
+    Ok(Json(control::address_statuses(&state.control).await))
+}
+
+#[derive(Deserialize)]
+struct NewAddress {
+    /// A network interface name or IP address, with an optional weight, as given on the command line (e.g. `eth0/2`).
+    address: String,
+}
+
+async fn add_address(
+    State(state): State<AdminState>,
+    Json(new): Json<NewAddress>,
+) -> ApiResult<Vec<AddressStatus>> {
+    let raw = RawWeightedAddress::from_str(&new.address)?;
+    for address in WeightedAddress::resolve(vec![raw])? {
+        tracing::info!(address = %address, "dispatch address added through the admin API");
+        state.control.dispatcher.add_address(address).await;
+    }
+    addresses(State(state)).await
+}
+
+#[derive(Deserialize)]
+struct AddressUpdate {
+    weight: NonZeroUsize,
+}
+
+async fn update_address(
+    State(state): State<AdminState>,
+    Path(address): Path<IpAddr>,
+    Json(update): Json<AddressUpdate>,
+) -> ApiResult<Vec<AddressStatus>> {
+    if !state
+        .control
+        .dispatcher
+        .set_weight(address, update.weight)
+        .await
+    {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("traffic isn't dispatched to `{}`", address),
+        ));
+    }
+    tracing::info!(address = %address, weight = update.weight, "dispatch weight changed through the admin API");
+    addresses(State(state)).await
+}
+
+async fn remove_address(
+    State(state): State<AdminState>,
+    Path(address): Path<IpAddr>,
+) -> ApiResult<Vec<AddressStatus>> {
+    state.control.dispatcher.remove_address(address).await?;
+    tracing::info!(address = %address, "dispatch address removed through the admin API");
+    addresses(State(state)).await
+}
+
+async fn clients(State(state): State<AdminState>) -> ApiResult<Vec<ClientStats>> {
+    Ok(Json(control::client_stats(&state.control)))
+}
+
+async fn connections(State(state): State<AdminState>) -> ApiResult<Vec<ConnectionInfo>> {
+    Ok(Json(
+        state
+            .control
+            .registry
+            .list()
+            .iter()
+            .map(|connection| ConnectionInfo::from(&**connection))
+            .collect(),
+    ))
+}
+
+async fn kill_connection(
+    State(state): State<AdminState>,
+    Path(id): Path<ConnectionId>,
+) -> Result<StatusCode, ApiError> {
+    if !state.control.registry.kill(id) {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("there is no active connection with ID {}", id),
+        ));
+    }
+    tracing::info!(id, "connection killed through the admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn rules(State(state): State<AdminState>) -> ApiResult<Vec<RuleInfo>> {
+    Ok(Json(state.control.rules.current().describe()))
+}
+
+/// Replaces the routing rules with the request body, in the `--rules` format.
+async fn set_rules(State(state): State<AdminState>, rules: String) -> ApiResult<Vec<RuleInfo>> {
+    Ok(Json(control::replace_rules(&state.control, &rules)?))
+}
@@ -0,0 +1,395 @@
+//! The gRPC control API, generated from `proto/dispatch.proto`.
+
+use std::{net::SocketAddr, num::NonZeroUsize, pin::Pin, str::FromStr, time::Duration};
+
+use eyre::{Result, WrapErr};
+use futures_util::{stream, Stream};
+use tokio::net::TcpListener;
+use tonic::{
+    metadata::MetadataMap,
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+
+use super::{Scope, Tokens};
+use crate::{
+    control::{self, AddressStats, AddressStatus, ClientStats, ConnectionInfo, ControlState},
+    dispatcher::{RawWeightedAddress, WeightedAddress},
+    rules::RuleInfo,
+};
+
+mod proto {
+    tonic::include_proto!("dispatch.v1");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .wrap_err_with(|| format!("Failed to bind the gRPC endpoint to `{}`", addr))
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    state: ControlState,
+    tokens: Tokens,
+    #[cfg(feature = "tls")] tls: Option<std::sync::Arc<tokio_rustls::rustls::ServerConfig>>,
+) -> Result<()> {
+    let service = ControlServer::with_interceptor(GrpcControl { state }, move |request| {
+        authenticate(&tokens, request)
+    });
+    let router = Server::builder().add_service(service);
+
+    #[cfg(feature = "tls")]
+    if let Some(config) = tls {
+        let incoming = super::tls::TlsListener::new(listener, config)?.into_stream();
+        return router
+            .serve_with_incoming(incoming)
+            .await
+            .wrap_err("The gRPC endpoint stopped unexpectedly");
+    }
+
+    router
+        .serve_with_incoming(TcpIncoming::from(listener))
+        .await
+        .wrap_err("The gRPC endpoint stopped unexpectedly")
+}
+
+/// Rejects calls that don't carry a valid bearer token, and records the scope it grants for `require_admin`.
+fn authenticate(tokens: &Tokens, mut request: Request<()>) -> Result<Request<()>, Status> {
+    let scope = bearer_token(request.metadata())
+        .and_then(|provided| tokens.scope(provided))
+        .ok_or_else(|| {
+            Status::unauthenticated(
+                "a valid `authorization: Bearer <token>` metadata entry is required",
+            )
+        })?;
+    request.extensions_mut().insert(scope);
+    Ok(request)
+}
+
+/// Rejects calls that make changes without the admin token.
+fn require_admin<T>(request: &Request<T>) -> Result<(), Status> {
+    match request.extensions().get::<Scope>() {
+        Some(Scope::Admin) => Ok(()),
+        _ => Err(Status::permission_denied(
+            "the admin token is required to make changes",
+        )),
+    }
+}
+
+fn bearer_token(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn invalid_argument(err: eyre::Report) -> Status {
+    Status::invalid_argument(format!("{:#}", err))
+}
+
+fn unexpected(response: control::Response) -> Status {
+    Status::internal(format!("unexpected response: {:?}", response))
+}
+
+fn parse_address(address: &str) -> Result<std::net::IpAddr, Status> {
+    address
+        .parse()
+        .map_err(|_| Status::invalid_argument(format!("`{}` isn't an IP address", address)))
+}
+
+impl From<AddressStatus> for proto::Address {
+    fn from(status: AddressStatus) -> proto::Address {
+        proto::Address {
+            address: status.address.to_string(),
+            weight: status.weight as u64,
+            healthy: status.healthy,
+            paused: status.paused,
+            over_quota: status.over_quota,
+        }
+    }
+}
+
+impl From<AddressStats> for proto::AddressStats {
+    fn from(address: AddressStats) -> proto::AddressStats {
+        proto::AddressStats {
+            address: address.address.to_string(),
+            active: address.stats.active,
+            total: address.stats.total,
+            bytes_up: address.stats.bytes_up,
+            bytes_down: address.stats.bytes_down,
+        }
+    }
+}
+
+impl From<ClientStats> for proto::ClientStats {
+    fn from(client: ClientStats) -> proto::ClientStats {
+        proto::ClientStats {
+            client: client.client.to_string(),
+            active: client.stats.active,
+            total: client.stats.total,
+            bytes_up: client.stats.bytes_up,
+            bytes_down: client.stats.bytes_down,
+        }
+    }
+}
+
+impl From<ConnectionInfo> for proto::Connection {
+    fn from(connection: ConnectionInfo) -> proto::Connection {
+        proto::Connection {
+            id: connection.id,
+            client: connection.client.to_string(),
+            destination: connection.destination,
+            address: connection.address.to_string(),
+            interface: connection.interface.to_string(),
+            bytes_up: connection.bytes_up,
+            bytes_down: connection.bytes_down,
+            duration_ms: connection.duration_ms,
+        }
+    }
+}
+
+impl From<RuleInfo> for proto::Rule {
+    fn from(rule: RuleInfo) -> proto::Rule {
+        proto::Rule {
+            pattern: rule.pattern,
+            action: rule.action,
+            hits: rule.hits,
+        }
+    }
+}
+
+fn rules(rules: Vec<RuleInfo>) -> Response<proto::Rules> {
+    Response::new(proto::Rules {
+        rules: rules.into_iter().map(Into::into).collect(),
+    })
+}
+
+struct GrpcControl {
+    state: ControlState,
+}
+
+impl GrpcControl {
+    async fn addresses(&self) -> Response<proto::Addresses> {
+        Response::new(proto::Addresses {
+            addresses: control::address_statuses(&self.state)
+                .await
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        })
+    }
+}
+
+async fn stats(state: &ControlState) -> Result<proto::Stats, Status> {
+    match control::handle(control::Request::Stats, state).await {
+        control::Response::Stats { addresses, .. } => Ok(proto::Stats {
+            addresses: addresses.into_iter().map(Into::into).collect(),
+        }),
+        response => Err(unexpected(response)),
+    }
+}
+
+type StatsStream = Pin<Box<dyn Stream<Item = Result<proto::Stats, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Control for GrpcControl {
+    async fn get_status(
+        &self,
+        _: Request<proto::GetStatusRequest>,
+    ) -> Result<Response<proto::Status>, Status> {
+        match control::handle(control::Request::Status, &self.state).await {
+            control::Response::Status(status) => Ok(Response::new(proto::Status {
+                version: status.version,
+                pid: status.pid,
+                uptime_secs: status.uptime_secs,
+                listen: status.listen.to_string(),
+                accepting: status.accepting,
+                connections: status.connections as u64,
+                addresses: status.addresses.into_iter().map(Into::into).collect(),
+                log_filter: status.log_filter.unwrap_or_default(),
+                log_file: status
+                    .log_file
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+                external: status
+                    .external
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_default(),
+            })),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn get_stats(
+        &self,
+        _: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::Stats>, Status> {
+        Ok(Response::new(stats(&self.state).await?))
+    }
+
+    type WatchStatsStream = StatsStream;
+
+    async fn watch_stats(
+        &self,
+        request: Request<proto::WatchStatsRequest>,
+    ) -> Result<Response<StatsStream>, Status> {
+        let interval = match request.into_inner().interval_ms {
+            0 => Duration::from_secs(1),
+            interval_ms => Duration::from_millis(interval_ms.into()),
+        };
+        let interval = tokio::time::interval(interval);
+
+        let stream = stream::unfold(
+            (interval, self.state.clone()),
+            |(mut interval, state)| async move {
+                interval.tick().await;
+                let stats = stats(&state).await;
+                Some((stats, (interval, state)))
+            },
+        );
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn add_address(
+        &self,
+        request: Request<proto::AddAddressRequest>,
+    ) -> Result<Response<proto::Addresses>, Status> {
+        require_admin(&request)?;
+        let raw = RawWeightedAddress::from_str(&request.into_inner().address)
+            .map_err(invalid_argument)?;
+        for address in WeightedAddress::resolve(vec![raw]).map_err(invalid_argument)? {
+            tracing::info!(address = %address, "dispatch address added through the gRPC API");
+            self.state.dispatcher.add_address(address).await;
+        }
+        Ok(self.addresses().await)
+    }
+
+    async fn set_weight(
+        &self,
+        request: Request<proto::SetWeightRequest>,
+    ) -> Result<Response<proto::Addresses>, Status> {
+        require_admin(&request)?;
+        let request = request.into_inner();
+        let address = parse_address(&request.address)?;
+        let weight = usize::try_from(request.weight)
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .ok_or_else(|| Status::invalid_argument("the weight must be a positive integer"))?;
+
+        if !self.state.dispatcher.set_weight(address, weight).await {
+            return Err(Status::not_found(format!(
+                "traffic isn't dispatched to `{}`",
+                address
+            )));
+        }
+        tracing::info!(address = %address, weight, "dispatch weight changed through the gRPC API");
+        Ok(self.addresses().await)
+    }
+
+    async fn remove_address(
+        &self,
+        request: Request<proto::RemoveAddressRequest>,
+    ) -> Result<Response<proto::Addresses>, Status> {
+        require_admin(&request)?;
+        let address = parse_address(&request.into_inner().address)?;
+        self.state
+            .dispatcher
+            .remove_address(address)
+            .await
+            .map_err(|err| Status::failed_precondition(format!("{:#}", err)))?;
+        tracing::info!(address = %address, "dispatch address removed through the gRPC API");
+        Ok(self.addresses().await)
+    }
+
+    async fn reload(
+        &self,
+        request: Request<proto::ReloadRequest>,
+    ) -> Result<Response<proto::Addresses>, Status> {
+        require_admin(&request)?;
+        match control::handle(control::Request::Reload, &self.state).await {
+            control::Response::Reloaded { .. } => Ok(self.addresses().await),
+            control::Response::Error { message } => Err(Status::failed_precondition(message)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn list_connections(
+        &self,
+        _: Request<proto::ListConnectionsRequest>,
+    ) -> Result<Response<proto::Connections>, Status> {
+        Ok(Response::new(proto::Connections {
+            connections: self
+                .state
+                .registry
+                .list()
+                .iter()
+                .map(|connection| ConnectionInfo::from(&**connection).into())
+                .collect(),
+        }))
+    }
+
+    async fn list_clients(
+        &self,
+        _: Request<proto::ListClientsRequest>,
+    ) -> Result<Response<proto::Clients>, Status> {
+        Ok(Response::new(proto::Clients {
+            clients: control::client_stats(&self.state)
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }))
+    }
+
+    async fn get_rules(
+        &self,
+        _: Request<proto::GetRulesRequest>,
+    ) -> Result<Response<proto::Rules>, Status> {
+        Ok(rules(self.state.rules.current().describe()))
+    }
+
+    async fn set_rules(
+        &self,
+        request: Request<proto::SetRulesRequest>,
+    ) -> Result<Response<proto::Rules>, Status> {
+        require_admin(&request)?;
+        control::replace_rules(&self.state, &request.into_inner().rules)
+            .map(rules)
+            .map_err(invalid_argument)
+    }
+
+    async fn kill_connection(
+        &self,
+        request: Request<proto::KillConnectionRequest>,
+    ) -> Result<Response<proto::KillConnectionResponse>, Status> {
+        require_admin(&request)?;
+        let id = request.into_inner().id;
+        if !self.state.registry.kill(id) {
+            return Err(Status::not_found(format!(
+                "there is no active connection with ID {}",
+                id
+            )));
+        }
+        tracing::info!(id, "connection killed through the gRPC API");
+        Ok(Response::new(proto::KillConnectionResponse {}))
+    }
+
+    async fn stop(
+        &self,
+        request: Request<proto::StopRequest>,
+    ) -> Result<Response<proto::StopResponse>, Status> {
+        require_admin(&request)?;
+        match control::handle(control::Request::Stop, &self.state).await {
+            control::Response::Stopping { connections, .. } => {
+                Ok(Response::new(proto::StopResponse {
+                    connections: connections as u64,
+                }))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+mod api;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+use std::{
+    fmt::{Debug, Formatter},
+    net::SocketAddr,
+    sync::atomic::Ordering,
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderName, StatusCode},
+    middleware,
+    response::sse::{self, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use eyre::{Result, WrapErr};
+use futures_util::{stream, Stream};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+
+use crate::{
+    connections::HandshakeLimit, control::ControlState, events::Events, health, ports,
+    quota::Quotas,
+};
+
+/// State of the running proxy, as exposed by the admin endpoint.
+#[derive(Clone, Debug)]
+pub struct AdminState {
+    pub control: ControlState,
+    pub events: Events,
+    /// The bearer tokens accepted by the `/api` routes, which are only served when one is set, and by `/events` and
+    /// `/metrics`, which are open when none is.
+    pub tokens: Tokens,
+    /// The handshake rate limit, whose counters are exposed as metrics.
+    pub handshake_limit: Option<HandshakeLimit>,
+    pub quotas: Quotas,
+    /// Serve the endpoint over TLS with this configuration.
+    #[cfg(feature = "tls")]
+    pub tls: Option<std::sync::Arc<tokio_rustls::rustls::ServerConfig>>,
+}
+
+/// What a bearer token allows on the APIs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Reading the state and statistics of the proxy.
+    Read,
+    /// Also changing its addresses, rules and connections, and stopping it.
+    Admin,
+}
+
+/// The bearer tokens accepted by the APIs.
+#[derive(Clone, Default)]
+pub struct Tokens {
+    pub admin: Option<String>,
+    pub read: Option<String>,
+}
+
+// The tokens are left out, since the state is recorded in spans.
+impl Debug for Tokens {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokens")
+            .field("admin", &self.admin.is_some())
+            .field("read", &self.read.is_some())
+            .finish()
+    }
+}
+
+impl Tokens {
+    pub fn is_empty(&self) -> bool {
+        self.admin.is_none() && self.read.is_none()
+    }
+
+    /// Returns the scope granted by a token, if it is valid.
+    pub fn scope(&self, provided: &str) -> Option<Scope> {
+        let matches = |token: &Option<String>| {
+            token
+                .as_ref()
+                .is_some_and(|token| constant_time_eq(token.as_bytes(), provided.as_bytes()))
+        };
+        // Both tokens are compared, so that timing doesn't reveal which one matched.
+        match (matches(&self.admin), matches(&self.read)) {
+            (true, _) => Some(Scope::Admin),
+            (false, true) => Some(Scope::Read),
+            (false, false) => None,
+        }
+    }
+}
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .wrap_err_with(|| format!("Failed to bind the admin endpoint to `{}`", addr))
+}
+
+pub async fn serve(listener: TcpListener, state: AdminState) -> Result<()> {
+    // The events and metrics reveal the clients and destinations of the proxy, so they require a token once one is set.
+    let observe = Router::new()
+        .route("/events", get(events))
+        .route("/metrics", get(metrics));
+    let mut app = Router::new().route("/healthz", get(healthz));
+    if state.tokens.is_empty() {
+        app = app.merge(observe);
+    } else {
+        app = app
+            .merge(observe.route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                api::authenticate,
+            )))
+            .nest("/api", api::router(state.clone()));
+    }
+    #[cfg(feature = "tls")]
+    let tls = state.tls.clone();
+    let app = app.with_state(state);
+
+    #[cfg(feature = "tls")]
+    if let Some(config) = tls {
+        return axum::serve(tls::TlsListener::new(listener, config)?, app)
+            .await
+            .wrap_err("The admin endpoint stopped unexpectedly");
+    }
+
+    axum::serve(listener, app)
+        .await
+        .wrap_err("The admin endpoint stopped unexpectedly")
+}
+
+/// Warns when an endpoint can be reached from other machines without TLS, since the tokens would be sent in cleartext.
+pub fn warn_if_exposed(name: &str, addr: SocketAddr, tls: bool) {
+    if !tls && !addr.ip().is_loopback() {
+        tracing::warn!(
+            address = %addr,
+            "the {} endpoint is reachable from other machines without TLS, so its tokens can be read on the network",
+            name
+        );
+    }
+}
+
+/// Succeeds when the listener is accepting connections and at least one dispatch address is usable and dispatched to.
+async fn healthz(State(state): State<AdminState>) -> (StatusCode, &'static str) {
+    if !state.control.accepting.load(Ordering::Relaxed) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the listener is not accepting connections\n",
+        );
+    }
+
+    let healthy = state
+        .control
+        .dispatcher
+        .weighted_ips()
+        .await
+        .into_iter()
+        .any(|ip| ip.is_active() && health::is_healthy(ip.ip));
+    if !healthy {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "none of the dispatch addresses are usable\n",
+        );
+    }
+
+    (StatusCode::OK, "ok\n")
+}
+
+/// Exposes metrics in the Prometheus text format.
+async fn metrics(State(state): State<AdminState>) -> ([(HeaderName, &'static str); 1], String) {
+    let ips = state.control.dispatcher.ips().await;
+    let mut metrics = ports::render_metrics(&ips, &state.control.registry);
+    if let Some(limit) = &state.handshake_limit {
+        metrics.push_str(&limit.render_metrics());
+    }
+    metrics.push_str(&state.quotas.render_metrics());
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics,
+    )
+}
+
+/// Streams connection lifecycle and health events as they happen, as server-sent events with JSON payloads.
+async fn events(
+    State(state): State<AdminState>,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let receiver = state.events.subscribe();
+
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    return Some((sse::Event::default().json_data(&event), receiver));
+                }
+                // Slow subscribers miss events rather than holding back the proxy.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Compares secrets in a time that doesn't depend on where they differ, so that they can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
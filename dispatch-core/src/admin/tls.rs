@@ -0,0 +1,198 @@
+//! TLS for the admin and gRPC endpoints, so that they can be reached from other machines without sending the tokens in
+//! cleartext, and for the SOCKS listener, so that clients can be authenticated by their certificates.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        Error, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// The certificate chain, in PEM.
+    pub cert: PathBuf,
+    /// The private key of the certificate, in PEM.
+    pub key: PathBuf,
+    /// When set, clients must present a certificate signed by this CA.
+    pub client_ca: Option<PathBuf>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).wrap_err_with(|| format!("Failed to read `{}`", path.display()))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(&read(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("Failed to parse the certificates in `{}`", path.display()))?;
+    if certs.is_empty() {
+        return Err(eyre::eyre!("No certificates found in `{}`", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Loads the certificate and key, and the client CA if any, into a configuration negotiating the given protocols.
+pub fn server_config(options: &TlsOptions, protocols: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+    let certs = read_certs(&options.cert)?;
+    let key = PrivateKeyDer::from_pem_slice(&read(&options.key)?).wrap_err_with(|| {
+        format!(
+            "Failed to parse the private key in `{}`",
+            options.key.display()
+        )
+    })?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match &options.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(cert).wrap_err_with(|| {
+                    format!("Invalid client CA certificate in `{}`", path.display())
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .wrap_err("Failed to set up client certificate verification")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certs, key).wrap_err_with(|| {
+        format!(
+            "The private key in `{}` doesn't match the certificate in `{}`",
+            options.key.display(),
+            options.cert.display()
+        )
+    })?;
+    config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// Completes the TLS handshake of a client, which fails if it takes too long.
+pub async fn handshake(
+    config: &Arc<ServerConfig>,
+    stream: TcpStream,
+) -> io::Result<TlsStream<TcpStream>> {
+    let acceptor = TlsAcceptor::from(Arc::clone(config));
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the TLS handshake timed out",
+            ))
+        })
+}
+
+/// Whether a handshake failed because the client didn't present a certificate signed by the client CA.
+pub fn is_certificate_error(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref().and_then(|err| err.downcast_ref::<Error>()),
+        Some(Error::NoCertificatesPresented | Error::InvalidCertificate(_))
+    )
+}
+
+/// Accepts TCP connections, and completes their TLS handshakes in the background so that a slow client doesn't hold up
+/// the others.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<TlsListener> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(32);
+        tokio::spawn(accept(listener, TlsAcceptor::from(config), sender));
+        Ok(TlsListener {
+            connections,
+            local_addr,
+        })
+    }
+
+    /// Turns the listener into a stream of connections, as expected by the gRPC server.
+    #[cfg(feature = "grpc")]
+    pub fn into_stream(self) -> impl futures_util::Stream<Item = io::Result<TlsStream<TcpStream>>> {
+        futures_util::stream::unfold(self.connections, |mut connections| async move {
+            let (stream, _) = connections.recv().await?;
+            Some((Ok(stream), connections))
+        })
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!(
+                    "{:?}",
+                    eyre::eyre!(err).wrap_err("Failed to accept a connection")
+                );
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if sender.is_closed() {
+            return;
+        }
+
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = sender.send((stream, addr)).await;
+                }
+                // Failed handshakes are common on exposed ports, e.g. from scanners.
+                Ok(Err(err)) => tracing::debug!(client = %addr, "TLS handshake failed: {}", err),
+                Err(_) => tracing::debug!(client = %addr, "TLS handshake timed out"),
+            }
+        });
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accepting task keeps running as long as the listener is alive.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
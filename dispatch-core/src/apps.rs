@@ -0,0 +1,208 @@
+//! Finding the application that a client on the same machine connects to the proxy from, by looking up the process
+//! that owns the other end of its connection, so that rules can route the traffic of each application differently,
+//! e.g. a browser over the fiber line and a torrent client over 4G.
+
+use std::net::SocketAddr;
+
+#[cfg(windows)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Normalizes the name of an application, as given in rules or found for a process: lowercase, without the `.exe`
+/// extension of Windows executables.
+pub fn normalize(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.strip_suffix(".exe") {
+        Some(stem) => stem.to_string(),
+        None => name,
+    }
+}
+
+/// The normalized name of the executable of the process that connected from `client` to the proxy at `proxy`, if it
+/// can be found. Only the processes of the same user can be found, unless the proxy runs as root, and this reads every
+/// process's open files, so it blocks.
+#[cfg(target_os = "linux")]
+pub fn app_of(client: SocketAddr, proxy: SocketAddr) -> Option<String> {
+    let inode = socket_inode(client, proxy)?;
+    let socket = format!("socket:[{}]", inode);
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+            continue;
+        };
+        let owns = fds.flatten().any(|fd| {
+            std::fs::read_link(fd.path()).is_ok_and(|target| target.as_os_str() == socket.as_str())
+        });
+        if owns {
+            return process_name(pid);
+        }
+    }
+    None
+}
+
+/// The inode of the socket of the client's end of the connection, as listed by the kernel in `/proc/net/tcp`, or in
+/// `/proc/net/tcp6` for IPv6 sockets, which also connect to IPv4 addresses.
+#[cfg(target_os = "linux")]
+fn socket_inode(client: SocketAddr, proxy: SocketAddr) -> Option<u64> {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (client, proxy) = (canonical(client), canonical(proxy));
+    ["/proc/net/tcp", "/proc/net/tcp6"].iter().find_map(|path| {
+        let table = std::fs::read_to_string(path).ok()?;
+        // Each line is `<slot>: <local address> <remote address> <state> ... <uid> <timeout> <inode> ...`.
+        table.lines().skip(1).find_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let local = canonical(parse_proc_addr(fields.get(1)?)?);
+            let remote = canonical(parse_proc_addr(fields.get(2)?)?);
+            if local != client || remote != proxy {
+                return None;
+            }
+            fields.get(9)?.parse().ok()
+        })
+    })
+}
+
+/// Parses an address of `/proc/net/tcp`, like `0100007F:1F90`, where the IP address is written as 32-bit words in the
+/// byte order of the host, and the port as a number.
+#[cfg(target_os = "linux")]
+fn parse_proc_addr(src: &str) -> Option<SocketAddr> {
+    let (ip, port) = src.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut octets = Vec::with_capacity(16);
+    for word in ip.as_bytes().chunks(8) {
+        let word = u32::from_str_radix(std::str::from_utf8(word).ok()?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match octets.len() {
+        4 => std::net::IpAddr::from(<[u8; 4]>::try_from(octets).ok()?),
+        16 => std::net::IpAddr::from(<[u8; 16]>::try_from(octets).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// The name of the executable of a process, or the name the process gave itself when its executable can't be read.
+#[cfg(target_os = "linux")]
+fn process_name(pid: u32) -> Option<String> {
+    if let Some(name) = std::fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .and_then(|exe| Some(exe.file_name()?.to_str()?.to_string()))
+    {
+        // The executable may have been replaced, e.g. by an update, since the process started.
+        return Some(normalize(name.strip_suffix(" (deleted)").unwrap_or(&name)));
+    }
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(normalize(comm.trim_end()))
+}
+
+/// The normalized name of the executable of the process that connected from `client` to the proxy at `proxy`, if it
+/// can be found.
+#[cfg(windows)]
+pub fn app_of(client: SocketAddr, proxy: SocketAddr) -> Option<String> {
+    let pid = owning_pid(client, proxy)?;
+    let pid = sysinfo::Pid::from_u32(pid);
+    let mut system = sysinfo::System::new();
+    if !system.refresh_process_specifics(pid, sysinfo::ProcessRefreshKind::new()) {
+        return None;
+    }
+    Some(normalize(system.process(pid)?.name()))
+}
+
+/// The process that owns the client's end of the connection, as listed in the TCP table of the system.
+#[cfg(windows)]
+fn owning_pid(client: SocketAddr, proxy: SocketAddr) -> Option<u32> {
+    use windows_sys::Win32::{
+        NetworkManagement::IpHelper::{
+            GetExtendedTcpTable, MIB_TCP6ROW_OWNER_PID, MIB_TCPROW_OWNER_PID,
+            TCP_TABLE_OWNER_PID_ALL,
+        },
+        Networking::WinSock::{AF_INET, AF_INET6},
+    };
+
+    const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (client, proxy) = (canonical(client), canonical(proxy));
+    for family in [AF_INET, AF_INET6] {
+        // The table can grow between getting its size and getting it.
+        let mut size = 0u32;
+        let mut buf = Vec::<u64>::new();
+        let res = loop {
+            let res = unsafe {
+                GetExtendedTcpTable(
+                    buf.as_mut_ptr().cast(),
+                    &mut size,
+                    0,
+                    family as u32,
+                    TCP_TABLE_OWNER_PID_ALL,
+                    0,
+                )
+            };
+            if res != ERROR_INSUFFICIENT_BUFFER {
+                break res;
+            }
+            buf = vec![0; (size as usize).div_ceil(8)];
+        };
+        if res != 0 || buf.is_empty() {
+            continue;
+        }
+        // The table is the number of rows followed by the rows, as in MIB_TCPTABLE_OWNER_PID.
+        let len = unsafe { *buf.as_ptr().cast::<u32>() } as usize;
+        let port = |port: u32| u16::from_be(port as u16);
+        let pid = if family == AF_INET {
+            let rows = unsafe {
+                let first = buf
+                    .as_ptr()
+                    .cast::<u8>()
+                    .add(4)
+                    .cast::<MIB_TCPROW_OWNER_PID>();
+                std::slice::from_raw_parts(first, len)
+            };
+            rows.iter().find_map(|row| {
+                let local = SocketAddr::new(
+                    Ipv4Addr::from(row.dwLocalAddr.to_ne_bytes()).into(),
+                    port(row.dwLocalPort),
+                );
+                let remote = SocketAddr::new(
+                    Ipv4Addr::from(row.dwRemoteAddr.to_ne_bytes()).into(),
+                    port(row.dwRemotePort),
+                );
+                (local == client && remote == proxy).then_some(row.dwOwningPid)
+            })
+        } else {
+            let rows = unsafe {
+                let first = buf
+                    .as_ptr()
+                    .cast::<u8>()
+                    .add(4)
+                    .cast::<MIB_TCP6ROW_OWNER_PID>();
+                std::slice::from_raw_parts(first, len)
+            };
+            rows.iter().find_map(|row| {
+                let local = canonical(SocketAddr::new(
+                    Ipv6Addr::from(row.ucLocalAddr).into(),
+                    port(row.dwLocalPort),
+                ));
+                let remote = canonical(SocketAddr::new(
+                    Ipv6Addr::from(row.ucRemoteAddr).into(),
+                    port(row.dwRemotePort),
+                ));
+                (local == client && remote == proxy).then_some(row.dwOwningPid)
+            })
+        };
+        if pid.is_some() {
+            return pid;
+        }
+    }
+    None
+}
+
+/// Applications can't be found on other systems, where rules can't match them.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn app_of(_client: SocketAddr, _proxy: SocketAddr) -> Option<String> {
+    None
+}
@@ -0,0 +1,153 @@
+//! The audit log, which records every decision on a requested destination as a line of JSON, e.g.
+//!
+//! ```text
+//! {"time":"2026-10-16T21:03:25.744Z","id":12,"client":"192.168.1.20","user":"alice","destination":"example.com:443","address":"93.184.215.14:443","interface":"192.168.1.10","verdict":"allowed"}
+//! {"time":"2026-10-16T21:03:26.102Z","client":"192.168.1.20","destination":"10.0.0.1:22","address":"10.0.0.1:22","verdict":"denied","rule":"@private deny"}
+//! {"time":"2026-10-16T21:03:27.390Z","client":"192.168.1.20","destination":"ads.example.com:443","verdict":"denied","rule":"ads.example.com block"}
+//! ```
+//!
+//! Unlike the debug logs, the audit log isn't filtered, rotated or redacted: it is meant to be kept as a record of
+//! what went through the proxy.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+
+use crate::{
+    connections::{Connection, ConnectionId},
+    rules::Denied,
+    users::User,
+};
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Allowed,
+    Denied,
+}
+
+/// A decision on a destination requested by a client.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    time: String,
+    /// The connection that was opened, when it was allowed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<ConnectionId>,
+    client: IpAddr,
+    /// The user the client authenticated as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// The destination as requested, with its domain name if it had one.
+    destination: String,
+    /// The address the destination resolved to, unless it was denied before its domain was resolved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<SocketAddr>,
+    /// The local address the connection egresses from, when it was allowed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<IpAddr>,
+    verdict: Verdict,
+    /// What denied the connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn allowed(connection: &Connection, user: Option<&User>) -> AuditRecord {
+        AuditRecord {
+            time: now(),
+            id: Some(connection.id),
+            client: connection.client.ip(),
+            user: user.map(|user| user.name.clone()),
+            destination: connection.destination.to_string(),
+            address: Some(connection.address),
+            interface: Some(connection.interface),
+            verdict: Verdict::Allowed,
+            rule: None,
+        }
+    }
+
+    pub fn denied(client: IpAddr, user: Option<&User>, denied: &Denied) -> AuditRecord {
+        AuditRecord {
+            time: now(),
+            id: None,
+            client,
+            user: user.map(|user| user.name.clone()),
+            destination: denied.destination.to_string(),
+            address: denied.destination.resolved(),
+            interface: None,
+            verdict: Verdict::Denied,
+            rule: Some(denied.rule.clone()),
+        }
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+/// Appends audit records to a file.
+///
+/// Writes happen on a dedicated thread so that connections never block on disk I/O.
+#[derive(Clone, Debug)]
+pub struct AuditLog(Sender<AuditRecord>);
+
+impl AuditLog {
+    /// Opens the audit log at `path`, creating it if needed and appending to it otherwise.
+    pub fn open(path: &Path) -> Result<AuditLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open the audit log `{}`", path.display()))?;
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("audit".into())
+            .spawn(move || write_records(file, receiver))
+            .wrap_err("Failed to spawn the audit thread")?;
+
+        Ok(AuditLog(sender))
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        // The writer thread only stops if the file becomes unwritable, in which case the error has already been
+        // reported.
+        let _ = self.0.send(record);
+    }
+}
+
+fn write_records(file: File, receiver: Receiver<AuditRecord>) {
+    let mut writer = BufWriter::new(file);
+    while let Ok(record) = receiver.recv() {
+        let mut res = write_record(&mut writer, &record);
+        // Records that are already queued are written together, and flushed once the queue is empty, so that the log
+        // is up to date whenever the proxy is idle.
+        while res.is_ok() {
+            match receiver.try_recv() {
+                Ok(record) => res = write_record(&mut writer, &record),
+                Err(_) => {
+                    res = writer.flush().map_err(Into::into);
+                    break;
+                }
+            }
+        }
+        if let Err(err) = res {
+            tracing::error!("{:?}", err.wrap_err("Failed to write to the audit log"));
+            return;
+        }
+    }
+}
+
+fn write_record(writer: &mut impl Write, record: &AuditRecord) -> Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
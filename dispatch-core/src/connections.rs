@@ -0,0 +1,489 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Formatter},
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::socks::Destination;
+
+pub type ConnectionId = u64;
+
+/// How many clients without live connections have their totals kept. Past that, the totals of the clients which
+/// connected least recently are forgotten, so that a proxy reached by many addresses doesn't grow without bound.
+const MAX_IDLE_CLIENTS: usize = 4096;
+
+/// Bytes relayed so far by a connection, updated live by the relay.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub up: AtomicU64,
+    pub down: AtomicU64,
+}
+
+/// A connection that is currently being relayed.
+#[derive(Debug)]
+pub struct Connection {
+    pub id: ConnectionId,
+    pub client: SocketAddr,
+    pub destination: Destination,
+    /// The address the destination resolved to.
+    pub address: SocketAddr,
+    /// The local address the connection egresses from.
+    pub interface: IpAddr,
+    pub started: Instant,
+    pub started_at: SystemTime,
+    pub traffic: Arc<Traffic>,
+    /// Notified when an operator asks for the connection to be closed.
+    pub killed: Notify,
+}
+
+impl Connection {
+    pub fn bytes_up(&self) -> u64 {
+        self.traffic.up.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_down(&self) -> u64 {
+        self.traffic.down.load(Ordering::Relaxed)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Makes the relay close the connection.
+    pub fn kill(&self) {
+        self.killed.notify_one();
+    }
+}
+
+/// Usage of a local address, or by a client, since the proxy started.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    /// Connections currently open.
+    pub active: u64,
+    /// Connections opened since the proxy started, including active ones.
+    pub total: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Default)]
+struct ConnectionRegistryInner {
+    next_id: ConnectionId,
+    connections: BTreeMap<ConnectionId, Arc<Connection>>,
+    /// Totals of closed connections per local address, plus the count of all connections. There are as many as local
+    /// addresses that the proxy dispatched to.
+    totals: HashMap<IpAddr, Usage>,
+    /// Totals of closed connections per client IP address, plus the count of all connections.
+    client_totals: HashMap<IpAddr, Usage>,
+    /// When each client last opened a connection, to forget the totals of the idle ones first.
+    client_seen: HashMap<IpAddr, Instant>,
+    /// How many live connections each client has.
+    client_live: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionRegistryInner {
+    /// Forgets the totals of the clients without live connections which connected least recently, once there are more
+    /// than `MAX_IDLE_CLIENTS` of them. A quarter of them are forgotten at once, so that most connections don't pay for
+    /// sorting the clients.
+    fn evict_idle_clients(&mut self) {
+        if self.client_totals.len() - self.client_live.len() <= MAX_IDLE_CLIENTS {
+            return;
+        }
+        let mut idle = self
+            .client_seen
+            .iter()
+            .filter(|(client, _)| !self.client_live.contains_key(client))
+            .map(|(client, seen)| (*seen, *client))
+            .collect::<Vec<_>>();
+        let excess = idle.len() - MAX_IDLE_CLIENTS * 3 / 4;
+        idle.sort_unstable();
+        for (_, client) in idle.into_iter().take(excess) {
+            self.client_totals.remove(&client);
+            self.client_seen.remove(&client);
+        }
+    }
+}
+
+/// Keeps track of all live connections, so that they can be inspected at runtime.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry(Arc<Mutex<ConnectionRegistryInner>>);
+
+impl Debug for ConnectionRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionRegistry")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    /// Registers a new connection. It is removed from the registry when the returned guard is dropped.
+    pub fn register(
+        &self,
+        client: SocketAddr,
+        destination: Destination,
+        address: SocketAddr,
+        interface: IpAddr,
+    ) -> ConnectionGuard {
+        let mut inner = self.0.lock().unwrap();
+
+        inner.next_id += 1;
+        let connection = Arc::new(Connection {
+            id: inner.next_id,
+            client,
+            destination,
+            address,
+            interface,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            traffic: Arc::default(),
+            killed: Notify::new(),
+        });
+        inner
+            .connections
+            .insert(connection.id, Arc::clone(&connection));
+        inner.totals.entry(interface).or_default().total += 1;
+        inner.client_totals.entry(client.ip()).or_default().total += 1;
+        inner.client_seen.insert(client.ip(), connection.started);
+        *inner.client_live.entry(client.ip()).or_default() += 1;
+
+        ConnectionGuard {
+            registry: self.clone(),
+            connection,
+        }
+    }
+
+    /// Returns all live connections, ordered by ID.
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        self.0
+            .lock()
+            .unwrap()
+            .connections
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Closes a live connection. Returns whether it was found.
+    pub fn kill(&self, id: ConnectionId) -> bool {
+        match self.0.lock().unwrap().connections.get(&id) {
+            Some(connection) => {
+                connection.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns how many live connections egress from each local address.
+    pub fn count_by_interface(&self) -> HashMap<IpAddr, usize> {
+        let mut counts = HashMap::new();
+        for connection in self.0.lock().unwrap().connections.values() {
+            *counts.entry(connection.interface).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Returns the usage of each local address since the proxy started, including live connections.
+    pub fn stats(&self) -> HashMap<IpAddr, Usage> {
+        let inner = self.0.lock().unwrap();
+        with_live_connections(&inner.totals, &inner.connections, |connection| {
+            connection.interface
+        })
+    }
+
+    /// Returns the usage by each client IP address since the proxy started, including live connections. Clients which
+    /// haven't connected in a while may be missing when there are many.
+    pub fn client_stats(&self) -> HashMap<IpAddr, Usage> {
+        let inner = self.0.lock().unwrap();
+        with_live_connections(&inner.client_totals, &inner.connections, |connection| {
+            connection.client.ip()
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().connections.len()
+    }
+}
+
+/// Adds the live connections to the totals of closed connections, grouped by `key`.
+fn with_live_connections(
+    totals: &HashMap<IpAddr, Usage>,
+    connections: &BTreeMap<ConnectionId, Arc<Connection>>,
+    key: impl Fn(&Connection) -> IpAddr,
+) -> HashMap<IpAddr, Usage> {
+    let mut stats = totals.clone();
+    for connection in connections.values() {
+        let stats = stats.entry(key(connection)).or_default();
+        stats.active += 1;
+        stats.bytes_up += connection.bytes_up();
+        stats.bytes_down += connection.bytes_down();
+    }
+    stats
+}
+
+/// Removes its connection from the registry when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    registry: ConnectionRegistry,
+    connection: Arc<Connection>,
+}
+
+impl std::ops::Deref for ConnectionGuard {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut inner = self.registry.0.lock().unwrap();
+        inner.connections.remove(&self.connection.id);
+        let (bytes_up, bytes_down) = (self.connection.bytes_up(), self.connection.bytes_down());
+        let totals = inner.totals.entry(self.connection.interface).or_default();
+        totals.bytes_up += bytes_up;
+        totals.bytes_down += bytes_down;
+        let totals = inner
+            .client_totals
+            .entry(self.connection.client.ip())
+            .or_default();
+        totals.bytes_up += bytes_up;
+        totals.bytes_down += bytes_down;
+        let client = self.connection.client.ip();
+        if let Some(live) = inner.client_live.get_mut(&client) {
+            *live -= 1;
+            if *live == 0 {
+                inner.client_live.remove(&client);
+            }
+        }
+        inner.evict_idle_clients();
+    }
+}
+
+/// Parses an IP address or a CIDR range of clients.
+pub fn parse_client_range(src: &str) -> Result<IpNet> {
+    if let Ok(ip) = src.parse::<IpAddr>() {
+        return Ok(ip.into());
+    }
+    src.parse::<IpNet>()
+        .map(|net| net.trunc())
+        .wrap_err_with(|| format!("`{}` isn't an IP address or a CIDR range", src))
+        .suggestion("Clients are given as an IP address or a CIDR range, e.g. `192.168.1.0/24`")
+}
+
+/// Which clients can use the proxy: those in the allowed ranges if any are given, except those in the denied ranges.
+#[derive(Clone, Debug, Default)]
+pub struct ClientFilter {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+}
+
+impl ClientFilter {
+    pub fn new(allowed: Vec<IpNet>, denied: Vec<IpNet>) -> ClientFilter {
+        ClientFilter { allowed, denied }
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener connect from IPv4-mapped IPv6 addresses.
+        let client = client.to_canonical();
+        (self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&client)))
+            && !self.denied.iter().any(|net| net.contains(&client))
+    }
+}
+
+/// Limits how many connections each client IP address can have open at once, including those still being set up, so
+/// that a single client can't starve the others.
+#[derive(Clone, Debug)]
+pub struct ClientLimit {
+    max: NonZeroUsize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientLimit {
+    pub fn new(max: NonZeroUsize) -> ClientLimit {
+        ClientLimit {
+            max,
+            open: Arc::default(),
+        }
+    }
+
+    pub fn max(&self) -> NonZeroUsize {
+        self.max
+    }
+
+    /// Counts a new connection from a client until the returned guard is dropped, unless the client already has as
+    /// many connections open as allowed.
+    pub fn acquire(&self, client: IpAddr) -> Option<ClientSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(client).or_default();
+        if *count >= self.max.get() {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot {
+            limit: self.clone(),
+            client,
+        })
+    }
+}
+
+/// Frees its slot in the client limit when dropped.
+#[derive(Debug)]
+pub struct ClientSlot {
+    limit: ClientLimit,
+    client: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut open = self.limit.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// Limits how fast each client IP address can open connections, so that scans and retry storms are turned away before
+/// the handshake instead of each costing a task, and possibly a DNS query and an outgoing connection.
+#[derive(Clone, Debug)]
+pub struct HandshakeLimit(Arc<HandshakeLimitInner>);
+
+#[derive(Debug)]
+struct HandshakeLimitInner {
+    /// How many handshakes each client can start per second.
+    rate: NonZeroU32,
+    /// How many handshakes each client can start at once after being idle.
+    burst: NonZeroU32,
+    /// The handshakes each client can start right away, and when they were last counted.
+    clients: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+    /// When clients whose allowance has refilled were last forgotten.
+    pruned: Mutex<Instant>,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl HandshakeLimit {
+    pub fn new(rate: NonZeroU32, burst: NonZeroU32) -> HandshakeLimit {
+        HandshakeLimit(Arc::new(HandshakeLimitInner {
+            rate,
+            burst,
+            clients: Mutex::default(),
+            pruned: Mutex::new(Instant::now()),
+            accepted: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn rate(&self) -> NonZeroU32 {
+        self.0.rate
+    }
+
+    pub fn burst(&self) -> NonZeroU32 {
+        self.0.burst
+    }
+
+    /// Counts a new handshake from a client, unless the client has used up its allowance.
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let (rate, burst) = (f64::from(self.0.rate.get()), f64::from(self.0.burst.get()));
+        let now = Instant::now();
+        let mut clients = self.0.clients.lock().unwrap();
+
+        // Clients whose allowance has refilled are as good as new, so they are forgotten every once in a while.
+        let mut pruned = self.0.pruned.lock().unwrap();
+        if now.duration_since(*pruned) >= Duration::from_secs(1) {
+            clients.retain(|_, (tokens, updated)| {
+                *tokens + now.duration_since(*updated).as_secs_f64() * rate < burst
+            });
+            *pruned = now;
+        }
+
+        let (tokens, updated) = clients.entry(client).or_insert((burst, now));
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(burst);
+        *updated = now;
+        if *tokens < 1.0 {
+            self.0.refused.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        *tokens -= 1.0;
+        self.0.accepted.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Renders the handshakes accepted and refused so far, and the clients being tracked, in the Prometheus text
+    /// format.
+    pub fn render_metrics(&self) -> String {
+        let clients = self.0.clients.lock().unwrap().len();
+        format!(
+            "# HELP dispatch_handshakes_accepted_total Connections accepted under the handshake rate limit.\n\
+            # TYPE dispatch_handshakes_accepted_total counter\n\
+            dispatch_handshakes_accepted_total {}\n\
+            # HELP dispatch_handshakes_refused_total Connections refused for exceeding the handshake rate limit.\n\
+            # TYPE dispatch_handshakes_refused_total counter\n\
+            dispatch_handshakes_refused_total {}\n\
+            # HELP dispatch_handshake_limited_clients Client addresses whose recent handshakes are being counted.\n\
+            # TYPE dispatch_handshake_limited_clients gauge\n\
+            dispatch_handshake_limited_clients {}\n",
+            self.0.accepted.load(Ordering::Relaxed),
+            self.0.refused.load(Ordering::Relaxed),
+            clients
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(registry: &ConnectionRegistry, client: u32) -> ConnectionGuard {
+        let address = SocketAddr::from(([192, 0, 2, 1], 443));
+        registry.register(
+            SocketAddr::new(IpAddr::V4(client.into()), 50000),
+            Destination {
+                domain: None,
+                addr: address,
+            },
+            address,
+            [192, 168, 1, 2].into(),
+        )
+    }
+
+    #[test]
+    fn idle_clients_are_forgotten_least_recent_first() {
+        let registry = ConnectionRegistry::new();
+        let live = register(&registry, 0);
+        for client in 1..=MAX_IDLE_CLIENTS as u32 + 1 {
+            drop(register(&registry, client));
+        }
+
+        let stats = registry.client_stats();
+        assert_eq!(stats.len(), MAX_IDLE_CLIENTS * 3 / 4 + 1);
+        assert!(stats.contains_key(&live.client.ip()));
+        assert!(!stats.contains_key(&IpAddr::V4(1.into())));
+        assert!(stats.contains_key(&IpAddr::V4((MAX_IDLE_CLIENTS as u32 + 1).into())));
+        assert_eq!(
+            registry.stats()[&[192, 168, 1, 2].into()].total,
+            MAX_IDLE_CLIENTS as u64 + 2
+        );
+    }
+}
@@ -0,0 +1,686 @@
+//! A local control channel, over which the CLI talks to a running proxy.
+//!
+//! The proxy listens on a Unix domain socket (or a named pipe on Windows). Each connection carries a single request and
+//! its response, both encoded as a line of JSON.
+
+mod transport;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Notify,
+};
+
+use crate::{
+    apps,
+    connections::{Connection, ConnectionId, ConnectionRegistry, Usage},
+    debug,
+    dispatcher::{
+        Dispatch, RawWeightedAddress, WeightedAddress, WeightedIp, WeightedRoundRobinDispatcher,
+    },
+    dns::Resolver,
+    health,
+    portmap::PortMapping,
+    rules::{RuleInfo, RuleSet, Rules, Verdict},
+    socks::Destination,
+};
+
+pub use transport::{bind, connect, serve};
+
+/// Whether a proxy answers on the control socket.
+pub async fn is_running(path: &Path) -> bool {
+    transport::connect(path).await.is_ok()
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ControlArgs {
+    /// Path of the control socket (a named pipe on Windows) [default: control.sock in the data directory, or
+    /// \\.\pipe\dispatch-proxy on Windows]
+    #[arg(long = "control", value_name = "PATH")]
+    pub path: Option<PathBuf>,
+}
+
+impl ControlArgs {
+    pub fn path(&self) -> Result<PathBuf> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => transport::default_path(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Stats,
+    Reload,
+    /// Changes the weight of a dispatch address, given as a network interface name or IP address.
+    SetWeight {
+        address: String,
+        weight: NonZeroUsize,
+    },
+    /// Takes a dispatch address out of rotation until it is resumed.
+    Pause {
+        address: String,
+    },
+    Resume {
+        address: String,
+    },
+    Connections,
+    /// Usage of the proxy per client IP address.
+    Clients,
+    Rules,
+    /// Replaces the routing rules with the given ones, in the `--rules` format, if they are all valid.
+    SetRules {
+        rules: String,
+    },
+    /// Changes the log filter, or restores the one given on the command line when `filter` is `None`.
+    SetLogFilter {
+        filter: Option<String>,
+        /// Restore the filter given on the command line after this long.
+        duration_secs: Option<u64>,
+    },
+    /// Tells which rule a connection to a destination would match, and where it would go, without connecting.
+    TestRoute {
+        host: String,
+        port: u16,
+        /// The client to test as, or the loopback address.
+        client: Option<IpAddr>,
+        user: Option<String>,
+        app: Option<String>,
+    },
+    /// Closes a live connection.
+    Kill {
+        id: ConnectionId,
+    },
+    Stop,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Status(Status),
+    Stats {
+        addresses: Vec<AddressStats>,
+        /// The rules in effect, with how many connections each one decided.
+        #[serde(default)]
+        rules: Vec<RuleInfo>,
+    },
+    Reloaded {
+        addresses: Vec<String>,
+        changes: AddressChanges,
+    },
+    WeightSet {
+        addresses: Vec<IpAddr>,
+        weight: usize,
+    },
+    Paused {
+        addresses: Vec<IpAddr>,
+    },
+    Resumed {
+        addresses: Vec<IpAddr>,
+    },
+    Connections {
+        connections: Vec<ConnectionInfo>,
+    },
+    Clients {
+        clients: Vec<ClientStats>,
+    },
+    Killed {
+        id: ConnectionId,
+    },
+    Rules {
+        rules: Vec<RuleInfo>,
+    },
+    Route(RouteTest),
+    LogFilterSet {
+        filter: String,
+        reset_in_secs: Option<u64>,
+    },
+    Stopping {
+        /// How many active connections are drained before exiting.
+        connections: usize,
+        drain_timeout_secs: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub version: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub listen: SocketAddr,
+    pub accepting: bool,
+    pub connections: usize,
+    pub log_filter: Option<String>,
+    /// The file that logs are written to, when they aren't written to stdout or the journal.
+    pub log_file: Option<PathBuf>,
+    /// The address at which clients outside the LAN reach the proxy, when its port is mapped on the gateway.
+    #[serde(default)]
+    pub external: Option<SocketAddr>,
+    pub addresses: Vec<AddressStatus>,
+}
+
+/// Where a connection to a destination would go, as told by `dispatch test-route`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteTest {
+    /// The destination, as the rules see it.
+    pub destination: String,
+    /// The address the destination resolved to.
+    pub address: SocketAddr,
+    /// The rule that matched, or `None` when the connection is dispatched as usual.
+    pub rule: Option<String>,
+    pub route: Route,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+pub enum Route {
+    Denied,
+    /// Dispatched from the local address that is next in rotation.
+    Dispatched {
+        address: IpAddr,
+        interface: Option<String>,
+    },
+    /// Connected from whichever address the system routes the destination from.
+    Direct,
+    Routed {
+        address: IpAddr,
+        interface: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddressStatus {
+    pub address: IpAddr,
+    pub weight: usize,
+    pub paused: bool,
+    #[serde(default)]
+    pub over_quota: bool,
+    pub healthy: bool,
+}
+
+/// How the dispatch addresses changed on reload.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AddressChanges {
+    pub added: Vec<IpAddr>,
+    pub removed: Vec<IpAddr>,
+    pub reweighted: Vec<WeightChange>,
+}
+
+impl AddressChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.reweighted.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WeightChange {
+    pub address: IpAddr,
+    pub from: usize,
+    pub to: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddressStats {
+    pub address: IpAddr,
+    #[serde(flatten)]
+    pub stats: Usage,
+}
+
+/// Usage of the proxy by a SOCKS client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientStats {
+    pub client: IpAddr,
+    #[serde(flatten)]
+    pub stats: Usage,
+}
+
+/// A live connection, as reported to management clients.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub client: SocketAddr,
+    pub destination: String,
+    pub address: SocketAddr,
+    pub interface: IpAddr,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+}
+
+impl From<&Connection> for ConnectionInfo {
+    fn from(connection: &Connection) -> ConnectionInfo {
+        ConnectionInfo {
+            id: connection.id,
+            client: connection.client,
+            destination: connection.destination.to_string(),
+            address: connection.address,
+            interface: connection.interface,
+            bytes_up: connection.bytes_up(),
+            bytes_down: connection.bytes_down(),
+            duration_ms: connection.duration().as_millis() as u64,
+        }
+    }
+}
+
+/// State of the running proxy, as exposed and managed through the control channel.
+#[derive(Clone, Debug)]
+pub struct ControlState {
+    pub dispatcher: WeightedRoundRobinDispatcher,
+    pub registry: ConnectionRegistry,
+    pub rules: Rules,
+    pub resolver: Resolver,
+    /// The dispatch addresses as given on the command line, which are resolved again on reload.
+    pub addresses: Vec<RawWeightedAddress>,
+    pub listen: SocketAddr,
+    pub started: Instant,
+    /// Whether the SOCKS listener is accepting connections.
+    pub accepting: Arc<AtomicBool>,
+    /// Notified when the proxy should stop.
+    pub shutdown: Arc<Notify>,
+    /// How long active connections are given to close when stopping.
+    pub drain_timeout: Duration,
+    /// The port mapped on the gateway with `--map-port`.
+    pub port_mapping: PortMapping,
+}
+
+pub async fn handle(request: Request, state: &ControlState) -> Response {
+    match request {
+        Request::Status => Response::Status(status_of(state).await),
+        Request::Stats => {
+            let mut stats = state.registry.stats();
+            let addresses = state
+                .dispatcher
+                .ips()
+                .await
+                .into_iter()
+                .map(|address| AddressStats {
+                    address,
+                    stats: stats.remove(&address).unwrap_or_default(),
+                })
+                .collect();
+            Response::Stats {
+                addresses,
+                rules: state.rules.current().describe(),
+            }
+        }
+        Request::Reload => match reload_addresses(state).await {
+            Ok((addresses, changes)) => Response::Reloaded { addresses, changes },
+            Err(err) => Response::Error {
+                message: format!("{:#}", err),
+            },
+        },
+        Request::SetWeight { address, weight } => {
+            match update_weight(state, &address, weight).await {
+                Ok(addresses) => {
+                    tracing::info!(address, weight, "dispatch weight changed");
+                    Response::WeightSet {
+                        addresses,
+                        weight: weight.get(),
+                    }
+                }
+                Err(err) => Response::Error {
+                    message: format!("{:#}", err),
+                },
+            }
+        }
+        Request::Pause { address } => match pause_address(state, &address).await {
+            Ok(addresses) => {
+                tracing::info!(address, "dispatch address paused");
+                Response::Paused { addresses }
+            }
+            Err(err) => Response::Error {
+                message: format!("{:#}", err),
+            },
+        },
+        Request::Resume { address } => match resume_address(state, &address).await {
+            Ok(addresses) => {
+                tracing::info!(address, "dispatch address resumed");
+                Response::Resumed { addresses }
+            }
+            Err(err) => Response::Error {
+                message: format!("{:#}", err),
+            },
+        },
+        Request::Connections => Response::Connections {
+            connections: state
+                .registry
+                .list()
+                .iter()
+                .map(|connection| ConnectionInfo::from(&**connection))
+                .collect(),
+        },
+        Request::Clients => Response::Clients {
+            clients: client_stats(state),
+        },
+        Request::Rules => Response::Rules {
+            rules: state.rules.current().describe(),
+        },
+        Request::SetRules { rules } => match replace_rules(state, &rules) {
+            Ok(rules) => Response::Rules { rules },
+            Err(err) => Response::Error {
+                message: format!("{:#}", err),
+            },
+        },
+        Request::SetLogFilter {
+            filter,
+            duration_secs,
+        } => match set_log_filter(filter, duration_secs) {
+            Ok(filter) => Response::LogFilterSet {
+                filter,
+                reset_in_secs: duration_secs,
+            },
+            Err(err) => Response::Error {
+                message: format!("{:#}", err),
+            },
+        },
+        Request::TestRoute {
+            host,
+            port,
+            client,
+            user,
+            app,
+        } => match explain_route(state, &host, port, client, user, app).await {
+            Ok(route) => Response::Route(route),
+            Err(err) => Response::Error {
+                message: format!("{:#}", err),
+            },
+        },
+        Request::Kill { id } => {
+            if state.registry.kill(id) {
+                tracing::info!(id, "connection killed");
+                Response::Killed { id }
+            } else {
+                Response::Error {
+                    message: format!("There is no active connection with ID {}", id),
+                }
+            }
+        }
+        Request::Stop => {
+            tracing::info!("stop requested");
+            state.shutdown.notify_one();
+            Response::Stopping {
+                connections: state.registry.count(),
+                drain_timeout_secs: state.drain_timeout.as_secs(),
+            }
+        }
+    }
+}
+
+/// Resolves the dispatch addresses given on the command line again, and dispatches to them from now on. Returns the
+/// new addresses, and how they differ from the previous ones.
+pub async fn reload_addresses(state: &ControlState) -> Result<(Vec<String>, AddressChanges)> {
+    let addresses = WeightedAddress::resolve(state.addresses.clone())?;
+    let descriptions = addresses.iter().map(ToString::to_string).collect();
+
+    let before = state.dispatcher.weighted_ips().await;
+    state.dispatcher.set_addresses(addresses).await;
+    let after = state.dispatcher.weighted_ips().await;
+
+    let weight_of = |ips: &[WeightedIp], ip: IpAddr| {
+        ips.iter()
+            .find(|weighted| weighted.ip == ip)
+            .map(|weighted| weighted.weight.get())
+    };
+    let mut changes = AddressChanges::default();
+    for weighted in &after {
+        match weight_of(&before, weighted.ip) {
+            None => changes.added.push(weighted.ip),
+            Some(from) if from != weighted.weight.get() => changes.reweighted.push(WeightChange {
+                address: weighted.ip,
+                from,
+                to: weighted.weight.get(),
+            }),
+            Some(_) => {}
+        }
+    }
+    changes.removed = before
+        .iter()
+        .map(|weighted| weighted.ip)
+        .filter(|&ip| weight_of(&after, ip).is_none())
+        .collect();
+
+    tracing::info!(
+        addresses = ?descriptions,
+        added = ?changes.added,
+        removed = ?changes.removed,
+        reweighted = changes.reweighted.len(),
+        "reloaded dispatch addresses"
+    );
+    Ok((descriptions, changes))
+}
+
+/// Resolves the destination like a client's request, and tells where the connection would go. A dispatched connection is
+/// shown going from the next local address in rotation, which another connection may take first.
+async fn explain_route(
+    state: &ControlState,
+    host: &str,
+    port: u16,
+    client: Option<IpAddr>,
+    user: Option<String>,
+    app: Option<String>,
+) -> Result<RouteTest> {
+    let destination = match host.parse::<IpAddr>() {
+        Ok(ip) => Destination {
+            domain: None,
+            addr: SocketAddr::new(ip, port),
+        },
+        Err(_) => {
+            let sources = state.dispatcher.local_addresses().await;
+            let addrs = state.resolver.lookup(host, port, &sources).await?;
+            Destination {
+                domain: Some(host.to_string()),
+                addr: addrs[0],
+            }
+        }
+    };
+    let client = client.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let app = app.as_deref().map(apps::normalize);
+    let (rule, verdict) =
+        state
+            .rules
+            .explain(client, user.as_deref(), app.as_deref(), &destination)?;
+    let route = match verdict {
+        Verdict::Deny(_) => Route::Denied,
+        Verdict::Dispatch { .. } => {
+            let address = state.dispatcher.peek(&destination.addr).await?;
+            let interface = state.dispatcher.interface_of(address).await;
+            Route::Dispatched {
+                address,
+                interface: interface.map(|interface| interface.name),
+            }
+        }
+        Verdict::Direct { .. } => Route::Direct,
+        Verdict::Route { ip, interface, .. } => Route::Routed {
+            address: ip,
+            interface: interface.map(|interface| interface.name),
+        },
+    };
+    Ok(RouteTest {
+        destination: destination.to_string(),
+        address: destination.addr,
+        rule,
+        route,
+    })
+}
+
+/// Resolves a network interface name or IP address to the IP addresses it dispatches from.
+fn resolve_ips(address: &str) -> Result<Vec<IpAddr>> {
+    let raw = RawWeightedAddress::new(address.parse()?, NonZeroUsize::MIN);
+    Ok(WeightedAddress::resolve(vec![raw])?
+        .iter()
+        .flat_map(WeightedAddress::ips)
+        .collect())
+}
+
+fn not_dispatched_error(address: &str) -> eyre::Report {
+    eyre::eyre!("Traffic isn't dispatched to `{}`", address)
+}
+
+/// Changes the weight of every IP address of a network interface, or of a single IP address. Returns the IP addresses
+/// that were updated.
+async fn update_weight(
+    state: &ControlState,
+    address: &str,
+    weight: NonZeroUsize,
+) -> Result<Vec<IpAddr>> {
+    let mut updated = vec![];
+    for ip in resolve_ips(address)? {
+        if state.dispatcher.set_weight(ip, weight).await {
+            updated.push(ip);
+        }
+    }
+
+    if updated.is_empty() {
+        return Err(not_dispatched_error(address));
+    }
+    Ok(updated)
+}
+
+async fn pause_address(state: &ControlState, address: &str) -> Result<Vec<IpAddr>> {
+    let mut paused = vec![];
+    for ip in resolve_ips(address)? {
+        if state.dispatcher.pause(ip).await? {
+            paused.push(ip);
+        }
+    }
+
+    if paused.is_empty() {
+        return Err(not_dispatched_error(address));
+    }
+    Ok(paused)
+}
+
+async fn resume_address(state: &ControlState, address: &str) -> Result<Vec<IpAddr>> {
+    let mut resumed = vec![];
+    for ip in resolve_ips(address)? {
+        if state.dispatcher.resume(ip).await {
+            resumed.push(ip);
+        }
+    }
+
+    if resumed.is_empty() {
+        return Err(not_dispatched_error(address));
+    }
+    Ok(resumed)
+}
+
+async fn status_of(state: &ControlState) -> Status {
+    Status {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        pid: std::process::id(),
+        uptime_secs: state.started.elapsed().as_secs(),
+        listen: state.listen,
+        accepting: state.accepting.load(Ordering::Relaxed),
+        connections: state.registry.count(),
+        log_filter: debug::filter::current(),
+        log_file: debug::log_path().map(Path::to_path_buf),
+        external: state.port_mapping.external(),
+        addresses: address_statuses(state).await,
+    }
+}
+
+/// Validates the given rules, and swaps them in. Returns the rules now in effect.
+pub fn replace_rules(state: &ControlState, src: &str) -> Result<Vec<RuleInfo>> {
+    let rules = RuleSet::parse(src)?;
+    let described = rules.describe();
+    state.rules.replace(rules)?;
+    tracing::info!(count = described.len(), "routing rules replaced");
+    Ok(described)
+}
+
+/// Changes the log filter, for `duration_secs` if given. Returns the active filter.
+fn set_log_filter(filter: Option<String>, duration_secs: Option<u64>) -> Result<String> {
+    let Some(filter) = filter else {
+        debug::filter::reset()?;
+        tracing::info!("log filter reset");
+        return Ok(debug::filter::current().unwrap_or_default());
+    };
+
+    let generation = debug::filter::set(&filter)?;
+    tracing::info!(filter, duration_secs, "log filter changed");
+    if let Some(duration_secs) = duration_secs {
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(duration_secs)).await;
+            match debug::filter::reset_if_unchanged(generation) {
+                Ok(true) => tracing::info!("temporary log filter expired"),
+                Ok(false) => {}
+                Err(err) => tracing::warn!("{:?}", err),
+            }
+        });
+    }
+    Ok(filter)
+}
+
+/// Returns the usage by each client, heaviest first.
+pub fn client_stats(state: &ControlState) -> Vec<ClientStats> {
+    let mut clients = state
+        .registry
+        .client_stats()
+        .into_iter()
+        .map(|(client, stats)| ClientStats { client, stats })
+        .collect::<Vec<_>>();
+    clients
+        .sort_by_key(|client| std::cmp::Reverse(client.stats.bytes_up + client.stats.bytes_down));
+    clients
+}
+
+pub async fn address_statuses(state: &ControlState) -> Vec<AddressStatus> {
+    state
+        .dispatcher
+        .weighted_ips()
+        .await
+        .into_iter()
+        .map(|ip| AddressStatus {
+            address: ip.ip,
+            weight: ip.weight.get(),
+            paused: ip.paused,
+            over_quota: ip.over_quota,
+            healthy: health::is_healthy(ip.ip),
+        })
+        .collect()
+}
+
+/// Reads a single request from a control connection, and writes back its response.
+async fn serve_connection<S>(stream: S, state: ControlState) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        // The client only checked whether the proxy is running.
+        return Ok(());
+    }
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => handle(request, &state).await,
+        Err(err) => Response::Error {
+            message: format!("Invalid request: {}", err),
+        },
+    };
+
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(b'\n');
+    stream.write_all(&response).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::Help;
+use eyre::{Result, WrapErr};
+
+use super::{serve_connection, ControlState};
+
+#[cfg(unix)]
+pub use unix::*;
+#[cfg(windows)]
+pub use windows::*;
+
+fn already_running_error(path: &Path) -> eyre::Report {
+    eyre::eyre!(
+        "Another instance of dispatch-proxy is already listening on the control socket at {}",
+        path.to_string_lossy()
+    )
+    .suggestion("Pass `--control <PATH>` to give each instance its own control socket.")
+}
+
+fn not_running_error(path: &Path) -> eyre::Report {
+    eyre::eyre!(
+        "Couldn't reach a running dispatch-proxy on the control socket at {}",
+        path.to_string_lossy()
+    )
+    .suggestion("Start the proxy with `dispatch start`, or pass the `--control <PATH>` it was started with.")
+}
+
+async fn serve_and_report<S>(stream: S, state: ControlState)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    if let Err(err) = serve_connection(stream, state).await {
+        tracing::warn!("{:?}", err.wrap_err("Failed to answer a control request"));
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::*;
+    use crate::paths;
+
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(paths::data_dir()?.join("control.sock"))
+    }
+
+    /// Listens on the control socket, which is removed when dropped.
+    #[derive(Debug)]
+    pub struct ControlListener {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl Drop for ControlListener {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub async fn bind(path: &Path) -> Result<ControlListener> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(already_running_error(path));
+            }
+            // Left behind by an instance that didn't exit cleanly.
+            std::fs::remove_file(path).wrap_err("Failed to remove a stale control socket")?;
+        }
+
+        let listener = UnixListener::bind(path).wrap_err_with(|| {
+            format!(
+                "Failed to create the control socket at {}",
+                path.to_string_lossy()
+            )
+        })?;
+        // Anyone who can connect to the socket can stop the proxy, so it is restricted to the current user.
+        std::fs::set_permissions(path, Permissions::from_mode(0o600))
+            .wrap_err("Failed to restrict access to the control socket")?;
+
+        Ok(ControlListener {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub async fn serve(listener: ControlListener, state: ControlState) -> Result<()> {
+        loop {
+            let (stream, _) = listener
+                .listener
+                .accept()
+                .await
+                .wrap_err("Failed to accept a control connection")?;
+            tokio::spawn(serve_and_report(stream, state.clone()));
+        }
+    }
+
+    pub async fn connect(path: &Path) -> Result<UnixStream> {
+        UnixStream::connect(path)
+            .await
+            .map_err(|err| eyre::eyre!(err).wrap_err(not_running_error(path)))
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use tokio::net::windows::named_pipe::{
+        ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+    };
+
+    use super::*;
+
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(PathBuf::from(r"\\.\pipe\dispatch-proxy"))
+    }
+
+    #[derive(Debug)]
+    pub struct ControlListener {
+        server: NamedPipeServer,
+        path: PathBuf,
+    }
+
+    pub async fn bind(path: &Path) -> Result<ControlListener> {
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::PermissionDenied => already_running_error(path),
+                _ => eyre::eyre!(err).wrap_err(format!(
+                    "Failed to create the control pipe at {}",
+                    path.to_string_lossy()
+                )),
+            })?;
+
+        Ok(ControlListener {
+            server,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub async fn serve(listener: ControlListener, state: ControlState) -> Result<()> {
+        let ControlListener { mut server, path } = listener;
+        loop {
+            server
+                .connect()
+                .await
+                .wrap_err("Failed to accept a control connection")?;
+            // A new instance of the pipe must be created before handing the connected one over, so that clients
+            // can always connect.
+            let next = ServerOptions::new()
+                .create(&path)
+                .wrap_err("Failed to create the control pipe")?;
+            let stream = std::mem::replace(&mut server, next);
+            tokio::spawn(serve_and_report(stream, state.clone()));
+        }
+    }
+
+    pub async fn connect(path: &Path) -> Result<NamedPipeClient> {
+        ClientOptions::new()
+            .open(path)
+            .map_err(|err| eyre::eyre!(err).wrap_err(not_running_error(path)))
+    }
+}
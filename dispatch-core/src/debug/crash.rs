@@ -0,0 +1,118 @@
+//! Crash reports: when the proxy panics, everything known about the crash is written to a file in the data directory,
+//! so that it can be attached to an issue as a whole, rather than pieced together from the terminal and the logs.
+
+use std::{
+    fmt::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+
+use super::{
+    tail::{strip_ansi_escapes, LogTail},
+    Configuration,
+};
+use crate::paths;
+
+/// Writes crash reports, from the panic hook.
+pub struct CrashReporter {
+    metadata: Vec<(&'static str, String)>,
+    log_tail: LogTail,
+    /// The last crash report written, which the panic message points to.
+    pub path: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl CrashReporter {
+    pub fn new(metadata: Vec<(&'static str, String)>, log_tail: LogTail) -> CrashReporter {
+        CrashReporter {
+            metadata,
+            log_tail,
+            path: Arc::default(),
+        }
+    }
+
+    /// Wraps a panic hook so that a crash report is written before it runs.
+    #[allow(deprecated)]
+    pub fn wrap(
+        self,
+        hook: Box<dyn Fn(&std::panic::PanicInfo<'_>) + Send + Sync + 'static>,
+    ) -> Box<dyn Fn(&std::panic::PanicInfo<'_>) + Send + Sync + 'static> {
+        Box::new(move |pi| {
+            let res = self.write(pi);
+            *self.path.lock().unwrap_or_else(|err| err.into_inner()) = res.as_ref().ok().cloned();
+            hook(pi);
+            if let Err(err) = res {
+                eprintln!("Failed to write a crash report: {:#}", err);
+            }
+        })
+    }
+
+    #[allow(deprecated)]
+    fn write(&self, pi: &std::panic::PanicInfo<'_>) -> Result<PathBuf> {
+        let now = SystemTime::now();
+        // Colons aren't allowed in file names on Windows.
+        let timestamp = humantime::format_rfc3339_seconds(now)
+            .to_string()
+            .replace(':', "-");
+        let path = paths::data_dir()?.join(format!("crash-{}.txt", timestamp));
+        std::fs::write(&path, self.report(pi, now))
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    #[allow(deprecated)]
+    fn report(&self, pi: &std::panic::PanicInfo<'_>, now: SystemTime) -> String {
+        let payload = pi
+            .payload()
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| pi.payload().downcast_ref::<&str>().cloned())
+            .unwrap_or("<non string panic payload>");
+        let location = pi
+            .location()
+            .map(|loc| format!("{}:{}", loc.file(), loc.line()))
+            .unwrap_or_else(|| "<unknown>".into());
+        let thread = std::thread::current();
+
+        let mut report = String::new();
+        writeln!(
+            report,
+            "dispatch-proxy crashed at {}",
+            humantime::format_rfc3339_seconds(now)
+        )
+        .unwrap();
+        writeln!(report).unwrap();
+        writeln!(report, "Message:  {}", payload).unwrap();
+        writeln!(report, "Location: {}", location).unwrap();
+        writeln!(report, "Thread:   {}", thread.name().unwrap_or("<unnamed>")).unwrap();
+        writeln!(report).unwrap();
+        for (key, value) in &self.metadata {
+            writeln!(report, "{}: {}", key, value).unwrap();
+        }
+        writeln!(report, "Configuration: {}", Configuration).unwrap();
+
+        writeln!(report, "\nSpan trace:").unwrap();
+        // Fields are formatted with colors when logs are written to a terminal.
+        let span_trace = strip_ansi_escapes(&tracing_error::SpanTrace::capture().to_string());
+        if span_trace.trim().is_empty() {
+            writeln!(report, "(no active spans)").unwrap();
+        } else {
+            writeln!(report, "{}", span_trace.trim_end()).unwrap();
+        }
+
+        writeln!(report, "\nBacktrace:").unwrap();
+        writeln!(report, "{}", std::backtrace::Backtrace::force_capture()).unwrap();
+
+        writeln!(report, "Recent logs:").unwrap();
+        let lines = self.log_tail.lines();
+        if lines.is_empty() {
+            writeln!(report, "(empty)").unwrap();
+        }
+        for line in lines {
+            writeln!(report, "{}", line).unwrap();
+        }
+        report
+    }
+}
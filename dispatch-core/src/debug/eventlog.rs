@@ -0,0 +1,97 @@
+use std::{ffi::OsStr, fmt::Write, iter, os::windows::ffi::OsStrExt, ptr};
+
+use eyre::Result;
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context, Layer};
+use windows_sys::Win32::{
+    Foundation::HANDLE,
+    System::EventLog::{
+        DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+        EVENTLOG_WARNING_TYPE,
+    },
+};
+
+const EVENT_SOURCE: &str = "dispatch-proxy";
+
+/// Forwards warnings and errors to the Windows Event Log, so that they show up in Event Viewer under the
+/// `dispatch-proxy` source.
+pub struct EventLogLayer {
+    handle: HANDLE,
+}
+
+impl EventLogLayer {
+    pub fn new() -> Result<EventLogLayer> {
+        let source = to_wide(EVENT_SOURCE);
+        // SAFETY: `source` is a valid, null-terminated wide string that outlives the call.
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        if handle == 0 {
+            return Err(eyre::eyre!(std::io::Error::last_os_error())
+                .wrap_err("Failed to register the Windows Event Log source"));
+        }
+        Ok(EventLogLayer { handle })
+    }
+}
+
+impl Drop for EventLogLayer {
+    fn drop(&mut self) {
+        // SAFETY: `handle` was returned by a successful call to `RegisterEventSourceW`.
+        unsafe {
+            DeregisterEventSource(self.handle);
+        }
+    }
+}
+
+impl<S> Layer<S> for EventLogLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let event_type = match *event.metadata().level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => return,
+        };
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let message = to_wide(&visitor.message);
+        let strings = [message.as_ptr()];
+        // SAFETY: `strings` holds a single valid, null-terminated wide string that outlives the call.
+        unsafe {
+            ReportEventW(
+                self.handle,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                strings.len() as u16,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !self.message.is_empty() {
+            self.message.push(' ');
+        }
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
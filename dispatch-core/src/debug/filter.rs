@@ -0,0 +1,95 @@
+//! The log filter, which can be changed while the proxy is running, e.g. to trace the SOCKS handshakes for a few
+//! minutes while diagnosing intermittent failures.
+
+use std::sync::{Mutex, OnceLock};
+
+use eyre::{Result, WrapErr};
+use tracing::Subscriber;
+use tracing_subscriber::{reload, EnvFilter};
+
+type Reload = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+static RELOAD: OnceLock<Reload> = OnceLock::new();
+static STATE: Mutex<Option<FilterState>> = Mutex::new(None);
+
+struct FilterState {
+    /// The filter given on the command line, which resets restore.
+    initial: String,
+    current: String,
+    /// Incremented on every change, so that a temporary change doesn't revert a later one.
+    generation: u64,
+}
+
+fn parse(filter: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(filter).wrap_err_with(|| format!("Invalid log filter `{}`", filter))
+}
+
+/// Parses the filter given on the command line, which resets restore.
+pub fn init(initial: &str) -> Result<EnvFilter> {
+    let filter = parse(initial)?;
+    *STATE.lock().unwrap() = Some(FilterState {
+        initial: initial.to_string(),
+        current: initial.to_string(),
+        generation: 0,
+    });
+    Ok(filter)
+}
+
+/// Wraps the filter into a layer whose filter can be replaced later.
+pub fn reloadable<S>(filter: EnvFilter) -> reload::Layer<EnvFilter, S>
+where
+    S: Subscriber,
+{
+    let (layer, handle) = reload::Layer::new(filter);
+    let _ = RELOAD.set(Box::new(move |filter| {
+        handle
+            .reload(filter)
+            .wrap_err("Failed to change the log filter")
+    }));
+    layer
+}
+
+/// Returns the active log filter.
+pub fn current() -> Option<String> {
+    STATE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|state| state.current.clone())
+}
+
+/// Replaces the log filter. Returns the generation of the change, to be passed to `reset_if_unchanged`.
+pub fn set(filter: &str) -> Result<u64> {
+    let parsed = parse(filter)?;
+    let mut state = STATE.lock().unwrap();
+    let (Some(state), Some(reload)) = (state.as_mut(), RELOAD.get()) else {
+        return Err(eyre::eyre!("Logging isn't initialized"));
+    };
+
+    reload(parsed)?;
+    state.current = filter.to_string();
+    state.generation += 1;
+    Ok(state.generation)
+}
+
+/// Restores the log filter given on the command line.
+pub fn reset() -> Result<()> {
+    let initial = match STATE.lock().unwrap().as_ref() {
+        Some(state) => state.initial.clone(),
+        None => return Ok(()),
+    };
+    set(&initial).map(|_| ())
+}
+
+/// Restores the log filter given on the command line, unless it was changed again since the given generation.
+pub fn reset_if_unchanged(generation: u64) -> Result<bool> {
+    let unchanged = STATE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|state| state.generation == generation);
+    if unchanged {
+        reset()?;
+    }
+    Ok(unchanged)
+}
@@ -0,0 +1,406 @@
+use std::{
+    fmt::{Display, Formatter},
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use color_eyre::section::PanicMessage;
+use eyre::{Result, WrapErr};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_error::ErrorLayer;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer, Registry};
+
+use crate::{paths, redact};
+
+mod crash;
+#[cfg(windows)]
+mod eventlog;
+pub mod filter;
+#[cfg(feature = "otlp")]
+mod otlp;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod tail;
+
+static CONFIGURATION: OnceLock<String> = OnceLock::new();
+static LOG_PATH: OnceLock<PathBuf> = OnceLock::new();
+static EXPORTERS: OnceLock<Vec<&'static str>> = OnceLock::new();
+
+struct DispatchPanicMessage {
+    log_path: Arc<Mutex<Option<PathBuf>>>,
+    crash_report: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl PanicMessage for DispatchPanicMessage {
+    // `PanicInfo` is deprecated in favor of `PanicHookInfo`, but the latter isn't available on our minimum supported
+    // Rust version yet.
+    #[allow(deprecated)]
+    fn display(
+        &self,
+        pi: &std::panic::PanicInfo<'_>,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        use owo_colors::OwoColorize;
+
+        writeln!(
+            f,
+            "{}",
+            "Oh no! dispatch-proxy encountered a critical error and crashed.".red()
+        )?;
+
+        let payload = pi
+            .payload()
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| pi.payload().downcast_ref::<&str>().cloned())
+            .unwrap_or("<non string panic payload>");
+
+        write!(f, "Message:  ")?;
+        writeln!(f, "{}", payload.cyan())?;
+
+        // If known, print panic location.
+        write!(f, "Location: ")?;
+        if let Some(loc) = pi.location() {
+            write!(f, "{}", loc.file().purple())?;
+            write!(f, ":")?;
+            write!(f, "{}", loc.line().purple())?;
+        } else {
+            write!(f, "<unknown>")?;
+        }
+        writeln!(f)?;
+
+        if let Some(ref log_path) = *self.log_path.lock().unwrap() {
+            writeln!(
+                f,
+                "A complete history of the events that preceded this error is available at {}",
+                log_path.to_string_lossy()
+            )?;
+        }
+        if let Some(ref crash_report) = *self.crash_report.lock().unwrap() {
+            writeln!(
+                f,
+                "A crash report with the backtrace and recent logs was written to {}, please attach it to any issue \
+                 about this crash",
+                crash_report.to_string_lossy()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn get_file_writer() -> Result<(PathBuf, NonBlocking, WorkerGuard)> {
+    let log_path = paths::data_dir()?.join("logs.txt");
+    let (file_appender, guard) =
+        tracing_appender::non_blocking(File::create(&log_path).wrap_err_with(|| {
+            format!(
+                "Failed to create a log file at {}",
+                log_path.to_string_lossy()
+            )
+        })?);
+    Ok((log_path, file_appender, guard))
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Layers that are installed alongside the main log output, regardless of the log strategy.
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+#[cfg_attr(not(any(windows, feature = "otlp")), allow(unused_mut))]
+fn extra_layers(
+    options: &LogOptions,
+    guard: &mut LogGuard,
+) -> (Vec<BoxedLayer>, Vec<eyre::Report>) {
+    let mut layers: Vec<BoxedLayer> = vec![];
+    let mut errors = vec![];
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &options.otlp_endpoint {
+        match otlp::otlp_layer(endpoint) {
+            Ok((tracer_provider, layer)) => {
+                guard.tracer_provider = Some(tracer_provider);
+                layers.push(layer);
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    #[cfg(windows)]
+    if options.event_log {
+        match eventlog::EventLogLayer::new() {
+            Ok(layer) => layers.push(Box::new(layer)),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    (layers, errors)
+}
+
+/// An empty `Vec` of layers reports a maximum level of `OFF`, which would disable all other layers, so it is replaced
+/// with `None` instead.
+fn non_empty(layers: Vec<BoxedLayer>) -> Option<Vec<BoxedLayer>> {
+    (!layers.is_empty()).then_some(layers)
+}
+
+fn init_tracing_subscriber_with_appender(
+    appender: NonBlocking,
+    filter: EnvFilter,
+    extra_layers: Vec<BoxedLayer>,
+) {
+    let fmt_layer = fmt::layer()
+        .with_target(false)
+        .with_ansi(false)
+        .with_writer(appender);
+
+    tracing_subscriber::registry()
+        .with(non_empty(extra_layers))
+        .with(filter::reloadable(filter))
+        .with(fmt_layer)
+        .with(ErrorLayer::default())
+        .init();
+}
+
+fn init_tracing_subscriber_with_stdout(filter: EnvFilter, extra_layers: Vec<BoxedLayer>) {
+    let fmt_layer = fmt::layer().with_target(false);
+
+    tracing_subscriber::registry()
+        .with(non_empty(extra_layers))
+        .with(filter::reloadable(filter))
+        .with(fmt_layer)
+        .with(ErrorLayer::default())
+        .init();
+}
+
+#[cfg(target_os = "linux")]
+fn init_tracing_subscriber_with_journald(
+    journald_layer: tracing_journald::Layer,
+    filter: EnvFilter,
+    extra_layers: Vec<BoxedLayer>,
+) {
+    tracing_subscriber::registry()
+        .with(non_empty(extra_layers))
+        .with(filter::reloadable(filter))
+        .with(journald_layer)
+        .with(ErrorLayer::default())
+        .init();
+}
+
+/// Whether the standard streams of the process are connected to the systemd journal, which is the case when running
+/// as a systemd service.
+#[cfg(target_os = "linux")]
+pub fn is_journald_stream() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some()
+}
+
+/// Whether the process runs in a container, whose logs are collected from its standard streams, e.g. by `docker logs`.
+pub fn is_container() -> bool {
+    // Container entrypoints run as PID 1, and the main runtimes leave a mark.
+    std::process::id() == 1
+        || std::env::var_os("container").is_some()
+        || std::env::var_os("KUBERNETES_SERVICE_HOST").is_some()
+        || Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+}
+
+/// Metadata attached to crash and error reports.
+fn issue_metadata() -> Vec<(&'static str, String)> {
+    vec![
+        ("Version", env!("CARGO_PKG_VERSION").to_string()),
+        (
+            "OS",
+            sysinfo::System::long_os_version().unwrap_or("Unknown".into()),
+        ),
+        ("Command", redact::redact_args(std::env::args()).join(" ")),
+    ]
+}
+
+/// The file that logs are written to, if they are written to a file.
+pub fn log_path() -> Option<&'static Path> {
+    LOG_PATH.get().map(PathBuf::as_path)
+}
+
+/// The exporters which run on threads of their own, started as logging is installed.
+pub fn exporters() -> &'static [&'static str] {
+    EXPORTERS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Records a summary of the active configuration, which is attached to issue reports.
+pub fn set_configuration(summary: String) {
+    let _ = CONFIGURATION.set(summary);
+}
+
+/// Displays the configuration summary, which is only known once the command has started.
+struct Configuration;
+
+impl Display for Configuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match CONFIGURATION.get() {
+            Some(summary) => write!(f, "{}", summary),
+            None => write!(f, "Unknown"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LogStrategy {
+    File,
+    Stdout,
+    /// Log to the systemd journal using its native protocol, which preserves structured fields.
+    #[cfg(target_os = "linux")]
+    Journald,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogOptions {
+    pub strategy: LogStrategy,
+    /// Which spans and events to record, in the `tracing_subscriber::EnvFilter` syntax.
+    pub filter: String,
+    /// Also report warnings and errors to the Windows Event Log.
+    #[cfg(windows)]
+    pub event_log: bool,
+    /// Export spans to the OTLP/HTTP collector at this endpoint.
+    #[cfg(feature = "otlp")]
+    pub otlp_endpoint: Option<String>,
+    /// Report panics and fatal errors to the Sentry project with this DSN.
+    #[cfg(feature = "sentry")]
+    pub sentry_dsn: Option<String>,
+}
+
+/// Flushes pending logs and spans when dropped.
+#[derive(Default)]
+pub struct LogGuard {
+    file_guard: Option<WorkerGuard>,
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+    #[cfg(feature = "sentry")]
+    sentry: Option<::sentry::ClientInitGuard>,
+}
+
+impl LogGuard {
+    /// Reports an error which is about to terminate the process to the crash reporting service, if enabled.
+    #[cfg_attr(not(feature = "sentry"), allow(unused_variables))]
+    pub fn report_fatal_error(&self, err: &eyre::Report) {
+        #[cfg(feature = "sentry")]
+        if self.sentry.is_some() {
+            sentry::capture_fatal_error(err);
+        }
+    }
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(err) = tracer_provider.shutdown() {
+                eprintln!("Failed to export pending spans: {}", err);
+            }
+        }
+    }
+}
+
+pub fn install(options: LogOptions) -> Result<LogGuard> {
+    std::env::set_var("RUST_LIB_BACKTRACE", "full");
+
+    let shared_log_path = Arc::new(Mutex::new(None));
+
+    let metadata = issue_metadata();
+
+    let mut hook_builder = color_eyre::config::HookBuilder::default()
+        .issue_url(concat!(env!("CARGO_PKG_REPOSITORY"), "/issues/new"));
+    for (key, value) in &metadata {
+        hook_builder = hook_builder.add_issue_metadata(key, value.clone());
+    }
+    let log_tail = tail::LogTail::default();
+    let crash_reporter = crash::CrashReporter::new(metadata.clone(), log_tail.clone());
+    let (panic_hook, eyre_hook) = hook_builder
+        .add_issue_metadata("Configuration", Configuration)
+        .add_issue_metadata("Recent logs", log_tail.clone())
+        .panic_message(DispatchPanicMessage {
+            log_path: Arc::clone(&shared_log_path),
+            crash_report: Arc::clone(&crash_reporter.path),
+        })
+        .display_env_section(false)
+        .theme(color_eyre::config::Theme::new())
+        .try_into_hooks()?;
+    eyre_hook.install()?;
+    std::panic::set_hook(crash_reporter.wrap(panic_hook.into_panic_hook()));
+
+    let filter = filter::init(&options.filter)?;
+
+    let mut guard = LogGuard::default();
+
+    // Installed after color-eyre so that its panic hook wraps color-eyre's.
+    #[cfg(feature = "sentry")]
+    if let Some(dsn) = &options.sentry_dsn {
+        guard.sentry = Some(sentry::init(dsn, &metadata));
+    }
+    let (mut extra_layers, extra_errors) = extra_layers(&options, &mut guard);
+    #[cfg_attr(not(any(feature = "otlp", feature = "sentry")), allow(unused_mut))]
+    let mut exporters = vec![];
+    #[cfg(feature = "otlp")]
+    if guard.tracer_provider.is_some() {
+        exporters.push("OTLP");
+    }
+    #[cfg(feature = "sentry")]
+    if guard.sentry.is_some() {
+        exporters.push("Sentry");
+    }
+    let _ = EXPORTERS.set(exporters);
+    extra_layers.push(Box::new(log_tail));
+
+    guard.file_guard = match options.strategy {
+        LogStrategy::File => match get_file_writer() {
+            Ok((log_path, file_appender, guard)) => {
+                let _ = LOG_PATH.set(log_path.clone());
+                shared_log_path.lock().unwrap().replace(log_path);
+
+                init_tracing_subscriber_with_appender(file_appender, filter, extra_layers);
+
+                Some(guard)
+            }
+            Err(err) => {
+                init_tracing_subscriber_with_stdout(filter, extra_layers);
+
+                tracing::error!("{:?}", err);
+                tracing::info!(
+                    "Failed to access the log file, all logs will be reported here instead."
+                );
+
+                None
+            }
+        },
+        LogStrategy::Stdout => {
+            init_tracing_subscriber_with_stdout(filter, extra_layers);
+            None
+        }
+        #[cfg(target_os = "linux")]
+        LogStrategy::Journald => match tracing_journald::layer() {
+            Ok(journald_layer) => {
+                // Fields are left unprefixed so that they can be queried directly, as in
+                // `journalctl -u dispatch DESTINATION=...`.
+                init_tracing_subscriber_with_journald(
+                    journald_layer.with_field_prefix(None),
+                    filter,
+                    extra_layers,
+                );
+                None
+            }
+            Err(err) => {
+                init_tracing_subscriber_with_stdout(filter, extra_layers);
+
+                tracing::error!("{:?}", eyre::eyre!(err));
+                tracing::info!(
+                    "Failed to connect to the systemd journal, all logs will be reported here instead."
+                );
+
+                None
+            }
+        },
+    };
+
+    for err in extra_errors {
+        tracing::warn!("{:?}", err);
+    }
+
+    Ok(guard)
+}
@@ -0,0 +1,30 @@
+use eyre::{Result, WrapErr};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_opentelemetry::OpenTelemetryLayer;
+
+use super::BoxedLayer;
+
+/// Creates a tracer provider which exports spans in batches to the OTLP/HTTP collector at `endpoint`, along with the
+/// layer that feeds it our `tracing` spans.
+pub fn otlp_layer(endpoint: &str) -> Result<(SdkTracerProvider, BoxedLayer)> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .wrap_err_with(|| format!("Failed to create an OTLP exporter for `{}`", endpoint))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+
+    let layer = OpenTelemetryLayer::new(provider.tracer(env!("CARGO_PKG_NAME")));
+
+    Ok((provider, Box::new(layer)))
+}
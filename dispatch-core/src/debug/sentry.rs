@@ -0,0 +1,28 @@
+use sentry::ClientInitGuard;
+
+/// Sends panics and fatal errors to the Sentry project identified by `dsn`, tagged with the same metadata as the
+/// auto-generated issue reports.
+pub fn init(dsn: &str, metadata: &[(&'static str, String)]) -> ClientInitGuard {
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            ..Default::default()
+        },
+    ));
+
+    sentry::configure_scope(|scope| {
+        for (key, value) in metadata {
+            scope.set_tag(&key.to_lowercase(), value);
+        }
+    });
+
+    guard
+}
+
+pub fn capture_fatal_error(err: &eyre::Report) {
+    let err: &(dyn std::error::Error + Send + Sync + 'static) = err.as_ref();
+    let mut event = sentry::event_from_error(err);
+    event.level = sentry::Level::Fatal;
+    sentry::capture_event(event);
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Formatter, Write},
+    sync::{Arc, Mutex},
+};
+
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context, Layer};
+
+/// How many log lines are kept for crash reports.
+const CAPACITY: usize = 200;
+/// How many of them are attached to issue reports.
+const ISSUE_LINES: usize = 20;
+/// Log lines are truncated to this many characters in issue reports, to keep issue URLs under GitHub's length limit.
+const MAX_LINE_LENGTH: usize = 200;
+
+/// Keeps the last log lines in memory, so that they can be attached to issue and crash reports.
+///
+/// Lines are recorded the same way as in the logs, so client and destination addresses are only hidden if `--redact`
+/// is enabled.
+#[derive(Clone, Default)]
+pub struct LogTail(Arc<Mutex<VecDeque<String>>>);
+
+impl<S> Layer<S> for LogTail
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // Fields must be formatted before locking: logged errors embed an issue report, which displays this tail.
+        let mut visitor = LineVisitor::default();
+        event.record(&mut visitor);
+
+        let line = format!("{} {}", event.metadata().level(), visitor.line);
+
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+impl LogTail {
+    /// The lines kept, oldest first. None are returned if the panic being reported happened while recording a line.
+    pub fn lines(&self) -> Vec<String> {
+        match self.0.try_lock() {
+            Ok(lines) => lines.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// Formats the end of the tail as a single cell of the issue metadata table.
+impl Display for LogTail {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lines = self.0.lock().unwrap();
+        if lines.is_empty() {
+            return write!(f, "(empty)");
+        }
+        let skip = lines.len().saturating_sub(ISSUE_LINES);
+        for (idx, line) in lines.iter().skip(skip).enumerate() {
+            if idx > 0 {
+                write!(f, "<br>")?;
+            }
+            match line.char_indices().nth(MAX_LINE_LENGTH) {
+                Some((end, _)) => write!(f, "<code>{}…</code>", escape(&line[..end]))?,
+                None => write!(f, "<code>{}</code>", escape(line))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct LineVisitor {
+    line: String,
+}
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !self.line.is_empty() {
+            self.line.push(' ');
+        }
+        if field.name() == "message" {
+            self.line.push_str(&summarize(&format!("{:?}", value)));
+        } else {
+            let _ = write!(self.line, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Reduces a message to a single line. Logged errors are multi-line reports, of which only the chain of causes is
+/// kept, and not the location, span trace, suggestions nor issue URL.
+fn summarize(message: &str) -> String {
+    let message = strip_ansi_escapes(message);
+    message
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty())
+        .take_while(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn strip_ansi_escapes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the control sequence up to and including its final byte, e.g. `\x1b[1;31m`.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Escapes characters that would break out of a Markdown table cell, or be interpreted as HTML.
+fn escape(line: &str) -> String {
+    line.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('|', "&#124;")
+}
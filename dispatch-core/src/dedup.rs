@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long identical warnings are coalesced for after the first one is logged.
+const WINDOW: Duration = Duration::from_secs(60);
+/// How often summaries of coalesced warnings are logged.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Repeated {
+    first_seen: Instant,
+    count: u64,
+}
+
+/// Coalesces identical warnings, so that failure storms (e.g. hundreds of clients failing to resolve the same domain)
+/// don't flood the logs.
+///
+/// The first occurrence of a warning is logged as usual, while those which follow within a minute are only counted
+/// and summarized once the minute has elapsed.
+#[derive(Clone, Default)]
+pub struct WarningDeduplicator(Arc<Mutex<HashMap<String, Repeated>>>);
+
+impl Debug for WarningDeduplicator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WarningDeduplicator")
+            .field("pending", &self.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl WarningDeduplicator {
+    pub fn new() -> WarningDeduplicator {
+        WarningDeduplicator::default()
+    }
+
+    /// Returns whether a warning should be logged, or whether it is a repetition of one that was logged recently.
+    pub fn should_log(&self, warning: String) -> bool {
+        let mut warnings = self.0.lock().unwrap();
+        match warnings.get_mut(&warning) {
+            Some(repeated) => {
+                repeated.count += 1;
+                false
+            }
+            None => {
+                warnings.insert(
+                    warning,
+                    Repeated {
+                        first_seen: Instant::now(),
+                        count: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Logs how many times each warning was repeated, for those whose window has elapsed.
+    fn flush(&self) {
+        let mut warnings = self.0.lock().unwrap();
+        warnings.retain(|warning, repeated| {
+            if repeated.first_seen.elapsed() < WINDOW {
+                return true;
+            }
+            if repeated.count > 0 {
+                tracing::warn!(
+                    repeated = repeated.count,
+                    "{} (repeated {} times in the last minute)",
+                    warning,
+                    repeated.count
+                );
+            }
+            false
+        });
+    }
+
+    /// Periodically logs summaries of coalesced warnings.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.flush();
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! The denial log, which records every connection the proxy refused as a line of JSON, e.g.
+//!
+//! ```text
+//! {"time":"2026-10-17T09:12:40.518Z","reason":"rule","client":"192.168.1.20","user":"alice","destination":"10.0.0.1:22","address":"10.0.0.1:22","rule":"@private deny"}
+//! {"time":"2026-10-17T09:12:41.003Z","reason":"authentication","client":"192.168.1.31","user":"admin"}
+//! {"time":"2026-10-17T09:12:41.270Z","reason":"handshake-rate","client":"203.0.113.7","rule":"handshake-rate=20"}
+//! ```
+//!
+//! Unlike the audit log, it only holds security-relevant events, so that they can be reviewed or alerted on without
+//! sifting through allowed connections. It is rotated once it reaches a given size, keeping the previous files as
+//! `<path>.1` (the most recent) to `<path>.5`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+
+use crate::{rules::Denied, users::User};
+
+/// How many rotated files are kept besides the current one.
+const ROTATED_FILES: usize = 5;
+
+/// Why a connection was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    /// A routing rule or the policy of the user denied the destination.
+    Rule,
+    /// The client or user used up their data quota.
+    Quota,
+    /// The client didn't authenticate, or failed to.
+    Authentication,
+    /// The client isn't allowed to use the proxy, as per `--allow-from` and `--deny-from`.
+    Client,
+    /// The client opened more connections per second than `--handshake-rate` allows.
+    HandshakeRate,
+    /// The client has as many connections open as `--max-connections-per-client` allows.
+    ClientConnections,
+}
+
+/// A connection refused by the proxy.
+#[derive(Debug, Serialize)]
+pub struct DenialRecord {
+    time: String,
+    reason: Reason,
+    client: IpAddr,
+    /// The user the client authenticated as, or tried to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// The destination as requested, with its domain name if it had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    /// The address the destination resolved to, unless it was denied before its domain was resolved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<SocketAddr>,
+    /// What refused the connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+}
+
+impl DenialRecord {
+    /// A connection refused before the handshake.
+    pub fn refused(reason: Reason, client: IpAddr, rule: Option<String>) -> DenialRecord {
+        DenialRecord {
+            time: now(),
+            reason,
+            client,
+            user: None,
+            destination: None,
+            address: None,
+            rule,
+        }
+    }
+
+    pub fn unauthenticated(client: IpAddr, user: Option<String>) -> DenialRecord {
+        DenialRecord {
+            user,
+            ..DenialRecord::refused(Reason::Authentication, client, None)
+        }
+    }
+
+    pub fn denied(client: IpAddr, user: Option<&User>, denied: &Denied) -> DenialRecord {
+        DenialRecord {
+            time: now(),
+            reason: denied.reason,
+            client,
+            user: user.map(|user| user.name.clone()),
+            destination: Some(denied.destination.to_string()),
+            address: denied.destination.resolved(),
+            rule: Some(denied.rule.clone()),
+        }
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+/// Appends denial records to a file, rotating it once it grows past a maximum size.
+///
+/// Writes happen on a dedicated thread so that connections never block on disk I/O.
+#[derive(Clone, Debug)]
+pub struct DenialLog(Sender<DenialRecord>);
+
+impl DenialLog {
+    /// Opens the denial log at `path`, creating it if needed and appending to it otherwise.
+    pub fn open(path: &Path, max_size: u64) -> Result<DenialLog> {
+        let file = RotatingFile::open(path.to_path_buf(), max_size)?;
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("denials".into())
+            .spawn(move || write_records(file, receiver))
+            .wrap_err("Failed to spawn the denial log thread")?;
+
+        Ok(DenialLog(sender))
+    }
+
+    pub fn record(&self, record: DenialRecord) {
+        // The writer thread only stops if the file becomes unwritable, in which case the error has already been
+        // reported.
+        let _ = self.0.send(record);
+    }
+}
+
+/// A file that is moved aside, along with the previous ones, when a write would grow it past its maximum size.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64) -> Result<RotatingFile> {
+        let file = open(&path)?;
+        let size = file.metadata().map_or(0, |metadata| metadata.len());
+        Ok(RotatingFile {
+            path,
+            max_size,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.writer.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        for index in (1..ROTATED_FILES).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+        self.writer = BufWriter::new(open(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("Failed to open the denial log `{}`", path.display()))
+}
+
+fn write_records(mut file: RotatingFile, receiver: Receiver<DenialRecord>) {
+    while let Ok(record) = receiver.recv() {
+        let mut res = write_record(&mut file, &record);
+        // Records that are already queued are written together, and flushed once the queue is empty, so that the log
+        // is up to date whenever the proxy is idle.
+        while res.is_ok() {
+            match receiver.try_recv() {
+                Ok(record) => res = write_record(&mut file, &record),
+                Err(_) => {
+                    res = file.writer.flush().map_err(Into::into);
+                    break;
+                }
+            }
+        }
+        if let Err(err) = res {
+            tracing::error!("{:?}", err.wrap_err("Failed to write to the denial log"));
+            return;
+        }
+    }
+}
+
+fn write_record(file: &mut RotatingFile, record: &DenialRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write(&line)
+}
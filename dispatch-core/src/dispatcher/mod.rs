@@ -0,0 +1,29 @@
+//! Dispatchers, which pick the local address that each outgoing connection is made from.
+
+#[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+mod plugin;
+mod weighted_rr;
+
+use std::net::{IpAddr, SocketAddr};
+
+use eyre::Result;
+
+use crate::net::NamedInterface;
+
+#[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+pub use plugin::Plugin;
+pub use weighted_rr::{
+    RawInterface, RawWeightedAddress, WeightedAddress, WeightedIp, WeightedRoundRobinDispatcher,
+};
+
+/// Picks the local address to connect to a remote address from.
+#[async_trait::async_trait]
+pub trait Dispatch {
+    async fn dispatch(&self, remote_address: &SocketAddr) -> Result<IpAddr>;
+
+    /// The local addresses that connections can currently be dispatched from.
+    async fn local_addresses(&self) -> Vec<IpAddr>;
+
+    /// The network interface a local address belongs to, when it was given by name.
+    async fn interface_of(&self, ip: IpAddr) -> Option<NamedInterface>;
+}
@@ -0,0 +1,212 @@
+//! Dispatch strategies loaded at runtime from dynamic libraries, to experiment with other ways of picking the local
+//! address of connections without forking the proxy. A plugin exports these functions with the C ABI:
+//!
+//! ```text
+//! struct dispatch_address { uint8_t ip[16]; uint16_t port; };
+//! struct dispatch_candidate { uint8_t ip[16]; uint64_t weight; };
+//!
+//! // The version of this interface that the plugin implements, currently 1.
+//! uint32_t dispatch_plugin_version(void);
+//!
+//! // Returns the index of the candidate that the connection to `remote` is made from, or a negative number to leave
+//! // the connection to the weighted round robin.
+//! int64_t dispatch_plugin_select(const struct dispatch_address *remote,
+//!                                const struct dispatch_candidate *candidates, size_t len, bool peek);
+//! ```
+//!
+//! IP addresses are in network byte order, with IPv4 addresses mapped to IPv6 (`::ffff:a.b.c.d`). The candidates are
+//! the local addresses which aren't paused or over their quota, of the address family of the remote address, in the
+//! order they were given. `peek` is set when the proxy only asks where a connection would go, e.g. for
+//! `dispatch test-route`, in which case the plugin shouldn't count it. Calls are made one at a time, so plugins don't
+//! need to synchronize their state, but they shouldn't block since connections wait for them.
+
+use std::{
+    ffi::{c_void, CStr, CString},
+    fmt::{Debug, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Help;
+use eyre::Result;
+
+use super::WeightedIp;
+use crate::redact::redact;
+
+/// The version of the interface that plugins must implement.
+const VERSION: u32 = 1;
+
+#[repr(C)]
+struct RawAddress {
+    ip: [u8; 16],
+    port: u16,
+}
+
+#[repr(C)]
+struct RawCandidate {
+    ip: [u8; 16],
+    weight: u64,
+}
+
+type VersionFn = unsafe extern "C" fn() -> u32;
+type SelectFn = unsafe extern "C" fn(*const RawAddress, *const RawCandidate, usize, bool) -> i64;
+
+/// A dispatch strategy loaded from a dynamic library.
+pub struct Plugin {
+    path: PathBuf,
+    select: SelectFn,
+    // Kept open for as long as `select` may be called.
+    _library: Library,
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Plugin> {
+        let library = Library::open(path).map_err(|err| {
+            eyre::eyre!(
+                "Couldn't load the dispatcher plugin `{}`: {}",
+                path.display(),
+                err
+            )
+        })?;
+        let symbol = |name: &CStr| {
+            library.symbol(name).ok_or_else(|| {
+                eyre::eyre!(
+                    "The dispatcher plugin `{}` doesn't export `{}`",
+                    path.display(),
+                    name.to_string_lossy()
+                )
+                .suggestion(
+                    "Export the functions of the plugin interface with `extern \"C\"` and `#[no_mangle]`, or \
+                    without C++ name mangling",
+                )
+            })
+        };
+
+        // SAFETY: the symbols are functions with these signatures, as the interface requires.
+        let version = unsafe {
+            std::mem::transmute::<*mut c_void, VersionFn>(symbol(c"dispatch_plugin_version")?)
+        };
+        let version = unsafe { version() };
+        if version != VERSION {
+            return Err(eyre::eyre!(
+                "The dispatcher plugin `{}` implements version {} of the plugin interface, but this proxy supports \
+                version {}",
+                path.display(),
+                version,
+                VERSION
+            ))
+            .suggestion("Rebuild the plugin against the interface of this version of dispatch");
+        }
+        let select = unsafe {
+            std::mem::transmute::<*mut c_void, SelectFn>(symbol(c"dispatch_plugin_select")?)
+        };
+
+        Ok(Plugin {
+            path: path.to_owned(),
+            select,
+            _library: library,
+        })
+    }
+
+    /// The local address that the plugin picks among the candidates to connect to the remote address from, if any.
+    pub(super) fn select(
+        &self,
+        remote_addr: &SocketAddr,
+        candidates: &[&WeightedIp],
+        peek: bool,
+    ) -> Option<IpAddr> {
+        let remote = RawAddress {
+            ip: octets(remote_addr.ip()),
+            port: remote_addr.port(),
+        };
+        let raw_candidates = candidates
+            .iter()
+            .map(|candidate| RawCandidate {
+                ip: octets(candidate.ip),
+                weight: usize::from(candidate.weight) as u64,
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: the pointers are valid for the duration of the call, and the dispatcher lock serializes calls.
+        let index =
+            unsafe { (self.select)(&remote, raw_candidates.as_ptr(), raw_candidates.len(), peek) };
+        if index < 0 {
+            return None;
+        }
+        match candidates.get(index as usize) {
+            Some(candidate) => Some(candidate.ip),
+            None => {
+                tracing::warn!(
+                    "The dispatcher {} picked candidate {} of {} for `{}`, dispatching with the round robin instead",
+                    self,
+                    index,
+                    candidates.len(),
+                    redact(remote_addr)
+                );
+                None
+            }
+        }
+    }
+}
+
+impl Debug for Plugin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+impl Display for Plugin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "plugin `{}`", self.path.display())
+    }
+}
+
+/// An IP address as IPv6 octets, with IPv4 addresses mapped to IPv6.
+fn octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// A dynamic library, closed when dropped.
+struct Library(*mut c_void);
+
+// SAFETY: the handle of a dynamic library can be used from any thread.
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    fn open(path: &Path) -> Result<Library, String> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| "the path contains a NUL byte".to_owned())?;
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(last_error());
+        }
+        Ok(Library(handle))
+    }
+
+    fn symbol(&self, name: &CStr) -> Option<*mut c_void> {
+        let symbol = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+        (!symbol.is_null()).then_some(symbol)
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.0) };
+    }
+}
+
+/// The error of the last failed call to `dlopen`.
+fn last_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    if error.is_null() {
+        return "unknown error".to_owned();
+    }
+    unsafe { CStr::from_ptr(error) }
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,613 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::Arc,
+};
+
+use color_eyre::Help;
+use eyre::{Context, Result};
+use network_interface::NetworkInterfaceConfig;
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+use crate::{
+    net::{get_valid_addresses, NamedInterface},
+    redact::redact,
+};
+
+use super::Dispatch;
+#[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+use super::Plugin;
+
+#[derive(Clone, Debug)]
+pub struct RawWeightedAddress {
+    interface: RawInterface,
+    weight: NonZeroUsize,
+}
+
+impl FromStr for RawWeightedAddress {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Self> {
+        let mut items = src.split('/');
+
+        let interface: RawInterface = items.next().unwrap().parse()?;
+
+        let weight = match items.next() {
+            Some(priority) => priority.parse()?,
+            None => NonZeroUsize::new(1).unwrap(),
+        };
+
+        Ok(RawWeightedAddress { interface, weight })
+    }
+}
+
+impl RawWeightedAddress {
+    pub fn new(interface: RawInterface, weight: NonZeroUsize) -> RawWeightedAddress {
+        RawWeightedAddress { interface, weight }
+    }
+
+    pub fn interface(&self) -> &RawInterface {
+        &self.interface
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RawInterface(String);
+
+impl RawInterface {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for RawInterface {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Self> {
+        Ok(RawInterface(src.to_string()))
+    }
+}
+
+impl Display for RawInterface {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Interface {
+    Named {
+        name: String,
+        index: u32,
+        ipv4: Option<Ipv4Addr>,
+        ipv6: Option<Ipv6Addr>,
+    },
+    Ip(IpAddr),
+}
+
+#[derive(Clone, Debug)]
+pub struct WeightedAddress {
+    interface: Interface,
+    weight: NonZeroUsize,
+}
+
+impl WeightedAddress {
+    /// Returns the network interface, when it was given by name rather than by IP address.
+    pub fn named_interface(&self) -> Option<NamedInterface> {
+        match &self.interface {
+            Interface::Named { name, index, .. } => Some(NamedInterface {
+                name: name.clone(),
+                index: *index,
+            }),
+            Interface::Ip(_) => None,
+        }
+    }
+
+    /// Returns the local IP addresses that traffic to this address is dispatched from.
+    pub fn ips(&self) -> Vec<IpAddr> {
+        match self.interface {
+            Interface::Named { ipv4, ipv6, .. } => ipv4
+                .map(IpAddr::V4)
+                .into_iter()
+                .chain(ipv6.map(IpAddr::V6))
+                .collect(),
+            Interface::Ip(ip) => vec![ip],
+        }
+    }
+
+    pub fn resolve(addresses: Vec<RawWeightedAddress>) -> Result<Vec<WeightedAddress>> {
+        let interfaces = network_interface::NetworkInterface::show()?;
+        let interfaces_by_name = interfaces
+            .iter()
+            .map(|interface| (interface.name.as_str(), interface))
+            .collect::<HashMap<_, _>>();
+
+        let mut resolved = Vec::with_capacity(addresses.len());
+
+        'interfaces: for RawWeightedAddress { interface, weight } in addresses {
+            if let Some(net_interface) = interfaces_by_name.get(interface.as_str()) {
+                let mut ipv4_addrs = vec![];
+                let mut ipv6_addrs = vec![];
+
+                let addresses = get_valid_addresses(&net_interface.addr);
+
+                for addr in addresses {
+                    match addr {
+                        IpAddr::V4(v4) => {
+                            ipv4_addrs.push(v4);
+                        }
+                        IpAddr::V6(v6) => {
+                            ipv6_addrs.push(v6);
+                        }
+                    }
+                }
+
+                let ipv4 = if let Some(ipv4) = ipv4_addrs.into_iter().next() {
+                    if ipv4.is_loopback() {
+                        return Err(eyre::eyre!(
+                            "Local address `{}` is a loopback address",
+                            ipv4
+                        ));
+                    }
+                    Some(ipv4)
+                } else {
+                    None
+                };
+
+                let ipv6 = if let Some(ipv6) = ipv6_addrs.into_iter().next() {
+                    if ipv6.is_loopback() {
+                        return Err(eyre::eyre!(
+                            "Local address `{}` is a loopback address",
+                            ipv6
+                        ));
+                    }
+                    Some(ipv6)
+                } else {
+                    None
+                };
+
+                if ipv4.is_none() && ipv6.is_none() {
+                    return Err(eyre::eyre!(
+                        "No IP addresses found for network interface `{}`",
+                        net_interface.name
+                    ));
+                }
+
+                resolved.push(WeightedAddress {
+                    interface: Interface::Named {
+                        name: net_interface.name.clone(),
+                        index: net_interface.index,
+                        ipv4,
+                        ipv6,
+                    },
+                    weight,
+                });
+                continue 'interfaces;
+            }
+
+            let ip: IpAddr = interface.as_str().parse().with_context(|| {
+                format!(
+                    "Failed to parse `{}` as an IP address or network interface name",
+                    interface.as_str()
+                )
+            })?;
+
+            resolved.push(WeightedAddress {
+                interface: Interface::Ip(ip),
+                weight,
+            });
+        }
+
+        Ok(resolved)
+    }
+}
+
+impl Display for WeightedAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match &self.interface {
+            Interface::Named {
+                name, ipv4, ipv6, ..
+            } => {
+                f.write_fmt(format_args!("{}/{}", name, self.weight))?;
+                if let Some(ipv4) = ipv4 {
+                    f.write_fmt(format_args!(" ({})", ipv4))?;
+                }
+                if let Some(ipv6) = ipv6 {
+                    f.write_fmt(format_args!(" ({})", ipv6))?;
+                }
+            }
+            Interface::Ip(ip) => {
+                f.write_fmt(format_args!("{}/{}", ip, self.weight))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WeightedIp {
+    pub ip: IpAddr,
+    pub weight: NonZeroUsize,
+    /// Paused addresses are kept, along with their weight, but aren't dispatched to until they are resumed.
+    pub paused: bool,
+    /// Addresses of interfaces which have used up their data quota aren't dispatched to until it resets.
+    pub over_quota: bool,
+    /// The network interface the address belongs to, when it was given by name.
+    pub interface: Option<NamedInterface>,
+}
+
+impl WeightedIp {
+    /// Whether connections can be dispatched to the address.
+    pub fn is_active(&self) -> bool {
+        !self.paused && !self.over_quota
+    }
+}
+
+#[derive(Debug)]
+struct WeightedRoundRobinDispatcherInner {
+    ipv4: State,
+    ipv6: State,
+    /// The plugin that picks the local addresses instead of the round robin, if any.
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    plugin: Option<Arc<Plugin>>,
+}
+
+#[derive(Debug)]
+struct State {
+    ips: Vec<WeightedIp>,
+    ip_idx: usize,
+    count: usize,
+}
+
+impl WeightedRoundRobinDispatcherInner {
+    fn new(addresses: Vec<WeightedAddress>) -> WeightedRoundRobinDispatcherInner {
+        debug_assert!(
+            !addresses.is_empty(),
+            "dispatcher should have at least one address"
+        );
+
+        let mut inner = WeightedRoundRobinDispatcherInner {
+            ipv4: State {
+                ips: vec![],
+                ip_idx: 0,
+                count: 0,
+            },
+            ipv6: State {
+                ips: vec![],
+                ip_idx: 0,
+                count: 0,
+            },
+            #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+            plugin: None,
+        };
+        for address in addresses {
+            inner.add(address);
+        }
+        inner
+    }
+
+    fn add(&mut self, address: WeightedAddress) {
+        for ip in address.ips() {
+            let state = match ip {
+                IpAddr::V4(_) => &mut self.ipv4,
+                IpAddr::V6(_) => &mut self.ipv6,
+            };
+            let interface = address.named_interface();
+            match state.ips.iter_mut().find(|weighted| weighted.ip == ip) {
+                Some(weighted) => {
+                    weighted.weight = address.weight;
+                    weighted.interface = interface;
+                }
+                None => state.ips.push(WeightedIp {
+                    ip,
+                    weight: address.weight,
+                    paused: false,
+                    over_quota: false,
+                    interface,
+                }),
+            }
+        }
+    }
+
+    fn state_of(&mut self, ip: IpAddr) -> &mut State {
+        match ip {
+            IpAddr::V4(_) => &mut self.ipv4,
+            IpAddr::V6(_) => &mut self.ipv6,
+        }
+    }
+
+    fn find_mut(&mut self, ip: IpAddr) -> Option<&mut WeightedIp> {
+        self.state_of(ip)
+            .ips
+            .iter_mut()
+            .find(|weighted| weighted.ip == ip)
+    }
+
+    fn ips(&self) -> impl Iterator<Item = &WeightedIp> {
+        self.ipv4.ips.iter().chain(&self.ipv6.ips)
+    }
+
+    fn dispatch(&mut self, remote_addr: &SocketAddr) -> Result<IpAddr> {
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        if let Some(ip) = self.plugged(remote_addr, false)? {
+            return Ok(ip);
+        }
+
+        let state = self.select_state(remote_addr)?;
+
+        while !state.ips[state.ip_idx].is_active() {
+            state.count = 0;
+            state.ip_idx = (state.ip_idx + 1) % state.ips.len();
+        }
+        let ip = &state.ips[state.ip_idx];
+
+        state.count += 1;
+        if state.count == usize::from(ip.weight) {
+            state.count = 0;
+            state.ip_idx = (state.ip_idx + 1) % state.ips.len();
+        }
+
+        Ok(ip.ip)
+    }
+
+    /// The local address that the next connection to the remote address would be dispatched from, without dispatching
+    /// it.
+    fn peek(&mut self, remote_addr: &SocketAddr) -> Result<IpAddr> {
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        if let Some(ip) = self.plugged(remote_addr, true)? {
+            return Ok(ip);
+        }
+
+        let state = self.select_state(remote_addr)?;
+        let mut ip_idx = state.ip_idx;
+        while !state.ips[ip_idx].is_active() {
+            ip_idx = (ip_idx + 1) % state.ips.len();
+        }
+        Ok(state.ips[ip_idx].ip)
+    }
+
+    /// The local address that the plugin picks for the remote address, if there is a plugin and it picks one.
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    fn plugged(&mut self, remote_addr: &SocketAddr, peek: bool) -> Result<Option<IpAddr>> {
+        let Some(plugin) = self.plugin.clone() else {
+            return Ok(None);
+        };
+        let state = self.select_state(remote_addr)?;
+        let candidates = state
+            .ips
+            .iter()
+            .filter(|weighted| weighted.is_active())
+            .collect::<Vec<_>>();
+        Ok(plugin.select(remote_addr, &candidates, peek))
+    }
+
+    fn select_state(&mut self, remote_addr: &SocketAddr) -> Result<&mut State> {
+        let state = match remote_addr.ip() {
+            IpAddr::V4(_) => &mut self.ipv4,
+            IpAddr::V6(_) => &mut self.ipv6,
+        };
+
+        if state.ips.is_empty() {
+            return Err(eyre::eyre!(
+                "Address type mismatch: no configured local address or interface can connect to \
+                remote address `{}` ({}) because the address types are incompatible",
+                redact(remote_addr),
+                addr_type(remote_addr.ip())
+            )
+            .suggestion(format!(
+                "Please ensure that the local addresses or network interfaces you have \
+                configured support {}",
+                addr_type(remote_addr.ip())
+            ))
+            .suggestion(
+                "If your IPv6 uplink is broken, start the proxy with `--prefer ipv4` to connect over \
+                IPv4 whenever the destination supports it",
+            ));
+        }
+
+        if state.ips.iter().all(|ip| ip.paused) {
+            return Err(eyre::eyre!(
+                "All the local addresses that can connect to remote address `{}` ({}) are paused",
+                redact(remote_addr),
+                addr_type(remote_addr.ip())
+            )
+            .suggestion("Resume one of them with `dispatch resume <ADDRESS>`"));
+        }
+        if state.ips.iter().all(|ip| !ip.is_active()) {
+            return Err(eyre::eyre!(
+                "All the local addresses that can connect to remote address `{}` ({}) are paused or have used up \
+                their data quota",
+                redact(remote_addr),
+                addr_type(remote_addr.ip())
+            )
+            .suggestion("Raise the data quota with `--quota`, or wait for it to reset"));
+        }
+
+        Ok(state)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WeightedRoundRobinDispatcher(Arc<Mutex<WeightedRoundRobinDispatcherInner>>);
+
+impl WeightedRoundRobinDispatcher {
+    pub fn new(addresses: Vec<WeightedAddress>) -> WeightedRoundRobinDispatcher {
+        WeightedRoundRobinDispatcher(Arc::new(Mutex::new(
+            WeightedRoundRobinDispatcherInner::new(addresses),
+        )))
+    }
+
+    /// The local address that the next connection to the remote address would be dispatched from, e.g. to tell where a
+    /// connection would go without making it.
+    pub async fn peek(&self, remote_addr: &SocketAddr) -> Result<IpAddr> {
+        self.0.lock().await.peek(remote_addr)
+    }
+
+    /// Returns all local IP addresses that traffic is dispatched to.
+    pub async fn ips(&self) -> Vec<IpAddr> {
+        self.weighted_ips()
+            .await
+            .into_iter()
+            .map(|ip| ip.ip)
+            .collect()
+    }
+
+    /// Returns all local IP addresses that traffic is dispatched to, along with their weights.
+    pub async fn weighted_ips(&self) -> Vec<WeightedIp> {
+        self.0.lock().await.ips().cloned().collect()
+    }
+
+    /// Replaces the addresses that traffic is dispatched to. Connections that are already established are unaffected.
+    /// Addresses that are kept stay paused.
+    pub async fn set_addresses(&self, addresses: Vec<WeightedAddress>) {
+        let mut dispatcher = self.0.lock().await;
+        let kept = dispatcher
+            .ips()
+            .filter(|weighted| weighted.paused || weighted.over_quota)
+            .map(|weighted| (weighted.ip, weighted.paused, weighted.over_quota))
+            .collect::<Vec<_>>();
+
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        let plugin = dispatcher.plugin.take();
+        *dispatcher = WeightedRoundRobinDispatcherInner::new(addresses);
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        {
+            dispatcher.plugin = plugin;
+        }
+        for (ip, paused, over_quota) in kept {
+            if let Some(weighted) = dispatcher.find_mut(ip) {
+                weighted.paused = paused;
+                weighted.over_quota = over_quota;
+            }
+        }
+    }
+
+    /// Picks the local addresses of connections with a plugin instead of the round robin. The addresses that the
+    /// plugin chooses from are still those of the dispatcher, and connections it leaves are dispatched as before.
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    pub async fn set_plugin(&self, plugin: Plugin) {
+        self.0.lock().await.plugin = Some(Arc::new(plugin));
+    }
+
+    /// Starts dispatching to an address, or updates its weight if traffic is already dispatched to it.
+    pub async fn add_address(&self, address: WeightedAddress) {
+        self.0.lock().await.add(address);
+    }
+
+    /// Changes the weight of an address. Returns whether traffic is dispatched to that address.
+    pub async fn set_weight(&self, ip: IpAddr, weight: NonZeroUsize) -> bool {
+        let mut dispatcher = self.0.lock().await;
+        let state = dispatcher.state_of(ip);
+        match state.ips.iter_mut().find(|weighted| weighted.ip == ip) {
+            Some(weighted) => {
+                weighted.weight = weight;
+                state.count = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops dispatching new connections to an address until it is resumed, while keeping its weight. Returns whether
+    /// traffic is dispatched to that address.
+    pub async fn pause(&self, ip: IpAddr) -> Result<bool> {
+        let mut dispatcher = self.0.lock().await;
+        if !dispatcher.ips().any(|weighted| weighted.ip == ip) {
+            return Ok(false);
+        }
+        if !dispatcher
+            .ips()
+            .any(|weighted| weighted.ip != ip && !weighted.paused)
+        {
+            return Err(eyre::eyre!(
+                "`{}` is the last active dispatch address and can't be paused",
+                ip
+            ));
+        }
+
+        if let Some(weighted) = dispatcher.find_mut(ip) {
+            weighted.paused = true;
+        }
+        Ok(true)
+    }
+
+    /// Starts dispatching to a paused address again. Returns whether traffic is dispatched to that address.
+    pub async fn resume(&self, ip: IpAddr) -> bool {
+        match self.0.lock().await.find_mut(ip) {
+            Some(weighted) => {
+                weighted.paused = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops or starts dispatching to an address whose interface has used up its data quota, or whose quota has reset.
+    pub async fn set_over_quota(&self, ip: IpAddr, over_quota: bool) {
+        if let Some(weighted) = self.0.lock().await.find_mut(ip) {
+            weighted.over_quota = over_quota;
+        }
+    }
+
+    /// Stops dispatching to an address. Connections that are already established are unaffected.
+    pub async fn remove_address(&self, ip: IpAddr) -> Result<()> {
+        let mut dispatcher = self.0.lock().await;
+        if dispatcher.ipv4.ips.len() + dispatcher.ipv6.ips.len() == 1 {
+            return Err(eyre::eyre!(
+                "`{}` is the last dispatch address and can't be removed",
+                ip
+            ));
+        }
+
+        let state = dispatcher.state_of(ip);
+        let idx = state
+            .ips
+            .iter()
+            .position(|weighted| weighted.ip == ip)
+            .ok_or_else(|| eyre::eyre!("Traffic isn't dispatched to `{}`", ip))?;
+        state.ips.remove(idx);
+        if state.ip_idx >= state.ips.len() {
+            state.ip_idx = 0;
+        }
+        state.count = 0;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Dispatch for WeightedRoundRobinDispatcher {
+    #[instrument(level = "debug", skip_all, fields(remote_addr = %redact(remote_addr)))]
+    async fn dispatch(&self, remote_addr: &SocketAddr) -> Result<IpAddr> {
+        let mut dispatcher = self.0.lock().await;
+        dispatcher.dispatch(remote_addr)
+    }
+
+    async fn local_addresses(&self) -> Vec<IpAddr> {
+        let dispatcher = self.0.lock().await;
+        dispatcher
+            .ips()
+            .filter(|weighted| weighted.is_active())
+            .map(|weighted| weighted.ip)
+            .collect()
+    }
+
+    async fn interface_of(&self, ip: IpAddr) -> Option<NamedInterface> {
+        let dispatcher = self.0.lock().await;
+        let interface = dispatcher
+            .ips()
+            .find(|weighted| weighted.ip == ip)
+            .and_then(|weighted| weighted.interface.clone());
+        interface
+    }
+}
+
+fn addr_type(addr: IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
@@ -0,0 +1,932 @@
+//! Resolution of the domain names requested by clients, with the nameservers given on the command line or else those of
+//! the system, queried from the dispatch addresses, or with the system resolver if they can't be read.
+//!
+//! With per-interface resolution, the local address to connect from is picked before the domain is resolved, and the
+//! domain is then resolved over that interface, with the nameservers associated with it. Carriers often answer with
+//! the CDN nodes closest to their own network, which are only the best choice for traffic going through that network.
+//!
+//! With the `encrypted-dns` feature, nameservers can also be queried over TLS or HTTPS.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts},
+    net::runtime::TokioRuntimeProvider,
+    system_conf, TokioResolver,
+};
+use tokio::net::lookup_host;
+
+use crate::{
+    dispatcher::{RawInterface, RawWeightedAddress, WeightedAddress},
+    rfc6724,
+    rules::is_same_or_subdomain,
+};
+
+/// How nameservers are queried.
+#[derive(Clone, Debug)]
+pub enum Protocol {
+    /// Over UDP, falling back to TCP for large answers.
+    Plain,
+    /// DNS over TLS, checking that the server has a certificate for the given name.
+    #[cfg(feature = "encrypted-dns")]
+    Tls { server_name: Arc<str> },
+    /// DNS over HTTPS, on `/dns-query` unless another path is given.
+    #[cfg(feature = "encrypted-dns")]
+    Https {
+        server_name: Arc<str>,
+        path: Option<Arc<str>>,
+    },
+}
+
+impl Protocol {
+    fn default_port(&self) -> u16 {
+        match self {
+            Protocol::Plain => 53,
+            #[cfg(feature = "encrypted-dns")]
+            Protocol::Tls { .. } => 853,
+            #[cfg(feature = "encrypted-dns")]
+            Protocol::Https { .. } => 443,
+        }
+    }
+}
+
+/// A DNS server, in the form of `[tls://|https://]<ip>[:port][#server-name[/path]][@interface]`.
+#[derive(Clone, Debug)]
+pub struct Nameserver {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    /// The network interface that queries go through, and, with per-interface resolution, the interface whose
+    /// connections the nameserver resolves domains for.
+    pub interface: Option<RawInterface>,
+}
+
+fn invalid_nameserver_error(src: &str) -> eyre::Report {
+    eyre::eyre!("`{}` isn't the address of a nameserver", src).suggestion(
+        "Nameservers are given as an IP address, an optional port, and an optional network interface, \
+        e.g. `1.1.1.1`, `[2620:fe::fe]:53` or `192.168.1.1@eth0`, and encrypted nameservers with their \
+        server name, e.g. `tls://1.1.1.1#cloudflare-dns.com` or `https://9.9.9.9#dns.quad9.net`",
+    )
+}
+
+fn parse_protocol(scheme: Option<&str>, server_name: Option<&str>, src: &str) -> Result<Protocol> {
+    match (scheme, server_name) {
+        (None, None) => Ok(Protocol::Plain),
+        (None, Some(_)) => Err(eyre::eyre!(
+            "The nameserver `{}` has a server name, which is only used with `tls://` and `https://`",
+            src
+        )),
+        #[cfg(feature = "encrypted-dns")]
+        (Some("tls"), Some(server_name)) => Ok(Protocol::Tls {
+            server_name: server_name.into(),
+        }),
+        #[cfg(feature = "encrypted-dns")]
+        (Some("https"), Some(server_name)) => Ok(match server_name.split_once('/') {
+            Some((server_name, path)) => Protocol::Https {
+                server_name: server_name.into(),
+                path: Some(format!("/{}", path).into()),
+            },
+            None => Protocol::Https {
+                server_name: server_name.into(),
+                path: None,
+            },
+        }),
+        #[cfg(feature = "encrypted-dns")]
+        (Some("tls" | "https"), None) => Err(eyre::eyre!(
+            "The encrypted nameserver `{}` has no server name to check its certificate against",
+            src
+        )
+        .suggestion("Add the server name after a `#`, e.g. `tls://1.1.1.1#cloudflare-dns.com`")),
+        #[cfg(not(feature = "encrypted-dns"))]
+        (Some("tls" | "https"), _) => Err(eyre::eyre!(
+            "The nameserver `{}` is encrypted, which requires the `encrypted-dns` feature",
+            src
+        )
+        .suggestion("Install dispatch with `cargo install dispatch-proxy --features encrypted-dns`")),
+        (Some(scheme), _) => Err(eyre::eyre!(
+            "Unsupported nameserver protocol `{}://` in `{}`",
+            scheme,
+            src
+        )
+        .suggestion("Use `tls://` for DNS over TLS, or `https://` for DNS over HTTPS")),
+    }
+}
+
+impl FromStr for Nameserver {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Nameserver> {
+        let (rest, interface) = match src.rsplit_once('@') {
+            Some((rest, interface)) => (rest, Some(interface.parse()?)),
+            None => (src, None),
+        };
+        let (scheme, rest) = match rest.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), rest),
+            None => (None, rest),
+        };
+        let (addr, server_name) = match rest.split_once('#') {
+            Some((addr, server_name)) => (addr, Some(server_name)),
+            None => (rest, None),
+        };
+
+        let protocol = parse_protocol(scheme, server_name, src)?;
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => match addr.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, protocol.default_port()),
+                Err(_) => return Err(invalid_nameserver_error(src)),
+            },
+        };
+
+        Ok(Nameserver {
+            addr,
+            protocol,
+            interface,
+        })
+    }
+}
+
+impl Display for Nameserver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match &self.protocol {
+            Protocol::Plain => write!(f, "{}", self.addr)?,
+            #[cfg(feature = "encrypted-dns")]
+            Protocol::Tls { server_name } => write!(f, "tls://{}#{}", self.addr, server_name)?,
+            #[cfg(feature = "encrypted-dns")]
+            Protocol::Https { server_name, path } => write!(
+                f,
+                "https://{}#{}{}",
+                self.addr,
+                server_name,
+                path.as_deref().unwrap_or_default()
+            )?,
+        }
+        if let Some(interface) = &self.interface {
+            write!(f, "@{}", interface)?;
+        }
+        Ok(())
+    }
+}
+
+/// A nameserver for a domain and its subdomains, in the form of `<domain>=<nameserver>`.
+#[derive(Clone, Debug)]
+pub struct ScopedNameserver {
+    /// A lowercase domain name, without a trailing dot.
+    pub domain: String,
+    pub nameserver: Nameserver,
+}
+
+impl FromStr for ScopedNameserver {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<ScopedNameserver> {
+        let (domain, nameserver) = src.split_once('=').ok_or_else(|| {
+            eyre::eyre!("`{}` isn't a domain-scoped nameserver", src).suggestion(
+                "Domain-scoped nameservers are given as `<domain>=<nameserver>`, e.g. \
+                `corp.example=10.0.0.53@eth1`",
+            )
+        })?;
+        if domain.starts_with("*.") {
+            return Err(eyre::eyre!("Invalid domain `{}`", domain).suggestion(
+                "Nameservers for a domain already resolve its subdomains, remove the leading `*.`",
+            ));
+        }
+        Ok(ScopedNameserver {
+            domain: normalize_host(domain)?,
+            nameserver: nameserver.parse()?,
+        })
+    }
+}
+
+impl Display for ScopedNameserver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}={}", self.domain, self.nameserver)
+    }
+}
+
+/// A static address for a domain, in the form of `<domain>=<ip>`.
+#[derive(Clone, Debug)]
+pub struct HostOverride {
+    pub domain: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for HostOverride {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<HostOverride> {
+        let (domain, ip) = src.split_once('=').ok_or_else(|| {
+            eyre::eyre!("`{}` isn't a host override", src).suggestion(
+                "Host overrides are given as `<domain>=<ip>`, e.g. `example.com=10.0.0.5`",
+            )
+        })?;
+        let ip = ip
+            .parse()
+            .wrap_err_with(|| format!("`{}` isn't an IP address", ip))?;
+        Ok(HostOverride {
+            domain: normalize_host(domain)?,
+            ip,
+        })
+    }
+}
+
+fn normalize_host(domain: &str) -> Result<String> {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    if domain.is_empty() || domain.contains(|c: char| c.is_whitespace() || c == '/') {
+        return Err(eyre::eyre!("`{}` isn't a domain name", domain));
+    }
+    Ok(domain.to_ascii_lowercase())
+}
+
+fn parse_hosts_line(line: &str) -> Result<(IpAddr, Vec<String>)> {
+    let mut fields = line.split_whitespace();
+    let ip = fields
+        .next()
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| eyre::eyre!("Expected an IP address followed by domains"))?;
+    let domains = fields.map(normalize_host).collect::<Result<Vec<_>>>()?;
+    if domains.is_empty() {
+        return Err(eyre::eyre!("Expected domains after the IP address"));
+    }
+    Ok((ip, domains))
+}
+
+/// Static addresses for domains, which take precedence over DNS.
+#[derive(Clone, Debug, Default)]
+pub struct Hosts {
+    entries: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    /// Parses entries in the format of the system hosts file: an IP address followed by the domains it's for, one per
+    /// line. Fails if any line is invalid.
+    pub fn parse(src: &str) -> Result<Hosts> {
+        let mut hosts = Hosts::default();
+        for (index, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (ip, domains) = parse_hosts_line(line).wrap_err_with(|| {
+                format!("Invalid hosts entry on line {}: `{}`", index + 1, line)
+            })?;
+            for domain in domains {
+                hosts.insert(HostOverride { domain, ip });
+            }
+        }
+        Ok(hosts)
+    }
+
+    pub fn read(path: &Path) -> Result<Hosts> {
+        let src = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read the hosts file `{}`", path.display()))?;
+        Hosts::parse(&src).wrap_err_with(|| format!("Invalid hosts file `{}`", path.display()))
+    }
+
+    /// Adds an address to a domain, after the addresses it already has.
+    pub fn insert(&mut self, host: HostOverride) {
+        let ips = self.entries.entry(host.domain).or_default();
+        if !ips.contains(&host.ip) {
+            ips.push(host.ip);
+        }
+    }
+
+    /// Merges the entries of other hosts into these.
+    pub fn extend(&mut self, other: Hosts) {
+        for (domain, ips) in other.entries {
+            for ip in ips {
+                self.insert(HostOverride {
+                    domain: domain.clone(),
+                    ip,
+                });
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn get(&self, domain: &str) -> Option<&[IpAddr]> {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        self.entries
+            .get(&domain.to_ascii_lowercase())
+            .map(Vec::as_slice)
+    }
+}
+
+/// How long answers of the system resolver are cached, since it doesn't report their TTL.
+const SYSTEM_TTL: Duration = Duration::from_secs(30);
+/// How many domains are cached at most.
+const CACHE_CAPACITY: usize = 4096;
+
+/// Which IP version to connect over first, when a destination can be reached over both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Prefer {
+    // The order of RFC 6724 when racing the addresses of a domain, which favors IPv6 when there is a local IPv6
+    // address, and IPv4 when picking the interface to resolve a domain over.
+    #[default]
+    Auto,
+    Ipv4,
+    Ipv6,
+}
+
+/// How domains are resolved.
+#[derive(Clone, Debug)]
+pub struct ResolverOptions {
+    /// The nameservers to query in turn, instead of the system resolver.
+    pub nameservers: Vec<Nameserver>,
+    /// Resolve domains over the interface that the connection goes through.
+    pub per_interface: bool,
+    /// How long answers are cached at most. Zero disables the cache.
+    pub cache_max_ttl: Duration,
+    /// How long failures are cached. Zero disables caching them.
+    pub negative_ttl: Duration,
+    /// How long to wait for an answer at most. Zero disables the timeout.
+    pub timeout: Duration,
+    /// Nameservers for specific domains and their subdomains, which take precedence over the other nameservers.
+    pub scoped: Vec<ScopedNameserver>,
+    /// Static addresses for domains, which are never queried.
+    pub hosts: Hosts,
+    /// Which IP version the addresses of a domain start with.
+    pub prefer: Prefer,
+}
+
+/// Resolves the domain names requested by clients.
+#[derive(Clone, Debug)]
+pub struct Resolver {
+    /// The configured nameservers, or else those of the system, or `None` to use the system resolver when they can't be
+    /// read.
+    nameservers: Option<Arc<NameserverSet>>,
+    per_interface: Option<Arc<PerInterface>>,
+    /// The domains with nameservers of their own, the most specific domains first.
+    scoped: Arc<[(String, NameserverSet)]>,
+    cache: Arc<Cache>,
+    hosts: Arc<Hosts>,
+    timeout: Duration,
+    prefer: Prefer,
+}
+
+#[derive(Debug)]
+struct PerInterface {
+    nameservers: Vec<Nameserver>,
+    /// The resolver of each local address, created on first use since addresses can change on reload.
+    resolvers: Mutex<HashMap<IpAddr, TokioResolver>>,
+}
+
+/// The domain, and the local address it was resolved over with per-interface resolution.
+type CacheKey = (String, Option<IpAddr>);
+
+/// The addresses of a domain, or why it couldn't be resolved.
+type Answer = Result<Vec<IpAddr>, Arc<str>>;
+
+#[derive(Debug)]
+struct CacheEntry {
+    answer: Answer,
+    expires: Instant,
+}
+
+/// Caches answers until their TTL expires, for at most `max_ttl`, so that bursts of connections to the same domain
+/// don't each wait for a query. Failures are cached for `negative_ttl`, so that a domain that doesn't resolve or an
+/// unresponsive nameserver doesn't hold up every connection.
+#[derive(Debug)]
+struct Cache {
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl Cache {
+    fn get(&self, key: &CacheKey) -> Option<Answer> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(key);
+            return None;
+        }
+        Some(entry.answer.clone())
+    }
+
+    fn insert(&self, key: CacheKey, ips: Vec<IpAddr>, valid_until: Instant) {
+        let now = Instant::now();
+        self.store(key, Ok(ips), valid_until.min(now + self.max_ttl));
+    }
+
+    fn insert_failure(&self, key: CacheKey, err: &eyre::Report) {
+        let message = format!("{:#}", err);
+        self.store(key, Err(message.into()), Instant::now() + self.negative_ttl);
+    }
+
+    fn store(&self, key: CacheKey, answer: Answer, expires: Instant) {
+        let now = Instant::now();
+        if expires <= now {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_CAPACITY {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= CACHE_CAPACITY {
+            let evicted = entries.keys().next().cloned();
+            if let Some(evicted) = evicted {
+                entries.remove(&evicted);
+            }
+        }
+        entries.insert(key, CacheEntry { answer, expires });
+    }
+}
+
+fn name_server_config(nameserver: &Nameserver, bind_addr: Option<SocketAddr>) -> NameServerConfig {
+    let ip = nameserver.addr.ip();
+    let mut config = match &nameserver.protocol {
+        Protocol::Plain => NameServerConfig::udp_and_tcp(ip),
+        #[cfg(feature = "encrypted-dns")]
+        Protocol::Tls { server_name } => NameServerConfig::tls(ip, Arc::clone(server_name)),
+        #[cfg(feature = "encrypted-dns")]
+        Protocol::Https { server_name, path } => {
+            NameServerConfig::https(ip, Arc::clone(server_name), path.clone())
+        }
+    };
+    for connection in &mut config.connections {
+        connection.port = nameserver.addr.port();
+        connection.bind_addr = bind_addr;
+    }
+    config
+}
+
+/// Returns the address of the interface to send queries to the nameserver from, if it was given one.
+fn bind_addr(nameserver: &Nameserver) -> Result<Option<SocketAddr>> {
+    let Some(interface) = &nameserver.interface else {
+        return Ok(None);
+    };
+    let ipv4 = nameserver.addr.is_ipv4();
+    let addresses = WeightedAddress::resolve(vec![RawWeightedAddress::new(
+        interface.clone(),
+        NonZeroUsize::MIN,
+    )])?;
+    addresses
+        .iter()
+        .flat_map(WeightedAddress::ips)
+        .find(|ip| ip.is_ipv4() == ipv4)
+        .map(|ip| Some(SocketAddr::new(ip, 0)))
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "The nameserver `{}` can't be reached through `{}`, which has no {} address",
+                nameserver,
+                interface,
+                if ipv4 { "IPv4" } else { "IPv6" }
+            )
+        })
+}
+
+/// Nameservers queried in turn. Queries are sent through the interface of the nameservers that were given one, and
+/// from the dispatch addresses, in turn, to the others, so that resolution doesn't bypass the load balancing or leak
+/// onto a link that isn't dispatched to.
+#[derive(Debug)]
+struct NameserverSet {
+    nameservers: Vec<Nameserver>,
+    /// The resolver sending queries from each dispatch address, created on first use since addresses can change on
+    /// reload.
+    resolvers: Mutex<HashMap<Option<IpAddr>, TokioResolver>>,
+    next: AtomicUsize,
+}
+
+impl NameserverSet {
+    fn new(nameservers: Vec<Nameserver>) -> Result<NameserverSet> {
+        // Fails early if an interface doesn't exist.
+        for nameserver in &nameservers {
+            bind_addr(nameserver)?;
+        }
+        Ok(NameserverSet {
+            nameservers,
+            resolvers: Mutex::default(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the resolver sending queries from the next dispatch address that can reach one of the nameservers
+    /// given without an interface.
+    fn resolver(&self, sources: &[IpAddr]) -> Result<TokioResolver> {
+        let usable = sources
+            .iter()
+            .filter(|source| {
+                self.nameservers.iter().any(|nameserver| {
+                    nameserver.interface.is_none() && nameserver.addr.is_ipv4() == source.is_ipv4()
+                })
+            })
+            .collect::<Vec<_>>();
+        let via = match usable.len() {
+            0 => None,
+            len => Some(*usable[self.next.fetch_add(1, Ordering::Relaxed) % len]),
+        };
+
+        let mut resolvers = self.resolvers.lock().unwrap();
+        if let Some(resolver) = resolvers.get(&via) {
+            return Ok(resolver.clone());
+        }
+        let resolver = self.build(via)?;
+        resolvers.insert(via, resolver.clone());
+        Ok(resolver)
+    }
+
+    fn build(&self, via: Option<IpAddr>) -> Result<TokioResolver> {
+        let mut configs = vec![];
+        for nameserver in &self.nameservers {
+            let bind_addr = match (&nameserver.interface, via) {
+                (Some(_), _) => bind_addr(nameserver)?,
+                (None, Some(via)) if via.is_ipv4() == nameserver.addr.is_ipv4() => {
+                    Some(SocketAddr::new(via, 0))
+                }
+                // The nameserver can't be reached from the dispatch address.
+                (None, _) => continue,
+            };
+            configs.push(name_server_config(nameserver, bind_addr));
+        }
+        if configs.is_empty() {
+            return Err(eyre::eyre!(
+                "None of the nameservers {} can be reached from the dispatch addresses",
+                self.nameservers
+                    .iter()
+                    .map(|nameserver| format!("`{}`", nameserver))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .suggestion(
+                "Dispatch to an address of the same IP version as the nameservers, or send the queries through \
+                a given interface with `<nameserver>@<interface>`",
+            ));
+        }
+
+        let mut options = ResolverOpts::default();
+        // Both families are needed to race them when connecting.
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        build(ResolverConfig::from_name_servers(configs), options)
+    }
+}
+
+fn build(config: ResolverConfig, mut options: ResolverOpts) -> Result<TokioResolver> {
+    // Answers are cached by `Cache` instead, which also covers the system resolver.
+    options.cache_size = 0;
+    TokioResolver::builder_with_config(config, TokioRuntimeProvider::default())
+        .with_options(options)
+        .build()
+        .wrap_err("Failed to set up the DNS resolver")
+}
+
+impl Resolver {
+    /// Creates a resolver querying the given nameservers in turn, or those of the system if there are none, or with
+    /// per-interface resolution, the nameservers of the interface that the connection goes through. Domains with
+    /// nameservers of their own are always resolved with those.
+    pub fn new(options: ResolverOptions) -> Result<Resolver> {
+        let ResolverOptions {
+            nameservers,
+            per_interface,
+            cache_max_ttl,
+            negative_ttl,
+            timeout,
+            scoped,
+            hosts,
+            prefer,
+        } = options;
+        let hosts = Arc::new(hosts);
+        let scoped = build_scoped(&scoped)?;
+        let cache = Arc::new(Cache {
+            max_ttl: cache_max_ttl,
+            negative_ttl,
+            entries: Mutex::default(),
+        });
+
+        if per_interface {
+            return Ok(Resolver {
+                nameservers: None,
+                per_interface: Some(Arc::new(PerInterface {
+                    nameservers,
+                    resolvers: Mutex::default(),
+                })),
+                scoped,
+                cache,
+                hosts,
+                timeout,
+                prefer,
+            });
+        }
+
+        // The nameservers of the system are queried directly rather than through the system resolver, so that the
+        // queries are sent from the dispatch addresses too.
+        let nameservers = if nameservers.is_empty() {
+            match system_nameservers() {
+                Ok(nameservers) if !nameservers.is_empty() => {
+                    Some(Arc::new(NameserverSet::new(nameservers)?))
+                }
+                Ok(_) => {
+                    tracing::warn!("The system has no nameservers, resolving with the system resolver over the default route instead");
+                    None
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "{:#}, resolving with the system resolver over the default route instead",
+                        err
+                    );
+                    None
+                }
+            }
+        } else {
+            Some(Arc::new(NameserverSet::new(nameservers)?))
+        };
+        Ok(Resolver {
+            nameservers,
+            per_interface: None,
+            scoped,
+            cache,
+            hosts,
+            timeout,
+            prefer,
+        })
+    }
+
+    /// Whether the domain must be resolved with `lookup_from`, once the local address to connect from is known. Domains
+    /// with static addresses or nameservers of their own resolve the same over every interface.
+    pub fn resolves_over_interface(&self, domain: &str) -> bool {
+        self.per_interface.is_some()
+            && self.hosts.get(domain).is_none()
+            && self.scope(domain).is_none()
+    }
+
+    pub fn prefer(&self) -> Prefer {
+        self.prefer
+    }
+
+    /// Resolves a domain name, returning its addresses with the given port, sorted as per RFC 6724 for connections
+    /// from the given local addresses, with those of the preferred IP version first if any. The returned addresses are
+    /// never empty.
+    pub async fn lookup(
+        &self,
+        domain: &str,
+        port: u16,
+        sources: &[IpAddr],
+    ) -> Result<Vec<SocketAddr>> {
+        let mut addrs = with_port(self.resolve(domain, None, sources).await?, port);
+        rfc6724::sort_destinations(&mut addrs, sources);
+        match self.prefer {
+            Prefer::Auto => {}
+            Prefer::Ipv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            Prefer::Ipv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+        }
+        Ok(addrs)
+    }
+
+    /// Resolves a domain name over the interface of the given local address, returning its addresses of the same IP
+    /// version with the given port, sorted as per RFC 6724. The returned addresses are never empty.
+    pub async fn lookup_from(
+        &self,
+        local_addr: IpAddr,
+        domain: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>> {
+        let over_interface = self.resolves_over_interface(domain);
+        let ips = self
+            .resolve(domain, over_interface.then_some(local_addr), &[local_addr])
+            .await?;
+        let mut addrs = with_port(ips, port);
+        rfc6724::sort_destinations(&mut addrs, &[local_addr]);
+        Ok(addrs)
+    }
+
+    /// Returns the static addresses of the domain, or its cached addresses, or queries them. The returned addresses are
+    /// never empty.
+    async fn resolve(
+        &self,
+        domain: &str,
+        local_addr: Option<IpAddr>,
+        sources: &[IpAddr],
+    ) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.hosts.get(domain) {
+            return Ok(ips.to_vec());
+        }
+
+        let key = (domain.to_ascii_lowercase(), local_addr);
+        if let Some(answer) = self.cache.get(&key) {
+            tracing::trace!(domain, "DNS cache hit");
+            return answer.map_err(|message| {
+                eyre::eyre!("{}", message)
+                    .note("This failure was cached, the domain will be queried again shortly")
+            });
+        }
+
+        let answer = match self.query_with_timeout(domain, local_addr, sources).await {
+            Ok((ips, _)) if ips.is_empty() => Err(eyre::eyre!("No addresses found")),
+            answer => answer,
+        };
+        match answer {
+            Ok((ips, valid_until)) => {
+                self.cache.insert(key, ips.clone(), valid_until);
+                Ok(ips)
+            }
+            Err(err) => {
+                self.cache.insert_failure(key, &err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn query_with_timeout(
+        &self,
+        domain: &str,
+        local_addr: Option<IpAddr>,
+        sources: &[IpAddr],
+    ) -> Result<(Vec<IpAddr>, Instant)> {
+        let query = self.query(domain, local_addr, sources);
+        if self.timeout.is_zero() {
+            return query.await;
+        }
+        tokio::time::timeout(self.timeout, query)
+            .await
+            .unwrap_or_else(|_| {
+                Err(eyre::eyre!(
+                    "No answer within {}",
+                    humantime::format_duration(self.timeout)
+                ))
+            })
+    }
+
+    /// Queries the domain from the local address to connect from with per-interface resolution, or else from one of
+    /// the given dispatch addresses.
+    async fn query(
+        &self,
+        domain: &str,
+        local_addr: Option<IpAddr>,
+        sources: &[IpAddr],
+    ) -> Result<(Vec<IpAddr>, Instant)> {
+        let resolver = match (self.scope(domain), &self.per_interface, local_addr) {
+            (Some(nameservers), _, _) => Some(nameservers.resolver(sources)?),
+            (None, Some(per_interface), Some(local_addr)) => {
+                Some(per_interface.resolver(local_addr)?)
+            }
+            _ => self
+                .nameservers
+                .as_ref()
+                .map(|nameservers| nameservers.resolver(sources))
+                .transpose()?,
+        };
+
+        match resolver {
+            None => {
+                let ips = lookup_host((domain, 0)).await?.map(|addr| addr.ip());
+                Ok((ips.collect(), Instant::now() + SYSTEM_TTL))
+            }
+            Some(resolver) => {
+                let lookup = resolver.lookup_ip(domain).await?;
+                Ok((lookup.iter().collect(), lookup.valid_until()))
+            }
+        }
+    }
+
+    /// Returns the resolver of the most specific domain with nameservers of its own that the domain belongs to.
+    fn scope(&self, domain: &str) -> Option<&NameserverSet> {
+        self.scoped
+            .iter()
+            .find(|(scope, _)| is_same_or_subdomain(domain, scope))
+            .map(|(_, nameservers)| nameservers)
+    }
+}
+
+/// Groups the domain-scoped nameservers by domain, and builds a resolver for each domain.
+fn build_scoped(scoped: &[ScopedNameserver]) -> Result<Arc<[(String, NameserverSet)]>> {
+    let mut domains = scoped
+        .iter()
+        .map(|scoped| scoped.domain.as_str())
+        .collect::<Vec<_>>();
+    domains.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    domains.dedup();
+
+    domains
+        .into_iter()
+        .map(|domain| {
+            let nameservers = scoped
+                .iter()
+                .filter(|scoped| scoped.domain == domain)
+                .map(|scoped| scoped.nameserver.clone())
+                .collect();
+            Ok((domain.to_string(), NameserverSet::new(nameservers)?))
+        })
+        .collect()
+}
+
+impl PerInterface {
+    fn resolver(&self, local_addr: IpAddr) -> Result<TokioResolver> {
+        let mut resolvers = self.resolvers.lock().unwrap();
+        if let Some(resolver) = resolvers.get(&local_addr) {
+            return Ok(resolver.clone());
+        }
+
+        let resolver = self
+            .create(local_addr)
+            .wrap_err_with(|| format!("Failed to set up DNS resolution over `{}`", local_addr))?;
+        resolvers.insert(local_addr, resolver.clone());
+        Ok(resolver)
+    }
+
+    /// Queries the nameservers of the interface if any were given, or else the nameservers given without an interface,
+    /// or else those of the system, from the local address.
+    fn create(&self, local_addr: IpAddr) -> Result<TokioResolver> {
+        let of_interface = self
+            .nameservers
+            .iter()
+            .filter(|nameserver| {
+                nameserver
+                    .interface
+                    .as_ref()
+                    .is_some_and(|interface| has_ip(interface, local_addr))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let nameservers = if !of_interface.is_empty() {
+            of_interface
+        } else if self.nameservers.iter().any(|ns| ns.interface.is_none()) {
+            self.nameservers
+                .iter()
+                .filter(|nameserver| nameserver.interface.is_none())
+                .cloned()
+                .collect()
+        } else {
+            system_nameservers()?
+        };
+
+        let bind_addr = SocketAddr::new(local_addr, 0);
+        let name_servers = nameservers
+            .iter()
+            .filter(|nameserver| nameserver.addr.is_ipv4() == local_addr.is_ipv4())
+            .map(|nameserver| name_server_config(nameserver, Some(bind_addr)))
+            .collect::<Vec<_>>();
+        if name_servers.is_empty() {
+            return Err(eyre::eyre!(
+                "There is no {} nameserver to query from `{}`",
+                if local_addr.is_ipv4() { "IPv4" } else { "IPv6" },
+                local_addr
+            )
+            .suggestion(
+                "Associate a nameserver with the interface, e.g. `--dns 192.168.1.1@eth0`",
+            ));
+        }
+
+        let mut options = ResolverOpts::default();
+        options.ip_strategy = if local_addr.is_ipv4() {
+            LookupIpStrategy::Ipv4Only
+        } else {
+            LookupIpStrategy::Ipv6Only
+        };
+        build(ResolverConfig::from_name_servers(name_servers), options)
+    }
+}
+
+/// The nameservers of the system's DNS configuration.
+fn system_nameservers() -> Result<Vec<Nameserver>> {
+    let (config, _) = system_conf::read_system_conf()
+        .wrap_err("Failed to read the system's DNS configuration")?;
+    Ok(config
+        .name_servers()
+        .iter()
+        .flat_map(|nameserver| {
+            nameserver.connections.first().map(|connection| Nameserver {
+                addr: SocketAddr::new(nameserver.ip, connection.port),
+                protocol: Protocol::Plain,
+                interface: None,
+            })
+        })
+        .collect())
+}
+
+fn with_port(ips: Vec<IpAddr>, port: u16) -> Vec<SocketAddr> {
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
+
+/// Whether the network interface has the given local address. Interfaces that can't be found have none.
+fn has_ip(interface: &RawInterface, ip: IpAddr) -> bool {
+    WeightedAddress::resolve(vec![RawWeightedAddress::new(
+        interface.clone(),
+        NonZeroUsize::MIN,
+    )])
+    .is_ok_and(|addresses| addresses.iter().any(|address| address.ips().contains(&ip)))
+}
@@ -0,0 +1,96 @@
+//! Events of the proxy, which the admin API streams and embedders can hook into.
+
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Serialize, Serializer};
+use tokio::sync::broadcast;
+
+use crate::{
+    connections::{Connection, ConnectionId},
+    redact::redact,
+};
+
+/// How many events a slow subscriber can lag behind before it starts missing some.
+const CAPACITY: usize = 1024;
+
+/// Something that happened in the proxy which external tools may want to react to.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ConnectionOpened {
+        id: ConnectionId,
+        #[serde(serialize_with = "redacted")]
+        client: SocketAddr,
+        #[serde(serialize_with = "redacted")]
+        destination: String,
+        #[serde(serialize_with = "redacted")]
+        address: SocketAddr,
+        interface: IpAddr,
+    },
+    ConnectionClosed {
+        id: ConnectionId,
+        bytes_up: u64,
+        bytes_down: u64,
+        duration_ms: u128,
+        close_reason: String,
+    },
+    HealthChanged {
+        address: IpAddr,
+        healthy: bool,
+    },
+}
+
+impl Event {
+    pub fn connection_opened(connection: &Connection) -> Event {
+        Event::ConnectionOpened {
+            id: connection.id,
+            client: connection.client,
+            destination: connection.destination.to_string(),
+            address: connection.address,
+            interface: connection.interface,
+        }
+    }
+
+    pub fn connection_closed(connection: &Connection, close_reason: &str) -> Event {
+        Event::ConnectionClosed {
+            id: connection.id,
+            bytes_up: connection.bytes_up(),
+            bytes_down: connection.bytes_down(),
+            duration_ms: connection.duration().as_millis(),
+            close_reason: close_reason.to_string(),
+        }
+    }
+}
+
+/// Broadcasts events to all current subscribers. Events published while nobody is subscribed are dropped.
+#[derive(Clone, Debug)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Events {
+    pub fn new() -> Events {
+        Events(broadcast::channel(CAPACITY).0)
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Events {
+        Events::new()
+    }
+}
+
+/// Serializes an address of a client or destination as is, or as a hash of it when redaction is enabled.
+fn redacted<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: std::fmt::Display,
+    S: Serializer,
+{
+    serializer.collect_str(&redact(value))
+}
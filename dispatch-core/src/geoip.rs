@@ -0,0 +1,335 @@
+//! Lookups of the country of IP addresses in a MaxMind DB file, such as the GeoLite2 and DB-IP country databases, for
+//! routing rules and user policies that match destinations by country.
+//!
+//! Only the parts of the MaxMind DB format needed to find the `country.iso_code` of an address are decoded, falling
+//! back to `registered_country.iso_code` for addresses, such as anycast ones, that have no country of their own.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+
+/// Marks the start of the metadata, at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// The zeros between the search tree and the data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+/// How deeply maps and arrays can be nested, so that a corrupt file can't overflow the stack.
+const MAX_DEPTH: usize = 32;
+
+/// An ISO 3166-1 alpha-2 country code, in uppercase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Country([u8; 2]);
+
+impl Country {
+    pub fn parse(src: &str) -> Result<Country> {
+        match src.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                Ok(Country([a.to_ascii_uppercase(), b.to_ascii_uppercase()]))
+            }
+            _ => Err(eyre::eyre!("`{}` isn't a country code", src)).suggestion(
+                "Countries are given by their two-letter ISO 3166 code, e.g. `country:FR`",
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Country {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Country codes are always ASCII letters.
+        f.write_str(std::str::from_utf8(&self.0).unwrap())
+    }
+}
+
+/// A GeoIP database, which can be reloaded while the proxy is running.
+#[derive(Clone, Debug)]
+pub struct GeoIp {
+    path: PathBuf,
+    database: Arc<RwLock<Arc<Database>>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp> {
+        let database = Database::read(path)?;
+        Ok(GeoIp {
+            path: path.to_path_buf(),
+            database: Arc::new(RwLock::new(Arc::new(database))),
+        })
+    }
+
+    /// The country an IP address is located in, if the database knows it.
+    pub fn country(&self, ip: IpAddr) -> Option<Country> {
+        let database = Arc::clone(&self.database.read().unwrap());
+        database.country(ip)
+    }
+
+    /// Reads the database again whenever the file changes, e.g. after a weekly update, checking at every interval. A
+    /// database that fails to load is reported, and the previous one is kept.
+    pub async fn watch(self, interval: Duration) {
+        let mut last_modified = modified(&self.path);
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let now = modified(&self.path);
+            if now.is_none() || now == last_modified {
+                continue;
+            }
+            last_modified = now;
+
+            let path = self.path.clone();
+            match tokio::task::spawn_blocking(move || Database::read(&path)).await {
+                Ok(Ok(database)) => {
+                    *self.database.write().unwrap() = Arc::new(database);
+                    tracing::info!(path = %self.path.display(), "GeoIP database reloaded");
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!(path = %self.path.display(), "failed to reload the GeoIP database: {:#}", err)
+                }
+                Err(err) => tracing::warn!("failed to reload the GeoIP database: {}", err),
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// A MaxMind DB file, loaded in memory.
+struct Database {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    /// The node where IPv4 addresses start, which is after 96 zero bits in IPv6 databases.
+    ipv4_start: usize,
+    ipv6: bool,
+    /// Where the data section starts and ends.
+    data: (usize, usize),
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("node_count", &self.node_count)
+            .field("record_size", &self.record_size)
+            .field("ipv6", &self.ipv6)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Database {
+    fn read(path: &Path) -> Result<Database> {
+        let bytes = std::fs::read(path)
+            .wrap_err_with(|| format!("Failed to read the GeoIP database `{}`", path.display()))?;
+        Database::parse(bytes)
+            .wrap_err_with(|| format!("Failed to load the GeoIP database `{}`", path.display()))
+            .suggestion("GeoIP databases are MaxMind DB files, such as GeoLite2-Country.mmdb or dbip-country-lite.mmdb")
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<Database> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| eyre::eyre!("The file isn't a MaxMind DB file"))?;
+        let metadata = Decoder(&bytes[marker + METADATA_MARKER.len()..]);
+        let invalid = || eyre::eyre!("The metadata of the database is invalid");
+        let Some((Value::Map(map), _)) = metadata.decode(0, 0) else {
+            return Err(invalid());
+        };
+        let field = |key| match metadata.get(map, key) {
+            Some(Value::Uint(value)) => usize::try_from(value).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        };
+        let (node_count, record_size, ip_version) = (
+            field("node_count")?,
+            field("record_size")?,
+            field("ip_version")?,
+        );
+
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(eyre::eyre!(
+                "Records of {} bits aren't supported",
+                record_size
+            ));
+        }
+        let tree_size = node_count
+            .checked_mul(record_size / 4)
+            .ok_or_else(invalid)?;
+        let data = (tree_size.saturating_add(DATA_SECTION_SEPARATOR), marker);
+        if data.0 > data.1 {
+            return Err(invalid());
+        }
+
+        let mut database = Database {
+            bytes,
+            node_count,
+            record_size,
+            ipv4_start: 0,
+            ipv6: ip_version == 6,
+            data,
+        };
+        if database.ipv6 {
+            for _ in 0..96 {
+                if database.ipv4_start >= node_count {
+                    break;
+                }
+                database.ipv4_start = database.record(database.ipv4_start, 0);
+            }
+        }
+        Ok(database)
+    }
+
+    /// The left or right record of a node of the search tree, which is within the tree since the data section starts
+    /// after it.
+    fn record(&self, node: usize, right: usize) -> usize {
+        let bytes = &self.bytes[node * self.record_size / 4..];
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as usize);
+        match self.record_size {
+            24 => be(&bytes[right * 3..right * 3 + 3]),
+            28 if right == 0 => ((bytes[3] as usize & 0xf0) << 20) | be(&bytes[..3]),
+            28 => ((bytes[3] as usize & 0x0f) << 24) | be(&bytes[4..7]),
+            _ => be(&bytes[right * 4..right * 4 + 4]),
+        }
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<Country> {
+        let (bits, len, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (u32::from(ip) as u128, 32, self.ipv4_start),
+            IpAddr::V6(ip) if self.ipv6 => (u128::from(ip), 128, 0),
+            IpAddr::V6(_) => return None,
+        };
+        for index in (0..len).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> index) as usize & 1);
+        }
+        // Addresses that aren't in the database lead to the node count itself.
+        let offset = node.checked_sub(self.node_count + DATA_SECTION_SEPARATOR)?;
+
+        let data = Decoder(&self.bytes[self.data.0..self.data.1]);
+        let (Value::Map(record), _) = data.decode(offset, 0)? else {
+            return None;
+        };
+        ["country", "registered_country"]
+            .into_iter()
+            .find_map(|key| {
+                let Some(Value::Map(country)) = data.get(record, key) else {
+                    return None;
+                };
+                let Some(Value::String(code)) = data.get(country, "iso_code") else {
+                    return None;
+                };
+                Country::parse(code).ok()
+            })
+    }
+}
+
+/// A value of the data section, as much as it matters to country lookups.
+enum Value<'a> {
+    String(&'a str),
+    Uint(u64),
+    /// The number of entries of a map, and where the first one starts.
+    Map((usize, usize)),
+    Other,
+}
+
+/// Decodes the values of a data section, with pointers relative to its start.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    /// Decodes the value at an offset, and returns it along with the offset of the next value.
+    fn decode(&self, offset: usize, depth: usize) -> Option<(Value<'a>, usize)> {
+        let mut offset = offset;
+        let control = *self.0.get(offset)?;
+        offset += 1;
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let size = ((control >> 3) & 0x3) as usize;
+            let bytes = self.0.get(offset..offset + size + 1)?;
+            let value = bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as usize);
+            let high = (control & 0x7) as usize;
+            let target = match size {
+                0 => high << 8 | value,
+                1 => (high << 16 | value) + 2048,
+                2 => (high << 24 | value) + 526336,
+                _ => value,
+            };
+            // Pointers can't point to pointers, which also keeps them from looping.
+            if *self.0.get(target)? >> 5 == 1 {
+                return None;
+            }
+            let (value, _) = self.decode(target, depth)?;
+            return Some((value, offset + size + 1));
+        }
+
+        if kind == 0 {
+            kind = 7 + *self.0.get(offset)?;
+            offset += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let bytes = self.0.get(offset..offset + extra)?;
+            let value = bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as usize);
+            offset += extra;
+            size = [29, 285, 65821][extra - 1] + value;
+        }
+
+        match kind {
+            2 => {
+                let bytes = self.0.get(offset..offset + size)?;
+                Some((
+                    Value::String(std::str::from_utf8(bytes).ok()?),
+                    offset + size,
+                ))
+            }
+            5 | 6 | 9 if size <= 8 => {
+                let bytes = self.0.get(offset..offset + size)?;
+                let value = bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as u64);
+                Some((Value::Uint(value), offset + size))
+            }
+            7 | 11 if depth < MAX_DEPTH => {
+                let entries = if kind == 7 { size * 2 } else { size };
+                let mut next = offset;
+                for _ in 0..entries {
+                    (_, next) = self.decode(next, depth + 1)?;
+                }
+                let value = if kind == 7 {
+                    Value::Map((size, offset))
+                } else {
+                    Value::Other
+                };
+                Some((value, next))
+            }
+            7 | 11 => None,
+            // Booleans are stored in the size.
+            14 => Some((Value::Other, offset)),
+            _ => Some((Value::Other, offset + size)),
+        }
+    }
+
+    /// The value of a key of a map.
+    fn get(&self, (len, offset): (usize, usize), key: &str) -> Option<Value<'a>> {
+        let mut offset = offset;
+        for _ in 0..len {
+            let (name, next) = self.decode(offset, 0)?;
+            let (value, next) = self.decode(next, 0)?;
+            if matches!(name, Value::String(name) if name == key) {
+                return Some(value);
+            }
+            offset = next;
+        }
+        None
+    }
+}
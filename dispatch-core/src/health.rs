@@ -0,0 +1,43 @@
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use crate::{
+    dispatcher::WeightedRoundRobinDispatcher,
+    events::{Event, Events},
+    net::bind_socket,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether outgoing connections can currently be made from a local address, i.e. whether its network interface is
+/// still up and still owns it.
+pub fn is_healthy(ip: IpAddr) -> bool {
+    bind_socket(ip).is_ok()
+}
+
+/// Periodically checks the health of all dispatch addresses, and reports whenever it changes.
+pub async fn monitor(dispatcher: WeightedRoundRobinDispatcher, events: Events) {
+    let mut health = HashMap::new();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        for ip in dispatcher.ips().await {
+            let healthy = is_healthy(ip);
+            match health.insert(ip, healthy) {
+                Some(was_healthy) if was_healthy != healthy => {
+                    if healthy {
+                        tracing::info!(address = %ip, "dispatch address is usable again");
+                    } else {
+                        tracing::warn!(address = %ip, "dispatch address is no longer usable");
+                    }
+                    events.publish(Event::HealthChanged {
+                        address: ip,
+                        healthy,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+}
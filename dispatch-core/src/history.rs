@@ -0,0 +1,167 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use rusqlite::{params, Connection};
+
+use crate::{connections::Connection as LiveConnection, paths};
+
+/// How often expired records are purged from the database.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS connections (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    destination TEXT NOT NULL,
+    domain TEXT,
+    address TEXT NOT NULL,
+    interface TEXT NOT NULL,
+    bytes_up INTEGER NOT NULL,
+    bytes_down INTEGER NOT NULL,
+    close_reason TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS connections_ended_at ON connections (ended_at);
+";
+
+/// A completed connection, as stored in the history database.
+#[derive(Debug)]
+pub struct HistoryRecord {
+    pub started_at: SystemTime,
+    pub ended_at: SystemTime,
+    pub client: String,
+    pub destination: String,
+    pub domain: Option<String>,
+    pub address: String,
+    pub interface: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub close_reason: String,
+}
+
+impl HistoryRecord {
+    pub fn new(connection: &LiveConnection, close_reason: &str) -> HistoryRecord {
+        HistoryRecord {
+            started_at: connection.started_at,
+            ended_at: SystemTime::now(),
+            client: connection.client.to_string(),
+            destination: connection.destination.to_string(),
+            domain: connection.destination.domain.clone(),
+            address: connection.address.to_string(),
+            interface: connection.interface.to_string(),
+            bytes_up: connection.bytes_up(),
+            bytes_down: connection.bytes_down(),
+            close_reason: close_reason.to_string(),
+        }
+    }
+}
+
+/// Records completed connections into a SQLite database.
+///
+/// Writes happen on a dedicated thread so that relays never block on disk I/O.
+#[derive(Clone, Debug)]
+pub struct History(Sender<HistoryRecord>);
+
+impl History {
+    /// Opens the history database at `path`, creating it if needed. Records older than `retention` are purged
+    /// periodically.
+    pub fn open(path: &Path, retention: Duration) -> Result<History> {
+        let db = open_db(path)?;
+        purge(&db, retention)?;
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("history".into())
+            .spawn(move || write_records(db, receiver, retention))
+            .wrap_err("Failed to spawn the history thread")?;
+
+        Ok(History(sender))
+    }
+
+    pub fn record(&self, record: HistoryRecord) {
+        // The writer thread only stops if the database becomes unusable, in which case the error has already been
+        // reported.
+        let _ = self.0.send(record);
+    }
+}
+
+pub fn default_path() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join("history.sqlite3"))
+}
+
+pub fn open_db(path: &Path) -> Result<Connection> {
+    let db = Connection::open(path).wrap_err_with(|| {
+        format!(
+            "Failed to open the connection history database at {}",
+            path.to_string_lossy()
+        )
+    })?;
+    db.execute_batch(SCHEMA)
+        .wrap_err("Failed to initialize the connection history database")?;
+    Ok(db)
+}
+
+fn write_records(db: Connection, receiver: Receiver<HistoryRecord>, retention: Duration) {
+    let mut last_purge = Instant::now();
+    loop {
+        let res = match receiver.recv_timeout(PURGE_INTERVAL) {
+            Ok(record) => insert(&db, &record),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if let Err(err) = res {
+            tracing::warn!("{:?}", err);
+        }
+
+        if last_purge.elapsed() >= PURGE_INTERVAL {
+            last_purge = Instant::now();
+            if let Err(err) = purge(&db, retention) {
+                tracing::warn!("{:?}", err);
+            }
+        }
+    }
+}
+
+fn insert(db: &Connection, record: &HistoryRecord) -> Result<()> {
+    db.execute(
+        "INSERT INTO connections (
+            started_at, ended_at, client, destination, domain, address, interface, bytes_up, bytes_down, close_reason
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            unix_secs(record.started_at),
+            unix_secs(record.ended_at),
+            record.client,
+            record.destination,
+            record.domain,
+            record.address,
+            record.interface,
+            record.bytes_up as i64,
+            record.bytes_down as i64,
+            record.close_reason,
+        ],
+    )
+    .wrap_err("Failed to record a connection into the history database")?;
+    Ok(())
+}
+
+fn purge(db: &Connection, retention: Duration) -> Result<()> {
+    let cutoff = unix_secs(
+        SystemTime::now()
+            .checked_sub(retention)
+            .unwrap_or(UNIX_EPOCH),
+    );
+    db.execute("DELETE FROM connections WHERE ended_at < ?1", [cutoff])
+        .wrap_err("Failed to purge expired connections from the history database")?;
+    Ok(())
+}
+
+pub fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,145 @@
+//! Reading the `Host` header of the first request of a plain HTTP connection, so that clients which resolve domains
+//! themselves and ask the proxy for an IP address can still be matched by domain rules, as the server name is for TLS.
+
+use crate::sni::host_name;
+
+/// The most bytes read looking for the end of the `Host` header, which is the request header size that most servers
+/// accept.
+pub const MAX_HEADER_LEN: usize = 8 * 1024;
+
+/// What the first bytes sent by a client tell about the host it requests.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    /// The `Host` header hasn't been read yet.
+    Incomplete,
+    /// The client doesn't speak HTTP/1, or its request has no `Host` header with a domain name.
+    NoHost,
+    /// The lowercase domain name the client requested, without its port.
+    Host(String),
+}
+
+/// Parses the request line and the headers of the first request of a connection, up to the `Host` header.
+pub fn parse(buf: &[u8]) -> Request {
+    let mut lines = buf.split_inclusive(|&byte| byte == b'\n');
+    let Some(request_line) = lines.next() else {
+        return Request::Incomplete;
+    };
+    // The method is made of uppercase letters, which tells other protocols apart before the whole line is read.
+    match request_line
+        .iter()
+        .position(|&byte| !byte.is_ascii_uppercase())
+    {
+        // Only uppercase letters so far.
+        None => return Request::Incomplete,
+        Some(len) if len == 0 || request_line[len] != b' ' => return Request::NoHost,
+        Some(_) => {}
+    }
+    if !request_line.ends_with(b"\n") {
+        return Request::Incomplete;
+    }
+    if !request_line
+        .trim_ascii_end()
+        .rsplit(|&byte| byte == b' ')
+        .next()
+        .is_some_and(|version| version.starts_with(b"HTTP/1."))
+    {
+        return Request::NoHost;
+    }
+
+    for line in lines {
+        if !line.ends_with(b"\n") {
+            return Request::Incomplete;
+        }
+        let line = line.trim_ascii_end();
+        // The headers end with an empty line.
+        if line.is_empty() {
+            return Request::NoHost;
+        }
+        let Some(colon) = line.iter().position(|&byte| byte == b':') else {
+            return Request::NoHost;
+        };
+        let (name, value) = line.split_at(colon);
+        if name.eq_ignore_ascii_case(b"host") {
+            return host(value[1..].trim_ascii()).map_or(Request::NoHost, Request::Host);
+        }
+    }
+    Request::Incomplete
+}
+
+/// Strips the port from the value of a `Host` header, and validates that the rest is a domain name.
+fn host(value: &[u8]) -> Option<String> {
+    // IPv6 addresses are enclosed in brackets, and aren't domain names anyway.
+    if value.starts_with(b"[") {
+        return None;
+    }
+    let name = match value.iter().rposition(|&byte| byte == b':') {
+        Some(colon) if value[colon + 1..].iter().all(u8::is_ascii_digit) => &value[..colon],
+        Some(_) => return None,
+        None => value,
+    };
+    host_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_host() {
+        let request =
+            b"GET /index.html HTTP/1.1\r\nUser-Agent: curl\r\nhost: WWW.Example.com:8080\r\n\r\n";
+        assert_eq!(parse(request), Request::Host("www.example.com".to_string()));
+        assert_eq!(
+            parse(b"POST / HTTP/1.0\nHost:example.com.\n"),
+            Request::Host("example.com".to_string())
+        );
+        // The rest of the request doesn't need to be read.
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept: te"),
+            Request::Host("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn waits_for_the_host_header() {
+        let request = b"GET / HTTP/1.1\r\nAccept: */*\r\nHost: example.com\r\n";
+        for len in [0, 1, 3, 10, 20, 35, request.len() - 1] {
+            assert_eq!(parse(&request[..len]), Request::Incomplete, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_what_isnt_an_http_1_request() {
+        // A TLS ClientHello, an SSH banner and a request line in lowercase.
+        assert_eq!(parse(&[0x16, 0x03, 0x01, 0x02, 0x00]), Request::NoHost);
+        assert_eq!(parse(b"SSH-2.0-OpenSSH_9.6\r\n"), Request::NoHost);
+        assert_eq!(parse(b"get / HTTP/1.1\r\n"), Request::NoHost);
+        assert_eq!(parse(b" / HTTP/1.1\r\n"), Request::NoHost);
+        // The HTTP/2 connection preface.
+        assert_eq!(parse(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), Request::NoHost);
+        assert_eq!(parse(b"GET /\r\n"), Request::NoHost);
+    }
+
+    #[test]
+    fn rejects_requests_without_a_valid_host() {
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\nHost: example.com\r\n"),
+            Request::NoHost
+        );
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nmalformed\r\n"), Request::NoHost);
+        for host in [
+            &b""[..],
+            b"192.0.2.1",
+            b"192.0.2.1:80",
+            b"[::1]:80",
+            b"example.com:http",
+            b"example.com:80:80",
+            b"exa mple.com",
+        ] {
+            let mut request = b"GET / HTTP/1.1\r\nHost: ".to_vec();
+            request.extend_from_slice(host);
+            request.extend_from_slice(b"\r\n");
+            assert_eq!(parse(&request), Request::NoHost, "{:?}", host);
+        }
+    }
+}
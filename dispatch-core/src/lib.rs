@@ -0,0 +1,72 @@
+//! The SOCKS proxy of [dispatch](https://github.com/alexkirsz/dispatch), which balances the traffic of its clients
+//! between several network interfaces, as a library for programs that embed it rather than running the `dispatch`
+//! binary.
+//!
+//! The public API is made of:
+//!
+//! - `dispatcher`, which picks the local address each outgoing connection is made from, with `Dispatch` and its
+//!   weighted round robin implementation `WeightedRoundRobinDispatcher`, whose choices a `Plugin` loaded from a
+//!   dynamic library can take over with the `plugins` feature;
+//! - `socks`, which handles the SOCKS4 and SOCKS5 handshakes of clients with `SocksHandshake`;
+//! - `server`, which runs the whole proxy with `server::server`, as `dispatch start` does, or inside the runtime of the
+//!   program with `ServerBuilder`, and relays connections with `server::relay`;
+//! - `events`, the connections and changes of health that hooks given to `ServerBuilder::on_event` are called with;
+//! - `net`, the helpers that list network interfaces and bind sockets to them.
+//!
+//! The other public modules are shared with the `dispatch` binary, and may change between releases.
+
+#[doc(hidden)]
+pub mod admin;
+mod apps;
+mod audit;
+#[doc(hidden)]
+pub mod connections;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod debug;
+mod dedup;
+mod denials;
+pub mod dispatcher;
+#[doc(hidden)]
+pub mod dns;
+pub mod events;
+mod geoip;
+mod health;
+#[doc(hidden)]
+pub mod history;
+mod http_host;
+mod lists;
+pub mod net;
+mod paths;
+mod portmap;
+mod ports;
+#[doc(hidden)]
+pub mod quota;
+#[doc(hidden)]
+pub mod redact;
+#[doc(hidden)]
+pub mod report;
+mod rfc6724;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod rlimit;
+#[doc(hidden)]
+pub mod rules;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod schedule;
+#[cfg(feature = "scripting")]
+mod script;
+pub mod server;
+#[doc(hidden)]
+pub mod service;
+mod sni;
+pub mod socks;
+#[doc(hidden)]
+pub mod system_proxy;
+#[doc(hidden)]
+pub mod throttle;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[doc(hidden)]
+pub mod users;
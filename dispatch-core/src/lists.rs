@@ -0,0 +1,478 @@
+//! Domain lists maintained by third parties, e.g. of ad servers or of the domains of a streaming service, which rules
+//! match with `list:<path or URL>` patterns.
+//!
+//! The format of a list is detected line by line, so that hosts files (`0.0.0.0 ads.example.com`), dnsmasq
+//! configurations (`address=/ads.example.com/0.0.0.0`), adblock filters (`||ads.example.com^`), v2ray domain lists
+//! (`domain:example.com`, `full:www.example.com`) and plain lists of domains can all be used as they are published.
+//! Lines that block anything other than whole domains, such as adblock filters of paths or of page elements, are
+//! skipped. A v2ray geosite database is read instead when the list is a `.dat` file followed by the category to match,
+//! e.g. `list:geosite.dat@netflix`.
+//!
+//! Lists are loaded along with the rules, and shared by the rules that match the same one, so that replacing the rules
+//! doesn't download them again. They are then refreshed at every `--list-refresh`: files when they changed, and URLs
+//! by downloading them again, keeping the previous version of a list that fails to load.
+
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Display, Formatter},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+
+use crate::sni::host_name;
+
+/// The largest list that is downloaded, well above the size of the largest blocklists.
+#[cfg(feature = "remote-lists")]
+const MAX_DOWNLOAD_SIZE: u64 = 64 * 1024 * 1024;
+#[cfg(feature = "remote-lists")]
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The lists in use by any rules, to share them between rules and refresh them.
+static LISTS: Mutex<Vec<Weak<DomainList>>> = Mutex::new(Vec::new());
+
+/// Where a list is loaded from, and for a geosite database, the category to match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Source {
+    location: Location,
+    category: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Location {
+    File(PathBuf),
+    Url(String),
+}
+
+impl Source {
+    fn parse(src: &str) -> Result<Source> {
+        if src.is_empty() {
+            return Err(eyre::eyre!("Expected a path or URL after `list:`"));
+        }
+        let (location, category) = match src.rsplit_once('@') {
+            Some((location, category)) if location.ends_with(".dat") => {
+                (location, Some(category.to_ascii_lowercase()))
+            }
+            _ if src.ends_with(".dat") => return Err(eyre::eyre!(
+                "Expected the category of the geosite database `{}`",
+                src
+            )
+            .suggestion(
+                "Follow the database with the category to match, e.g. `list:geosite.dat@netflix`",
+            )),
+            _ => (src, None),
+        };
+        let location = if location.starts_with("http://") || location.starts_with("https://") {
+            Location::Url(location.to_string())
+        } else {
+            Location::File(PathBuf::from(location))
+        };
+        Ok(Source { location, category })
+    }
+
+    /// Reads the list, unless it's a file that hasn't been modified since `modified`. Returns the time the file was
+    /// modified, if it's a file.
+    fn load(&self, modified: Option<SystemTime>) -> Result<Option<(Domains, Option<SystemTime>)>> {
+        let (bytes, modified) = match &self.location {
+            Location::File(path) => {
+                let now = std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+                if modified.is_some() && now == modified {
+                    return Ok(None);
+                }
+                let bytes = std::fs::read(path)
+                    .wrap_err_with(|| format!("Failed to read the list `{}`", path.display()))?;
+                (bytes, now)
+            }
+            Location::Url(url) => (download(url)?, None),
+        };
+        let domains = match &self.category {
+            Some(category) => parse_geosite(&bytes, category)?,
+            None => parse_text(&String::from_utf8_lossy(&bytes)),
+        };
+        if domains.is_empty() {
+            return Err(eyre::eyre!("The list `{}` has no domains", self));
+        }
+        Ok(Some((domains, modified)))
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Location::File(path) => Display::fmt(&path.display(), f)?,
+            Location::Url(url) => f.write_str(url)?,
+        }
+        if let Some(category) = &self.category {
+            write!(f, "@{}", category)?;
+        }
+        Ok(())
+    }
+}
+
+/// A list of domains, which is refreshed while the proxy is running.
+pub struct DomainList {
+    source: Source,
+    domains: RwLock<Arc<Domains>>,
+    /// When the file was last modified, to only read it again once it changed.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl DomainList {
+    /// Loads the list from `src`, the path or URL that follows `list:`, or reuses it if some rules already do.
+    pub fn load(src: &str) -> Result<Arc<DomainList>> {
+        let source = Source::parse(src)?;
+        let mut lists = LISTS.lock().unwrap();
+        lists.retain(|list| list.strong_count() > 0);
+        if let Some(list) = lists
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|list| list.source == source)
+        {
+            return Ok(list);
+        }
+
+        let (domains, modified) = source
+            .load(None)?
+            .expect("lists are always loaded the first time");
+        tracing::info!(list = %source, count = domains.len(), "domain list loaded");
+        let list = Arc::new(DomainList {
+            source,
+            domains: RwLock::new(Arc::new(domains)),
+            modified: Mutex::new(modified),
+        });
+        lists.push(Arc::downgrade(&list));
+        Ok(list)
+    }
+
+    /// Whether the list contains a domain, or one of the domains it's a subdomain of.
+    pub fn contains(&self, domain: &str) -> bool {
+        let domains = Arc::clone(&self.domains.read().unwrap());
+        domains.contains(
+            &domain
+                .strip_suffix('.')
+                .unwrap_or(domain)
+                .to_ascii_lowercase(),
+        )
+    }
+
+    /// Loads the list again if it changed. A list that fails to load is reported, and the previous version is kept.
+    fn refresh(&self) {
+        let modified = *self.modified.lock().unwrap();
+        match self.source.load(modified) {
+            Ok(Some((domains, modified))) => {
+                tracing::info!(list = %self.source, count = domains.len(), "domain list refreshed");
+                *self.domains.write().unwrap() = Arc::new(domains);
+                *self.modified.lock().unwrap() = modified;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!(list = %self.source, "failed to refresh the domain list: {:#}", err)
+            }
+        }
+    }
+}
+
+impl Display for DomainList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.source, f)
+    }
+}
+
+impl Debug for DomainList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DomainList")
+            .field("source", &self.source)
+            .field("count", &self.domains.read().unwrap().len())
+            .finish()
+    }
+}
+
+/// Refreshes the lists in use at every interval.
+pub async fn refresh(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let lists = LISTS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        for list in lists {
+            if let Err(err) = tokio::task::spawn_blocking(move || list.refresh()).await {
+                tracing::warn!("failed to refresh a domain list: {}", err);
+            }
+        }
+    }
+}
+
+/// The domains of a list, matched with their subdomains unless the list gives them in full.
+#[derive(Debug, Default)]
+struct Domains {
+    /// Domains that match with their subdomains.
+    suffixes: HashSet<String>,
+    /// Domains that only match themselves.
+    full: HashSet<String>,
+    /// Strings that match any domain containing them.
+    keywords: Vec<String>,
+}
+
+impl Domains {
+    fn contains(&self, domain: &str) -> bool {
+        if self.full.contains(domain)
+            || self
+                .keywords
+                .iter()
+                .any(|keyword| domain.contains(keyword.as_str()))
+        {
+            return true;
+        }
+        let mut suffix = domain;
+        loop {
+            if self.suffixes.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.suffixes.len() + self.full.len() + self.keywords.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn add_suffix(&mut self, name: &str) {
+        if let Some(name) = host_name(name.as_bytes()) {
+            self.suffixes.insert(name);
+        }
+    }
+
+    fn add_full(&mut self, name: &str) {
+        if let Some(name) = host_name(name.as_bytes()) {
+            self.full.insert(name);
+        }
+    }
+}
+
+/// Parses a list in any of the text formats, skipping the lines that don't give whole domains.
+fn parse_text(src: &str) -> Domains {
+    let mut domains = Domains::default();
+    for line in src.lines() {
+        let line = line.trim();
+        // Adblock filters of page elements, like `example.com##.banner`, and exceptions, which unblock domains.
+        if line.is_empty()
+            || line.starts_with('!')
+            || line.starts_with("@@")
+            || line.contains("##")
+            || line.contains("#@#")
+        {
+            continue;
+        }
+
+        // `||example.com^` blocks the domain and its subdomains, and filters with options or paths are skipped.
+        if let Some(filter) = line.strip_prefix("||") {
+            if let Some(name) = filter
+                .strip_suffix('^')
+                .or_else(|| filter.strip_suffix("^|"))
+            {
+                domains.add_suffix(name);
+            }
+            continue;
+        }
+        // `address=/a.example/b.example/0.0.0.0` answers for the domains between the slashes and their subdomains.
+        if let Some(rest) = ["address=/", "server=/", "local=/"]
+            .iter()
+            .find_map(|prefix| line.strip_prefix(prefix))
+        {
+            let mut names = rest.split('/').collect::<Vec<_>>();
+            names.pop();
+            for name in names {
+                domains.add_suffix(name);
+            }
+            continue;
+        }
+
+        // Comments start with `#`, at the beginning of a line or after a space.
+        let line = match line.find('#') {
+            Some(0) => continue,
+            Some(comment) if line[..comment].ends_with(char::is_whitespace) => {
+                line[..comment].trim_end()
+            }
+            _ => line,
+        };
+        let mut fields = line.split_whitespace();
+        let Some(first) = fields.next() else {
+            continue;
+        };
+        // The attributes of v2ray domains, like `@ads`, follow them.
+        if let Some(name) = first.strip_prefix("domain:") {
+            domains.add_suffix(name);
+        } else if let Some(name) = first.strip_prefix("full:") {
+            domains.add_full(name);
+        } else if let Some(keyword) = first.strip_prefix("keyword:") {
+            if !keyword.is_empty() {
+                domains.keywords.push(keyword.to_ascii_lowercase());
+            }
+        } else if first.starts_with("regexp:") || first.starts_with("include:") {
+            continue;
+        } else if first.parse::<IpAddr>().is_ok() {
+            // Hosts files give each name in full, and map local names that aren't worth matching.
+            for name in fields.filter(|name| name.contains('.')) {
+                domains.add_full(name);
+            }
+        } else if fields.next().is_none() {
+            domains.add_suffix(first.strip_prefix("*.").unwrap_or(first));
+        }
+    }
+    domains
+}
+
+/// Reads the domains of a category of a v2ray geosite database, a `GeoSiteList` protobuf message. Regular expressions
+/// are skipped.
+fn parse_geosite(bytes: &[u8], category: &str) -> Result<Domains> {
+    let invalid = || eyre::eyre!("Invalid geosite database");
+
+    // `GeoSiteList { repeated GeoSite entry = 1; }`
+    let mut list = Message(bytes);
+    while let Some((number, value)) = list.field().ok_or_else(invalid)? {
+        let (1, Value::Bytes(site)) = (number, value) else {
+            continue;
+        };
+        // `GeoSite { string country_code = 1; repeated Domain domain = 2; }`
+        let mut site = Message(site);
+        let mut code = None;
+        let mut entries = vec![];
+        while let Some((number, value)) = site.field().ok_or_else(invalid)? {
+            match (number, value) {
+                (1, Value::Bytes(value)) => code = Some(value),
+                (2, Value::Bytes(value)) => entries.push(value),
+                _ => {}
+            }
+        }
+        if !code.is_some_and(|code| code.eq_ignore_ascii_case(category.as_bytes())) {
+            continue;
+        }
+
+        let mut domains = Domains::default();
+        for entry in entries {
+            // `Domain { Type type = 1; string value = 2; }`, where the types are plain (a keyword), regex, domain and
+            // full.
+            let mut entry = Message(entry);
+            let (mut kind, mut value) = (0, None);
+            while let Some((number, field)) = entry.field().ok_or_else(invalid)? {
+                match (number, field) {
+                    (1, Value::Varint(field)) => kind = field,
+                    (2, Value::Bytes(field)) => value = Some(String::from_utf8_lossy(field)),
+                    _ => {}
+                }
+            }
+            let Some(value) = value else {
+                continue;
+            };
+            match kind {
+                0 if !value.is_empty() => domains.keywords.push(value.to_ascii_lowercase()),
+                2 => domains.add_suffix(&value),
+                3 => domains.add_full(&value),
+                _ => {}
+            }
+        }
+        return Ok(domains);
+    }
+    Err(eyre::eyre!(
+        "The geosite database has no `{}` category",
+        category
+    ))
+}
+
+/// The fields of a protobuf message.
+struct Message<'a>(&'a [u8]);
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl<'a> Message<'a> {
+    /// The number and value of the next field, `Some(None)` at the end of the message, or `None` if it's truncated.
+    fn field(&mut self) -> Option<Option<(u64, Value<'a>)>> {
+        if self.0.is_empty() {
+            return Some(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => self.skip(8)?,
+            2 => {
+                let len = usize::try_from(self.varint()?).ok()?;
+                if self.0.len() < len {
+                    return None;
+                }
+                let (bytes, rest) = self.0.split_at(len);
+                self.0 = rest;
+                Value::Bytes(bytes)
+            }
+            5 => self.skip(4)?,
+            _ => return None,
+        };
+        Some(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.0.split_first()?;
+            self.0 = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self, len: usize) -> Option<Value<'a>> {
+        self.0 = self.0.get(len..)?;
+        Some(Value::Fixed)
+    }
+}
+
+#[cfg(feature = "remote-lists")]
+fn download(url: &str) -> Result<Vec<u8>> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(DOWNLOAD_TIMEOUT))
+        .build()
+        .into();
+    let mut response = agent
+        .get(url)
+        .call()
+        .wrap_err_with(|| format!("Failed to download the list `{}`", url))?;
+    response
+        .body_mut()
+        .with_config()
+        .limit(MAX_DOWNLOAD_SIZE)
+        .read_to_vec()
+        .wrap_err_with(|| format!("Failed to download the list `{}`", url))
+}
+
+#[cfg(not(feature = "remote-lists"))]
+fn download(url: &str) -> Result<Vec<u8>> {
+    Err(eyre::eyre!("Can't download the list `{}`, downloading lists isn't supported by this build", url).suggestion(
+        "Install dispatch with `cargo install dispatch-proxy --features remote-lists`, or download the list to a file",
+    ))
+}
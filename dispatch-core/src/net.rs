@@ -0,0 +1,588 @@
+//! Helpers to list the addresses of network interfaces, bind sockets to them, and set the options of outbound sockets.
+
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+#[cfg(target_os = "linux")]
+use std::num::NonZeroUsize;
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tracing::instrument;
+
+use color_eyre::Section;
+use eyre::Result;
+
+use tokio::net::{TcpListener, TcpSocket};
+
+#[instrument(level = "debug")]
+pub fn bind_socket(addr: IpAddr) -> std::io::Result<TcpSocket> {
+    bind_socket_to(SocketAddr::new(addr, 0))
+}
+
+fn bind_socket_to(addr: SocketAddr) -> std::io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    Ok(socket)
+}
+
+/// A network interface that a dispatch address or a routing rule was given by name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamedInterface {
+    pub name: String,
+    pub index: u32,
+}
+
+/// A firewall mark for the connections through an interface, or through any interface, in the form of
+/// `[<interface>=]<mark>`.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug)]
+pub struct Fwmark {
+    /// The network interface name or IP address, as given to `start`.
+    pub interface: Option<String>,
+    pub mark: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl FromStr for Fwmark {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Fwmark> {
+        let (interface, mark) = match src.split_once('=') {
+            Some((interface, mark)) => (Some(interface.to_string()), mark),
+            None => (None, src),
+        };
+        let mark = match mark.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => mark.parse(),
+        }
+        .map_err(|err| {
+            eyre::eyre!(err)
+                .wrap_err(format!("`{}` isn't a firewall mark", mark))
+                .suggestion("Firewall marks are given in decimal or in hexadecimal, e.g. `0x10`")
+        })?;
+        Ok(Fwmark { interface, mark })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Display for Fwmark {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(interface) = &self.interface {
+            write!(f, "{}=", interface)?;
+        }
+        write!(f, "{:#x}", self.mark)
+    }
+}
+
+/// A range of source ports for the connections through an interface, or through any interface, in the form of
+/// `[<interface>=]<first>-<last>`. Ports are used in turn.
+#[derive(Clone, Debug)]
+pub struct SourcePorts {
+    /// The network interface name or IP address, as given to `start`.
+    pub interface: Option<String>,
+    pub first: u16,
+    pub last: u16,
+    next: Arc<AtomicUsize>,
+}
+
+impl SourcePorts {
+    // A range always has at least one port.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        usize::from(self.last - self.first) + 1
+    }
+
+    fn next(&self) -> u16 {
+        let offset = self.next.fetch_add(1, Ordering::Relaxed) % self.len();
+        self.first + offset as u16
+    }
+}
+
+impl FromStr for SourcePorts {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<SourcePorts> {
+        let (interface, range) = match src.split_once('=') {
+            Some((interface, range)) => (Some(interface.to_string()), range),
+            None => (None, src),
+        };
+        let invalid = || {
+            eyre::eyre!("`{}` isn't a range of ports", range).suggestion(
+                "Ranges of ports are given as `<first>-<last>`, from 1 to 65535, e.g. `20000-20999`",
+            )
+        };
+        let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+        let (Ok(first), Ok(last)) = (first.parse::<u16>(), last.parse::<u16>()) else {
+            return Err(invalid());
+        };
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(SourcePorts {
+            interface,
+            first,
+            last,
+            next: Arc::default(),
+        })
+    }
+}
+
+impl Display for SourcePorts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(interface) = &self.interface {
+            write!(f, "{}=", interface)?;
+        }
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// A Differentiated Services Code Point, which routers can apply QoS to, given as a number from 0 to 63 or by name,
+/// e.g. `ef` for voice, `af41` or `cs1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// The value of the IP_TOS and IPV6_TCLASS options, of which the DSCP is the upper 6 bits.
+    #[cfg(unix)]
+    fn tos(self) -> u32 {
+        u32::from(self.0) << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Dscp> {
+        let name = src.to_ascii_lowercase();
+        let value = match name.as_str() {
+            "ef" => Some(46),
+            "va" => Some(44),
+            "le" => Some(1),
+            _ => match (name.strip_prefix("cs"), name.strip_prefix("af")) {
+                (Some(class), _) => class
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|class| *class <= 7)
+                    .map(|class| class << 3),
+                (_, Some(af)) => match af.as_bytes() {
+                    [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => {
+                        Some((class - b'0') << 3 | (drop - b'0') << 1)
+                    }
+                    _ => None,
+                },
+                _ => name.parse::<u8>().ok().filter(|value| *value <= 63),
+            },
+        };
+        value.map(Dscp).ok_or_else(|| {
+            eyre::eyre!("`{}` isn't a DSCP", src).suggestion(
+                "DSCPs are given as a number from 0 to 63, or by name: `ef`, `va`, `le`, `cs0` to `cs7`, or `af11` \
+                to `af43`",
+            )
+        })
+    }
+}
+
+impl Display for Dscp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            46 => f.write_str("ef"),
+            44 => f.write_str("va"),
+            1 => f.write_str("le"),
+            value if value & 0b111 == 0 => write!(f, "cs{}", value >> 3),
+            value if (1..=4).contains(&(value >> 3)) && matches!(value & 0b111, 2 | 4 | 6) => {
+                write!(f, "af{}{}", value >> 3, (value & 0b111) >> 1)
+            }
+            value => value.fmt(f),
+        }
+    }
+}
+
+/// Options applied to the sockets of outbound connections.
+#[derive(Clone, Debug, Default)]
+pub struct OutboundOptions {
+    /// Bind sockets to the network interface of their local address with SO_BINDTODEVICE, when it was given by name.
+    #[cfg(target_os = "linux")]
+    pub bind_device: bool,
+    /// Mark sockets with SO_MARK, for policy routing.
+    #[cfg(target_os = "linux")]
+    pub fwmarks: Vec<Fwmark>,
+    /// Create sockets with IPPROTO_MPTCP, so that each connection can use several paths.
+    #[cfg(target_os = "linux")]
+    pub mptcp: bool,
+    /// Abort connections when the data they sent stays unacknowledged for this long, with TCP_USER_TIMEOUT, or
+    /// TCP_MAXRT on Windows.
+    #[cfg(any(target_os = "linux", windows))]
+    pub user_timeout: Option<Duration>,
+    /// Bind sockets to source ports in these ranges instead of ephemeral ones.
+    pub source_ports: Vec<SourcePorts>,
+    /// Mark packets with a DSCP, unless a routing rule gives one.
+    #[cfg(unix)]
+    pub dscp: Option<Dscp>,
+}
+
+impl OutboundOptions {
+    /// Applies the options to a socket bound to a local address, which belongs to the given network interface when it
+    /// was given by name, along with the DSCP of the routing rule that applies to the connection, if any.
+    ///
+    /// On Windows, sockets are always bound to the interface, since the interface with the lowest metric is otherwise
+    /// used whatever the local address.
+    pub fn apply(
+        &self,
+        socket: &TcpSocket,
+        interface: Option<&NamedInterface>,
+        dscp: Option<Dscp>,
+    ) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let (true, Some(interface)) = (self.bind_device, interface) {
+            socket.bind_device(Some(interface.name.as_bytes()))?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = self.fwmark(interface, socket.local_addr()?.ip()) {
+            socket2::SockRef::from(socket).set_mark(mark)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(timeout) = self.user_timeout {
+            socket2::SockRef::from(socket).set_tcp_user_timeout(Some(timeout))?;
+        }
+        #[cfg(windows)]
+        if let Some(timeout) = self.user_timeout {
+            set_max_retransmission_time(socket, timeout)?;
+        }
+        #[cfg(unix)]
+        if let Some(dscp) = dscp.or(self.dscp) {
+            let socket = socket2::SockRef::from(socket);
+            if socket.local_addr()?.is_ipv6() {
+                socket.set_tclass_v6(dscp.tos())?;
+            } else {
+                socket.set_tos_v4(dscp.tos())?;
+            }
+        }
+        #[cfg(windows)]
+        if let Some(interface) = interface {
+            set_unicast_interface(socket, interface.index)?;
+        }
+        #[cfg(windows)]
+        let _ = dscp;
+        #[cfg(not(any(target_os = "linux", windows)))]
+        let _ = interface;
+        Ok(())
+    }
+
+    /// Returns the firewall mark given for the interface or the local address of a connection, or else for every
+    /// connection.
+    #[cfg(target_os = "linux")]
+    fn fwmark(&self, interface: Option<&NamedInterface>, local_addr: IpAddr) -> Option<u32> {
+        given_for(
+            &self.fwmarks,
+            |fwmark| fwmark.interface.as_deref(),
+            interface,
+            local_addr,
+        )
+        .map(|fwmark| fwmark.mark)
+    }
+
+    /// Returns the range of source ports given for the interface or the local address of a connection, or else for
+    /// every connection.
+    pub fn source_ports(
+        &self,
+        interface: Option<&NamedInterface>,
+        local_addr: IpAddr,
+    ) -> Option<&SourcePorts> {
+        given_for(
+            &self.source_ports,
+            |ports| ports.interface.as_deref(),
+            interface,
+            local_addr,
+        )
+    }
+
+    /// Creates a socket bound to a local address, which belongs to the given network interface when it was given by
+    /// name, with MPTCP if enabled. The source port is the next one in the range given for the interface or the
+    /// address if any, and connecting from it fails if it's already connected to the same destination.
+    pub fn bind(
+        &self,
+        addr: IpAddr,
+        interface: Option<&NamedInterface>,
+    ) -> std::io::Result<TcpSocket> {
+        let port = self
+            .source_ports(interface, addr)
+            .map_or(0, SourcePorts::next);
+        let addr = SocketAddr::new(addr, port);
+        #[cfg(target_os = "linux")]
+        if self.mptcp {
+            let socket = mptcp_socket(addr.ip())?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&addr.into())?;
+            return Ok(TcpSocket::from_std_stream(socket.into()));
+        }
+        bind_socket_to(addr)
+    }
+
+    /// Fails early when the options can't be applied, e.g. for lack of privileges.
+    pub fn check(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.mptcp {
+            mptcp_socket(IpAddr::from([0, 0, 0, 0])).map_err(|err| {
+                eyre::eyre!(err)
+                    .wrap_err("Failed to create an MPTCP socket")
+                    .suggestion(
+                        "MPTCP requires Linux 5.6 or later, with the `net.mptcp.enabled` sysctl set to 1, e.g. with \
+                        `sudo sysctl -w net.mptcp.enabled=1`",
+                    )
+            })?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(fwmark) = self.fwmarks.first() {
+            TcpSocket::new_v4()
+                .and_then(|socket| socket2::SockRef::from(&socket).set_mark(fwmark.mark))
+                .map_err(|err| {
+                    eyre::eyre!(err)
+                        .wrap_err("Failed to mark the sockets of outbound connections")
+                        .suggestion(
+                            "Marking connections requires the CAP_NET_ADMIN capability, e.g. with \
+                            `sudo setcap cap_net_admin+ep $(which dispatch)`",
+                        )
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the option given for the interface or the local address of a connection, or else for every connection.
+fn given_for<'a, T>(
+    options: &'a [T],
+    interface_of: impl Fn(&T) -> Option<&str>,
+    interface: Option<&NamedInterface>,
+    local_addr: IpAddr,
+) -> Option<&'a T> {
+    let matches = |option: &&T| {
+        interface_of(option).is_some_and(|given| is_interface(given, interface, local_addr))
+    };
+    options
+        .iter()
+        .find(matches)
+        .or_else(|| options.iter().find(|option| interface_of(option).is_none()))
+}
+
+/// Whether a network interface name or IP address, as given to `start`, designates the interface or the local address
+/// of a connection.
+pub fn is_interface(given: &str, interface: Option<&NamedInterface>, local_addr: IpAddr) -> bool {
+    interface.is_some_and(|interface| interface.name == given) || given.parse() == Ok(local_addr)
+}
+
+/// Creates a non-blocking MPTCP socket for connecting from a local address. Connections fall back to plain TCP when the
+/// destination doesn't support MPTCP.
+#[cfg(target_os = "linux")]
+fn mptcp_socket(addr: IpAddr) -> std::io::Result<socket2::Socket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(SocketAddr::new(addr, 0)),
+        Type::STREAM,
+        Some(Protocol::MPTCP),
+    )?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Sends the packets of a socket through the interface with the given index, with IP_UNICAST_IF or IPV6_UNICAST_IF.
+#[cfg(windows)]
+fn set_unicast_interface(socket: &TcpSocket, index: u32) -> std::io::Result<()> {
+    use windows_sys::Win32::Networking::WinSock::{
+        IPPROTO_IP, IPPROTO_IPV6, IPV6_UNICAST_IF, IP_UNICAST_IF,
+    };
+
+    // The IPv4 option takes the index in network byte order, unlike the IPv6 one.
+    let (level, name, value) = match socket.local_addr()? {
+        std::net::SocketAddr::V4(_) => (IPPROTO_IP, IP_UNICAST_IF, index.to_be()),
+        std::net::SocketAddr::V6(_) => (IPPROTO_IPV6, IPV6_UNICAST_IF, index),
+    };
+    set_option(socket, level, name, value)
+}
+
+/// Aborts a connection when the data it sent stays unacknowledged for the given time, rounded up to the second, with
+/// TCP_MAXRT.
+#[cfg(windows)]
+fn set_max_retransmission_time(socket: &TcpSocket, timeout: Duration) -> std::io::Result<()> {
+    use windows_sys::Win32::Networking::WinSock::{IPPROTO_TCP, TCP_MAXRT};
+
+    let seconds = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+    set_option(
+        socket,
+        IPPROTO_TCP,
+        TCP_MAXRT,
+        u32::try_from(seconds).unwrap_or(u32::MAX),
+    )
+}
+
+#[cfg(windows)]
+fn set_option(socket: &TcpSocket, level: i32, name: i32, value: u32) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use windows_sys::Win32::Networking::WinSock::{setsockopt, SOCKET_ERROR};
+
+    // SAFETY: the socket is open for the duration of the call, and `value` outlives it.
+    let res = unsafe {
+        setsockopt(
+            socket.as_raw_socket() as _,
+            level,
+            name,
+            &value as *const u32 as *const u8,
+            std::mem::size_of::<u32>() as i32,
+        )
+    };
+    if res == SOCKET_ERROR {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Binds a listener, which queues at most `backlog` connections that haven't been accepted yet.
+pub fn bind_listener(addr: SocketAddr, backlog: NonZeroU32) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // As with `TcpListener::bind`, which only sets it on Unix, where it doesn't allow stealing the address.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog.get())
+}
+
+/// Binds a listener with SO_REUSEPORT, so that the kernel balances new connections between it and the other listeners
+/// bound to the same address.
+#[cfg(target_os = "linux")]
+pub fn bind_shared_listener(addr: SocketAddr, backlog: NonZeroU32) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog.get())
+}
+
+/// Binds several listeners to the same address with SO_REUSEPORT, between which the kernel balances new connections.
+/// Each queues at most `backlog` connections that haven't been accepted yet.
+#[cfg(target_os = "linux")]
+pub fn bind_listeners(
+    addr: SocketAddr,
+    count: NonZeroUsize,
+    backlog: NonZeroU32,
+) -> std::io::Result<Vec<TcpListener>> {
+    if count.get() == 1 {
+        return Ok(vec![bind_listener(addr, backlog)?]);
+    }
+    let mut listeners: Vec<TcpListener> = Vec::with_capacity(count.get());
+    for _ in 0..count.get() {
+        // When given port 0, the other listeners must share the port picked for the first one.
+        let addr = match listeners.first() {
+            Some(listener) => listener.local_addr()?,
+            None => addr,
+        };
+        listeners.push(bind_shared_listener(addr, backlog)?);
+    }
+    Ok(listeners)
+}
+
+pub fn get_valid_addresses(addresses: &[Addr]) -> Vec<IpAddr> {
+    addresses
+        .iter()
+        .map(|addr| addr.ip())
+        .filter(|addr| !is_local_address(addr))
+        .filter(|addr| bind_socket(*addr).is_ok())
+        .collect()
+}
+
+/// How often the addresses of the network interfaces of this host are listed again.
+const LOCAL_IPS_REFRESH: Duration = Duration::from_secs(10);
+
+/// The addresses of the network interfaces of this host, listed again periodically rather than whenever they are
+/// needed, since listing them queries every interface.
+#[derive(Clone, Debug, Default)]
+pub struct LocalIps {
+    ips: Arc<RwLock<Arc<[IpAddr]>>>,
+}
+
+impl LocalIps {
+    pub fn new() -> LocalIps {
+        LocalIps {
+            ips: Arc::new(RwLock::new(list_local_ips().unwrap_or_default().into())),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ips.read().unwrap().contains(&ip)
+    }
+
+    /// Lists the addresses again at every interval, as interfaces come and go and change addresses. Addresses that
+    /// fail to be listed are reported, and the previous ones are kept.
+    pub async fn watch(self) {
+        let mut interval = tokio::time::interval(LOCAL_IPS_REFRESH);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            match tokio::task::spawn_blocking(list_local_ips).await {
+                Ok(Ok(ips)) => *self.ips.write().unwrap() = ips.into(),
+                Ok(Err(err)) => {
+                    tracing::warn!(
+                        "failed to list the addresses of the network interfaces: {:#}",
+                        err
+                    )
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "failed to list the addresses of the network interfaces: {}",
+                        err
+                    )
+                }
+            }
+        }
+    }
+}
+
+fn list_local_ips() -> Result<Vec<IpAddr>> {
+    Ok(NetworkInterface::show()?
+        .iter()
+        .flat_map(|interface| &interface.addr)
+        .map(|addr| addr.ip())
+        .collect())
+}
+
+pub fn is_local_address(addr: &IpAddr) -> bool {
+    if addr.is_loopback() {
+        return true;
+    }
+
+    match addr {
+        IpAddr::V4(ip) => {
+            if ip.is_link_local() {
+                return true;
+            }
+        }
+        IpAddr::V6(ip) => {
+            // Check for link-local (fe80::/10)
+            if ip.segments()[0] & 0xffc0 == 0xfe80 {
+                return true;
+            }
+        }
+    }
+
+    false
+}
@@ -0,0 +1,12 @@
+use std::path::PathBuf;
+
+use eyre::{Result, WrapErr};
+
+/// Returns the directory where dispatch-proxy stores its logs and data, creating it if needed.
+pub fn data_dir() -> Result<PathBuf> {
+    let project_dirs = directories::ProjectDirs::from("", "", "dispatch-proxy")
+        .ok_or_else(|| eyre::eyre!("Couldn't find the user's home directory"))?;
+    let data_dir = project_dirs.data_local_dir();
+    std::fs::create_dir_all(data_dir).wrap_err("Failed to create data directory")?;
+    Ok(data_dir.to_path_buf())
+}
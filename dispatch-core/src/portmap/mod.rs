@@ -0,0 +1,185 @@
+//! Port mappings on the gateway, so that clients outside the LAN can reach the proxy, e.g. to balance a laptop's
+//! traffic over the connections of home while away. NAT-PMP is tried first, being the simpler of the two, then UPnP.
+//!
+//! Both only map IPv4 ports, and mappings expire unless they are renewed, so they are renewed for as long as the proxy
+//! runs, and removed when it stops.
+
+mod natpmp;
+mod upnp;
+
+use std::{
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::{owo_colors::OwoColorize, Section};
+use eyre::Result;
+
+/// How long mappings are requested for. They are renewed halfway through.
+const LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How long to wait before trying again when the gateway didn't map the port.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A port mapped on the gateway to the listener of the proxy.
+#[derive(Clone, Debug)]
+struct Mapping {
+    protocol: Protocol,
+    /// The port of the listener.
+    internal: u16,
+    /// The address at which clients outside the LAN reach the proxy.
+    external: SocketAddrV4,
+    /// How long the gateway keeps the mapping, or `None` if until it is removed.
+    lifetime: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+enum Protocol {
+    NatPmp(natpmp::Gateway),
+    Upnp(upnp::Gateway),
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::NatPmp(_) => f.write_str("NAT-PMP"),
+            Protocol::Upnp(_) => f.write_str("UPnP"),
+        }
+    }
+}
+
+/// The port mapping of the proxy, shared between the task that maintains it and the control socket that reports it.
+#[derive(Clone, Debug, Default)]
+pub struct PortMapping(Arc<Mutex<Option<Mapping>>>);
+
+impl PortMapping {
+    /// The address at which clients outside the LAN reach the proxy, while the port is mapped.
+    pub fn external(&self) -> Option<SocketAddr> {
+        let mapping = self.0.lock().unwrap();
+        mapping.as_ref().map(|mapping| mapping.external.into())
+    }
+
+    /// Maps the port of the listener on the gateway, and keeps renewing the mapping, or trying again if it failed. The
+    /// external address is printed when `announce` is set, and only logged otherwise.
+    pub async fn maintain(self, listen: SocketAddr, announce: bool) {
+        let SocketAddr::V4(listen) = listen else {
+            unreachable!("only IPv4 listeners are mapped");
+        };
+        let mut failing = false;
+        loop {
+            let previous = self.0.lock().unwrap().clone();
+            let wait = match map(listen, previous.as_ref()).await {
+                Ok(mapping) => {
+                    let changed =
+                        previous.map(|previous| previous.external) != Some(mapping.external);
+                    if changed {
+                        if announce {
+                            println!(
+                                "Reachable from outside the LAN on {}, mapped with {}",
+                                mapping.external.bold(),
+                                mapping.protocol
+                            );
+                        }
+                        tracing::info!(external = %mapping.external, protocol = %mapping.protocol, "port mapped");
+                    }
+                    failing = false;
+                    let wait = mapping.lifetime.map_or(LIFETIME, |lifetime| lifetime / 2);
+                    *self.0.lock().unwrap() = Some(mapping);
+                    wait
+                }
+                Err(err) => {
+                    // Only the first failure is worth a warning, since the gateway usually keeps refusing.
+                    if !failing {
+                        tracing::warn!(
+                            "Failed to map the port of the proxy on the gateway: {:?}",
+                            err
+                        );
+                    } else {
+                        tracing::debug!(
+                            "Failed to map the port of the proxy on the gateway: {:#}",
+                            err
+                        );
+                    }
+                    failing = true;
+                    *self.0.lock().unwrap() = None;
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Removes the mapping from the gateway, if the port is mapped.
+    pub async fn remove(&self) {
+        let Some(mapping) = self.0.lock().unwrap().take() else {
+            return;
+        };
+        let res = match &mapping.protocol {
+            Protocol::NatPmp(gateway) => gateway.unmap(mapping.internal).await,
+            Protocol::Upnp(gateway) => gateway.unmap(mapping.external.port()).await,
+        };
+        match res {
+            Ok(()) => tracing::info!(external = %mapping.external, "port mapping removed"),
+            Err(err) => tracing::warn!("Failed to remove the port mapping: {:#}", err),
+        }
+    }
+}
+
+/// Checks that the proxy listens on an address that the gateway can map a port to.
+pub fn check_listen_address(listen: SocketAddr) -> Result<()> {
+    let ip = match listen {
+        SocketAddr::V4(addr) => *addr.ip(),
+        SocketAddr::V6(_) => {
+            return Err(eyre::eyre!(
+                "`--map-port` requires an IPv4 listen address, but the proxy listens on {}",
+                listen
+            ))
+            .suggestion("NAT-PMP and UPnP only map IPv4 ports, pass `--ip 0.0.0.0` or a LAN address of this machine")
+        }
+    };
+    if ip.is_loopback() {
+        return Err(eyre::eyre!(
+            "`--map-port` requires the proxy to listen on the LAN, but it listens on {}",
+            listen
+        ))
+        .suggestion("Pass `--ip 0.0.0.0` or a LAN address of this machine");
+    }
+    Ok(())
+}
+
+/// Maps the port of the listener, renewing the previous mapping with the same protocol, or else trying NAT-PMP, then
+/// UPnP.
+async fn map(listen: SocketAddrV4, previous: Option<&Mapping>) -> Result<Mapping> {
+    match previous.map(|mapping| &mapping.protocol) {
+        Some(Protocol::NatPmp(gateway)) => return gateway.map(listen).await,
+        Some(Protocol::Upnp(gateway)) => return gateway.map(listen).await,
+        None => {}
+    }
+
+    let natpmp = match natpmp::Gateway::discover(local_ip(listen)).await {
+        Ok(gateway) => match gateway.map(listen).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(err) => err,
+        },
+        Err(err) => err,
+    };
+    let upnp = match upnp::Gateway::discover(local_ip(listen)).await {
+        Ok(gateway) => match gateway.map(listen).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(err) => err,
+        },
+        Err(err) => err,
+    };
+    Err(eyre::eyre!("The gateway didn't map the port")
+        .note(format!("NAT-PMP: {:#}", natpmp))
+        .note(format!("UPnP: {:#}", upnp))
+        .suggestion(
+            "Enable NAT-PMP or UPnP on the router, or forward the port to this machine by hand",
+        ))
+}
+
+/// The local address to talk to the gateway from, when the proxy listens on a specific one.
+fn local_ip(listen: SocketAddrV4) -> Option<Ipv4Addr> {
+    Some(*listen.ip()).filter(|ip| !ip.is_unspecified())
+}
@@ -0,0 +1,196 @@
+//! NAT-PMP (RFC 6886), as spoken by Apple routers, and by most routers running miniupnpd, over UDP to the gateway.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use tokio::net::UdpSocket;
+
+use super::{Mapping, Protocol, LIFETIME};
+
+const PORT: u16 = 5351;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+/// How long to wait for the first answer, doubled on each retransmission as per the RFC, which allows for more of them.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 4;
+
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    addr: Ipv4Addr,
+    /// The local address to send requests from, which the gateway maps ports to.
+    local: Option<Ipv4Addr>,
+}
+
+impl Gateway {
+    /// Finds the default gateway, and checks that it speaks NAT-PMP.
+    pub async fn discover(local: Option<Ipv4Addr>) -> Result<Gateway> {
+        let gateway = Gateway {
+            addr: default_gateway()?,
+            local,
+        };
+        gateway.external_address().await?;
+        Ok(gateway)
+    }
+
+    pub async fn map(&self, listen: SocketAddrV4) -> Result<Mapping> {
+        let external = self.external_address().await?;
+        let response = self
+            .request(&map_request(listen.port(), listen.port(), LIFETIME))
+            .await?;
+        let port = u16::from_be_bytes([response[10], response[11]]);
+        let lifetime = u32::from_be_bytes(response[12..16].try_into().unwrap());
+        Ok(Mapping {
+            protocol: Protocol::NatPmp(self.clone()),
+            internal: listen.port(),
+            external: SocketAddrV4::new(external, port),
+            lifetime: Some(Duration::from_secs(lifetime.into())),
+        })
+    }
+
+    /// Removes the mapping of the given port of the listener.
+    pub async fn unmap(&self, internal: u16) -> Result<()> {
+        // A lifetime of zero deletes the mapping, and the external port must then be zero too.
+        self.request(&map_request(internal, 0, Duration::ZERO))
+            .await
+            .map(drop)
+    }
+
+    async fn external_address(&self) -> Result<Ipv4Addr> {
+        let response = self.request(&[0, OP_EXTERNAL_ADDRESS]).await?;
+        let ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+        if ip.is_unspecified() {
+            return Err(eyre::eyre!(
+                "The gateway at {} has no external address yet",
+                self.addr
+            ));
+        }
+        Ok(ip)
+    }
+
+    /// Sends a request until it's answered, and returns the answer after checking that it succeeded.
+    async fn request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind((self.local.unwrap_or(Ipv4Addr::UNSPECIFIED), 0)).await?;
+        socket
+            .connect(SocketAddr::from((self.addr, PORT)))
+            .await
+            .wrap_err_with(|| format!("Failed to reach the gateway at {}", self.addr))?;
+
+        let op = request[1];
+        let mut timeout = INITIAL_TIMEOUT;
+        let mut response = [0u8; 16];
+        for _ in 0..ATTEMPTS {
+            socket.send(request).await?;
+            let len = match tokio::time::timeout(timeout, socket.recv(&mut response)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    timeout *= 2;
+                    continue;
+                }
+            };
+            // Answers are at least 8 bytes long, and have the opcode of the request with the high bit set.
+            if len < 8 || response[1] != op | 0x80 {
+                continue;
+            }
+            let result = u16::from_be_bytes([response[2], response[3]]);
+            if result != 0 {
+                return Err(eyre::eyre!(
+                    "The gateway at {} refused the request: {}",
+                    self.addr,
+                    describe_result(result)
+                ));
+            }
+            let expected = if op == OP_EXTERNAL_ADDRESS { 12 } else { 16 };
+            if len < expected {
+                return Err(eyre::eyre!(
+                    "The gateway at {} sent a truncated answer",
+                    self.addr
+                ));
+            }
+            return Ok(response[..len].to_vec());
+        }
+        Err(eyre::eyre!(
+            "The gateway at {} didn't answer NAT-PMP requests",
+            self.addr
+        ))
+    }
+}
+
+fn map_request(internal: u16, external: u16, lifetime: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = OP_MAP_TCP;
+    request[4..6].copy_from_slice(&internal.to_be_bytes());
+    request[6..8].copy_from_slice(&external.to_be_bytes());
+    request[8..12].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request
+}
+
+fn describe_result(result: u16) -> &'static str {
+    match result {
+        1 => "unsupported version",
+        2 => "port mapping is disabled",
+        3 => "the gateway isn't connected to the internet",
+        4 => "no ports are left to map",
+        5 => "unsupported request",
+        _ => "unknown error",
+    }
+}
+
+/// The gateway of the default IPv4 route.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<Ipv4Addr> {
+    const RTF_GATEWAY: u32 = 0x2;
+
+    let routes =
+        std::fs::read_to_string("/proc/net/route").wrap_err("Failed to read /proc/net/route")?;
+    // Addresses are written as the hexadecimal value of their bytes in native order.
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let destination = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+            (destination == 0 && flags & RTF_GATEWAY != 0)
+                .then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+        })
+        .next()
+        .ok_or_else(|| eyre::eyre!("There is no default IPv4 route"))
+}
+
+/// The gateway of the default IPv4 route, from `route print`.
+#[cfg(windows)]
+fn default_gateway() -> Result<Ipv4Addr> {
+    let output = std::process::Command::new("route")
+        .args(["print", "-4", "0.0.0.0"])
+        .output()
+        .wrap_err("Failed to run route")?;
+    // Routes are listed as destination, netmask, gateway, interface and metric.
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields[..] {
+                ["0.0.0.0", "0.0.0.0", gateway, ..] => gateway.parse().ok(),
+                _ => None,
+            }
+        })
+        .next()
+        .ok_or_else(|| eyre::eyre!("There is no default IPv4 route"))
+}
+
+/// The gateway of the default IPv4 route, from `route get`.
+#[cfg(not(any(target_os = "linux", windows)))]
+fn default_gateway() -> Result<Ipv4Addr> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .wrap_err("Failed to run route")?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:")?.trim().parse().ok())
+        .ok_or_else(|| eyre::eyre!("There is no default IPv4 route"))
+}
@@ -0,0 +1,331 @@
+//! UPnP Internet Gateway Devices: the gateway is discovered with SSDP, and ports are mapped with SOAP requests to its
+//! WANIPConnection or WANPPPConnection service.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::{timeout, Instant},
+};
+
+use super::{Mapping, Protocol, LIFETIME};
+
+const SSDP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// How long to wait for gateways to answer the search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long to wait for the answer to an HTTP request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The services that map ports, the ones of routers with their own IP address first.
+const SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const DESCRIPTION: &str = "dispatch-proxy";
+
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    /// The address and path of the control URL of the service.
+    control: (SocketAddr, String),
+    service: &'static str,
+    /// The local address to map ports to, when the proxy listens on a specific one.
+    local: Option<Ipv4Addr>,
+}
+
+impl Gateway {
+    /// Searches the LAN for a gateway, and finds its service that maps ports.
+    pub async fn discover(local: Option<Ipv4Addr>) -> Result<Gateway> {
+        let socket = UdpSocket::bind((local.unwrap_or(Ipv4Addr::UNSPECIFIED), 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+            SSDP, SEARCH_TARGET
+        );
+        socket
+            .send_to(search.as_bytes(), SSDP)
+            .await
+            .wrap_err("Failed to search for a UPnP gateway")?;
+
+        let deadline = Instant::now() + SEARCH_TIMEOUT;
+        let mut buf = [0u8; 2048];
+        let mut last_err = None;
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, _) = res?;
+            let answer = String::from_utf8_lossy(&buf[..len]);
+            let Some(location) = header(&answer, "location") else {
+                continue;
+            };
+            // Several devices can answer, e.g. a mesh of routers, only one of which is the gateway.
+            match Gateway::describe(location, local).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| eyre::eyre!("No UPnP gateway answered on the LAN")))
+    }
+
+    /// Reads the description of a device, and finds its service that maps ports.
+    async fn describe(location: &str, local: Option<Ipv4Addr>) -> Result<Gateway> {
+        let (addr, path) = parse_url(location)?;
+        let (status, description) = http(addr, "GET", &path, &[], "").await?;
+        if status != 200 {
+            return Err(eyre::eyre!(
+                "The UPnP device at {} answered with HTTP status {}",
+                location,
+                status
+            ));
+        }
+
+        let services = description
+            .split("<service>")
+            .skip(1)
+            .filter_map(|service| Some((tag(service, "serviceType")?, tag(service, "controlURL")?)))
+            .collect::<Vec<_>>();
+        let (service, control_url) = SERVICES
+            .iter()
+            .find_map(|wanted| {
+                services
+                    .iter()
+                    .find(|(service, _)| service == wanted)
+                    .map(|(_, control_url)| (*wanted, *control_url))
+            })
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "The UPnP device at {} isn't a gateway that maps ports",
+                    location
+                )
+            })?;
+        let control = if control_url.starts_with("http://") {
+            parse_url(control_url)?
+        } else {
+            (addr, format!("/{}", control_url.trim_start_matches('/')))
+        };
+        Ok(Gateway {
+            control,
+            service,
+            local,
+        })
+    }
+
+    pub async fn map(&self, listen: SocketAddrV4) -> Result<Mapping> {
+        let external = self.soap("GetExternalIPAddress", &[]).await?;
+        let external: Ipv4Addr = tag(&external, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .filter(|ip: &Ipv4Addr| !ip.is_unspecified())
+            .ok_or_else(|| eyre::eyre!("The UPnP gateway has no external address yet"))?;
+
+        let internal = match self.local {
+            Some(local) => local,
+            None => self.local_ip().await?,
+        };
+        let port = listen.port().to_string();
+        let add = |lease: Duration| {
+            [
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.clone()),
+                ("NewProtocol", "TCP".into()),
+                ("NewInternalPort", port.clone()),
+                ("NewInternalClient", internal.to_string()),
+                ("NewEnabled", "1".into()),
+                ("NewPortMappingDescription", DESCRIPTION.into()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ]
+        };
+        let lifetime = match self.soap("AddPortMapping", &add(LIFETIME)).await {
+            Ok(_) => Some(LIFETIME),
+            // OnlyPermanentLeasesSupported: older gateways only keep mappings until they are removed.
+            Err(err) if err.to_string().contains("(725)") => {
+                self.soap("AddPortMapping", &add(Duration::ZERO)).await?;
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Mapping {
+            protocol: Protocol::Upnp(self.clone()),
+            internal: listen.port(),
+            external: SocketAddrV4::new(external, listen.port()),
+            lifetime,
+        })
+    }
+
+    /// Removes the mapping of the given external port.
+    pub async fn unmap(&self, external: u16) -> Result<()> {
+        self.soap(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external.to_string()),
+                ("NewProtocol", "TCP".into()),
+            ],
+        )
+        .await
+        .map(drop)
+    }
+
+    /// The local address that the gateway is reached from.
+    async fn local_ip(&self) -> Result<Ipv4Addr> {
+        // Connecting a UDP socket picks the route to the gateway without sending anything.
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(self.control.0).await?;
+        match socket.local_addr()? {
+            SocketAddr::V4(addr) => Ok(*addr.ip()),
+            SocketAddr::V6(_) => Err(eyre::eyre!("The UPnP gateway isn't reachable over IPv4")),
+        }
+    }
+
+    /// Calls an action of the service, and returns the body of the answer.
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String> {
+        let args = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect::<String>();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}>\
+             </s:Body></s:Envelope>\r\n",
+            action, self.service, args
+        );
+        let soap_action = format!("\"{}#{}\"", self.service, action);
+        let (addr, path) = &self.control;
+        let (status, answer) = http(
+            *addr,
+            "POST",
+            path,
+            &[
+                ("Content-Type", "text/xml; charset=\"utf-8\""),
+                ("SOAPAction", &soap_action),
+            ],
+            &body,
+        )
+        .await?;
+        if status != 200 {
+            return Err(
+                match (tag(&answer, "errorCode"), tag(&answer, "errorDescription")) {
+                    (Some(code), Some(description)) => eyre::eyre!(
+                        "The UPnP gateway refused {}: {} ({})",
+                        action,
+                        description,
+                        code
+                    ),
+                    _ => eyre::eyre!(
+                        "The UPnP gateway refused {} with HTTP status {}",
+                        action,
+                        status
+                    ),
+                },
+            );
+        }
+        Ok(answer)
+    }
+}
+
+/// Parses an `http://` URL into the address to connect to and the path to request.
+fn parse_url(url: &str) -> Result<(SocketAddr, String)> {
+    let invalid = || eyre::eyre!("`{}` isn't the URL of a UPnP device", url);
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let addr = match host.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(host.parse().map_err(|_| invalid())?, 80),
+    };
+    Ok((addr, path.into()))
+}
+
+/// Makes an HTTP/1.1 request, and returns the status and the body of the answer.
+async fn http(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, String)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            addr,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).await?;
+        Ok::<_, std::io::Error>(answer)
+    };
+    let answer = timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| eyre::eyre!("{} didn't answer", addr))?
+        .wrap_err_with(|| format!("Failed to request {}", addr))?;
+
+    let answer = String::from_utf8_lossy(&answer);
+    let (head, body) = answer
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| eyre::eyre!("{} sent an invalid HTTP answer", addr))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| eyre::eyre!("{} sent an invalid HTTP answer", addr))?;
+    let body = match header(head, "transfer-encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => dechunk(body),
+        _ => body.to_string(),
+    };
+    Ok((status, body))
+}
+
+/// Decodes a body sent with the chunked transfer encoding.
+fn dechunk(mut body: &str) -> String {
+    let mut decoded = String::new();
+    while let Some((size, rest)) = body.split_once("\r\n") {
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        decoded.push_str(&rest[..size]);
+        body = rest[size..].trim_start_matches("\r\n");
+    }
+    decoded
+}
+
+/// The value of a header of an HTTP message, whose name is case-insensitive.
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// The text of the first element with the given name in an XML document, whatever its namespace prefix.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let element = rest[..end].split_whitespace().next().unwrap_or_default();
+        let local = element.rsplit(':').next().unwrap_or(element);
+        if !element.starts_with('/') && local == name {
+            let text = &rest[end + 1..];
+            return Some(text[..text.find('<')?].trim());
+        }
+        rest = &rest[end + 1..];
+    }
+}
@@ -0,0 +1,100 @@
+use std::{collections::HashSet, net::IpAddr, ops::RangeInclusive, time::Duration};
+
+use crate::{connections::ConnectionRegistry, dispatcher::WeightedRoundRobinDispatcher};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// A warning is logged when this fraction of the ephemeral ports of an address is in use.
+const WARN_THRESHOLD: f64 = 0.8;
+/// Once warned about, an address must go back under this fraction before it is warned about again, so that usage
+/// hovering around the threshold doesn't flood the logs.
+const RECOVER_THRESHOLD: f64 = 0.7;
+
+/// The range of source ports the OS assigns to outgoing connections.
+pub fn ephemeral_port_range() -> RangeInclusive<u16> {
+    #[cfg(target_os = "linux")]
+    if let Some(range) = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range")
+        .ok()
+        .and_then(|range| parse_port_range(&range))
+    {
+        return range;
+    }
+
+    // The IANA range, used by default on macOS and Windows.
+    49152..=65535
+}
+
+#[cfg(target_os = "linux")]
+fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let mut bounds = range.split_whitespace().map(str::parse);
+    let start = bounds.next()?.ok()?;
+    let end = bounds.next()?.ok()?;
+    (start <= end).then_some(start..=end)
+}
+
+/// How many source ports are available to outgoing connections from each local address.
+pub fn capacity() -> usize {
+    ephemeral_port_range().len()
+}
+
+/// Periodically checks how many source ports relayed connections use on each dispatch address, and warns when one of
+/// them is close to running out, since binds then start failing.
+///
+/// Only ports used by the proxy itself are counted, so exhaustion may happen sooner if other programs make many
+/// connections from the same addresses.
+pub async fn monitor(dispatcher: WeightedRoundRobinDispatcher, registry: ConnectionRegistry) {
+    let capacity = capacity();
+    let mut exhausted = HashSet::new();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let usage = registry.count_by_interface();
+        for ip in dispatcher.ips().await {
+            let in_use = usage.get(&ip).copied().unwrap_or(0);
+            let ratio = in_use as f64 / capacity as f64;
+            if ratio >= WARN_THRESHOLD && exhausted.insert(ip) {
+                tracing::warn!(
+                    address = %ip,
+                    in_use,
+                    capacity,
+                    "dispatch address is running out of ephemeral ports"
+                );
+            } else if ratio < RECOVER_THRESHOLD && exhausted.remove(&ip) {
+                tracing::info!(
+                    address = %ip,
+                    in_use,
+                    capacity,
+                    "dispatch address has enough ephemeral ports again"
+                );
+            }
+        }
+    }
+}
+
+/// Renders the ephemeral port usage of each dispatch address in the Prometheus text format.
+pub fn render_metrics(ips: &[IpAddr], registry: &ConnectionRegistry) -> String {
+    let usage = registry.count_by_interface();
+
+    let mut metrics = String::new();
+    metrics.push_str(
+        "# HELP dispatch_ephemeral_ports_in_use Source ports used by relayed connections, per dispatch address.\n",
+    );
+    metrics.push_str("# TYPE dispatch_ephemeral_ports_in_use gauge\n");
+    for ip in ips {
+        metrics.push_str(&format!(
+            "dispatch_ephemeral_ports_in_use{{address=\"{}\"}} {}\n",
+            ip,
+            usage.get(ip).copied().unwrap_or(0)
+        ));
+    }
+    metrics.push_str(
+        "# HELP dispatch_ephemeral_ports_capacity Source ports available to outgoing connections from each address.\n",
+    );
+    metrics.push_str("# TYPE dispatch_ephemeral_ports_capacity gauge\n");
+    metrics.push_str(&format!(
+        "dispatch_ephemeral_ports_capacity {}\n",
+        capacity()
+    ));
+    metrics
+}
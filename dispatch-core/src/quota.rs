@@ -0,0 +1,910 @@
+//! Data usage quotas of network interfaces, for metered uplinks such as LTE modems, and of users and clients, so that
+//! they share them fairly. The bytes relayed are counted toward the quotas as they go, and saved to a file so that
+//! restarts don't forget them. Once an interface has used up its quota, its connections are ended, and new ones are
+//! neither dispatched nor routed to it, and once a user or client has, their connections are ended and refused, or
+//! throttled, until the quota resets at the start of the next day or billing period, at midnight UTC.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dispatcher::WeightedRoundRobinDispatcher,
+    net::{self, NamedInterface},
+    paths,
+    redact::redact,
+    report::{format_bytes, parse_bytes},
+    throttle::{Rate, Throttle, TokenBucket},
+    users::User,
+};
+
+/// How often quotas are checked, and their usage saved.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub fn default_path() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join("quotas.json"))
+}
+
+/// How long a quota lasts before it resets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    /// A month starting on the given day of the month, or on its last day in shorter months.
+    Month {
+        billing_day: u8,
+    },
+}
+
+impl Period {
+    /// Returns the start of the period that a time falls into, and the start of the next one, in seconds since the
+    /// Unix epoch.
+    fn bounds(self, now: u64) -> (u64, u64) {
+        let today = now / SECS_PER_DAY;
+        match self {
+            Period::Day => (today * SECS_PER_DAY, (today + 1) * SECS_PER_DAY),
+            Period::Month { billing_day } => {
+                let (year, month, day) = civil_from_days(today);
+                let (year, month) = if day >= billing_day_of(billing_day, year, month) {
+                    (year, month)
+                } else {
+                    previous_month(year, month)
+                };
+                let (next_year, next_month) = next_month(year, month);
+                let start = days_from_civil(year, month, billing_day_of(billing_day, year, month));
+                let end = days_from_civil(
+                    next_year,
+                    next_month,
+                    billing_day_of(billing_day, next_year, next_month),
+                );
+                (start * SECS_PER_DAY, end * SECS_PER_DAY)
+            }
+        }
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Period::Day => f.write_str("day"),
+            Period::Month { billing_day: 1 } => f.write_str("month"),
+            Period::Month { billing_day } => write!(f, "month@{}", billing_day),
+        }
+    }
+}
+
+/// An amount of data per period, in the form of `<size>/<period>`, where the period is `day` or `month`, optionally
+/// followed by the billing day of the month, e.g. `20GiB/month@15`. Bytes relayed in either direction count toward it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allowance {
+    pub bytes: u64,
+    pub period: Period,
+}
+
+impl FromStr for Allowance {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Allowance> {
+        let Some((size, period)) = src.split_once('/') else {
+            return Err(eyre::eyre!("`{}` isn't a data quota", src).suggestion(
+                "Data quotas are given as `<size>/<period>`, where the period is `day` or `month`, optionally \
+                followed by the billing day, e.g. `20GiB/month@15`",
+            ));
+        };
+        let period = match period.split_once('@') {
+            None if period == "day" => Period::Day,
+            None if period == "month" => Period::Month { billing_day: 1 },
+            Some(("month", day)) => match day.parse() {
+                Ok(billing_day @ 1..=31) => Period::Month { billing_day },
+                _ => {
+                    return Err(eyre::eyre!("`{}` isn't a day of the month", day)
+                        .suggestion("Billing days are between 1 and 31, and fall on the last day of shorter months"))
+                }
+            },
+            _ => {
+                return Err(eyre::eyre!("`{}` isn't a quota period", period)
+                    .suggestion("Quota periods are `day` or `month`, e.g. `month@15` for a billing day on the 15th"))
+            }
+        };
+        let bytes = parse_bytes(size)? as u64;
+        if bytes == 0 {
+            return Err(eyre::eyre!("The data quota `{}` is empty", src));
+        }
+        Ok(Allowance { bytes, period })
+    }
+}
+
+impl Display for Allowance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+        // The largest unit that the size is a whole number of, so that it parses back.
+        let (mut size, mut unit) = (self.bytes, 0);
+        while size % 1024 == 0 && unit < UNITS.len() - 1 {
+            size /= 1024;
+            unit += 1;
+        }
+        write!(f, "{}{}/{}", size, UNITS[unit], self.period)
+    }
+}
+
+/// A data usage quota of a network interface, in the form of `<interface>=<size>/<period>`, e.g.
+/// `wwan0=20GiB/month@15`.
+#[derive(Clone, Debug)]
+pub struct Quota {
+    /// The network interface name or IP address, as given to `start`.
+    pub interface: String,
+    pub allowance: Allowance,
+}
+
+impl FromStr for Quota {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Quota> {
+        let Some((interface, allowance)) = src.split_once('=') else {
+            return Err(eyre::eyre!("`{}` isn't a data quota", src).suggestion(
+                "Data quotas of interfaces are given as `<interface>=<size>/<period>`, where the period is `day` or \
+                `month`, optionally followed by the billing day, e.g. `wwan0=20GiB/month@15`",
+            ));
+        };
+        Ok(Quota {
+            interface: interface.to_string(),
+            allowance: allowance.parse()?,
+        })
+    }
+}
+
+/// What a quota counts the data usage of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Subject {
+    Interface(String),
+    User(String),
+    Client(IpAddr),
+}
+
+impl Subject {
+    /// The label of the subject in metrics.
+    fn label(&self) -> String {
+        match self {
+            Subject::Interface(interface) => format!("interface=\"{}\"", interface),
+            Subject::User(name) => format!("user=\"{}\"", name),
+            Subject::Client(client) => format!("client=\"{}\"", client),
+        }
+    }
+}
+
+impl Display for Subject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Subject::Interface(interface) => write!(f, "interface `{}`", interface),
+            Subject::User(name) => write!(f, "user `{}`", name),
+            Subject::Client(client) => write!(f, "client {}", redact(client)),
+        }
+    }
+}
+
+/// The usage of a quota, as saved between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedUsage {
+    /// When the period that the usage was counted in started, in seconds since the Unix epoch.
+    period_start: u64,
+    bytes: u64,
+}
+
+/// The usage of every quota, as saved between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedUsages {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    interfaces: HashMap<String, SavedUsage>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    users: HashMap<String, SavedUsage>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    clients: HashMap<IpAddr, SavedUsage>,
+}
+
+#[derive(Debug)]
+struct Usage {
+    subject: Subject,
+    allowance: Allowance,
+    /// The bytes relayed in the current period, counted by the relay.
+    used: Arc<AtomicU64>,
+    state: Mutex<UsageState>,
+    /// The buckets of each direction that connections opened once the quota is used up take from, when they are
+    /// throttled rather than refused.
+    buckets: Option<(TokenBucket, TokenBucket)>,
+}
+
+#[derive(Debug)]
+struct UsageState {
+    period_start: u64,
+    /// How many of the warning thresholds have been crossed in the current period.
+    warned: usize,
+    exhausted: bool,
+}
+
+impl Usage {
+    /// Starts counting the usage of a quota from what was saved, unless its period has ended since.
+    fn new(
+        subject: Subject,
+        allowance: Allowance,
+        saved: Option<SavedUsage>,
+        over_quota_rate: Option<Rate>,
+    ) -> Usage {
+        let (period_start, _) = allowance.period.bounds(unix_secs(SystemTime::now()));
+        let saved = saved
+            .filter(|saved| saved.period_start == period_start)
+            .unwrap_or_default();
+        Usage {
+            subject,
+            allowance,
+            used: Arc::new(AtomicU64::new(saved.bytes)),
+            state: Mutex::new(UsageState {
+                period_start,
+                warned: 0,
+                exhausted: false,
+            }),
+            buckets: over_quota_rate.map(|rate| (TokenBucket::new(rate), TokenBucket::new(rate))),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.allowance.bytes
+    }
+
+    /// Counts the bytes of a connection toward the quota, which throttles the connection once the quota is used up, or
+    /// ends it if connections over the quota aren't throttled.
+    fn throttle(&self, up: &mut Throttle, down: &mut Throttle) {
+        let (up_bucket, down_bucket) = self.buckets.clone().unzip();
+        up.count(Arc::clone(&self.used), self.allowance.bytes, up_bucket);
+        down.count(Arc::clone(&self.used), self.allowance.bytes, down_bucket);
+    }
+
+    /// Resets the quota if its period has ended, and logs the warning thresholds that are crossed. Returns whether the
+    /// quota is used up.
+    fn check(&self, now: u64, warnings: &[u8]) -> bool {
+        let allowance = &self.allowance;
+        let (period_start, period_end) = allowance.period.bounds(now);
+        let mut state = self.state.lock().unwrap();
+        if state.period_start != period_start {
+            self.used.store(0, Ordering::Relaxed);
+            *state = UsageState {
+                period_start,
+                warned: 0,
+                exhausted: false,
+            };
+            tracing::info!("the data quota of the {} has reset", self.subject);
+        }
+
+        let used = self.used.load(Ordering::Relaxed);
+        let percent = used.saturating_mul(100) / allowance.bytes;
+        let crossed = warnings
+            .iter()
+            .filter(|&&warning| percent >= u64::from(warning))
+            .count();
+        if crossed > state.warned && used < allowance.bytes {
+            tracing::warn!(
+                used = %format_bytes(used),
+                quota = %allowance,
+                "{}% of the data quota of the {} is used",
+                warnings[crossed - 1],
+                self.subject
+            );
+        }
+        state.warned = state.warned.max(crossed);
+
+        if used >= allowance.bytes && !state.exhausted {
+            state.exhausted = true;
+            let consequence = match (&self.subject, &self.buckets) {
+                (Subject::Interface(_), _) => "connections are no longer dispatched to it",
+                (_, Some(_)) => "its new connections are throttled",
+                (_, None) => "its connections are refused",
+            };
+            tracing::warn!(
+                used = %format_bytes(used),
+                quota = %allowance,
+                resets = %humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(period_end)),
+                "the data quota of the {} is used up, {} until it resets",
+                self.subject,
+                consequence
+            );
+        }
+        state.exhausted
+    }
+
+    fn saved(&self) -> SavedUsage {
+        SavedUsage {
+            period_start: self.state.lock().unwrap().period_start,
+            bytes: self.used.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The quotas to enforce, and how.
+#[derive(Clone, Debug, Default)]
+pub struct QuotaOptions {
+    pub interfaces: Vec<Quota>,
+    /// The quotas of the users who have one.
+    pub users: Vec<(String, Allowance)>,
+    /// The quota of each client IP address.
+    pub clients: Option<Allowance>,
+    /// Throttle the new connections of users and clients who have used up their quota to this rate, instead of
+    /// refusing them.
+    pub over_quota_rate: Option<Rate>,
+    /// The percentages of the quotas that are warned about once used.
+    pub warnings: Vec<u8>,
+}
+
+impl QuotaOptions {
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.is_empty() && self.users.is_empty() && self.clients.is_none()
+    }
+}
+
+#[derive(Debug)]
+struct QuotasInner {
+    interfaces: Vec<Usage>,
+    users: HashMap<String, Usage>,
+    client_allowance: Option<Allowance>,
+    /// The usage of the clients that connected in the current period.
+    clients: Mutex<HashMap<IpAddr, Arc<Usage>>>,
+    over_quota_rate: Option<Rate>,
+    path: Option<PathBuf>,
+    /// The percentages of the quotas that are warned about once used, in increasing order.
+    warnings: Vec<u8>,
+}
+
+/// The data usage quotas of network interfaces, users and clients, shared by all connections.
+#[derive(Clone, Debug)]
+pub struct Quotas(Arc<QuotasInner>);
+
+impl Default for Quotas {
+    fn default() -> Quotas {
+        Quotas(Arc::new(QuotasInner {
+            interfaces: vec![],
+            users: HashMap::new(),
+            client_allowance: None,
+            clients: Mutex::default(),
+            over_quota_rate: None,
+            path: None,
+            warnings: vec![],
+        }))
+    }
+}
+
+impl Quotas {
+    /// Sets up quotas with the usage saved at the given path, unless their period has ended since.
+    pub fn open(options: QuotaOptions, path: &Path) -> Result<Quotas> {
+        let mut saved: SavedUsages = match std::fs::read(path) {
+            Ok(saved) => serde_json::from_slice(&saved)
+                .wrap_err_with(|| {
+                    format!("Failed to read the data usage from `{}`", path.display())
+                })
+                .suggestion("Remove the file to count the usage of every quota from zero")?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => SavedUsages::default(),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| {
+                    format!("Failed to read the data usage from `{}`", path.display())
+                })
+            }
+        };
+
+        let QuotaOptions {
+            interfaces,
+            users,
+            clients,
+            over_quota_rate,
+            mut warnings,
+        } = options;
+        let interfaces = interfaces
+            .into_iter()
+            .map(|quota| {
+                let saved = saved.interfaces.remove(&quota.interface);
+                Usage::new(
+                    Subject::Interface(quota.interface),
+                    quota.allowance,
+                    saved,
+                    None,
+                )
+            })
+            .collect();
+        let users = users
+            .into_iter()
+            .map(|(name, allowance)| {
+                let saved = saved.users.remove(&name);
+                let usage = Usage::new(
+                    Subject::User(name.clone()),
+                    allowance,
+                    saved,
+                    over_quota_rate,
+                );
+                (name, usage)
+            })
+            .collect();
+        let client_allowance = clients;
+        let clients = client_allowance
+            .map(|allowance| {
+                saved
+                    .clients
+                    .into_iter()
+                    .map(|(client, saved)| {
+                        let usage = Usage::new(
+                            Subject::Client(client),
+                            allowance,
+                            Some(saved),
+                            over_quota_rate,
+                        );
+                        (client, Arc::new(usage))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        warnings.sort_unstable();
+        warnings.dedup();
+        Ok(Quotas(Arc::new(QuotasInner {
+            interfaces,
+            users,
+            client_allowance,
+            clients: Mutex::new(clients),
+            over_quota_rate,
+            path: Some(path.to_path_buf()),
+            warnings,
+        })))
+    }
+
+    pub fn interfaces(&self) -> impl Iterator<Item = (&str, &Allowance)> {
+        self.0
+            .interfaces
+            .iter()
+            .filter_map(|usage| match &usage.subject {
+                Subject::Interface(interface) => Some((interface.as_str(), &usage.allowance)),
+                _ => None,
+            })
+    }
+
+    pub fn users(&self) -> usize {
+        self.0.users.len()
+    }
+
+    pub fn clients(&self) -> Option<&Allowance> {
+        self.0.client_allowance.as_ref()
+    }
+
+    pub fn over_quota_rate(&self) -> Option<Rate> {
+        self.0.over_quota_rate
+    }
+
+    /// Counts the bytes of a connection toward the quotas of its network interface, from a local address which belongs
+    /// to the given network interface when it was given by name, whether it was dispatched or routed there. The
+    /// connection ends once one of them is used up.
+    pub fn count(
+        &self,
+        interface: Option<&NamedInterface>,
+        local_addr: IpAddr,
+        up: &mut Throttle,
+        down: &mut Throttle,
+    ) {
+        for usage in &self.0.interfaces {
+            if let Subject::Interface(given) = &usage.subject {
+                if net::is_interface(given, interface, local_addr) {
+                    usage.throttle(up, down);
+                }
+            }
+        }
+    }
+
+    /// Returns the quota that refuses a new connection routed to a network interface, from a local address which belongs
+    /// to the given network interface when it was given by name, once the interface has used up its quota.
+    pub fn interface_refusal(
+        &self,
+        interface: Option<&NamedInterface>,
+        local_addr: IpAddr,
+    ) -> Option<String> {
+        self.0
+            .interfaces
+            .iter()
+            .find_map(|usage| match &usage.subject {
+                Subject::Interface(given)
+                    if usage.is_exhausted() && net::is_interface(given, interface, local_addr) =>
+                {
+                    Some(format!("{}={}", given, usage.allowance))
+                }
+                _ => None,
+            })
+    }
+
+    /// Returns the quota that refuses a new connection from a client, or the user it authenticated as, when either has
+    /// used up their quota and isn't throttled instead.
+    pub fn refusal(&self, client: IpAddr, user: Option<&User>) -> Option<String> {
+        if self.0.over_quota_rate.is_some() {
+            return None;
+        }
+        if let Some((name, usage)) = user.and_then(|user| self.0.users.get_key_value(&user.name)) {
+            if usage.is_exhausted() {
+                return Some(format!("{} quota={}", name, usage.allowance));
+            }
+        }
+        let clients = self.0.clients.lock().unwrap();
+        match clients.get(&client.to_canonical()) {
+            Some(usage) if usage.is_exhausted() => {
+                Some(format!("client-quota={}", usage.allowance))
+            }
+            _ => None,
+        }
+    }
+
+    /// Counts the bytes of a connection toward the quotas of its client and of the user it authenticated as, and
+    /// throttles or ends it as soon as either has used up their quota.
+    pub fn throttle(
+        &self,
+        client: IpAddr,
+        user: Option<&User>,
+        up: &mut Throttle,
+        down: &mut Throttle,
+    ) {
+        if let Some(usage) = user.and_then(|user| self.0.users.get(&user.name)) {
+            usage.throttle(up, down);
+        }
+        if let Some(allowance) = self.0.client_allowance {
+            let client = client.to_canonical();
+            let usage = Arc::clone(self.0.clients.lock().unwrap().entry(client).or_insert_with(
+                || {
+                    Arc::new(Usage::new(
+                        Subject::Client(client),
+                        allowance,
+                        None,
+                        self.0.over_quota_rate,
+                    ))
+                },
+            ));
+            usage.throttle(up, down);
+        }
+    }
+
+    /// Resets the quotas whose period has ended, logs the warning thresholds that are crossed, and stops dispatching to
+    /// the addresses of the interfaces which have used up their quota.
+    async fn check(&self, dispatcher: &WeightedRoundRobinDispatcher) {
+        let now = unix_secs(SystemTime::now());
+        let warnings = &self.0.warnings;
+        let mut exhausted = vec![];
+        for usage in &self.0.interfaces {
+            if usage.check(now, warnings) {
+                if let Subject::Interface(interface) = &usage.subject {
+                    exhausted.push(interface.clone());
+                }
+            }
+        }
+        for usage in self.0.users.values() {
+            usage.check(now, warnings);
+        }
+        {
+            let mut clients = self.0.clients.lock().unwrap();
+            for usage in clients.values() {
+                usage.check(now, warnings);
+            }
+            // Clients are forgotten once their quota has reset and they have no connection left to count.
+            clients.retain(|_, usage| {
+                usage.used.load(Ordering::Relaxed) > 0 || Arc::strong_count(&usage.used) > 1
+            });
+        }
+
+        for ip in dispatcher.weighted_ips().await {
+            let over_quota = exhausted
+                .iter()
+                .any(|interface| net::is_interface(interface, ip.interface.as_ref(), ip.ip));
+            dispatcher.set_over_quota(ip.ip, over_quota).await;
+        }
+    }
+
+    /// Saves the usage of the quotas, so that it is picked up on the next start.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.0.path else {
+            return Ok(());
+        };
+        let mut saved = SavedUsages::default();
+        for usage in &self.0.interfaces {
+            if let Subject::Interface(interface) = &usage.subject {
+                saved.interfaces.insert(interface.clone(), usage.saved());
+            }
+        }
+        for (name, usage) in &self.0.users {
+            saved.users.insert(name.clone(), usage.saved());
+        }
+        for (client, usage) in self.0.clients.lock().unwrap().iter() {
+            saved.clients.insert(*client, usage.saved());
+        }
+
+        // Written to a temporary file first, so that a crash while writing doesn't lose the usage.
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(&saved)?)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .wrap_err_with(|| format!("Failed to save the data usage to `{}`", path.display()))
+    }
+
+    /// Periodically checks the quotas and saves their usage.
+    pub async fn monitor(self, dispatcher: WeightedRoundRobinDispatcher) {
+        if self.0.path.is_none() {
+            return;
+        }
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check(&dispatcher).await;
+            if let Err(err) = self.save() {
+                tracing::warn!("{:?}", err);
+            }
+        }
+    }
+
+    /// Renders the usage of each quota in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let clients = self.0.clients.lock().unwrap();
+        let usages = self
+            .0
+            .interfaces
+            .iter()
+            .chain(self.0.users.values())
+            .chain(clients.values().map(AsRef::as_ref))
+            .collect::<Vec<_>>();
+        if usages.is_empty() {
+            return String::new();
+        }
+
+        let mut metrics = String::new();
+        metrics.push_str(
+            "# HELP dispatch_quota_used_bytes Bytes relayed through each interface, or by each user or client, in the \
+            current quota period.\n",
+        );
+        metrics.push_str("# TYPE dispatch_quota_used_bytes gauge\n");
+        for usage in &usages {
+            metrics.push_str(&format!(
+                "dispatch_quota_used_bytes{{{}}} {}\n",
+                usage.subject.label(),
+                usage.used.load(Ordering::Relaxed)
+            ));
+        }
+        metrics.push_str(
+            "# HELP dispatch_quota_bytes Data quota of each interface, user or client per period.\n",
+        );
+        metrics.push_str("# TYPE dispatch_quota_bytes gauge\n");
+        for usage in &usages {
+            metrics.push_str(&format!(
+                "dispatch_quota_bytes{{{}}} {}\n",
+                usage.subject.label(),
+                usage.allowance.bytes
+            ));
+        }
+        metrics
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The day of the month that a billing day falls on, i.e. the last day of months that are too short.
+fn billing_day_of(billing_day: u8, year: i64, month: u8) -> u8 {
+    billing_day.min(days_in_month(year, month))
+}
+
+fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn previous_month(year: i64, month: u8) -> (i64, u8) {
+    match month {
+        1 => (year - 1, 12),
+        month => (year, month - 1),
+    }
+}
+
+fn next_month(year: i64, month: u8) -> (i64, u8) {
+    match month {
+        12 => (year + 1, 1),
+        month => (year, month + 1),
+    }
+}
+
+/// Converts days since the Unix epoch to a date of the proleptic Gregorian calendar, as per Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: u64) -> (i64, u8, u8) {
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u8;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a date of the proleptic Gregorian calendar to days since the Unix epoch, as per Howard Hinnant's
+/// `days_from_civil`.
+fn days_from_civil(year: i64, month: u8, day: u8) -> u64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = i64::from((month + 9) % 12);
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era - 719_468) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Seconds since the Unix epoch at the start of a day, plus some hours.
+    fn at(year: i64, month: u8, day: u8, hours: u64) -> u64 {
+        days_from_civil(year, month, day) * SECS_PER_DAY + hours * 60 * 60
+    }
+
+    fn month(billing_day: u8) -> Period {
+        Period::Month { billing_day }
+    }
+
+    #[test]
+    fn converts_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 1, 1), 19_723);
+        for days in 0..(200 * 366) {
+            let (year, month, day) = civil_from_days(days);
+            assert!((1..=days_in_month(year, month)).contains(&day));
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn counts_leap_years() {
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(2023, 4), 30);
+        assert_eq!(days_in_month(2023, 12), 31);
+    }
+
+    #[test]
+    fn days_start_at_midnight_utc() {
+        assert_eq!(
+            Period::Day.bounds(at(2023, 12, 31, 23)),
+            (at(2023, 12, 31, 0), at(2024, 1, 1, 0))
+        );
+        assert_eq!(
+            Period::Day.bounds(at(2024, 2, 29, 0)),
+            (at(2024, 2, 29, 0), at(2024, 3, 1, 0))
+        );
+    }
+
+    #[test]
+    fn months_start_on_the_billing_day() {
+        assert_eq!(
+            month(1).bounds(at(2024, 3, 1, 0)),
+            (at(2024, 3, 1, 0), at(2024, 4, 1, 0))
+        );
+        assert_eq!(
+            month(15).bounds(at(2024, 3, 14, 23)),
+            (at(2024, 2, 15, 0), at(2024, 3, 15, 0))
+        );
+        assert_eq!(
+            month(15).bounds(at(2024, 3, 15, 0)),
+            (at(2024, 3, 15, 0), at(2024, 4, 15, 0))
+        );
+    }
+
+    #[test]
+    fn months_roll_over_years() {
+        assert_eq!(
+            month(15).bounds(at(2024, 1, 10, 0)),
+            (at(2023, 12, 15, 0), at(2024, 1, 15, 0))
+        );
+        assert_eq!(
+            month(15).bounds(at(2023, 12, 20, 0)),
+            (at(2023, 12, 15, 0), at(2024, 1, 15, 0))
+        );
+        assert_eq!(
+            month(1).bounds(at(2023, 12, 31, 23)),
+            (at(2023, 12, 1, 0), at(2024, 1, 1, 0))
+        );
+    }
+
+    #[test]
+    fn billing_days_fall_on_the_last_day_of_shorter_months() {
+        // February of a leap year, and of a common year.
+        assert_eq!(
+            month(31).bounds(at(2024, 2, 10, 0)),
+            (at(2024, 1, 31, 0), at(2024, 2, 29, 0))
+        );
+        assert_eq!(
+            month(31).bounds(at(2024, 2, 29, 0)),
+            (at(2024, 2, 29, 0), at(2024, 3, 31, 0))
+        );
+        assert_eq!(
+            month(30).bounds(at(2023, 3, 1, 0)),
+            (at(2023, 2, 28, 0), at(2023, 3, 30, 0))
+        );
+        assert_eq!(
+            month(29).bounds(at(2100, 2, 28, 0)),
+            (at(2100, 2, 28, 0), at(2100, 3, 29, 0))
+        );
+        // A 30-day month.
+        assert_eq!(
+            month(31).bounds(at(2024, 4, 30, 0)),
+            (at(2024, 4, 30, 0), at(2024, 5, 31, 0))
+        );
+    }
+
+    #[test]
+    fn parses_allowances() {
+        let allowance = "20GiB/month@15".parse::<Allowance>().unwrap();
+        assert_eq!(allowance.bytes, 20 << 30);
+        assert_eq!(allowance.period, month(15));
+        assert_eq!(allowance.to_string(), "20GiB/month@15");
+        assert_eq!("1GiB/month".parse::<Allowance>().unwrap().period, month(1));
+        assert_eq!(
+            "1GiB/day".parse::<Allowance>().unwrap().to_string(),
+            "1GiB/day"
+        );
+        for src in [
+            "20GiB",
+            "20GiB/week",
+            "20GiB/month@0",
+            "20GiB/month@32",
+            "0GiB/day",
+        ] {
+            assert!(src.parse::<Allowance>().is_err(), "{}", src);
+        }
+    }
+    #[tokio::test]
+    async fn ends_or_throttles_connections_once_used_up() {
+        let allowance = "1KiB/day".parse::<Allowance>().unwrap();
+        let client = Subject::Client(IpAddr::from([192, 0, 2, 1]));
+        let ended = Usage::new(client.clone(), allowance, None, None);
+        let (mut up, mut down) = Default::default();
+        ended.throttle(&mut up, &mut down);
+        assert!(up.take(600).await.is_ok());
+        assert!(down.take(600).await.is_ok());
+        assert_eq!(ended.used.load(Ordering::Relaxed), 1200);
+        assert!(up.take(1).await.is_err());
+        assert!(down.take(1).await.is_err());
+
+        let rate = "1Gbps".parse::<Rate>().unwrap();
+        let throttled = Usage::new(client, allowance, None, Some(rate));
+        let (mut up, mut down) = Default::default();
+        throttled.throttle(&mut up, &mut down);
+        assert!(up.take(1200).await.is_ok());
+        assert!(up.take(1).await.is_ok());
+        assert_eq!(throttled.used.load(Ordering::Relaxed), 1201);
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::{Debug, Display, Formatter},
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Hashes are keyed per process, so that values can be correlated within a single log but can't be recovered by
+/// hashing every possible IP address.
+static KEY: OnceLock<RandomState> = OnceLock::new();
+
+/// Redacts client and destination addresses from all log output and issue metadata from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Wraps a value that shouldn't appear in logs when redaction is enabled, e.g. a client or destination address.
+pub fn redact<T>(value: T) -> Redacted<T> {
+    Redacted(value)
+}
+
+/// Formats as the wrapped value, or as a short hash of it when redaction is enabled.
+pub struct Redacted<T>(T);
+
+impl<T> Display for Redacted<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if is_enabled() {
+            write_hash(f, &self.0.to_string())
+        } else {
+            Display::fmt(&self.0, f)
+        }
+    }
+}
+
+impl<T> Debug for Redacted<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if is_enabled() {
+            write_hash(f, &format!("{:?}", self.0))
+        } else {
+            Debug::fmt(&self.0, f)
+        }
+    }
+}
+
+fn write_hash(f: &mut Formatter<'_>, value: &str) -> std::fmt::Result {
+    let hash = KEY.get_or_init(RandomState::new).hash_one(value);
+    write!(f, "<redacted:{:08x}>", hash as u32)
+}
+
+/// Options whose values are always hidden, whether redaction is enabled or not.
+const SECRET_OPTIONS: [&str; 3] = ["--admin-token", "--read-token", "--sentry-dsn"];
+
+/// Redacts the arguments of a command line that contain an IP address, such as `--ip 10.0.0.1` or
+/// `192.168.1.12/7`. Secrets are always hidden.
+pub fn redact_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut previous_is_secret_option = false;
+    args.into_iter()
+        .map(|arg| {
+            let is_secret = previous_is_secret_option;
+            previous_is_secret_option = SECRET_OPTIONS.contains(&arg.as_str());
+
+            if let Some((option, _)) = arg
+                .split_once('=')
+                .filter(|(option, _)| SECRET_OPTIONS.contains(option))
+            {
+                format!("{}=<secret>", option)
+            } else if is_secret {
+                "<secret>".to_string()
+            } else if is_enabled() && contains_address(&arg) {
+                redact(arg).to_string()
+            } else {
+                arg
+            }
+        })
+        .collect()
+}
+
+fn contains_address(arg: &str) -> bool {
+    arg.split(['=', '/'])
+        .any(|part| part.parse::<IpAddr>().is_ok() || part.parse::<SocketAddr>().is_ok())
+}
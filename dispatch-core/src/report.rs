@@ -0,0 +1,181 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use color_eyre::Help;
+use eyre::{Result, WrapErr};
+use owo_colors::OwoColorize;
+use rusqlite::Connection;
+use serde::Serialize;
+use term_table::{
+    row::Row,
+    table_cell::{Alignment, TableCell},
+    Table, TableStyle,
+};
+
+use crate::history::{open_db, unix_secs};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ReportFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    since: u64,
+    interfaces: Vec<UsageSummary>,
+    destinations: Vec<UsageSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageSummary {
+    name: String,
+    connections: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+/// Prints per-interface and per-destination usage aggregated from the connection history.
+pub fn report(history_path: &Path, since: Duration, format: ReportFormat) -> Result<()> {
+    if !history_path.exists() {
+        return Err(eyre::eyre!(
+            "No connection history found at {}",
+            history_path.to_string_lossy()
+        )
+        .suggestion("Start the proxy with `--history` to record connections."));
+    }
+
+    let db = open_db(history_path)?;
+    let since = SystemTime::now().checked_sub(since).unwrap_or(UNIX_EPOCH);
+    let report = Report {
+        since: unix_secs(since) as u64,
+        interfaces: summarize(&db, "interface", since)?,
+        destinations: summarize(&db, "COALESCE(domain, destination)", since)?,
+    };
+
+    match format {
+        ReportFormat::Table => print_tables(&report),
+        ReportFormat::Csv => print_csv(&report),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    Ok(())
+}
+
+fn summarize(db: &Connection, key: &str, since: SystemTime) -> Result<Vec<UsageSummary>> {
+    let mut statement = db.prepare(&format!(
+        "SELECT {key}, COUNT(*), SUM(bytes_up), SUM(bytes_down) FROM connections
+        WHERE ended_at >= ?1
+        GROUP BY 1
+        ORDER BY SUM(bytes_up) + SUM(bytes_down) DESC"
+    ))?;
+    let summaries = statement
+        .query_map([unix_secs(since)], |row| {
+            Ok(UsageSummary {
+                name: row.get(0)?,
+                connections: row.get::<_, i64>(1)? as u64,
+                bytes_up: row.get::<_, i64>(2)? as u64,
+                bytes_down: row.get::<_, i64>(3)? as u64,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err("Failed to read the connection history")?;
+    Ok(summaries)
+}
+
+fn print_tables(report: &Report) {
+    for (title, summaries) in [
+        ("Interface", &report.interfaces),
+        ("Destination", &report.destinations),
+    ] {
+        let mut table = Table::new();
+        table.style = TableStyle::extended();
+        table.add_row(Row::new(
+            [title, "Connections", "Up", "Down"]
+                .into_iter()
+                .map(|header| TableCell::new(header.bold())),
+        ));
+        for summary in summaries {
+            table.add_row(Row::new(vec![
+                TableCell::new(&summary.name),
+                TableCell::new_with_alignment(summary.connections, 1, Alignment::Right),
+                TableCell::new_with_alignment(format_bytes(summary.bytes_up), 1, Alignment::Right),
+                TableCell::new_with_alignment(
+                    format_bytes(summary.bytes_down),
+                    1,
+                    Alignment::Right,
+                ),
+            ]));
+        }
+        println!("{}", table.render());
+    }
+}
+
+fn print_csv(report: &Report) {
+    println!("kind,name,connections,bytes_up,bytes_down");
+    for (kind, summaries) in [
+        ("interface", &report.interfaces),
+        ("destination", &report.destinations),
+    ] {
+        for summary in summaries {
+            println!(
+                "{},{},{},{},{}",
+                kind,
+                csv_field(&summary.name),
+                summary.connections,
+                summary.bytes_up,
+                summary.bytes_down
+            );
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Parses a size in bytes, with an optional binary unit, e.g. 64KiB or 1M.
+pub fn parse_bytes(src: &str) -> Result<usize> {
+    let (value, unit) = src.split_at(src.find(|c: char| !c.is_ascii_digit()).unwrap_or(src.len()));
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        "t" | "tib" => 1024 * 1024 * 1024 * 1024,
+        _ => {
+            return Err(eyre::eyre!("Unknown unit `{}` in `{}`", unit, src))
+                .suggestion("Use B, KiB, MiB, GiB or TiB, e.g. 64KiB");
+        }
+    };
+    value
+        .parse::<usize>()
+        .wrap_err_with(|| format!("Invalid size `{}`", src))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| eyre::eyre!("The size `{}` is too large", src))
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
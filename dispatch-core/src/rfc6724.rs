@@ -0,0 +1,234 @@
+//! Destination address ordering as per RFC 6724, which sorts the addresses a domain resolved to by how likely they are
+//! to work from the local addresses available, e.g. so that a unique local IPv6 destination isn't tried first when
+//! only global addresses can reach it, or an IPv6 destination when there is no local IPv6 address at all.
+//!
+//! Rules 3, 4 and 7, about deprecated addresses, home addresses and native transport, are left out since the proxy
+//! doesn't know about them, and rule 9 only applies to IPv6 destinations.
+
+use std::{
+    cmp::Reverse,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+/// The default policy table of RFC 6724, as `(prefix, prefix length, precedence, label)`, longest prefixes first.
+const POLICY_TABLE: [(Ipv6Addr, u8, u8, u8); 9] = [
+    (Ipv6Addr::LOCALHOST, 128, 50, 0),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96, 35, 4),
+    (Ipv6Addr::UNSPECIFIED, 96, 1, 3),
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 32, 5, 5),
+    (Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16, 30, 2),
+    (Ipv6Addr::new(0x3ffe, 0, 0, 0, 0, 0, 0, 0), 16, 1, 12),
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10, 1, 11),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7, 3, 13),
+    (Ipv6Addr::UNSPECIFIED, 0, 40, 1),
+];
+
+const SCOPE_LINK_LOCAL: u8 = 0x2;
+const SCOPE_SITE_LOCAL: u8 = 0x5;
+const SCOPE_GLOBAL: u8 = 0xe;
+
+/// IPv4 addresses are looked up as IPv4-mapped IPv6 addresses.
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn policy(ip: IpAddr) -> (u8, u8) {
+    let bits = u128::from(to_ipv6(ip));
+    POLICY_TABLE
+        .iter()
+        .find(|(prefix, len, _, _)| common_prefix_len(bits, u128::from(*prefix)) >= u32::from(*len))
+        .map(|&(_, _, precedence, label)| (precedence, label))
+        .expect("the policy table has a default entry")
+}
+
+fn precedence(ip: IpAddr) -> u8 {
+    policy(ip).0
+}
+
+fn label(ip: IpAddr) -> u8 {
+    policy(ip).1
+}
+
+fn scope(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(ip) if ip.is_loopback() || ip.is_link_local() => SCOPE_LINK_LOCAL,
+        IpAddr::V4(_) => SCOPE_GLOBAL,
+        IpAddr::V6(ip) if ip.is_multicast() => ip.segments()[0] as u8 & 0xf,
+        IpAddr::V6(ip) if ip.is_loopback() || ip.segments()[0] & 0xffc0 == 0xfe80 => {
+            SCOPE_LINK_LOCAL
+        }
+        IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfec0 => SCOPE_SITE_LOCAL,
+        IpAddr::V6(_) => SCOPE_GLOBAL,
+    }
+}
+
+fn common_prefix_len(a: u128, b: u128) -> u32 {
+    (a ^ b).leading_zeros()
+}
+
+/// Picks the local address that a destination would be reached from, among those of its IP version: preferably one of
+/// the same scope, then with the same label, then sharing the longest prefix with it.
+fn source(destination: IpAddr, sources: &[IpAddr]) -> Option<IpAddr> {
+    sources
+        .iter()
+        .copied()
+        .filter(|source| source.is_ipv4() == destination.is_ipv4())
+        .max_by_key(|&source| {
+            (
+                scope(source) == scope(destination),
+                label(source) == label(destination),
+                common_prefix_len(
+                    u128::from(to_ipv6(source)),
+                    u128::from(to_ipv6(destination)),
+                ),
+            )
+        })
+}
+
+/// Sorts destinations from the most to the least preferred when connecting from one of the given local addresses.
+/// Destinations that compare equal keep their order.
+pub fn sort_destinations(destinations: &mut [SocketAddr], sources: &[IpAddr]) {
+    destinations.sort_by_cached_key(|destination| {
+        let destination = destination.ip();
+        let source = source(destination, sources);
+        Reverse((
+            // Rule 1: avoid unusable destinations.
+            source.is_some(),
+            // Rule 2: prefer matching scope.
+            source.is_some_and(|source| scope(source) == scope(destination)),
+            // Rule 5: prefer matching label.
+            source.is_some_and(|source| label(source) == label(destination)),
+            // Rule 6: prefer higher precedence.
+            precedence(destination),
+            // Rule 8: prefer smaller scope.
+            Reverse(scope(destination)),
+            // Rule 9: use the longest matching prefix.
+            match (source, destination) {
+                (Some(IpAddr::V6(source)), IpAddr::V6(destination)) => {
+                    common_prefix_len(u128::from(source), u128::from(destination)).min(64)
+                }
+                _ => 0,
+            },
+        ))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(src: &str) -> IpAddr {
+        src.parse().unwrap()
+    }
+
+    fn sorted(destinations: &[&str], sources: &[&str]) -> Vec<IpAddr> {
+        let mut destinations = destinations
+            .iter()
+            .map(|destination| SocketAddr::new(ip(destination), 443))
+            .collect::<Vec<_>>();
+        let sources = sources.iter().map(|source| ip(source)).collect::<Vec<_>>();
+        sort_destinations(&mut destinations, &sources);
+        destinations.iter().map(SocketAddr::ip).collect()
+    }
+
+    #[test]
+    fn looks_up_precedences() {
+        assert_eq!(precedence(ip("::1")), 50);
+        assert_eq!(precedence(ip("2001:db8::1")), 40);
+        assert_eq!(precedence(ip("192.0.2.1")), 35);
+        assert_eq!(precedence(ip("::ffff:192.0.2.1")), 35);
+        assert_eq!(precedence(ip("2002:c000:201::1")), 30);
+        assert_eq!(precedence(ip("2001::1")), 5);
+        assert_eq!(precedence(ip("fd00::1")), 3);
+        assert_eq!(precedence(ip("::192.0.2.1")), 1);
+        assert_eq!(precedence(ip("3ffe::1")), 1);
+        assert_eq!(precedence(ip("fec0::1")), 1);
+    }
+
+    #[test]
+    fn looks_up_scopes() {
+        assert_eq!(scope(ip("127.0.0.1")), SCOPE_LINK_LOCAL);
+        assert_eq!(scope(ip("169.254.1.1")), SCOPE_LINK_LOCAL);
+        assert_eq!(scope(ip("10.0.0.1")), SCOPE_GLOBAL);
+        assert_eq!(scope(ip("::1")), SCOPE_LINK_LOCAL);
+        assert_eq!(scope(ip("fe80::1")), SCOPE_LINK_LOCAL);
+        assert_eq!(scope(ip("fec0::1")), SCOPE_SITE_LOCAL);
+        assert_eq!(scope(ip("ff05::1")), SCOPE_SITE_LOCAL);
+        assert_eq!(scope(ip("2001:db8::1")), SCOPE_GLOBAL);
+    }
+
+    #[test]
+    fn avoids_unusable_destinations() {
+        assert_eq!(
+            sorted(&["2001:db8::1", "192.0.2.1"], &["10.0.0.2"]),
+            [ip("192.0.2.1"), ip("2001:db8::1")]
+        );
+        assert_eq!(
+            sorted(&["192.0.2.1", "2001:db8::1"], &["2001:db8::2"]),
+            [ip("2001:db8::1"), ip("192.0.2.1")]
+        );
+    }
+
+    #[test]
+    fn prefers_ipv6_over_ipv4() {
+        assert_eq!(
+            sorted(&["192.0.2.1", "2001:db8::1"], &["10.0.0.2", "2001:db8::2"]),
+            [ip("2001:db8::1"), ip("192.0.2.1")]
+        );
+    }
+
+    #[test]
+    fn prefers_matching_labels() {
+        // Examples of section 10.2 of RFC 6724.
+        assert_eq!(
+            sorted(&["fd00::1", "2001:db8::1"], &["2001:db8::2"]),
+            [ip("2001:db8::1"), ip("fd00::1")]
+        );
+        assert_eq!(
+            sorted(
+                &["2001:db8::1", "2002:c633:6401::1"],
+                &["2002:c633:6401::2", "2001:db8::2"]
+            ),
+            [ip("2001:db8::1"), ip("2002:c633:6401::1")]
+        );
+        assert_eq!(
+            sorted(
+                &["2001:db8::1", "2002:c633:6401::1"],
+                &["2002:c633:6401::2"]
+            ),
+            [ip("2002:c633:6401::1"), ip("2001:db8::1")]
+        );
+    }
+
+    #[test]
+    fn prefers_matching_and_smaller_scopes() {
+        assert_eq!(
+            sorted(&["2001:db8::1", "fe80::1"], &["2001:db8::2", "fe80::2"]),
+            [ip("fe80::1"), ip("2001:db8::1")]
+        );
+        assert_eq!(
+            sorted(&["fe80::1", "2001:db8::1"], &["2001:db8::2"]),
+            [ip("2001:db8::1"), ip("fe80::1")]
+        );
+    }
+
+    #[test]
+    fn prefers_longest_matching_prefixes() {
+        assert_eq!(
+            sorted(&["2001:db8:3ffe::1", "2001:db8:1::1"], &["2001:db8:1::2"]),
+            [ip("2001:db8:1::1"), ip("2001:db8:3ffe::1")]
+        );
+        // Only the first 64 bits count, and IPv4 destinations keep their order.
+        assert_eq!(
+            sorted(&["2001:db8:1::1:1", "2001:db8:1::1"], &["2001:db8:1::2"]),
+            [ip("2001:db8:1::1:1"), ip("2001:db8:1::1")]
+        );
+        assert_eq!(
+            sorted(&["192.0.2.1", "10.0.0.1"], &["10.0.0.2"]),
+            [ip("192.0.2.1"), ip("10.0.0.1")]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! The limit on open files, which every relayed connection counts against twice, once per socket. Most systems default
+//! to 1024 (or 256 on macOS), which a browser loading a few pages through the proxy exhausts quickly, after which
+//! accepting and connecting fail with "Too many open files".
+
+use std::io;
+
+/// The soft limit on open files, before and after raising it.
+#[derive(Clone, Copy, Debug)]
+pub struct OpenFiles {
+    pub previous: u64,
+    pub current: u64,
+}
+
+/// Raises the soft limit on open files to `target`, or to the most that the hard limit and the system allow. The limit
+/// is never lowered.
+pub fn raise_open_files(target: u64) -> io::Result<OpenFiles> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let previous = limit.rlim_cur;
+    let wanted = target.min(max_open_files()?.min(limit.rlim_max));
+    if wanted <= previous {
+        return Ok(OpenFiles {
+            previous,
+            current: previous,
+        });
+    }
+    limit.rlim_cur = wanted;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(OpenFiles {
+        previous,
+        current: wanted,
+    })
+}
+
+/// The most files a process can open, whatever its hard limit.
+#[cfg(target_os = "linux")]
+fn max_open_files() -> io::Result<u64> {
+    // The hard limit can't be above it, so there is nothing more to cap.
+    Ok(u64::MAX)
+}
+
+/// The most files a process can open, whatever its hard limit, which is usually infinite while `setrlimit` refuses
+/// anything above `kern.maxfilesperproc`.
+#[cfg(target_os = "macos")]
+fn max_open_files() -> io::Result<u64> {
+    let mut max: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>();
+    let res = unsafe {
+        libc::sysctlbyname(
+            c"kern.maxfilesperproc".as_ptr(),
+            &mut max as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(max as u64)
+}
@@ -0,0 +1,1526 @@
+//! Routing rules, which send traffic to matching destinations through a given network interface, or deny it.
+//!
+//! Rules are written one per line, as `<pattern> <action> [<option>=<value>...]`:
+//!
+//! ```text
+//! # Stream over the fiber line, and keep clients away from the LAN.
+//! netflix.com      eth0
+//! 192.168.0.0/16   deny
+//! # Log in to the captive portal of the hotel through whichever interface the system routes it to.
+//! portal.example   direct
+//! # Let routers prioritize calls.
+//! sip.example.com  dispatch  dscp=ef
+//! # Keep guests to 2 Mbps each.
+//! *                dispatch  from=192.168.2.0/24  client-rate=2Mbps
+//! # Keep the bank seeing the same address for a session, and give up quickly on a flaky API.
+//! bank.example     dispatch  sticky=30m
+//! api.example.com  dispatch  connect-timeout=5s
+//! # Send the media PC over 4G, and the work laptop over the fiber line.
+//! *                wlan0     from=192.168.1.20
+//! *                eth0      user=work
+//! # Send the browser over the fiber line and the torrent client over 4G, when they run on the proxy's machine.
+//! *                eth0      app=firefox
+//! *                wlan0     app=qbittorrent
+//! # Only send backups over the metered 4G link at night.
+//! backup.example   wlan0     time=01:00-06:00
+//! backup.example   eth0
+//! # Keep clients away from internal services.
+//! @proxy           deny
+//! @loopback        deny
+//! # Keep clients away from a country, as located by the GeoIP database.
+//! country:KP       deny
+//! # Block ads, telling clients that the host is unreachable rather than that the proxy refused.
+//! ads.example.com  block     reply=host-unreachable
+//! list:ads.txt     block
+//! # Only allow web and SSH traffic.
+//! *                dispatch  port=80,443,22
+//! *                deny
+//! ```
+//!
+//! A domain pattern matches the domain and its subdomains, when the client requested a domain name, and a `list:`
+//! pattern followed by a path or URL matches the domains of a hosts file, dnsmasq configuration, adblock filter list,
+//! v2ray domain list or geosite database. An IP address or CIDR range matches the address the destination resolved to,
+//! and `*` matches every destination. Named ranges match the loopback addresses with `@loopback`, private addresses
+//! with `@private`, link-local addresses with `@link-local`, and the addresses the proxy itself listens on with
+//! `@proxy`, while `country:<code>` matches the addresses the GeoIP database locates in a country. A destination that
+//! matches a domain or list pattern is still denied if the address it resolved to is denied by the first of the rules
+//! with an address pattern that matches it, so that domains can't be used to reach denied addresses, e.g. with DNS
+//! rebinding. A domain that a rule denies is refused before it is resolved, so that it isn't even looked up, unless a
+//! rule with an address pattern that doesn't deny comes first. The action is either `deny` (or `block`, which is the
+//! same), `dispatch` to dispatch as usual, `direct` to connect without binding to any address and let the system route
+//! the connection, or the network interface name or IP address to connect from. An allowlist is a list of rules
+//! followed by `* deny`.
+//!
+//! Options restrict a rule to the clients in a range with `from=<ip or range>`, to the clients that authenticated as
+//! one of a list of users with `user=<names>`, to the clients on the same machine that connect from one of a list of
+//! applications with `app=<names>`, given by the names of their executables, and to the destination ports in a list of
+//! ports and ranges with `port=<ports>`, e.g. `port=80,443,8000-8999`. Denied connections are refused with the SOCKS5
+//! reply "connection not allowed by ruleset", or another one given with `reply=<reply>`, one of `not-allowed`,
+//! `network-unreachable`, `host-unreachable` and `connection-refused`. Connections that aren't denied can also be
+//! marked with a DSCP with `dscp=<dscp>`, and limited to a bandwidth in each direction, either each on its own with
+//! `rate=<rate>`, or together with the other connections of the same client with `client-rate=<rate>`. They can be
+//! given up on when an address doesn't answer within `connect-timeout=<duration>` rather than the timeout of the
+//! system, and dispatched connections can be kept on the interface of the last connection of the same client to the
+//! same destination with `sticky=<duration>`, for as long as it connected within that duration. Rules can apply only on
+//! some days of the week with `days=<days>`, e.g. `days=mon-fri,sun`, and at some times of day with `time=<times>`,
+//! e.g. `time=09:00-12:00,22:00-06:00`, where a range that ends before it starts goes on past midnight, as told by the
+//! local clock of the proxy when the connection is made. The first matching rule applies, and traffic that matches no
+//! rule is dispatched as usual.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    apps,
+    connections::parse_client_range,
+    denials::Reason,
+    dispatcher::{RawInterface, RawWeightedAddress, WeightedAddress},
+    geoip::{Country, GeoIp},
+    lists::DomainList,
+    net::{Dscp, LocalIps, NamedInterface},
+    redact::redact,
+    schedule::{LocalTime, Schedule},
+    socks::Destination,
+    throttle::{Rate, Throttle, TokenBucket},
+    users::User,
+};
+
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Any,
+    /// A lowercase domain name, without a trailing dot.
+    Domain(String),
+    /// The domains of a list maintained by a third party.
+    List(Arc<DomainList>),
+    Net(IpNet),
+    /// Loopback addresses, and the unspecified addresses, which also reach the local host.
+    Loopback,
+    /// Private IPv4 addresses, shared addresses of carrier-grade NAT, and unique local IPv6 addresses.
+    Private,
+    LinkLocal,
+    /// The addresses the proxy itself listens on.
+    Proxy,
+    /// The addresses located in a country.
+    Country(Country),
+}
+
+impl Pattern {
+    pub fn matches(&self, destination: &Destination, environment: &Environment) -> bool {
+        // An IPv4-mapped IPv6 address reaches the IPv4 address it maps.
+        let ip = destination.addr.ip().to_canonical();
+        match self {
+            Pattern::Any => true,
+            Pattern::Domain(domain) => destination
+                .domain
+                .as_deref()
+                .is_some_and(|requested| is_same_or_subdomain(requested, domain)),
+            Pattern::List(list) => destination
+                .domain
+                .as_deref()
+                .is_some_and(|requested| list.contains(requested)),
+            Pattern::Net(net) => net.contains(&ip),
+            Pattern::Loopback => match ip {
+                IpAddr::V4(ip) => ip.is_loopback() || ip.octets()[0] == 0,
+                IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+            },
+            Pattern::Private => match ip {
+                IpAddr::V4(ip) => {
+                    let [a, b, ..] = ip.octets();
+                    ip.is_private() || (a == 100 && b & 0xc0 == 64)
+                }
+                IpAddr::V6(ip) => ip.segments()[0] & 0xfe00 == 0xfc00,
+            },
+            Pattern::LinkLocal => match ip {
+                IpAddr::V4(ip) => ip.is_link_local(),
+                IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+            },
+            Pattern::Proxy => environment
+                .endpoints
+                .contains(SocketAddr::new(ip, destination.addr.port())),
+            Pattern::Country(country) => environment
+                .geoip
+                .as_ref()
+                .is_some_and(|geoip| geoip.country(ip) == Some(*country)),
+        }
+    }
+
+    /// Whether the pattern matches addresses, rather than every destination or domain names.
+    pub fn matches_addresses(&self) -> bool {
+        !matches!(self, Pattern::Any | Pattern::Domain(_) | Pattern::List(_))
+    }
+
+    /// Whether matching the pattern requires a GeoIP database.
+    pub fn needs_geoip(&self) -> bool {
+        matches!(self, Pattern::Country(_))
+    }
+}
+
+/// What patterns are matched against besides the destination: the addresses of the proxy, and the GeoIP database.
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    pub endpoints: Endpoints,
+    pub geoip: Option<GeoIp>,
+}
+
+impl Environment {
+    /// Fails if some rules or user policies match countries, but there is no GeoIP database to locate addresses with.
+    pub fn check(&self, needs_geoip: bool) -> Result<()> {
+        if needs_geoip && self.geoip.is_none() {
+            return Err(
+                eyre::eyre!("Matching destinations by country requires a GeoIP database")
+                    .suggestion(
+                    "Pass a MaxMind DB country database with `--geoip`, e.g. GeoLite2-Country.mmdb",
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The addresses the proxy itself listens on, for SOCKS clients and the admin and gRPC endpoints.
+#[derive(Clone, Debug, Default)]
+pub struct Endpoints {
+    socks: Option<SocketAddr>,
+    others: Vec<SocketAddr>,
+    /// The addresses of this host, which reach the endpoints that listen on all of them.
+    local: LocalIps,
+}
+
+impl Endpoints {
+    pub fn new(socks: SocketAddr, others: Vec<SocketAddr>, local: LocalIps) -> Endpoints {
+        Endpoints {
+            socks: Some(socks),
+            others,
+            local,
+        }
+    }
+
+    /// Whether connecting to an address could reach one of the endpoints.
+    fn contains(&self, addr: SocketAddr) -> bool {
+        self.socks
+            .iter()
+            .chain(&self.others)
+            .any(|endpoint| self.reaches(addr, *endpoint))
+    }
+
+    /// Whether connecting to an address could reach the SOCKS listener, so that the connection would come back to the
+    /// proxy, and loop until it runs out of sockets.
+    pub fn loops(&self, addr: SocketAddr) -> bool {
+        self.socks.is_some_and(|socks| self.reaches(addr, socks))
+    }
+
+    /// Whether connecting to an address could reach an endpoint listening on another, including through a loopback
+    /// address, or any address of this host when the endpoint listens on all of them.
+    fn reaches(&self, addr: SocketAddr, endpoint: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        addr.port() == endpoint.port()
+            && (ip == endpoint.ip().to_canonical()
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || (endpoint.ip().is_unspecified() && self.local.contains(ip)))
+    }
+}
+
+/// Whether the requested domain is the given lowercase domain or one of its subdomains.
+pub fn is_same_or_subdomain(requested: &str, domain: &str) -> bool {
+    let requested = requested.strip_suffix('.').unwrap_or(requested).as_bytes();
+    let domain = domain.as_bytes();
+    match requested.len().checked_sub(domain.len()) {
+        Some(0) => requested.eq_ignore_ascii_case(domain),
+        Some(start) => {
+            requested[start - 1] == b'.' && requested[start..].eq_ignore_ascii_case(domain)
+        }
+        None => false,
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Pattern> {
+        match src {
+            "*" => return Ok(Pattern::Any),
+            "@loopback" => return Ok(Pattern::Loopback),
+            "@private" => return Ok(Pattern::Private),
+            "@link-local" => return Ok(Pattern::LinkLocal),
+            "@proxy" => return Ok(Pattern::Proxy),
+            _ if src.starts_with('@') => {
+                return Err(eyre::eyre!("Unknown named range `{}`", src).suggestion(
+                    "Named ranges are `@loopback`, `@private`, `@link-local` and `@proxy`",
+                ));
+            }
+            _ => {}
+        }
+        if let Some(country) = src.strip_prefix("country:") {
+            return Ok(Pattern::Country(Country::parse(country)?));
+        }
+        if let Some(list) = src.strip_prefix("list:") {
+            return Ok(Pattern::List(DomainList::load(list)?));
+        }
+        if src.contains('/') {
+            let net = src
+                .parse::<IpNet>()
+                .wrap_err_with(|| format!("Failed to parse `{}` as a CIDR range", src))?;
+            return Ok(Pattern::Net(net.trunc()));
+        }
+        if let Ok(ip) = src.parse::<IpAddr>() {
+            return Ok(Pattern::Net(ip.into()));
+        }
+
+        let domain = src.strip_suffix('.').unwrap_or(src);
+        if domain.starts_with("*.") {
+            return Err(eyre::eyre!("Invalid domain pattern `{}`", src)
+                .suggestion("Domain patterns already match subdomains, remove the leading `*.`"));
+        }
+        let valid = !domain.is_empty()
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && label
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+            });
+        if !valid {
+            return Err(eyre::eyre!(
+                "`{}` isn't a domain name, IP address or CIDR range",
+                src
+            ));
+        }
+        Ok(Pattern::Domain(domain.to_ascii_lowercase()))
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Pattern::Any => f.write_str("*"),
+            Pattern::Loopback => f.write_str("@loopback"),
+            Pattern::Private => f.write_str("@private"),
+            Pattern::LinkLocal => f.write_str("@link-local"),
+            Pattern::Proxy => f.write_str("@proxy"),
+            Pattern::Country(country) => write!(f, "country:{}", country),
+            Pattern::Domain(domain) => domain.fmt(f),
+            Pattern::List(list) => write!(f, "list:{}", list),
+            Pattern::Net(net) if net.prefix_len() == net.max_prefix_len() => net.addr().fmt(f),
+            Pattern::Net(net) => net.fmt(f),
+        }
+    }
+}
+
+/// Destination ports, as a list of ports and ranges of ports.
+#[derive(Clone, Debug)]
+pub struct Ports(Vec<RangeInclusive<u16>>);
+
+impl Ports {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&port))
+    }
+}
+
+impl FromStr for Ports {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Ports> {
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| eyre::eyre!("`{}` isn't a port", port))
+                .suggestion("Ports are given as numbers from 1 to 65535, or ranges of them, e.g. `80,443,8000-8999`")
+        };
+        let ranges = src
+            .split(',')
+            .map(|range| match range.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse_port(start)?, parse_port(end)?);
+                    if start > end {
+                        return Err(eyre::eyre!("The port range `{}` is empty", range));
+                    }
+                    Ok(start..=end)
+                }
+                None => parse_port(range).map(|port| port..=port),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Ports(ranges))
+    }
+}
+
+impl Display for Ports {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for (index, range) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The SOCKS5 reply that denied connections are refused with. SOCKS4 only has a single reply for failures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Reply {
+    /// "Connection not allowed by ruleset".
+    #[default]
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+}
+
+impl FromStr for Reply {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Reply> {
+        match src {
+            "not-allowed" => Ok(Reply::NotAllowed),
+            "network-unreachable" => Ok(Reply::NetworkUnreachable),
+            "host-unreachable" => Ok(Reply::HostUnreachable),
+            "connection-refused" => Ok(Reply::ConnectionRefused),
+            _ => Err(eyre::eyre!("Unknown reply `{}`", src).suggestion(
+                "Replies are `not-allowed`, `network-unreachable`, `host-unreachable` and `connection-refused`",
+            )),
+        }
+    }
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(match self {
+            Reply::NotAllowed => "not-allowed",
+            Reply::NetworkUnreachable => "network-unreachable",
+            Reply::HostUnreachable => "host-unreachable",
+            Reply::ConnectionRefused => "connection-refused",
+        })
+    }
+}
+
+/// A network interface name or IP address to connect from.
+#[derive(Clone, Debug)]
+pub struct Route {
+    interface: RawInterface,
+    /// The local addresses of the interface, resolved when the route is loaded.
+    ips: Vec<IpAddr>,
+    /// The network interface, when it was given by name.
+    named: Option<NamedInterface>,
+}
+
+impl Route {
+    /// Resolves the local addresses of an interface, or reuses those it already resolved to while loading the same
+    /// file.
+    pub fn resolve(
+        interface: &str,
+        resolved: &mut HashMap<String, WeightedAddress>,
+    ) -> Result<Route> {
+        let address = match resolved.get(interface) {
+            Some(address) => address.clone(),
+            None => {
+                let raw = RawWeightedAddress::new(interface.parse()?, NonZeroUsize::MIN);
+                let address = WeightedAddress::resolve(vec![raw])?
+                    .pop()
+                    .expect("an address resolves to itself");
+                resolved.insert(interface.to_string(), address.clone());
+                address
+            }
+        };
+        Ok(Route {
+            interface: interface.parse()?,
+            ips: address.ips(),
+            named: address.named_interface(),
+        })
+    }
+
+    /// Connects to a destination from the local address of the interface of the same IP version, on behalf of the
+    /// rule or user the route was given by.
+    fn verdict(
+        &self,
+        destination: &Destination,
+        options: ConnectOptions,
+        by: &dyn Display,
+    ) -> Result<Verdict> {
+        let ipv4 = destination.addr.is_ipv4();
+        self.ips
+            .iter()
+            .find(|ip| ip.is_ipv4() == ipv4)
+            .map(|ip| Verdict::Route {
+                ip: *ip,
+                interface: self.named.clone(),
+                options,
+            })
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "The {} routes to `{}`, which has no {} address",
+                    by,
+                    self.interface,
+                    if ipv4 { "IPv4" } else { "IPv6" }
+                )
+            })
+    }
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.interface.fmt(f)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    Deny,
+    Dispatch,
+    Direct,
+    Route(Route),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Action::Deny => f.write_str("deny"),
+            Action::Dispatch => f.write_str("dispatch"),
+            Action::Direct => f.write_str("direct"),
+            Action::Route(route) => route.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: Pattern,
+    action: Action,
+    /// The clients the rule applies to, or all of them.
+    clients: Option<IpNet>,
+    /// The users the rule applies to, or all clients, whether they authenticated or not.
+    users: Option<Vec<String>>,
+    /// The normalized names of the applications the rule applies to, when clients connect from the same machine.
+    apps: Option<Vec<String>>,
+    /// The destination ports the rule applies to, or all of them.
+    ports: Option<Ports>,
+    dscp: Option<Dscp>,
+    /// The reply to denied connections.
+    reply: Option<Reply>,
+    /// The bandwidth limit of each connection.
+    rate: Option<Rate>,
+    /// The bandwidth limit of all the connections of each client.
+    client_rate: Option<Rate>,
+    /// How long to wait for each attempt to connect to an address of the destination.
+    connect_timeout: Option<Duration>,
+    /// How long a client keeps being dispatched to a destination from the local address of its last connection.
+    sticky: Option<Duration>,
+    /// The days and times of day the rule applies at, by the local clock.
+    schedule: Schedule,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+        environment: &Environment,
+    ) -> bool {
+        self.applies(client, user, app, destination.addr.port())
+            && self.pattern.matches(destination, environment)
+    }
+
+    /// Whether the options of the rule, rather than its pattern, let it apply to a connection, at the current time.
+    fn applies(&self, client: IpAddr, user: Option<&str>, app: Option<&str>, port: u16) -> bool {
+        self.clients
+            .is_none_or(|clients| clients.contains(&client.to_canonical()))
+            && self
+                .users
+                .as_ref()
+                .is_none_or(|users| user.is_some_and(|user| users.iter().any(|name| name == user)))
+            && self
+                .apps
+                .as_ref()
+                .is_none_or(|apps| app.is_some_and(|app| apps.iter().any(|name| name == app)))
+            && self.ports.as_ref().is_none_or(|ports| ports.contains(port))
+            && (self.schedule.is_always() || self.schedule.contains(LocalTime::now()))
+    }
+
+    /// The action, followed by the options.
+    fn action_with_options(&self) -> String {
+        let mut action = self.action.to_string();
+        if let Some(clients) = self.clients {
+            action += &format!(" from={}", Pattern::Net(clients));
+        }
+        if let Some(users) = &self.users {
+            action += &format!(" user={}", users.join(","));
+        }
+        if let Some(apps) = &self.apps {
+            action += &format!(" app={}", apps.join(","));
+        }
+        if let Some(ports) = &self.ports {
+            action += &format!(" port={}", ports);
+        }
+        if let Some(dscp) = self.dscp {
+            action += &format!(" dscp={}", dscp);
+        }
+        if let Some(reply) = self.reply {
+            action += &format!(" reply={}", reply);
+        }
+        if let Some(rate) = self.rate {
+            action += &format!(" rate={}", rate);
+        }
+        if let Some(rate) = self.client_rate {
+            action += &format!(" client-rate={}", rate);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            action += &format!(" connect-timeout={}", humantime::format_duration(timeout));
+        }
+        if let Some(sticky) = self.sticky {
+            action += &format!(" sticky={}", humantime::format_duration(sticky));
+        }
+        if let Some(days) = self.schedule.days {
+            action += &format!(" days={}", days);
+        }
+        if let Some(times) = &self.schedule.times {
+            action += &format!(" time={}", times);
+        }
+        action
+    }
+
+    /// How the connections that the rule doesn't deny are made.
+    fn connect_options(&self) -> ConnectOptions {
+        ConnectOptions {
+            dscp: self.dscp,
+            timeout: self.connect_timeout,
+            sticky: None,
+        }
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{} {}", self.pattern, self.action_with_options())
+    }
+}
+
+/// What to do with a connection, according to the rules.
+#[derive(Clone, Debug)]
+pub enum Verdict {
+    /// The connection is dispatched as usual.
+    Dispatch {
+        options: ConnectOptions,
+    },
+    /// Connect from whichever address the system routes the destination from, e.g. to reach the LAN.
+    Direct {
+        options: ConnectOptions,
+    },
+    /// Connect from this local address, which belongs to this network interface when it was given by name.
+    Route {
+        ip: IpAddr,
+        interface: Option<NamedInterface>,
+        options: ConnectOptions,
+    },
+    Deny(Denied),
+}
+
+/// How a connection is made, as given by the options of the rule that matched it.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// The DSCP to mark the connection with.
+    pub dscp: Option<Dscp>,
+    /// How long to wait for each attempt to connect to an address of the destination, instead of the system's timeout.
+    pub timeout: Option<Duration>,
+    /// The local address to dispatch the connection from, when it was dispatched recently.
+    pub sticky: Option<Sticky>,
+}
+
+/// The local address that the connections of a client to a destination were last dispatched from, for rules with
+/// `sticky=<duration>`, so that sites which tie sessions to the client's address keep seeing the same one.
+#[derive(Clone, Debug)]
+pub struct Sticky {
+    addresses: Arc<Mutex<StickyAddresses>>,
+    key: StickyKey,
+    duration: Duration,
+}
+
+/// The rule index, the client, the domain or IP address of the destination, and whether it's an IPv6 one, since a
+/// local address only reaches destinations of its own IP version.
+type StickyKey = (usize, IpAddr, String, bool);
+/// The local address of each key, and when it stops sticking. Rules share the map, so each entry carries the expiry of
+/// its own rule.
+type StickyAddresses = HashMap<StickyKey, (IpAddr, Instant)>;
+
+impl Sticky {
+    /// The local address of the last connection, unless it was dispatched longer ago than the duration of the rule.
+    pub fn get(&self) -> Option<IpAddr> {
+        let addresses = self.addresses.lock().unwrap();
+        addresses
+            .get(&self.key)
+            .filter(|(_, expiry)| Instant::now() < *expiry)
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Remembers the local address a connection was dispatched from.
+    pub fn set(&self, ip: IpAddr) {
+        let mut addresses = self.addresses.lock().unwrap();
+        let now = Instant::now();
+        // Forget the destinations that clients haven't connected to for a while.
+        addresses.retain(|_, (_, expiry)| now < *expiry);
+        addresses.insert(self.key.clone(), (ip, now + self.duration));
+    }
+}
+
+/// The verdict on a connection, and the rule that decided it, if any.
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub verdict: Verdict,
+    /// The rules and the index of the rule that decided the connection, unless it was decided by the script or the
+    /// proxy itself.
+    rule: Option<(Arc<RuleSet>, usize)>,
+}
+
+impl Decision {
+    /// Counts a hit of the rule that decided the connection, once it was made or refused.
+    pub fn record_hit(&self) {
+        if let Some((rules, index)) = &self.rule {
+            rules.record_hit(*index);
+        }
+    }
+
+    /// Adds the bandwidth limits of the rule that decided the connection to the throttles of each direction.
+    pub fn throttle(&self, client: IpAddr, up: &mut Throttle, down: &mut Throttle) {
+        if let Some((rules, index)) = &self.rule {
+            rules.throttle(*index, client, up, down);
+        }
+    }
+}
+
+/// The error reported when a rule denies a connection.
+#[derive(Clone, Debug)]
+pub struct Denied {
+    pub destination: Destination,
+    pub rule: String,
+    pub reason: Reason,
+    pub reply: Reply,
+}
+
+impl Display for Denied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "The connection to `{}` was denied by the rule `{}`",
+            redact(&self.destination),
+            self.rule
+        )
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// A rule, as reported to management clients.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleInfo {
+    pub pattern: String,
+    /// The action, followed by the options of the rule, e.g. `eth0 dscp=ef`.
+    pub action: String,
+    /// How many connections the rule decided since it was loaded.
+    #[serde(default)]
+    pub hits: u64,
+}
+
+/// An ordered list of rules.
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    /// The buckets of each direction of the clients of rules with a `client-rate`, by rule index and client.
+    client_buckets: Arc<Mutex<ClientBuckets>>,
+    /// The local addresses that the clients of rules with `sticky=` were last dispatched from.
+    sticky: Arc<Mutex<StickyAddresses>>,
+    /// How many connections each rule decided, by rule index.
+    hits: Arc<Vec<AtomicU64>>,
+}
+
+type ClientBuckets = HashMap<(usize, IpAddr), (TokenBucket, TokenBucket)>;
+
+impl RuleSet {
+    /// Parses rules, and resolves the network interfaces they route to. Fails if any rule is invalid.
+    pub fn parse(src: &str) -> Result<RuleSet> {
+        let mut resolved = HashMap::new();
+        let mut rules = vec![];
+
+        for (index, line) in src.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let rule = parse_rule(line, &mut resolved)
+                .wrap_err_with(|| format!("Invalid rule on line {}: `{}`", index + 1, line))?;
+            rules.push(rule);
+        }
+
+        Ok(RuleSet {
+            hits: Arc::new(rules.iter().map(|_| AtomicU64::new(0)).collect()),
+            rules,
+            client_buckets: Arc::default(),
+            sticky: Arc::default(),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<RuleSet> {
+        let src = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read the rules file `{}`", path.display()))?;
+        RuleSet::parse(&src)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether some rules match countries.
+    pub fn needs_geoip(&self) -> bool {
+        self.rules.iter().any(|rule| rule.pattern.needs_geoip())
+    }
+
+    /// Whether some rules match applications, which are then looked up for the clients on the same machine.
+    pub fn needs_apps(&self) -> bool {
+        self.rules.iter().any(|rule| rule.apps.is_some())
+    }
+
+    pub fn describe(&self) -> Vec<RuleInfo> {
+        self.rules
+            .iter()
+            .zip(self.hits.iter())
+            .map(|(rule, hits)| RuleInfo {
+                pattern: rule.pattern.to_string(),
+                action: rule.action_with_options(),
+                hits: hits.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Counts a hit of a rule, once per connection it decided, whether the connection was made or refused.
+    pub fn record_hit(&self, index: usize) {
+        self.hits[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The rule that would decide a connection, if any, and its verdict, without counting a hit.
+    pub fn explain(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+        environment: &Environment,
+    ) -> Result<(Option<String>, Verdict)> {
+        let (index, verdict) = self.decide(client, user, app, destination, environment)?;
+        Ok((index.map(|index| self.rules[index].to_string()), verdict))
+    }
+
+    /// The verdict of the rules on a connection, along with the index of the rule that decided it, if any, without
+    /// counting a hit.
+    pub fn decide(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+        environment: &Environment,
+    ) -> Result<(Option<usize>, Verdict)> {
+        let Some((index, rule)) = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(client, user, app, destination, environment))
+        else {
+            return Ok((
+                None,
+                Verdict::Dispatch {
+                    options: ConnectOptions::default(),
+                },
+            ));
+        };
+
+        // A domain could resolve to an address that is denied, e.g. an internal one after DNS rebinding, so the
+        // address is checked against the rules that match addresses too.
+        if let Pattern::Domain(_) | Pattern::List(_) = rule.pattern {
+            let denied = self
+                .rules
+                .iter()
+                .enumerate()
+                .find(|(_, rule)| {
+                    rule.pattern.matches_addresses()
+                        && rule.matches(client, user, app, destination, environment)
+                })
+                .filter(|(_, rule)| matches!(rule.action, Action::Deny));
+            if let Some((index, denied)) = denied {
+                return Ok((
+                    Some(index),
+                    Verdict::Deny(Denied {
+                        destination: destination.clone(),
+                        rule: denied.to_string(),
+                        reason: Reason::Rule,
+                        reply: denied.reply.unwrap_or_default(),
+                    }),
+                ));
+            }
+        }
+
+        let verdict = self.verdict(index, rule, client, destination)?;
+        Ok((Some(index), verdict))
+    }
+
+    /// The verdict on a domain before it is resolved, given with the unspecified address of an IP version, along with
+    /// the index of the rule that decided it, if any, without counting a hit. There is none when it depends on the
+    /// addresses the domain resolves to, i.e. when a rule with an address pattern that doesn't deny comes first, since
+    /// it could route or allow them. Rules with an address pattern that deny are passed over, since they can only deny
+    /// the domain once resolved, which is decided again then.
+    pub fn decide_domain(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+        environment: &Environment,
+    ) -> Result<Option<(Option<usize>, Verdict)>> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.applies(client, user, app, destination.addr.port()) {
+                continue;
+            }
+            if rule.pattern.matches_addresses() {
+                match rule.action {
+                    Action::Deny => continue,
+                    _ => return Ok(None),
+                }
+            }
+            if rule.pattern.matches(destination, environment) {
+                let verdict = self.verdict(index, rule, client, destination)?;
+                return Ok(Some((Some(index), verdict)));
+            }
+        }
+        Ok(Some((
+            None,
+            Verdict::Dispatch {
+                options: ConnectOptions::default(),
+            },
+        )))
+    }
+
+    /// The verdict of the action of a rule on a connection.
+    fn verdict(
+        &self,
+        index: usize,
+        rule: &Rule,
+        client: IpAddr,
+        destination: &Destination,
+    ) -> Result<Verdict> {
+        match &rule.action {
+            Action::Deny => Ok(Verdict::Deny(Denied {
+                destination: destination.clone(),
+                rule: rule.to_string(),
+                reason: Reason::Rule,
+                reply: rule.reply.unwrap_or_default(),
+            })),
+            Action::Dispatch => {
+                let sticky = rule.sticky.map(|duration| Sticky {
+                    addresses: Arc::clone(&self.sticky),
+                    key: (
+                        index,
+                        client,
+                        destination
+                            .domain
+                            .clone()
+                            .unwrap_or_else(|| destination.addr.ip().to_string()),
+                        destination.addr.is_ipv6(),
+                    ),
+                    duration,
+                });
+                Ok(Verdict::Dispatch {
+                    options: ConnectOptions {
+                        sticky,
+                        ..rule.connect_options()
+                    },
+                })
+            }
+            Action::Direct => Ok(Verdict::Direct {
+                options: rule.connect_options(),
+            }),
+            Action::Route(route) => route.verdict(
+                destination,
+                rule.connect_options(),
+                &format!("rule `{}`", rule),
+            ),
+        }
+    }
+
+    /// Adds the bandwidth limits of a rule to the throttles of each direction of a connection of a client.
+    fn throttle(&self, index: usize, client: IpAddr, up: &mut Throttle, down: &mut Throttle) {
+        let rule = &self.rules[index];
+        if let Some(rate) = rule.rate {
+            up.push(TokenBucket::new(rate));
+            down.push(TokenBucket::new(rate));
+        }
+        if let Some(rate) = rule.client_rate {
+            let mut client_buckets = self.client_buckets.lock().unwrap();
+            // Forget the clients whose connections have all closed.
+            client_buckets.retain(|_, (up, _)| up.is_shared());
+            let (client_up, client_down) = client_buckets
+                .entry((index, client))
+                .or_insert_with(|| (TokenBucket::new(rate), TokenBucket::new(rate)));
+            up.push(client_up.clone());
+            down.push(client_down.clone());
+        }
+    }
+}
+
+/// The line without its comment. Comments start with `#`, at the beginning of a line or after a space, so that `#`
+/// can appear in patterns like the URLs of `list:`.
+fn strip_comment(line: &str) -> &str {
+    let comment = line
+        .match_indices('#')
+        .map(|(index, _)| index)
+        .find(|&index| index == 0 || line[..index].ends_with(char::is_whitespace));
+    match comment {
+        Some(comment) => &line[..comment],
+        None => line,
+    }
+}
+
+fn parse_rule(line: &str, resolved: &mut HashMap<String, WeightedAddress>) -> Result<Rule> {
+    let mut fields = line.split_whitespace();
+    let (Some(pattern), Some(action)) = (fields.next(), fields.next()) else {
+        return Err(eyre::eyre!("Expected a pattern followed by an action"));
+    };
+
+    let pattern = pattern.parse()?;
+    let (
+        mut clients,
+        mut users,
+        mut apps,
+        mut ports,
+        mut dscp,
+        mut reply,
+        mut rate,
+        mut client_rate,
+    ) = (None, None, None, None, None, None, None, None);
+    let (mut connect_timeout, mut sticky) = (None, None);
+    let mut schedule = Schedule::default();
+    for option in fields {
+        match option.split_once('=') {
+            Some(("from", value)) => clients = Some(parse_client_range(value)?),
+            Some(("user", value)) => users = Some(parse_users(value)?),
+            Some(("app", value)) => apps = Some(parse_apps(value)?),
+            Some(("port", value)) => ports = Some(value.parse()?),
+            Some(("dscp", value)) => dscp = Some(parse_dscp(value)?),
+            Some(("reply", value)) => reply = Some(value.parse()?),
+            Some(("rate", value)) => rate = Some(value.parse()?),
+            Some(("client-rate", value)) => client_rate = Some(value.parse()?),
+            Some(("connect-timeout", value)) => {
+                connect_timeout = Some(parse_duration("connect-timeout", value)?)
+            }
+            Some(("sticky", value)) => sticky = Some(parse_duration("sticky", value)?),
+            Some(("days", value)) => schedule.days = Some(value.parse()?),
+            Some(("time", value)) => schedule.times = Some(value.parse()?),
+            _ => {
+                return Err(eyre::eyre!("Unknown rule option `{}`", option).suggestion(
+                    "Rule options are `from=<ip or range>`, `user=<names>`, `app=<names>`, `port=<ports>`, `dscp=<dscp>`, \
+                    `reply=<reply>`, `rate=<rate>`, `client-rate=<rate>`, `connect-timeout=<duration>`, \
+                    `sticky=<duration>`, `days=<days>` and `time=<times>`, e.g. `port=80,443` or `time=22:00-06:00`",
+                ))
+            }
+        }
+    }
+    let action = match action {
+        "deny" | "block" if dscp.is_some() => {
+            return Err(eyre::eyre!(
+                "Denied connections can't be marked with a DSCP"
+            ));
+        }
+        "deny" | "block" if rate.is_some() || client_rate.is_some() => {
+            return Err(eyre::eyre!("Denied connections can't be rate limited"));
+        }
+        "deny" | "block" if connect_timeout.is_some() || sticky.is_some() => {
+            return Err(eyre::eyre!(
+                "Denied connections can't have a connect timeout or be sticky"
+            ));
+        }
+        "deny" | "block" => Action::Deny,
+        _ if reply.is_some() => {
+            return Err(eyre::eyre!("Only denied connections have a reply")
+                .suggestion("Remove `reply=`, or make the action `deny`"));
+        }
+        _ if sticky.is_some() && action != "dispatch" => {
+            return Err(
+                eyre::eyre!("Only dispatched connections can be sticky").suggestion(
+                    "Remove `sticky=`, or make the action `dispatch`, since the other actions always connect from the \
+                    same interface",
+                ),
+            );
+        }
+        "dispatch" => Action::Dispatch,
+        "direct" => Action::Direct,
+        interface => Action::Route(Route::resolve(interface, resolved)?),
+    };
+
+    Ok(Rule {
+        pattern,
+        action,
+        clients,
+        users,
+        apps,
+        ports,
+        dscp,
+        reply,
+        rate,
+        client_rate,
+        connect_timeout,
+        sticky,
+        schedule,
+    })
+}
+
+/// Parses the duration of a rule option, which must be above zero.
+fn parse_duration(option: &str, src: &str) -> Result<Duration> {
+    let duration = humantime::parse_duration(src)
+        .wrap_err_with(|| format!("Invalid duration in `{}={}`", option, src))
+        .suggestion("Durations are written like `10s` or `5m`")?;
+    if duration.is_zero() {
+        return Err(eyre::eyre!("The duration of `{}=` must be above 0", option));
+    }
+    Ok(duration)
+}
+
+/// Parses the names of the users of a rule, separated by commas.
+fn parse_users(src: &str) -> Result<Vec<String>> {
+    src.split(',')
+        .map(|name| {
+            if name.is_empty() {
+                return Err(eyre::eyre!("Empty user name in `user={}`", src).suggestion(
+                    "Separate the names of the users with commas, e.g. `user=alice,bob`",
+                ));
+            }
+            Ok(name.to_string())
+        })
+        .collect()
+}
+
+/// Parses the names of the applications of a rule, separated by commas.
+#[cfg(any(target_os = "linux", windows))]
+fn parse_apps(src: &str) -> Result<Vec<String>> {
+    src.split(',')
+        .map(|name| {
+            if name.is_empty() {
+                return Err(eyre::eyre!("Empty application name in `app={}`", src).suggestion(
+                    "Separate the names of the executables with commas, e.g. `app=firefox,qbittorrent`",
+                ));
+            }
+            Ok(apps::normalize(name))
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn parse_apps(_src: &str) -> Result<Vec<String>> {
+    Err(
+        eyre::eyre!("Matching applications is only supported on Linux and Windows").suggestion(
+            "Run a proxy per application instead, and point each application to its own proxy",
+        ),
+    )
+}
+
+#[cfg(unix)]
+fn parse_dscp(src: &str) -> Result<Dscp> {
+    src.parse()
+}
+
+#[cfg(windows)]
+fn parse_dscp(_src: &str) -> Result<Dscp> {
+    Err(eyre::eyre!("Marking connections with a DSCP isn't supported on Windows").suggestion(
+        "Windows ignores the DSCP set by applications, mark the traffic of dispatch with a QoS policy instead",
+    ))
+}
+
+/// The rules in effect, which can be replaced while the proxy is running.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    inner: Arc<Mutex<Arc<RuleSet>>>,
+    environment: Arc<Environment>,
+    /// The script that routes connections before the rules, if any.
+    #[cfg(feature = "scripting")]
+    script: Option<Arc<ScriptRouter>>,
+}
+
+/// A routing script, with the routes to the interfaces it returned, which are resolved once.
+#[cfg(feature = "scripting")]
+#[derive(Debug)]
+struct ScriptRouter {
+    script: Script,
+    routes: Mutex<HashMap<String, WeightedAddress>>,
+}
+
+impl Rules {
+    pub fn new(rules: RuleSet, environment: Environment) -> Rules {
+        Rules {
+            inner: Arc::new(Mutex::new(Arc::new(rules))),
+            environment: Arc::new(environment),
+            #[cfg(feature = "scripting")]
+            script: None,
+        }
+    }
+
+    /// Routes connections with a script before the rules, which only decide the connections the script leaves to
+    /// them.
+    #[cfg(feature = "scripting")]
+    pub fn with_script(self, script: Script) -> Rules {
+        Rules {
+            script: Some(Arc::new(ScriptRouter {
+                script,
+                routes: Mutex::new(HashMap::new()),
+            })),
+            ..self
+        }
+    }
+
+    pub fn current(&self) -> Arc<RuleSet> {
+        Arc::clone(&self.inner.lock().unwrap())
+    }
+
+    /// Swaps in new rules, unless they match countries without a GeoIP database. Connections that are being
+    /// established keep using the rules they started with.
+    pub fn replace(&self, rules: RuleSet) -> Result<()> {
+        self.environment.check(rules.needs_geoip())?;
+        *self.inner.lock().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    /// The decision of the rules on a connection, followed by the policy of the user, if the client authenticated.
+    /// Connections to the proxy itself are always denied, as if by an `@proxy deny` rule, since they would loop. The
+    /// hit of the rule isn't counted, since a connection can be decided more than once, e.g. for each address of its
+    /// destination.
+    pub fn decide(
+        &self,
+        client: IpAddr,
+        user: Option<&User>,
+        app: Option<&str>,
+        destination: &Destination,
+    ) -> Result<Decision> {
+        if self.environment.endpoints.loops(destination.addr) {
+            return Ok(Decision {
+                verdict: Verdict::Deny(Denied {
+                    destination: destination.clone(),
+                    rule: format!("{} deny", Pattern::Proxy),
+                    reason: Reason::Rule,
+                    reply: Reply::NotAllowed,
+                }),
+                rule: None,
+            });
+        }
+
+        if let Some(user) = user {
+            if !user.allows(destination, &self.environment) {
+                return Ok(Decision {
+                    verdict: Verdict::Deny(Denied {
+                        destination: destination.clone(),
+                        rule: user.to_string(),
+                        reason: Reason::Rule,
+                        reply: Reply::NotAllowed,
+                    }),
+                    rule: None,
+                });
+            }
+        }
+
+        let name = user.map(|user| user.name.as_str());
+        #[cfg(feature = "scripting")]
+        let scripted = self
+            .scripted(client, name, app, destination)
+            .map(|(verdict, tags)| {
+                if !tags.is_empty() {
+                    tracing::info!(%destination, tags = %tags.join(","), "Tagged by the script");
+                }
+                verdict
+            });
+        #[cfg(not(feature = "scripting"))]
+        let scripted = None;
+        let (verdict, rule) = match scripted {
+            Some(verdict) => (verdict, None),
+            None => {
+                let rules = self.current();
+                let (index, verdict) =
+                    rules.decide(client, name, app, destination, &self.environment)?;
+                (verdict, index.map(|index| (rules, index)))
+            }
+        };
+        let verdict = route_user(verdict, user, destination)?;
+        Ok(Decision { verdict, rule })
+    }
+
+    /// The decision of the policy of the user and of the rules on a domain before it is resolved, given with the
+    /// unspecified address of an IP version, unless it depends on the addresses the domain resolves to, or a script
+    /// decides connections. The hit of the rule isn't counted.
+    pub fn decide_domain(
+        &self,
+        client: IpAddr,
+        user: Option<&User>,
+        app: Option<&str>,
+        destination: &Destination,
+    ) -> Result<Option<Decision>> {
+        if let Some(user) = user {
+            if user.denies_domain(destination, &self.environment) {
+                return Ok(Some(Decision {
+                    verdict: Verdict::Deny(Denied {
+                        destination: destination.clone(),
+                        rule: user.to_string(),
+                        reason: Reason::Rule,
+                        reply: Reply::NotAllowed,
+                    }),
+                    rule: None,
+                }));
+            }
+        }
+
+        // The script is given the address of the destination, which isn't known yet.
+        #[cfg(feature = "scripting")]
+        if self.script.is_some() {
+            return Ok(None);
+        }
+
+        let rules = self.current();
+        let name = user.map(|user| user.name.as_str());
+        let Some((index, verdict)) =
+            rules.decide_domain(client, name, app, destination, &self.environment)?
+        else {
+            return Ok(None);
+        };
+        let verdict = route_user(verdict, user, destination)?;
+        Ok(Some(Decision {
+            verdict,
+            rule: index.map(|index| (rules, index)),
+        }))
+    }
+
+    /// The rule that would decide a connection and its verdict, without counting a hit. The policies of users aren't
+    /// applied, since only their names are given.
+    pub fn explain(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+    ) -> Result<(Option<String>, Verdict)> {
+        if self.environment.endpoints.loops(destination.addr) {
+            let rule = format!("{} deny", Pattern::Proxy);
+            return Ok((
+                Some(rule.clone()),
+                Verdict::Deny(Denied {
+                    destination: destination.clone(),
+                    rule,
+                    reason: Reason::Rule,
+                    reply: Reply::NotAllowed,
+                }),
+            ));
+        }
+        #[cfg(feature = "scripting")]
+        if let Some((verdict, _)) = self.scripted(client, user, app, destination) {
+            let script = self.script.as_ref().map(|router| router.script.to_string());
+            return Ok((script, verdict));
+        }
+        self.current()
+            .explain(client, user, app, destination, &self.environment)
+    }
+
+    /// The verdict of the script on a connection and the tags it gave it, unless it left the connection to the rules.
+    /// A script that fails leaves the connection to the rules too, so that a bug in it doesn't take the proxy down.
+    #[cfg(feature = "scripting")]
+    fn scripted(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+    ) -> Option<(Verdict, Vec<String>)> {
+        let router = self.script.as_ref()?;
+        let script = &router.script;
+        let verdict = script
+            .route(client, user, app, destination)
+            .and_then(|decision| {
+                let Some(decision) = decision else {
+                    return Ok(None);
+                };
+                let verdict = match decision.action.as_str() {
+                    "deny" | "block" => Verdict::Deny(Denied {
+                        destination: destination.clone(),
+                        rule: script.to_string(),
+                        reason: Reason::Rule,
+                        reply: Reply::NotAllowed,
+                    }),
+                    "dispatch" => Verdict::Dispatch {
+                        options: ConnectOptions::default(),
+                    },
+                    "direct" => Verdict::Direct {
+                        options: ConnectOptions::default(),
+                    },
+                    interface => {
+                        let route = Route::resolve(interface, &mut router.routes.lock().unwrap())?;
+                        route.verdict(destination, ConnectOptions::default(), script)?
+                    }
+                };
+                Ok(Some((verdict, decision.tags)))
+            });
+        match verdict {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                tracing::warn!(%destination, "The {} failed, applying the rules: {:#}", script, err);
+                None
+            }
+        }
+    }
+}
+
+/// Routes the connections that are dispatched to the interface of the user, if it has one.
+fn route_user(verdict: Verdict, user: Option<&User>, destination: &Destination) -> Result<Verdict> {
+    match verdict {
+        Verdict::Dispatch { options } => match user.and_then(|user| Some((user, user.route()?))) {
+            Some((user, route)) => {
+                route.verdict(destination, options, &format!("user `{}`", user.name))
+            }
+            None => Ok(Verdict::Dispatch { options }),
+        },
+        verdict => Ok(verdict),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn sticky(addresses: &Arc<Mutex<StickyAddresses>>, rule: usize, duration: Duration) -> Sticky {
+        Sticky {
+            addresses: Arc::clone(addresses),
+            key: (
+                rule,
+                [10, 0, 0, 2].into(),
+                "bank.example".to_string(),
+                false,
+            ),
+            duration,
+        }
+    }
+
+    #[test]
+    fn comments_start_at_the_beginning_of_a_line_or_after_a_space() {
+        assert_eq!(strip_comment("# a comment"), "");
+        assert_eq!(
+            strip_comment("example.com deny # a comment"),
+            "example.com deny "
+        );
+        assert_eq!(
+            strip_comment("example.com deny\t#a comment"),
+            "example.com deny\t"
+        );
+        assert_eq!(
+            strip_comment("list:https://example.com/lists#ads deny # a comment"),
+            "list:https://example.com/lists#ads deny "
+        );
+        assert_eq!(
+            strip_comment("list:lists#ads.txt deny"),
+            "list:lists#ads.txt deny"
+        );
+    }
+
+    #[test]
+    fn parse_keeps_hashes_inside_patterns() {
+        let path =
+            std::env::temp_dir().join(format!("dispatch-rules-{}#ads.txt", std::process::id()));
+        std::fs::write(&path, "ads.example\n").unwrap();
+        let src = format!(
+            "# Block ads\n\nlist:{} deny # from the list\nexample.com direct\n",
+            path.display()
+        );
+        let rules = RuleSet::parse(&src);
+        std::fs::remove_file(&path).unwrap();
+
+        let rules = rules.unwrap();
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(
+            rules.rules[0].to_string(),
+            format!("list:{} deny", path.display())
+        );
+        assert_eq!(rules.rules[1].to_string(), "example.com direct");
+        assert!(RuleSet::parse("example.com#comment deny").is_err());
+    }
+
+    #[test]
+    fn sticky_entries_expire_with_their_own_rule() {
+        let addresses = Arc::default();
+        let long = sticky(&addresses, 0, Duration::from_secs(60 * 60));
+        let short = sticky(&addresses, 1, Duration::from_millis(10));
+
+        long.set([192, 168, 1, 2].into());
+        std::thread::sleep(Duration::from_millis(20));
+        short.set([192, 168, 1, 3].into());
+        assert_eq!(long.get(), Some([192, 168, 1, 2].into()));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(short.get(), None);
+        long.set([192, 168, 1, 4].into());
+        assert_eq!(addresses.lock().unwrap().len(), 1);
+    }
+    #[test]
+    fn domains_are_decided_before_being_resolved_unless_their_addresses_matter() {
+        let decide = |src: &str, domain: &str| {
+            let destination = Destination {
+                domain: Some(domain.to_string()),
+                addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 443),
+            };
+            RuleSet::parse(src)
+                .unwrap()
+                .decide_domain(
+                    [10, 0, 0, 2].into(),
+                    None,
+                    None,
+                    &destination,
+                    &Environment::default(),
+                )
+                .unwrap()
+                .map(|(index, verdict)| (index, matches!(verdict, Verdict::Deny(_))))
+        };
+
+        let rules = "@private deny\nads.example deny\nexample.com direct\n* deny port=22";
+        assert_eq!(decide(rules, "ads.example"), Some((Some(1), true)));
+        assert_eq!(decide(rules, "www.example.com"), Some((Some(2), false)));
+        assert_eq!(decide(rules, "other.example"), Some((None, false)));
+        assert_eq!(
+            decide("ads.example deny port=80\n* deny", "ads.example"),
+            Some((Some(1), true))
+        );
+        // An address could be routed or allowed by a rule that comes first.
+        assert_eq!(
+            decide("10.0.0.0/8 direct\nads.example deny", "ads.example"),
+            None
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Sandboxing of the proxy on Linux, to limit what an attacker could do with it if a bug let them run code in it.
+//!
+//! Landlock restricts the filesystem to the paths the proxy uses: its data directory, where logs, the history, the
+//! quotas and the control socket are kept by default, the files given on the command line, and read-only access to
+//! the system files needed to resolve domains. A seccomp filter then makes the syscalls that a proxy has no use for
+//! fail, such as starting programs, tracing other processes, mounting filesystems or loading kernel modules.
+//!
+//! Landlock only restricts the calling thread and the threads it starts afterwards, so the sandbox is applied before
+//! the runtime starts its threads. The threads started with logging, before the options of `start` are even read, are
+//! left out: the writer of the log file, and the OTLP and Sentry exporters when they are enabled. They only write to
+//! the log file or send to their collector, but code run on them by an attacker wouldn't be held to the filesystem
+//! restrictions. The seccomp filter is applied to every thread of the process, including these.
+
+use std::{
+    fs::OpenOptions,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+/// The rights that apply to files, as opposed to directories.
+const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+
+const ACCESS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const ACCESS_WRITE: u64 = ACCESS_READ
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_REFER
+    | ACCESS_FS_TRUNCATE;
+
+/// The system paths read while resolving domains and reporting errors, e.g. `/etc/resolv.conf`, which may link to
+/// `/run`, and the NSS modules loaded by the system resolver.
+const SYSTEM_PATHS: &[&str] = &["/etc", "/run", "/usr", "/lib", "/lib64", "/proc", "/sys"];
+const DEVICES: &[&str] = &["/dev/null", "/dev/urandom"];
+
+/// Syscalls that fail with `EPERM` in the sandbox.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_move_mount,
+    libc::SYS_open_tree,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_syslog,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// Set in the numbers of the syscalls of the x32 ABI, which would otherwise get around the filter.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// The paths the proxy can access once sandboxed, besides the system ones.
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+/// How the process was sandboxed.
+#[derive(Debug)]
+pub struct Sandboxed {
+    /// The version of the Landlock ABI the filesystem was restricted with, if the kernel supports it.
+    pub landlock: Option<u32>,
+    /// Whether syscalls were restricted, which requires a supported architecture.
+    pub seccomp: bool,
+}
+
+impl Sandbox {
+    /// Allows reading a file, or the files in a directory.
+    pub fn read(&mut self, path: &Path) {
+        self.read.push(path.to_path_buf());
+    }
+
+    /// Allows reading, writing, creating and removing the files in a directory.
+    pub fn write(&mut self, dir: &Path) {
+        self.write.push(dir.to_path_buf());
+    }
+
+    /// Sandboxes the process, with every thread it starts afterwards.
+    pub fn apply(&self) -> Result<Sandboxed> {
+        // SAFETY: Setting no_new_privs takes no pointers, and is required by both Landlock and seccomp.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(
+                eyre::eyre!(io::Error::last_os_error()).wrap_err("Failed to set no_new_privs")
+            );
+        }
+        let landlock = self
+            .restrict_filesystem()
+            .wrap_err("Failed to restrict the filesystem with Landlock")?;
+        let seccomp = restrict_syscalls().wrap_err("Failed to restrict syscalls with seccomp")?;
+        Ok(Sandboxed { landlock, seccomp })
+    }
+
+    fn restrict_filesystem(&self) -> Result<Option<u32>> {
+        // SAFETY: Querying the ABI version takes no attributes.
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Ok(None);
+        }
+        let handled = match abi {
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            3 | 4 => (1 << 15) - 1,
+            _ => (1 << 16) - 1,
+        };
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: `attr` is a valid ruleset attribute of the given size.
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: The ruleset file descriptor was just created, and is owned by nothing else.
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as libc::c_int) };
+
+        let system = SYSTEM_PATHS
+            .iter()
+            .map(|path| (Path::new(path), ACCESS_READ));
+        let devices = DEVICES
+            .iter()
+            .map(|path| (Path::new(path), ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE));
+        let read = self.read.iter().map(|path| (path.as_path(), ACCESS_READ));
+        let write = self.write.iter().map(|path| (path.as_path(), ACCESS_WRITE));
+        for (path, access) in system.chain(devices).chain(read).chain(write) {
+            add_path_rule(&ruleset, path, access & handled)
+                .wrap_err_with(|| format!("Failed to allow access to `{}`", path.display()))?;
+        }
+
+        // SAFETY: The ruleset file descriptor is valid.
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Some(abi as u32))
+    }
+}
+
+/// Allows access to a path and what's beneath it. Paths that don't exist, such as `/lib64` on some distributions, are
+/// skipped.
+fn add_path_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+    let file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let access = if file.metadata()?.is_dir() {
+        access
+    } else {
+        access & ACCESS_FS_FILE
+    };
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+    };
+    // SAFETY: `attr` is a valid path beneath attribute, and both file descriptors are valid.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr,
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn restrict_syscalls() -> Result<bool> {
+    let load = |offset| libc::sock_filter {
+        code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    };
+    let jump = |op, k, jt, jf| libc::sock_filter {
+        code: (libc::BPF_JMP | op | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let ret = |k| libc::sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let deny = ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+    // The architecture and the syscall number are at these offsets of `seccomp_data`. Syscalls of other
+    // architectures, whose numbers differ, kill the process.
+    let mut filter = vec![
+        load(4),
+        jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(0),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1), deny]);
+    for syscall in DENIED_SYSCALLS {
+        filter.extend([jump(libc::BPF_JEQ, *syscall as u32, 0, 1), deny]);
+    }
+    filter.push(ret(libc::SECCOMP_RET_ALLOW));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: `program` points to a valid filter, which the kernel copies. TSYNC applies it to the threads that are
+    // already running too.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    match res {
+        0 => Ok(true),
+        // With TSYNC, the ID of a thread that couldn't be synchronized is returned.
+        thread if thread > 0 => Err(eyre::eyre!(
+            "The filter couldn't be applied to thread {}",
+            thread
+        )),
+        _ => Err(io::Error::last_os_error().into()),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn restrict_syscalls() -> Result<bool> {
+    Ok(false)
+}
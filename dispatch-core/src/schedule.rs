@@ -0,0 +1,302 @@
+//! Time windows of rules, given by days of the week with `days=` and times of day with `time=`, which are checked
+//! against the local clock of the proxy when connections are made, e.g. to send backups over a metered link only at
+//! night.
+
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::Section;
+use eyre::Result;
+
+pub const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// The local day of the week, from 0 for Monday to 6 for Sunday, and the minute of the day.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalTime {
+    pub weekday: u8,
+    pub minute: u16,
+}
+
+impl LocalTime {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn now() -> LocalTime {
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+        unsafe { libc::localtime_r(&now, &mut tm) };
+        LocalTime {
+            // `tm_wday` counts from Sunday.
+            weekday: ((tm.tm_wday + 6) % 7) as u8,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u16,
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn now() -> LocalTime {
+        use windows_sys::Win32::System::SystemInformation::GetLocalTime;
+
+        let mut time = unsafe { std::mem::zeroed() };
+        unsafe { GetLocalTime(&mut time) };
+        LocalTime {
+            // `wDayOfWeek` counts from Sunday.
+            weekday: ((time.wDayOfWeek + 6) % 7) as u8,
+            minute: time.wHour * 60 + time.wMinute,
+        }
+    }
+}
+
+/// Days of the week, as a list of days and ranges of days, e.g. `mon-fri,sun`.
+#[derive(Clone, Copy, Debug)]
+pub struct Days(u8);
+
+impl Days {
+    pub fn contains(&self, weekday: u8) -> bool {
+        self.0 & (1 << weekday) != 0
+    }
+}
+
+impl FromStr for Days {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Days> {
+        let parse_day = |day: &str| {
+            DAYS.iter()
+                .position(|name| day.eq_ignore_ascii_case(name))
+                .ok_or_else(|| eyre::eyre!("`{}` isn't a day of the week", day))
+                .suggestion("Days are given by their first three letters, or ranges of them, e.g. `mon-fri,sun`")
+        };
+        let mut days = 0u8;
+        for range in src.split(',') {
+            match range.split_once('-') {
+                // Ranges can wrap around the end of the week, e.g. `fri-mon`.
+                Some((start, end)) => {
+                    let (start, end) = (parse_day(start)?, parse_day(end)?);
+                    let mut day = start;
+                    loop {
+                        days |= 1 << day;
+                        if day == end {
+                            break;
+                        }
+                        day = (day + 1) % 7;
+                    }
+                }
+                None => days |= 1 << parse_day(range)?,
+            }
+        }
+        Ok(Days(days))
+    }
+}
+
+impl Display for Days {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        let mut day = 0;
+        while day < 7 {
+            if !self.contains(day) {
+                day += 1;
+                continue;
+            }
+            let start = day;
+            while day + 1 < 7 && self.contains(day + 1) {
+                day += 1;
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            match day - start {
+                0 => f.write_str(DAYS[start as usize])?,
+                1 => write!(f, "{},{}", DAYS[start as usize], DAYS[day as usize])?,
+                _ => write!(f, "{}-{}", DAYS[start as usize], DAYS[day as usize])?,
+            }
+            day += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Windows of time of day, as a list of ranges, e.g. `09:00-12:00,14:00-18:00`. A range that ends before it starts
+/// goes on past midnight, e.g. `22:00-06:00`.
+#[derive(Clone, Debug)]
+pub struct Times(Vec<(u16, u16)>);
+
+/// When a rule applies, by day of the week and time of day, or always when neither is given.
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    pub days: Option<Days>,
+    pub times: Option<Times>,
+}
+
+impl Schedule {
+    pub fn is_always(&self) -> bool {
+        self.days.is_none() && self.times.is_none()
+    }
+
+    /// Whether the time falls in the schedule. Past midnight, a window that started the day before belongs to that
+    /// day, so that `days=fri time=22:00-06:00` goes on until Saturday morning.
+    pub fn contains(&self, now: LocalTime) -> bool {
+        let yesterday = (now.weekday + 6) % 7;
+        let on = |weekday: u8| self.days.is_none_or(|days| days.contains(weekday));
+        match &self.times {
+            None => on(now.weekday),
+            Some(Times(ranges)) => ranges.iter().any(|&(start, end)| {
+                if start < end {
+                    on(now.weekday) && (start..end).contains(&now.minute)
+                } else {
+                    (on(now.weekday) && now.minute >= start) || (on(yesterday) && now.minute < end)
+                }
+            }),
+        }
+    }
+}
+
+impl FromStr for Times {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Times> {
+        let parse_time = |time: &str| {
+            time.split_once(':')
+                .and_then(|(hour, minute)| {
+                    Some((hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?))
+                })
+                .filter(|&(hour, minute)| minute < 60 && (hour < 24 || hour == 24 && minute == 0))
+                .map(|(hour, minute)| hour * 60 + minute)
+                .ok_or_else(|| eyre::eyre!("`{}` isn't a time of day", time))
+                .suggestion(
+                    "Times of day are given from 00:00 to 24:00, in ranges like `22:00-06:00`",
+                )
+        };
+        let ranges = src
+            .split(',')
+            .map(|range| {
+                let (start, end) = range.split_once('-').ok_or_else(|| {
+                    eyre::eyre!("`{}` isn't a range of times", range)
+                        .suggestion("Give the start and the end of the range, e.g. `22:00-06:00`")
+                })?;
+                let (start, end) = (parse_time(start)?, parse_time(end)?);
+                if start == end {
+                    return Err(eyre::eyre!("The range of times `{}` is empty", range));
+                }
+                Ok((start, end))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Times(ranges))
+    }
+}
+
+impl Display for Times {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (index, (start, end)) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(
+                f,
+                "{:02}:{:02}-{:02}:{:02}",
+                start / 60,
+                start % 60,
+                end / 60,
+                end % 60
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MON: u8 = 0;
+    const FRI: u8 = 4;
+    const SAT: u8 = 5;
+    const SUN: u8 = 6;
+
+    fn at(weekday: u8, hour: u16, minute: u16) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: hour * 60 + minute,
+        }
+    }
+
+    fn schedule(days: Option<&str>, times: Option<&str>) -> Schedule {
+        Schedule {
+            days: days.map(|days| days.parse().unwrap()),
+            times: times.map(|times| times.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn parses_days() {
+        let days = "mon-wed,sat".parse::<Days>().unwrap();
+        assert!((0..7).all(|day| days.contains(day) == [0, 1, 2, 5].contains(&day)));
+        assert_eq!(days.to_string(), "mon-wed,sat");
+        // Ranges wrap around the end of the week.
+        assert_eq!(
+            "FRI-mon".parse::<Days>().unwrap().to_string(),
+            "mon,fri-sun"
+        );
+        assert_eq!("sat,sun".parse::<Days>().unwrap().to_string(), "sat,sun");
+        assert_eq!("sun-sun".parse::<Days>().unwrap().to_string(), "sun");
+        for src in ["", "monday", "mon-", "mon,,tue"] {
+            assert!(src.parse::<Days>().is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn parses_times() {
+        let times = "09:00-12:30,22:00-6:00,18:00-24:00"
+            .parse::<Times>()
+            .unwrap();
+        assert_eq!(times.to_string(), "09:00-12:30,22:00-06:00,18:00-24:00");
+        for src in [
+            "",
+            "09:00",
+            "09:00-09:00",
+            "24:01-06:00",
+            "09:60-10:00",
+            "9-10",
+            "-1:00-02:00",
+        ] {
+            assert!(src.parse::<Times>().is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn applies_times_of_day() {
+        let schedule = schedule(None, Some("09:00-12:00,14:00-24:00"));
+        assert!(!schedule.contains(at(MON, 8, 59)));
+        assert!(schedule.contains(at(MON, 9, 0)));
+        assert!(!schedule.contains(at(MON, 12, 0)));
+        assert!(schedule.contains(at(SUN, 23, 59)));
+        assert!(!schedule.contains(at(MON, 0, 0)));
+    }
+
+    #[test]
+    fn applies_days() {
+        let schedule = schedule(Some("sat-sun"), None);
+        assert!(!schedule.contains(at(FRI, 23, 59)));
+        assert!(schedule.contains(at(SAT, 0, 0)));
+        assert!(schedule.contains(at(SUN, 23, 59)));
+        assert!(!schedule.contains(at(MON, 0, 0)));
+        assert!(Schedule::default().contains(at(MON, 0, 0)));
+    }
+
+    #[test]
+    fn windows_past_midnight_belong_to_the_day_they_start() {
+        let friday = schedule(Some("fri"), Some("22:00-06:00"));
+        assert!(!friday.contains(at(FRI, 21, 59)));
+        assert!(friday.contains(at(FRI, 22, 0)));
+        assert!(friday.contains(at(SAT, 5, 59)));
+        assert!(!friday.contains(at(SAT, 6, 0)));
+        assert!(!friday.contains(at(SAT, 22, 0)));
+        // The morning of Friday belongs to Thursday's window.
+        assert!(!friday.contains(at(FRI, 1, 0)));
+
+        // Sunday's window goes on into Monday.
+        let sunday = schedule(Some("sun"), Some("23:00-01:00"));
+        assert!(sunday.contains(at(MON, 0, 30)));
+        assert!(!sunday.contains(at(SUN, 0, 30)));
+    }
+}
@@ -0,0 +1,181 @@
+//! Routing connections with a Rhai script, for policies that rules can't express. The script defines a `route`
+//! function, which is called with a map describing each connection before the rules are applied:
+//!
+//! ```text
+//! fn route(conn) {
+//!     // Send video calls of the office over the fiber line, during working hours.
+//!     if in_range(conn.client, "192.168.1.0/24") && is_subdomain(conn.domain, "zoom.us") && conn.hour < 18 {
+//!         return #{ action: "eth0", tags: ["office", "calls"] };
+//!     }
+//!     if conn.app == "qbittorrent" && conn.port != 443 {
+//!         return "wlan0";
+//!     }
+//!     // Leave the other connections to the rules.
+//! }
+//! ```
+//!
+//! The map has the `client` IP address, the `user` the client authenticated as and the `app` it connects from, when
+//! known, the `domain` it requested, if any, the `ip` and `port` of the destination, and the local `weekday` (`mon` to
+//! `sun`), `hour` and `minute`. The function returns an action like those of rules (`deny` or `block`, `dispatch`,
+//! `direct`, or the network interface name or IP address to connect from), or a map of the `action` and `tags` to log
+//! the connection with, or nothing to leave the connection to the rules. Scripts can't access files or the network,
+//! and a script that fails or runs for too long leaves the connection to the rules.
+
+use std::{
+    fmt::{Debug, Display, Formatter},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+use ipnet::IpNet;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::{
+    schedule::{LocalTime, DAYS},
+    socks::Destination,
+};
+
+/// How many operations the script may run for each connection, so that a runaway loop doesn't stall the proxy.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// A compiled routing script.
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+}
+
+/// What the script decided for a connection.
+#[derive(Debug)]
+pub struct Decision {
+    /// The action, written as in rules.
+    pub action: String,
+    /// The tags to log the connection with.
+    pub tags: Vec<String>,
+}
+
+impl Script {
+    pub fn read(path: &Path) -> Result<Script> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.register_fn("in_range", in_range);
+        engine.register_fn("is_subdomain", is_subdomain);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| eyre::eyre!("{}", err))
+            .wrap_err_with(|| format!("Failed to load the script `{}`", path.display()))?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == "route" && function.params.len() == 1)
+        {
+            return Err(eyre::eyre!(
+                "The script `{}` doesn't define a `route(conn)` function",
+                path.display()
+            ));
+        }
+        Ok(Script {
+            path: path.to_path_buf(),
+            engine,
+            ast,
+        })
+    }
+
+    /// Calls the `route` function of the script on a connection, which returns nothing when the script leaves it to
+    /// the rules.
+    pub fn route(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        app: Option<&str>,
+        destination: &Destination,
+    ) -> Result<Option<Decision>> {
+        let optional =
+            |value: Option<&str>| value.map_or(Dynamic::UNIT, |value| value.to_string().into());
+        let now = LocalTime::now();
+        let mut conn = Map::new();
+        conn.insert("client".into(), client.to_canonical().to_string().into());
+        conn.insert("user".into(), optional(user));
+        conn.insert("app".into(), optional(app));
+        conn.insert("domain".into(), optional(destination.domain.as_deref()));
+        conn.insert(
+            "ip".into(),
+            destination.addr.ip().to_canonical().to_string().into(),
+        );
+        conn.insert(
+            "port".into(),
+            Dynamic::from_int(destination.addr.port().into()),
+        );
+        conn.insert("weekday".into(), DAYS[now.weekday as usize].into());
+        conn.insert("hour".into(), Dynamic::from_int((now.minute / 60).into()));
+        conn.insert("minute".into(), Dynamic::from_int((now.minute % 60).into()));
+
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "route", (conn,))
+            .map_err(|err| eyre::eyre!("{}", err))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        if result.is_string() {
+            return Ok(Some(Decision {
+                action: result.into_string().expect("checked to be a string"),
+                tags: Vec::new(),
+            }));
+        }
+        let Some(mut decision) = result.try_cast::<Map>() else {
+            return Err(eyre::eyre!(
+                "`route` returned neither an action, a map nor nothing"
+            ));
+        };
+        let action = decision
+            .remove("action")
+            .and_then(|action| action.into_string().ok())
+            .ok_or_else(|| eyre::eyre!("`route` returned a map without an `action`"))?;
+        let tags = match decision.remove("tags") {
+            None => Vec::new(),
+            Some(tags) if tags.is_string() => vec![tags.to_string()],
+            Some(tags) => tags
+                .into_array()
+                .map_err(|_| eyre::eyre!("`route` returned `tags` that aren't a list"))?
+                .into_iter()
+                .map(|tag| tag.to_string())
+                .collect(),
+        };
+        Ok(Some(Decision { action, tags }))
+    }
+}
+
+impl Debug for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+impl Display for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "script `{}`", self.path.display())
+    }
+}
+
+/// Whether an IP address is in a CIDR range, e.g. `in_range(conn.ip, "10.0.0.0/8")`.
+fn in_range(ip: &str, range: &str) -> Result<bool, Box<EvalAltResult>> {
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|_| format!("`{}` isn't an IP address", ip))?;
+    let range = range
+        .parse::<IpNet>()
+        .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("`{}` isn't an IP address or range", range))?;
+    Ok(range.contains(&ip))
+}
+
+/// Whether a domain is a parent domain or one of its subdomains, as domain patterns of rules match, e.g.
+/// `is_subdomain(conn.domain, "example.com")`. Connections to IP addresses have no domain, which matches nothing.
+fn is_subdomain(domain: Dynamic, parent: &str) -> bool {
+    let Ok(domain) = domain.into_string() else {
+        return false;
+    };
+    let (domain, parent) = (domain.to_ascii_lowercase(), parent.to_ascii_lowercase());
+    domain == parent || domain.ends_with(&format!(".{}", parent))
+}
@@ -0,0 +1,2247 @@
+//! The proxy server, which accepts SOCKS clients, dispatches their connections and relays them, along with the
+//! control socket, the admin endpoint and the background tasks the options enable.
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{owo_colors::OwoColorize, Help};
+use eyre::Result;
+use ipnet::IpNet;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        Notify,
+    },
+    task::{JoinHandle, JoinSet},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::instrument;
+
+#[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+use crate::dispatcher::Plugin;
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+#[cfg(target_os = "linux")]
+use crate::service::systemd::Notifier;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::UringRelay;
+use crate::{
+    admin::{self, AdminState, Tokens},
+    apps,
+    audit::{AuditLog, AuditRecord},
+    connections::{ClientFilter, ClientLimit, ConnectionRegistry, HandshakeLimit, Traffic},
+    control::{self, ControlState},
+    dedup::WarningDeduplicator,
+    denials::{DenialLog, DenialRecord, Reason},
+    dispatcher::{Dispatch, RawWeightedAddress, WeightedAddress, WeightedRoundRobinDispatcher},
+    dns::{HostOverride, Hosts, Nameserver, Prefer, Resolver, ResolverOptions, ScopedNameserver},
+    events::{Event, Events},
+    geoip::GeoIp,
+    health,
+    history::{History, HistoryRecord},
+    lists,
+    net::{LocalIps, OutboundOptions},
+    portmap::{self, PortMapping},
+    ports,
+    quota::{self, Allowance, Quota, QuotaOptions, Quotas},
+    redact::redact,
+    report::format_bytes,
+    rules::{Denied, Endpoints, Environment, RuleSet, Rules, Verdict},
+    socks::{SocksHandshake, Unauthenticated},
+    throttle::{InterfaceLimit, Limits, Rate, Throttle},
+    users::{User, Users},
+};
+
+/// State shared by all connections.
+#[derive(Clone, Debug)]
+struct Context {
+    registry: ConnectionRegistry,
+    history: Option<History>,
+    audit: Option<AuditLog>,
+    denials: Option<DenialLog>,
+    events: Events,
+    warnings: WarningDeduplicator,
+    rules: Rules,
+    resolver: Resolver,
+    /// The size of the buffer of each direction of a relayed connection.
+    buffer_size: usize,
+    /// Disable Nagle's algorithm on both sides of relayed connections.
+    tcp_nodelay: bool,
+    outbound: OutboundOptions,
+    /// Read the server name of TLS connections to IP addresses, to apply the domain rules.
+    sniff_sni: bool,
+    /// Read the `Host` header of plain HTTP connections to IP addresses, likewise.
+    sniff_http: bool,
+    /// Accept clients over TLS.
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+    /// The users who can connect, when clients must authenticate.
+    users: Option<Users>,
+    client_filter: ClientFilter,
+    client_limit: Option<ClientLimit>,
+    handshake_limit: Option<HandshakeLimit>,
+    limits: Limits,
+    quotas: Quotas,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<UringRelay>,
+}
+
+/// The connection of a client, in the clear or over TLS.
+#[derive(Debug)]
+enum ClientSocket {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl ClientSocket {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientSocket::Tcp(socket) => socket,
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(socket) => socket.get_ref().0,
+        }
+    }
+}
+
+impl AsyncRead for ClientSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientSocket::Tcp(socket) => Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(socket) => Pin::new(socket).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientSocket::Tcp(socket) => Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(socket) => Pin::new(socket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientSocket::Tcp(socket) => Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(socket) => Pin::new(socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientSocket::Tcp(socket) => Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(socket) => Pin::new(socket).poll_shutdown(cx),
+        }
+    }
+}
+
+#[instrument(skip_all, fields(client = %redact(client_addr)))]
+async fn handle_socket<D>(
+    socket: TcpStream,
+    client_addr: SocketAddr,
+    dispatcher: D,
+    context: Context,
+) -> Result<()>
+where
+    D: Dispatch + Clone + Debug,
+{
+    // Set before the handshake, so that the first bytes relayed aren't held back while the reply is unacknowledged.
+    if context.tcp_nodelay {
+        socket.set_nodelay(true)?;
+    }
+
+    // Finding the application of a client reads the open files of every process, so it's only done for the clients on
+    // the same machine, when rules match applications.
+    let app = match socket.local_addr() {
+        Ok(proxy_addr)
+            if client_addr.ip().to_canonical().is_loopback()
+                && context.rules.current().needs_apps() =>
+        {
+            tokio::task::spawn_blocking(move || apps::app_of(client_addr, proxy_addr)).await?
+        }
+        _ => None,
+    };
+
+    #[cfg(feature = "tls")]
+    let mut socket = match &context.tls {
+        Some(config) => match admin::tls::handshake(config, socket).await {
+            Ok(socket) => ClientSocket::Tls(Box::new(socket)),
+            Err(err) => {
+                // Failed handshakes are common on exposed ports, e.g. from scanners, unlike certificates that aren't
+                // signed by the client CA.
+                if admin::tls::is_certificate_error(&err) {
+                    tracing::info!(client = %redact(client_addr), "refused the certificate of a client: {}", err);
+                    if let Some(denials) = &context.denials {
+                        denials.record(DenialRecord::unauthenticated(client_addr.ip(), None));
+                    }
+                } else {
+                    tracing::debug!(client = %redact(client_addr), "TLS handshake failed: {}", err);
+                }
+                return Ok(());
+            }
+        },
+        None => ClientSocket::Tcp(socket),
+    };
+    #[cfg(not(feature = "tls"))]
+    let mut socket = ClientSocket::Tcp(socket);
+
+    let (server_socket, destination, user, decision, sniffed) = {
+        let (client_reader, client_writer) = tokio::io::split(&mut socket);
+
+        let mut handshake = SocksHandshake::new(
+            client_reader,
+            client_writer,
+            client_addr.ip(),
+            app.clone(),
+            dispatcher.clone(),
+            context.rules.clone(),
+            context.resolver.clone(),
+            context.outbound.clone(),
+            context.users.clone(),
+            context.quotas.clone(),
+            context.sniff_sni,
+            context.sniff_http,
+        );
+
+        match handshake.handshake().await {
+            Err(err) => {
+                if let Some(denied) = err.downcast_ref::<Denied>() {
+                    let user = handshake.user();
+                    if let Some(audit) = &context.audit {
+                        audit.record(AuditRecord::denied(
+                            client_addr.ip(),
+                            user.as_deref(),
+                            denied,
+                        ));
+                    }
+                    if let Some(denials) = &context.denials {
+                        denials.record(DenialRecord::denied(
+                            client_addr.ip(),
+                            user.as_deref(),
+                            denied,
+                        ));
+                    }
+                }
+                if let (Some(denials), Some(unauthenticated)) =
+                    (&context.denials, err.downcast_ref::<Unauthenticated>())
+                {
+                    denials.record(DenialRecord::unauthenticated(
+                        client_addr.ip(),
+                        unauthenticated.user().map(str::to_owned),
+                    ));
+                }
+                return Err(err.wrap_err(eyre::eyre!(
+                    "An error occurred during the proxy handshake procedure"
+                )));
+            }
+            Ok((server_socket, destination)) => (
+                server_socket,
+                destination,
+                handshake.user(),
+                handshake.decision(),
+                handshake.take_sniffed(),
+            ),
+        }
+    };
+
+    let local_addr = match socket.tcp().peer_addr() {
+        Ok(local_addr) => local_addr,
+        Err(err) => match err.raw_os_error() {
+            // InvalidInput: Invalid argument
+            // Occurs if the socket was closed in the time it took us to get to this point.
+            Some(22) => return Ok(()),
+            _ => return Err(err.into()),
+        },
+    };
+    let remote_addr = server_socket.peer_addr()?;
+    let interface = server_socket.local_addr()?.ip();
+    if context.tcp_nodelay {
+        server_socket.set_nodelay(true)?;
+    }
+    // Routes name their interface, while addresses the dispatcher picked belong to the interface it knows them from.
+    let named = match decision.as_ref().map(|decision| &decision.verdict) {
+        Some(Verdict::Route {
+            interface: Some(named),
+            ..
+        }) => Some(named.clone()),
+        _ => dispatcher.interface_of(interface).await,
+    };
+    let (mut up, mut down) = context.limits.throttles(named.as_ref(), interface);
+    context
+        .quotas
+        .count(named.as_ref(), interface, &mut up, &mut down);
+    if let Some(decision) = &decision {
+        decision.throttle(client_addr.ip(), &mut up, &mut down);
+    }
+    if let Some(user) = &user {
+        user.throttle(&mut up, &mut down);
+    }
+    context
+        .quotas
+        .throttle(client_addr.ip(), user.as_deref(), &mut up, &mut down);
+    let connection = context
+        .registry
+        .register(local_addr, destination, remote_addr, interface);
+    tracing::info!(
+        id = connection.id,
+        client = %redact(connection.client),
+        user = user.as_ref().map(|user| user.name.as_str()),
+        app = app.as_deref(),
+        destination = %redact(&connection.destination),
+        address = %redact(connection.address),
+        interface = %connection.interface,
+        "connection initiated"
+    );
+    if let Some(audit) = &context.audit {
+        audit.record(AuditRecord::allowed(&connection, user.as_deref()));
+    }
+    context
+        .events
+        .publish(Event::connection_opened(&connection));
+
+    let res = tokio::select! {
+        res = relay_sockets(socket, server_socket, &sniffed, &connection.traffic, (up, down), &context) => res,
+        _ = connection.killed.notified() => Ok(CloseReason::Killed),
+    };
+
+    let close_reason = match &res {
+        Ok(close_reason) => close_reason.as_str(),
+        Err(_) => "error",
+    };
+    tracing::info!(
+        id = connection.id,
+        client = %redact(connection.client),
+        destination = %redact(&connection.destination),
+        address = %redact(connection.address),
+        interface = %connection.interface,
+        bytes_up = connection.bytes_up(),
+        bytes_down = connection.bytes_down(),
+        duration = ?connection.duration(),
+        close_reason,
+        "connection terminated"
+    );
+
+    context
+        .events
+        .publish(Event::connection_closed(&connection, close_reason));
+    if let Some(history) = &context.history {
+        history.record(HistoryRecord::new(&connection, close_reason));
+    }
+
+    res.map(|_| ())
+}
+
+/// Which side of a relayed connection ended it.
+#[derive(Clone, Copy, Debug)]
+pub enum CloseReason {
+    Client,
+    Destination,
+    ClientReset,
+    DestinationReset,
+    /// Closed through the control socket or the admin API.
+    Killed,
+    /// Closed once a data quota that the connection counts toward was used up.
+    QuotaUsedUp,
+}
+
+impl CloseReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Client => "client closed",
+            CloseReason::Destination => "destination closed",
+            CloseReason::ClientReset => "client reset",
+            CloseReason::DestinationReset => "destination reset",
+            CloseReason::Killed => "killed",
+            CloseReason::QuotaUsedUp => "quota used up",
+        }
+    }
+}
+
+/// How a direction of a relayed connection ended.
+pub enum PipeEnd {
+    Eof,
+    /// The side being read from reset the connection.
+    ReaderReset,
+    /// The side being written to reset the connection.
+    WriterReset,
+    /// A data quota that the connection counts toward is used up.
+    QuotaUsedUp,
+}
+
+/// Copies from the reader to the writer until EOF, then shuts the writer down so that the half-close reaches the other
+/// side, which can keep sending in the other direction.
+async fn pipe<R, W>(
+    mut reader: R,
+    mut writer: W,
+    transferred: &AtomicU64,
+    throttle: &mut Throttle,
+    buffer_size: usize,
+) -> Result<PipeEnd>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let read = match reader.read(&mut buf).await {
+            Ok(0) => return shutdown(&mut writer).await,
+            // TLS clients often close the connection without a `close_notify` alert, which reads as an unexpected EOF.
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                return shutdown(&mut writer).await
+            }
+            Ok(read) => read,
+            Err(err) if is_reset(&err) => return Ok(PipeEnd::ReaderReset),
+            Err(err) => return Err(eyre::eyre!(err)),
+        };
+        if let Some(end) = forward(&mut writer, &buf[..read], transferred, throttle).await? {
+            return Ok(end);
+        }
+    }
+}
+
+/// Writes bytes read from one side to the other, as fast as the throttle allows, and counts them as transferred.
+/// Returns how the direction ended, if it couldn't write them.
+async fn forward<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buf: &[u8],
+    transferred: &AtomicU64,
+    throttle: &mut Throttle,
+) -> Result<Option<PipeEnd>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if throttle.take(buf.len()).await.is_err() {
+        return Ok(Some(PipeEnd::QuotaUsedUp));
+    }
+    match writer.write_all(buf).await {
+        Ok(()) => {
+            transferred.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            Ok(None)
+        }
+        Err(err) if is_reset(&err) => Ok(Some(PipeEnd::WriterReset)),
+        Err(err) => Err(eyre::eyre!(err)),
+    }
+}
+
+/// Shuts the writer down once the reader reached EOF.
+async fn shutdown<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<PipeEnd> {
+    match writer.shutdown().await {
+        Ok(()) => Ok(PipeEnd::Eof),
+        // The other side already went away entirely.
+        Err(err) if err.kind() == ErrorKind::NotConnected => Ok(PipeEnd::Eof),
+        Err(err) if is_reset(&err) => Ok(PipeEnd::WriterReset),
+        Err(err) => Err(eyre::eyre!(err)),
+    }
+}
+
+/// Resets are a normal way for either side to end a connection, e.g. when a browser cancels a request, rather than an
+/// error of the proxy. Writing to a side that reset the connection fails with a broken pipe instead.
+pub fn is_reset(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
+
+/// Relays both directions until both have ended, so that a side which half-closes the connection still receives what
+/// the other side sends afterwards, or until either side resets the connection. The close reason is the side which
+/// closed first, or the side which reset the connection.
+pub async fn relay(
+    client_to_destination: impl Future<Output = Result<PipeEnd>>,
+    destination_to_client: impl Future<Output = Result<PipeEnd>>,
+) -> Result<CloseReason> {
+    tokio::pin!(client_to_destination, destination_to_client);
+
+    let (mut client_closed, mut destination_closed) = (false, false);
+    let mut close_reason = None;
+    while !(client_closed && destination_closed) {
+        tokio::select! {
+            res = &mut client_to_destination, if !client_closed => match res? {
+                PipeEnd::Eof => {
+                    client_closed = true;
+                    close_reason.get_or_insert(CloseReason::Client);
+                }
+                PipeEnd::ReaderReset => return Ok(CloseReason::ClientReset),
+                PipeEnd::WriterReset => return Ok(CloseReason::DestinationReset),
+                PipeEnd::QuotaUsedUp => return Ok(CloseReason::QuotaUsedUp),
+            },
+            res = &mut destination_to_client, if !destination_closed => match res? {
+                PipeEnd::Eof => {
+                    destination_closed = true;
+                    close_reason.get_or_insert(CloseReason::Destination);
+                }
+                PipeEnd::ReaderReset => return Ok(CloseReason::DestinationReset),
+                PipeEnd::WriterReset => return Ok(CloseReason::ClientReset),
+                PipeEnd::QuotaUsedUp => return Ok(CloseReason::QuotaUsedUp),
+            },
+        }
+    }
+
+    Ok(close_reason.expect("a side has closed"))
+}
+
+/// Relays a connection on the runtime it was accepted on, or on the io_uring threads unless it is over TLS.
+/// The bytes read from the client during the handshake, if any, are relayed first.
+async fn relay_sockets(
+    client: ClientSocket,
+    mut destination: TcpStream,
+    sniffed: &[u8],
+    traffic: &Arc<Traffic>,
+    mut throttles: (Throttle, Throttle),
+    context: &Context,
+) -> Result<CloseReason> {
+    match forward(&mut destination, sniffed, &traffic.up, &mut throttles.0).await? {
+        None => {}
+        Some(PipeEnd::QuotaUsedUp) => return Ok(CloseReason::QuotaUsedUp),
+        Some(_) => return Ok(CloseReason::DestinationReset),
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let client = match (&context.uring, client) {
+        (Some(uring), ClientSocket::Tcp(client)) => {
+            return uring
+                .relay(client, destination, Arc::clone(traffic), throttles)
+                .await
+        }
+        (_, client) => client,
+    };
+    match client {
+        ClientSocket::Tcp(mut client) => {
+            relay_halves(
+                client.split(),
+                &mut destination,
+                traffic,
+                throttles,
+                context,
+            )
+            .await
+        }
+        #[cfg(feature = "tls")]
+        ClientSocket::Tls(client) => {
+            relay_halves(
+                tokio::io::split(client),
+                &mut destination,
+                traffic,
+                throttles,
+                context,
+            )
+            .await
+        }
+    }
+}
+
+async fn relay_halves<R, W>(
+    (client_reader, client_writer): (R, W),
+    destination: &mut TcpStream,
+    traffic: &Arc<Traffic>,
+    (mut up, mut down): (Throttle, Throttle),
+    context: &Context,
+) -> Result<CloseReason>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (destination_reader, destination_writer) = destination.split();
+    relay(
+        pipe(
+            client_reader,
+            destination_writer,
+            &traffic.up,
+            &mut up,
+            context.buffer_size,
+        ),
+        pipe(
+            destination_reader,
+            client_writer,
+            &traffic.down,
+            &mut down,
+            context.buffer_size,
+        ),
+    )
+    .await
+}
+
+/// Bounds of the relay buffer size, since each connection allocates two buffers.
+const MIN_BUFFER_SIZE: usize = 1024;
+const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// Which address to accept connections on.
+    pub addr: SocketAddr,
+    /// Accept SOCKS clients over TLS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<admin::tls::TlsOptions>,
+    /// Record completed connections into the history database at this path, keeping them for the given duration.
+    pub history: Option<(PathBuf, Duration)>,
+    /// Record every decision on a requested destination into the audit log at this path.
+    pub audit_log: Option<PathBuf>,
+    /// Point the SOCKS proxy settings of the system at the proxy while it runs.
+    pub system_proxy: bool,
+    /// Map the port of the proxy on the gateway while it runs.
+    pub map_port: bool,
+    /// Record every refused connection into the denial log at this path, rotating it past the given size.
+    pub denial_log: Option<(PathBuf, u64)>,
+    /// Which address to serve the admin endpoint on.
+    pub admin: Option<SocketAddr>,
+    /// The bearer token which enables the admin API.
+    pub admin_token: Option<String>,
+    /// The bearer token which enables the read-only routes of the admin API.
+    pub read_token: Option<String>,
+    /// Which address to serve the gRPC control API on.
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<SocketAddr>,
+    /// Serve the admin and gRPC endpoints over TLS.
+    #[cfg(feature = "tls")]
+    pub admin_tls: Option<admin::tls::TlsOptions>,
+    /// The file to read routing rules from.
+    pub rules: Option<PathBuf>,
+    /// The Rhai script that routes connections before the rules.
+    #[cfg(feature = "scripting")]
+    pub script: Option<PathBuf>,
+    /// The dynamic library that picks the local address of connections instead of the round robin.
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    pub dispatcher_plugin: Option<PathBuf>,
+    /// Read the server name of TLS connections to IP addresses, to apply the domain rules.
+    pub sniff_sni: bool,
+    /// Read the `Host` header of plain HTTP connections to IP addresses, likewise.
+    pub sniff_http: bool,
+    /// The file to read the users who can connect from, when clients must authenticate.
+    pub users: Option<PathBuf>,
+    /// The GeoIP database to locate destinations with.
+    pub geoip: Option<PathBuf>,
+    /// How often to check whether the GeoIP database changed.
+    pub geoip_reload: Duration,
+    /// How often to refresh the domain lists matched by the rules.
+    pub list_refresh: Duration,
+    /// The nameservers to resolve domains with, instead of the system resolver.
+    pub nameservers: Vec<Nameserver>,
+    /// The nameservers to resolve specific domains and their subdomains with.
+    pub scoped_nameservers: Vec<ScopedNameserver>,
+    /// Resolve domains over the interface that the connection goes through.
+    pub dns_per_interface: bool,
+    /// How long DNS answers are cached at most.
+    pub dns_cache_max_ttl: Duration,
+    /// How long failures to resolve a domain are cached.
+    pub dns_negative_ttl: Duration,
+    /// How long to wait for a domain to resolve.
+    pub dns_timeout: Duration,
+    /// Static addresses for domains.
+    pub hosts: Vec<HostOverride>,
+    /// Files to read static addresses for domains from.
+    pub hosts_files: Vec<PathBuf>,
+    /// Which IP version to connect over first.
+    pub prefer: Prefer,
+    /// Where to listen for control requests, if anywhere.
+    pub control: Option<PathBuf>,
+    /// How long to wait for active connections to close when stopping.
+    pub drain_timeout: Duration,
+    /// How long to wait for the control socket and the address to be free at startup.
+    pub wait_for_port: Duration,
+    /// Bandwidth limits of network interfaces.
+    pub rate_limits: Vec<InterfaceLimit>,
+    /// Data usage quotas of network interfaces.
+    pub quotas: Vec<Quota>,
+    /// Where the usage of the quotas is saved, in the data directory by default.
+    pub quota_path: Option<PathBuf>,
+    /// The percentages of the quotas that are warned about once used.
+    pub quota_warnings: Vec<u8>,
+    /// The data usage quota of each client IP address.
+    pub client_quota: Option<Allowance>,
+    /// Throttle the connections of users and clients over their quota to this rate, instead of ending and refusing them.
+    pub over_quota_rate: Option<Rate>,
+    /// The clients that can use the proxy, or all of them.
+    pub allow_from: Vec<IpNet>,
+    /// The clients that can't use the proxy.
+    pub deny_from: Vec<IpNet>,
+    /// How many connections each client IP address can have open at once.
+    pub max_connections_per_client: Option<std::num::NonZeroUsize>,
+    /// How many connections each client IP address can open per second.
+    pub handshake_rate: Option<std::num::NonZeroU32>,
+    /// How many connections each client IP address can open at once, the handshake rate by default.
+    pub handshake_burst: Option<std::num::NonZeroU32>,
+    /// The size of the buffer of each direction of a relayed connection.
+    pub buffer_size: usize,
+    /// Disable Nagle's algorithm on both sides of relayed connections.
+    pub tcp_nodelay: bool,
+    /// Options applied to the sockets of outbound connections.
+    pub outbound: OutboundOptions,
+    /// Sandbox the process with Landlock and seccomp.
+    #[cfg(target_os = "linux")]
+    pub sandbox: bool,
+    /// How many threads the runtime runs tasks on, one per CPU by default.
+    pub workers: Option<std::num::NonZeroUsize>,
+    /// Run every task on the main thread.
+    pub single_thread: bool,
+    /// How many sockets to accept connections on.
+    #[cfg(target_os = "linux")]
+    pub acceptors: std::num::NonZeroUsize,
+    /// How many connections each listening socket queues before they are accepted.
+    pub backlog: std::num::NonZeroU32,
+    /// The limit on open files to raise to.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub max_open_files: u64,
+    /// Relay connections with io_uring.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub io_uring: bool,
+    /// Print what the proxy does when it starts and handle the signals of the process, as the `dispatch` binary does,
+    /// rather than leave the terminal and the signals to the program that embeds the proxy.
+    pub standalone: bool,
+}
+
+// The tokens are left out, since the options are recorded in spans.
+impl Debug for ServerOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_struct("ServerOptions");
+        f.field("addr", &self.addr)
+            .field("history", &self.history)
+            .field("audit_log", &self.audit_log)
+            .field("denial_log", &self.denial_log)
+            .field("system_proxy", &self.system_proxy)
+            .field("map_port", &self.map_port)
+            .field("admin", &self.admin)
+            .field("rules", &self.rules)
+            .field("sniff_sni", &self.sniff_sni)
+            .field("sniff_http", &self.sniff_http)
+            .field("users", &self.users)
+            .field("geoip", &self.geoip)
+            .field("geoip_reload", &self.geoip_reload)
+            .field("list_refresh", &self.list_refresh)
+            .field("nameservers", &self.nameservers)
+            .field("scoped_nameservers", &self.scoped_nameservers)
+            .field("dns_per_interface", &self.dns_per_interface)
+            .field("dns_cache_max_ttl", &self.dns_cache_max_ttl)
+            .field("dns_negative_ttl", &self.dns_negative_ttl)
+            .field("dns_timeout", &self.dns_timeout)
+            .field("hosts", &self.hosts)
+            .field("hosts_files", &self.hosts_files)
+            .field("prefer", &self.prefer)
+            .field("control", &self.control)
+            .field("standalone", &self.standalone)
+            .field("drain_timeout", &self.drain_timeout)
+            .field("wait_for_port", &self.wait_for_port)
+            .field("rate_limits", &self.rate_limits)
+            .field("quotas", &self.quotas)
+            .field("quota_path", &self.quota_path)
+            .field("quota_warnings", &self.quota_warnings)
+            .field("client_quota", &self.client_quota)
+            .field("over_quota_rate", &self.over_quota_rate)
+            .field("allow_from", &self.allow_from)
+            .field("deny_from", &self.deny_from)
+            .field(
+                "max_connections_per_client",
+                &self.max_connections_per_client,
+            )
+            .field("handshake_rate", &self.handshake_rate)
+            .field("handshake_burst", &self.handshake_burst)
+            .field("buffer_size", &self.buffer_size)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("outbound", &self.outbound)
+            .field("workers", &self.workers)
+            .field("single_thread", &self.single_thread)
+            .field("backlog", &self.backlog);
+        #[cfg(feature = "grpc")]
+        f.field("grpc", &self.grpc);
+        #[cfg(feature = "tls")]
+        f.field("tls", &self.tls)
+            .field("admin_tls", &self.admin_tls);
+        #[cfg(feature = "scripting")]
+        f.field("script", &self.script);
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        f.field("dispatcher_plugin", &self.dispatcher_plugin);
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        f.field("io_uring", &self.io_uring);
+        #[cfg(target_os = "linux")]
+        f.field("acceptors", &self.acceptors)
+            .field("sandbox", &self.sandbox);
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        f.field("max_open_files", &self.max_open_files);
+        f.finish_non_exhaustive()
+    }
+}
+
+#[instrument]
+async fn start_server(
+    options: ServerOptions,
+    raw_addresses: Vec<RawWeightedAddress>,
+    #[cfg(target_os = "linux")] notifier: Option<Arc<Notifier>>,
+) -> Result<()> {
+    setup(
+        options,
+        raw_addresses,
+        #[cfg(target_os = "linux")]
+        notifier,
+    )
+    .await?
+    .run()
+    .await
+}
+
+/// A proxy that listens for connections and runs its background tasks, but doesn't accept connections until it runs.
+struct Server {
+    listeners: Vec<TcpListener>,
+    backlog: std::num::NonZeroU32,
+    dispatcher: WeightedRoundRobinDispatcher,
+    context: Context,
+    accepting: Arc<AtomicBool>,
+    shutdown: Arc<Notify>,
+    drain_timeout: Duration,
+    port_mapping: PortMapping,
+    system_proxy: bool,
+    /// Print what the proxy does, as `dispatch start`, rather than only logging it.
+    standalone: bool,
+    background: JoinSet<()>,
+    connections: ConnectionTasks,
+    #[cfg(target_os = "linux")]
+    notifier: Option<Arc<Notifier>>,
+}
+
+/// Loads everything the options refer to, binds the control socket and the listeners, and starts the background tasks.
+async fn setup(
+    options: ServerOptions,
+    raw_addresses: Vec<RawWeightedAddress>,
+    #[cfg(target_os = "linux")] notifier: Option<Arc<Notifier>>,
+) -> Result<Server> {
+    let ServerOptions {
+        addr,
+        #[cfg(feature = "tls")]
+        tls,
+        history,
+        audit_log,
+        denial_log,
+        system_proxy,
+        map_port,
+        admin,
+        admin_token,
+        read_token,
+        #[cfg(feature = "grpc")]
+        grpc,
+        #[cfg(feature = "tls")]
+        admin_tls,
+        rules,
+        #[cfg(feature = "scripting")]
+            script: script_path,
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        dispatcher_plugin,
+        sniff_sni,
+        sniff_http,
+        users,
+        geoip: geoip_path,
+        geoip_reload,
+        list_refresh,
+        nameservers,
+        scoped_nameservers,
+        dns_per_interface,
+        dns_cache_max_ttl,
+        dns_negative_ttl,
+        dns_timeout,
+        hosts,
+        hosts_files,
+        prefer,
+        control,
+        drain_timeout,
+        wait_for_port,
+        rate_limits,
+        quotas,
+        quota_path,
+        quota_warnings,
+        client_quota,
+        over_quota_rate,
+        allow_from,
+        deny_from,
+        max_connections_per_client,
+        handshake_rate,
+        handshake_burst,
+        buffer_size,
+        tcp_nodelay,
+        outbound,
+        #[cfg(target_os = "linux")]
+            sandbox: _,
+        workers: _,
+        single_thread: _,
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        io_uring,
+        #[cfg(target_os = "linux")]
+        acceptors,
+        backlog,
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+            max_open_files: _,
+        standalone,
+    } = options;
+
+    if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&buffer_size) {
+        return Err(eyre::eyre!(
+            "The buffer size must be between {} and {}, got {}",
+            format_bytes(MIN_BUFFER_SIZE as u64),
+            format_bytes(MAX_BUFFER_SIZE as u64),
+            format_bytes(buffer_size as u64)
+        ))
+        .suggestion(
+            "64KiB to 256KiB buffers speed up large transfers over fast links, while the default of 8KiB suits \
+            routers with little memory",
+        );
+    }
+
+    if map_port {
+        portmap::check_listen_address(addr)?;
+    }
+    // Every gRPC call requires a token, so the endpoint would refuse them all.
+    #[cfg(feature = "grpc")]
+    if grpc.is_some() && admin_token.is_none() && read_token.is_none() {
+        return Err(eyre::eyre!(
+            "The gRPC endpoint can't be served without an admin or read-only token"
+        ))
+        .suggestion(
+            "Set a token with `--admin-token` or `--read-token`, or the `admin_token` option",
+        );
+    }
+
+    let addresses = WeightedAddress::resolve(raw_addresses.clone())?;
+    let rules = match rules {
+        Some(path) => RuleSet::read(&path)?,
+        None => RuleSet::default(),
+    };
+    #[cfg(feature = "scripting")]
+    let script = script_path.as_deref().map(Script::read).transpose()?;
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    let plugin = dispatcher_plugin.as_deref().map(Plugin::load).transpose()?;
+    let users = users.as_deref().map(Users::read).transpose()?;
+    let geoip = geoip_path.as_deref().map(GeoIp::open).transpose()?;
+    let local_ips = LocalIps::new();
+    let mut endpoints = vec![];
+    endpoints.extend(admin);
+    #[cfg(feature = "grpc")]
+    endpoints.extend(grpc);
+    let environment = Environment {
+        endpoints: Endpoints::new(addr, endpoints, local_ips.clone()),
+        geoip: geoip.clone(),
+    };
+    environment
+        .check(rules.needs_geoip() || users.iter().flat_map(Users::iter).any(User::needs_geoip))?;
+    let mut overrides = Hosts::default();
+    for path in &hosts_files {
+        overrides.extend(Hosts::read(path)?);
+    }
+    for host in hosts {
+        overrides.insert(host);
+    }
+    let overridden = overrides.len();
+    let resolver = Resolver::new(ResolverOptions {
+        nameservers: nameservers.clone(),
+        per_interface: dns_per_interface,
+        cache_max_ttl: dns_cache_max_ttl,
+        negative_ttl: dns_negative_ttl,
+        timeout: dns_timeout,
+        scoped: scoped_nameservers.clone(),
+        hosts: overrides,
+        prefer,
+    })?;
+
+    let history = history
+        .map(|(path, retention)| History::open(&path, retention))
+        .transpose()?;
+    let audit = audit_log.as_deref().map(AuditLog::open).transpose()?;
+    let denials = denial_log
+        .as_ref()
+        .map(|(path, max_size)| DenialLog::open(path, *max_size))
+        .transpose()?;
+    let quota_options = QuotaOptions {
+        interfaces: quotas,
+        users: users
+            .iter()
+            .flat_map(Users::iter)
+            .filter_map(|user| Some((user.name.clone(), *user.quota()?)))
+            .collect(),
+        clients: client_quota,
+        over_quota_rate,
+        warnings: quota_warnings,
+    };
+    let quotas = if quota_options.is_empty() {
+        Quotas::default()
+    } else {
+        let path = match quota_path {
+            Some(path) => path,
+            None => quota::default_path()?,
+        };
+        Quotas::open(quota_options, &path)?
+    };
+
+    outbound.check()?;
+    #[cfg(feature = "tls")]
+    let tls_config = tls
+        .as_ref()
+        .map(|options| admin::tls::server_config(options, &[]))
+        .transpose()?;
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let uring = io_uring
+        .then(|| UringRelay::start(buffer_size))
+        .transpose()?;
+
+    // A previous instance that is restarting may still hold the control socket and the address while it drains its
+    // connections.
+    let mut retry = BindRetry::new(wait_for_port, standalone);
+    let mut control_listener = None;
+    if let Some(control) = &control {
+        control_listener = Some(loop {
+            match control::bind(control).await {
+                Ok(listener) => break listener,
+                Err(err) if control::is_running(control).await => {
+                    if !retry.wait("the previous instance to stop").await {
+                        return Err(err.suggestion(
+                            "Pass `--wait-for-port <DURATION>` to wait for it to stop, e.g. when restarting",
+                        ));
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        });
+    }
+    let listeners = loop {
+        #[cfg(target_os = "linux")]
+        let res = crate::net::bind_listeners(addr, acceptors, backlog);
+        #[cfg(not(target_os = "linux"))]
+        let res = crate::net::bind_listener(addr, backlog).map(|listener| vec![listener]);
+        match res {
+            Ok(listeners) => break listeners,
+            Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                if !retry.wait(&format!("{} to be free", addr)).await {
+                    return Err(eyre::eyre!(err)
+                        .wrap_err(format!("{} is already in use", addr))
+                        .suggestion(
+                            "Pass `--wait-for-port <DURATION>` to wait for it to be free, e.g. when a previous \
+                             instance is still stopping",
+                        ));
+                }
+            }
+            Err(err) => {
+                return Err(eyre::eyre!(err).wrap_err(format!("Failed to listen on {}", addr)))
+            }
+        }
+    };
+
+    let handshake_limit =
+        handshake_rate.map(|rate| HandshakeLimit::new(rate, handshake_burst.unwrap_or(rate)));
+    if standalone {
+        println!("SOCKS proxy started on {}", addr.bold());
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &tls {
+            println!("Accepting clients over {}", "TLS".bold());
+            if let Some(path) = &tls.client_ca {
+                println!(
+                    "Requiring client certificates signed by {}",
+                    path.display().bold()
+                );
+            }
+        }
+        if listeners.len() > 1 {
+            println!(
+                "Accepting connections on {} sockets",
+                listeners.len().bold()
+            );
+        }
+        println!(
+            "Dispatching to {} {}",
+            if addresses.len() > 1 {
+                "addresses"
+            } else {
+                "address"
+            },
+            addresses
+                .iter()
+                .map(|addr| format!("{}", addr.bold()))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        if !rules.is_empty() {
+            println!("Applying {} routing rules", rules.len().bold());
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(path) = &script_path {
+            println!("Routing with the script {}", path.display().bold());
+        }
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        if let Some(path) = &dispatcher_plugin {
+            println!("Dispatching with the plugin {}", path.display().bold());
+        }
+        if let Some(path) = &geoip_path {
+            println!("Locating destinations with {}", path.display().bold());
+        }
+        if let Some(path) = &audit_log {
+            println!("Auditing destinations to {}", path.display().bold());
+        }
+        if let Some((path, _)) = &denial_log {
+            println!("Logging denied connections to {}", path.display().bold());
+        }
+        if let Some(users) = &users {
+            println!("Authenticating {} users", users.len().bold());
+        }
+        for limit in &rate_limits {
+            println!(
+                "Limiting {} to {}",
+                limit.interface.bold(),
+                limit.rate.bold()
+            );
+        }
+        for (interface, allowance) in quotas.interfaces() {
+            println!("Capping {} at {}", interface.bold(), allowance.bold());
+        }
+        if quotas.users() > 0 {
+            println!("Capping the data usage of {} users", quotas.users().bold());
+        }
+        if let Some(allowance) = quotas.clients() {
+            println!("Capping each client at {}", allowance.bold());
+        }
+        if let Some(rate) = quotas.over_quota_rate() {
+            println!(
+                "Throttling users and clients over their quota to {}",
+                rate.bold()
+            );
+        }
+        for net in &allow_from {
+            println!("Accepting clients from {}", net.bold());
+        }
+        for net in &deny_from {
+            println!("Refusing clients from {}", net.bold());
+        }
+        if let Some(max) = max_connections_per_client {
+            println!("Limiting each client to {} open connections", max.bold());
+        }
+        if let Some(limit) = &handshake_limit {
+            println!(
+                "Limiting each client to {} new connections per second, {} at once",
+                limit.rate().bold(),
+                limit.burst().bold()
+            );
+        }
+        if dns_per_interface {
+            println!("Resolving domains over the interface of each connection");
+        }
+        if sniff_sni {
+            println!("Reading the server name of TLS connections to IP addresses");
+        }
+        if sniff_http {
+            println!("Reading the Host header of HTTP connections to IP addresses");
+        }
+        if overridden > 0 {
+            println!("Overriding the addresses of {} domains", overridden.bold());
+        }
+        match prefer {
+            Prefer::Auto => {}
+            Prefer::Ipv4 => println!("Preferring {}", "IPv4".bold()),
+            Prefer::Ipv6 => println!("Preferring {}", "IPv6".bold()),
+        }
+        if !nameservers.is_empty() {
+            println!(
+                "Resolving domains with {}",
+                nameservers
+                    .iter()
+                    .map(|nameserver| format!("{}", nameserver.bold()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+        }
+        for scoped in &scoped_nameservers {
+            println!(
+                "Resolving {} with {}",
+                scoped.domain.bold(),
+                scoped.nameserver.bold()
+            );
+        }
+        #[cfg(target_os = "linux")]
+        for fwmark in &outbound.fwmarks {
+            match &fwmark.interface {
+                Some(interface) => println!(
+                    "Marking connections through {} with {}",
+                    interface.bold(),
+                    format!("{:#x}", fwmark.mark).bold()
+                ),
+                None => println!(
+                    "Marking connections with {}",
+                    format!("{:#x}", fwmark.mark).bold()
+                ),
+            }
+        }
+        for ports in &outbound.source_ports {
+            let range = format!("{}-{}", ports.first, ports.last);
+            match &ports.interface {
+                Some(interface) => println!(
+                    "Connecting through {} from ports {}",
+                    interface.bold(),
+                    range.bold()
+                ),
+                None => println!("Connecting from ports {}", range.bold()),
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if outbound.mptcp {
+            println!("Connecting with {}", "MPTCP".bold());
+        }
+        #[cfg(unix)]
+        if let Some(dscp) = outbound.dscp {
+            println!("Marking packets with DSCP {}", dscp.bold());
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(uring) = &uring {
+            println!(
+                "Relaying connections with io_uring on {} {}",
+                uring.threads().bold(),
+                if uring.threads() > 1 {
+                    "threads"
+                } else {
+                    "thread"
+                }
+            );
+        }
+    }
+
+    let dispatcher = WeightedRoundRobinDispatcher::new(addresses);
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    if let Some(plugin) = plugin {
+        dispatcher.set_plugin(plugin).await;
+    }
+    let rules = Rules::new(rules, environment);
+    #[cfg(feature = "scripting")]
+    let rules = match script {
+        Some(script) => rules.with_script(script),
+        None => rules,
+    };
+    let context = Context {
+        registry: ConnectionRegistry::new(),
+        history,
+        audit,
+        denials,
+        events: Events::new(),
+        warnings: WarningDeduplicator::new(),
+        rules,
+        resolver,
+        buffer_size,
+        tcp_nodelay,
+        outbound,
+        sniff_sni,
+        sniff_http,
+        #[cfg(feature = "tls")]
+        tls: tls_config,
+        users,
+        client_filter: ClientFilter::new(allow_from, deny_from),
+        client_limit: max_connections_per_client.map(ClientLimit::new),
+        handshake_limit,
+        limits: Limits::new(rate_limits.clone()),
+        quotas,
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        uring,
+    };
+    let accepting = Arc::new(AtomicBool::new(true));
+    let shutdown = Arc::new(Notify::new());
+    let port_mapping = PortMapping::default();
+    // Stopped along with the proxy, for programs that embed it and keep running.
+    let mut background = JoinSet::new();
+
+    background.spawn(health::monitor(dispatcher.clone(), context.events.clone()));
+    background.spawn(local_ips.watch());
+    background.spawn(context.warnings.clone().run());
+    background.spawn(ports::monitor(dispatcher.clone(), context.registry.clone()));
+    background.spawn(context.quotas.clone().monitor(dispatcher.clone()));
+    if let Some(geoip) = geoip {
+        if !geoip_reload.is_zero() {
+            background.spawn(geoip.watch(geoip_reload));
+        }
+    }
+    if !list_refresh.is_zero() {
+        background.spawn(lists::refresh(list_refresh));
+    }
+
+    let control_state = ControlState {
+        dispatcher: dispatcher.clone(),
+        registry: context.registry.clone(),
+        rules: context.rules.clone(),
+        resolver: context.resolver.clone(),
+        addresses: raw_addresses,
+        listen: addr,
+        started: Instant::now(),
+        accepting: Arc::clone(&accepting),
+        shutdown: Arc::clone(&shutdown),
+        drain_timeout,
+        port_mapping: port_mapping.clone(),
+    };
+
+    let tokens = Tokens {
+        admin: admin_token,
+        read: read_token,
+    };
+    #[cfg(feature = "tls")]
+    let tls_enabled = admin_tls.is_some();
+    #[cfg(not(feature = "tls"))]
+    let tls_enabled = false;
+
+    if let Some(admin_addr) = admin {
+        #[cfg(feature = "tls")]
+        let tls = admin_tls
+            .as_ref()
+            .map(|options| admin::tls::server_config(options, &[b"http/1.1"]))
+            .transpose()?;
+        let admin_listener = admin::bind(admin_addr).await?;
+        if standalone {
+            println!("Admin endpoint started on {}", admin_addr.bold());
+        }
+        admin::warn_if_exposed("admin", admin_addr, tls_enabled);
+        let state = AdminState {
+            control: control_state.clone(),
+            events: context.events.clone(),
+            tokens: tokens.clone(),
+            handshake_limit: context.handshake_limit.clone(),
+            quotas: context.quotas.clone(),
+            #[cfg(feature = "tls")]
+            tls,
+        };
+        background.spawn(async move {
+            if let Err(err) = admin::serve(admin_listener, state).await {
+                tracing::error!("{:?}", err);
+            }
+        });
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = grpc {
+        #[cfg(feature = "tls")]
+        let tls = admin_tls
+            .as_ref()
+            .map(|options| admin::tls::server_config(options, &[b"h2"]))
+            .transpose()?;
+        let grpc_listener = admin::grpc::bind(grpc_addr).await?;
+        if standalone {
+            println!("gRPC endpoint started on {}", grpc_addr.bold());
+        }
+        admin::warn_if_exposed("gRPC", grpc_addr, tls_enabled);
+        let state = control_state.clone();
+        background.spawn(async move {
+            let res = admin::grpc::serve(
+                grpc_listener,
+                state,
+                tokens,
+                #[cfg(feature = "tls")]
+                tls,
+            )
+            .await;
+            if let Err(err) = res {
+                tracing::error!("{:?}", err);
+            }
+        });
+    }
+
+    if standalone {
+        #[cfg(unix)]
+        {
+            background.spawn(reload_on_signal(control_state.clone()));
+            background.spawn(report_connections_on_signal(context.registry.clone()));
+            background.spawn(stop_on_signal(Arc::clone(&shutdown)));
+        }
+        if system_proxy {
+            background.spawn(stop_on_interrupt(Arc::clone(&shutdown)));
+        }
+    }
+    if system_proxy {
+        crate::system_proxy::set(addr, standalone)?;
+    }
+
+    if let Some(control_listener) = control_listener {
+        background.spawn(async move {
+            if let Err(err) = control::serve(control_listener, control_state).await {
+                tracing::error!("{:?}", err);
+            }
+        });
+    }
+    if map_port {
+        background.spawn(port_mapping.clone().maintain(addr, standalone));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(notifier) = &notifier {
+        background.spawn(Arc::clone(notifier).watchdog());
+        notifier.ready(&format!("Accepting connections on {}", addr));
+    }
+
+    Ok(Server {
+        listeners,
+        backlog,
+        dispatcher,
+        context,
+        accepting,
+        shutdown,
+        drain_timeout,
+        port_mapping,
+        system_proxy,
+        standalone,
+        background,
+        connections: ConnectionTasks::default(),
+        #[cfg(target_os = "linux")]
+        notifier,
+    })
+}
+
+impl Server {
+    /// The address the proxy accepts connections on, with the port that was picked when listening on port 0.
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listeners[0].local_addr()?)
+    }
+
+    /// Accepts connections until the proxy is stopped, then waits for the active connections to close, closes those that
+    /// outlast the drain timeout, and stops the background tasks.
+    async fn run(mut self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        let shared = self.listeners.len() > 1;
+        let mut tasks = JoinSet::new();
+        for listener in self.listeners {
+            let binding = Binding {
+                // The port that was picked when binding to port 0.
+                addr: listener.local_addr()?,
+                backlog: self.backlog,
+                #[cfg(target_os = "linux")]
+                shared,
+            };
+            tasks.spawn(accept(
+                listener,
+                binding,
+                self.dispatcher.clone(),
+                self.context.clone(),
+                Arc::clone(&self.accepting),
+                self.connections.clone(),
+            ));
+        }
+        let res = tokio::select! {
+            Some(res) = tasks.join_next() => {
+                self.accepting.store(false, Ordering::Relaxed);
+                self.connections.close().await;
+                // Accepting only stops if it panicked.
+                res.map_err(Into::into)
+            }
+            _ = self.shutdown.notified() => {
+                self.accepting.store(false, Ordering::Relaxed);
+                #[cfg(target_os = "linux")]
+                if let Some(notifier) = &self.notifier {
+                    notifier.stopping();
+                }
+                // Closes the listeners.
+                tasks.shutdown().await;
+                drain(&self.context.registry, self.drain_timeout).await;
+                self.connections.close().await;
+                self.context.quotas.save()
+            }
+        };
+
+        // The gateway would otherwise keep forwarding the port until the mapping expires.
+        self.port_mapping.remove().await;
+        // The system proxy would otherwise point at a proxy that isn't running anymore.
+        if self.system_proxy {
+            if let Err(err) = crate::system_proxy::unset(self.standalone) {
+                tracing::warn!("{:?}", err);
+            }
+        }
+        self.background.shutdown().await;
+        res
+    }
+}
+
+/// Retries binding the control socket and the listeners while they are busy, for at most the duration of
+/// `--wait-for-port`.
+struct BindRetry {
+    deadline: Instant,
+    backoff: Duration,
+    /// What is being waited for, once announced.
+    waiting: Option<String>,
+    /// Print what is being waited for, besides logging it.
+    announce: bool,
+}
+
+impl BindRetry {
+    fn new(wait: Duration, announce: bool) -> Self {
+        BindRetry {
+            deadline: Instant::now() + wait,
+            backoff: ACCEPT_BACKOFF,
+            waiting: None,
+            announce,
+        }
+    }
+
+    /// Waits before trying again, or returns false if the deadline is reached.
+    async fn wait(&mut self, what: &str) -> bool {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return false;
+        }
+        if self.waiting.as_deref() != Some(what) {
+            if self.announce {
+                println!("Waiting for {}", what);
+            }
+            tracing::info!("waiting for {}", what);
+            self.waiting = Some(what.to_string());
+        }
+        tokio::time::sleep(self.backoff.min(self.deadline - now)).await;
+        self.backoff = (self.backoff * 2).min(MAX_ACCEPT_BACKOFF);
+        true
+    }
+}
+
+/// How a listener was bound, to bind it again if it breaks.
+#[derive(Clone, Copy, Debug)]
+struct Binding {
+    addr: SocketAddr,
+    backlog: std::num::NonZeroU32,
+    /// Whether the address is shared with other listeners with SO_REUSEPORT.
+    #[cfg(target_os = "linux")]
+    shared: bool,
+}
+
+impl Binding {
+    fn bind(&self) -> std::io::Result<TcpListener> {
+        #[cfg(target_os = "linux")]
+        if self.shared {
+            return crate::net::bind_shared_listener(self.addr, self.backlog);
+        }
+        crate::net::bind_listener(self.addr, self.backlog)
+    }
+}
+
+/// The first delay before accepting again after a failure, doubled on each consecutive one.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Accepts connections on a listener and handles them.
+///
+/// Failing to accept doesn't stop the proxy, since the relayed connections would go down with it. When the process or
+/// the system runs out of file descriptors or memory, accepting is retried with a growing delay until connections
+/// close and free some up. Any other error means the listener itself is broken, so it is bound again.
+async fn accept(
+    mut listener: TcpListener,
+    binding: Binding,
+    dispatcher: WeightedRoundRobinDispatcher,
+    context: Context,
+    accepting: Arc<AtomicBool>,
+    connections: ConnectionTasks,
+) {
+    let mut backoff = ACCEPT_BACKOFF;
+    let mut failures = 0;
+    loop {
+        let (socket, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // The client gave up on the connection before it was accepted.
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) if is_exhausted(&err) => {
+                if failures == 0 {
+                    tracing::error!(
+                        addr = %binding.addr,
+                        "Failed to accept connections, retrying until some are closed: {}",
+                        err
+                    );
+                }
+                failures += 1;
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+            Err(err) => {
+                tracing::error!(
+                    addr = %binding.addr,
+                    "The listener failed, binding it again: {}",
+                    err
+                );
+                accepting.store(false, Ordering::Relaxed);
+                // The address must be free for the new listener to bind it.
+                drop(listener);
+                listener = rebind(binding).await;
+                accepting.store(true, Ordering::Relaxed);
+                backoff = ACCEPT_BACKOFF;
+                failures = 0;
+                continue;
+            }
+        };
+        if failures > 0 {
+            tracing::info!(
+                addr = %binding.addr,
+                failures,
+                "accepting connections again"
+            );
+            backoff = ACCEPT_BACKOFF;
+            failures = 0;
+        }
+        if !context.client_filter.allows(client_addr.ip()) {
+            // Dropping the socket closes it before the handshake.
+            let warning = format!(
+                "Refused a connection from {}, which isn't allowed to use the proxy",
+                redact(client_addr.ip())
+            );
+            if context.warnings.should_log(warning.clone()) {
+                tracing::warn!("{}", warning);
+            }
+            if let Some(denials) = &context.denials {
+                denials.record(DenialRecord::refused(
+                    Reason::Client,
+                    client_addr.ip(),
+                    None,
+                ));
+            }
+            continue;
+        }
+        if let Some(limit) = &context.handshake_limit {
+            if !limit.allows(client_addr.ip()) {
+                // Dropping the socket closes it before the handshake.
+                let warning = format!(
+                    "Refused a connection from {}, which opens more than {} connections per second",
+                    redact(client_addr.ip()),
+                    limit.rate()
+                );
+                if context.warnings.should_log(warning.clone()) {
+                    tracing::warn!("{}", warning);
+                }
+                if let Some(denials) = &context.denials {
+                    denials.record(DenialRecord::refused(
+                        Reason::HandshakeRate,
+                        client_addr.ip(),
+                        Some(format!("handshake-rate={}", limit.rate())),
+                    ));
+                }
+                continue;
+            }
+        }
+        let slot = match &context.client_limit {
+            Some(limit) => match limit.acquire(client_addr.ip()) {
+                Some(slot) => Some(slot),
+                None => {
+                    // Dropping the socket closes it before the handshake.
+                    let warning = format!(
+                        "Refused a connection from {}, which already has {} open connections",
+                        redact(client_addr.ip()),
+                        limit.max()
+                    );
+                    if context.warnings.should_log(warning.clone()) {
+                        tracing::warn!("{}", warning);
+                    }
+                    if let Some(denials) = &context.denials {
+                        denials.record(DenialRecord::refused(
+                            Reason::ClientConnections,
+                            client_addr.ip(),
+                            Some(format!("max-connections-per-client={}", limit.max())),
+                        ));
+                    }
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let dispatcher = dispatcher.clone();
+        let context = context.clone();
+        connections.spawn(async move {
+            let _slot = slot;
+            let warnings = context.warnings.clone();
+            if let Err(err) = handle_socket(socket, client_addr, dispatcher, context).await {
+                if let Some(denied) = err.downcast_ref::<Denied>() {
+                    tracing::info!(
+                        client = %redact(client_addr),
+                        destination = %redact(&denied.destination),
+                        rule = denied.rule,
+                        "connection denied"
+                    );
+                    return;
+                }
+                // Errors that happen during the handling of a socket are only reported as warnings, since they're
+                // considered to be recoverable. On the other hand, panics are unrecoverable and are reported as errors.
+                if warnings.should_log(format!("{:#}", err)) {
+                    tracing::warn!(client = %redact(client_addr), "{:?}", err);
+                }
+            }
+        });
+    }
+}
+
+/// The tasks that handle the accepted connections, which are closed when the proxy stops, so that none outlives it in
+/// the runtime of a program that embeds it.
+#[derive(Clone, Default)]
+struct ConnectionTasks {
+    tracker: TaskTracker,
+    closed: CancellationToken,
+}
+
+impl ConnectionTasks {
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let closed = self.closed.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = closed.cancelled() => {}
+            }
+        });
+    }
+
+    /// Closes the connections that are still open, and waits for their tasks to end.
+    async fn close(&self) {
+        self.tracker.close();
+        self.closed.cancel();
+        self.tracker.wait().await;
+    }
+}
+
+/// Binds a broken listener again, retrying with a growing delay until it succeeds.
+async fn rebind(binding: Binding) -> TcpListener {
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        match binding.bind() {
+            Ok(listener) => {
+                tracing::info!(addr = %binding.addr, "listener bound again");
+                return listener;
+            }
+            Err(err) => {
+                tracing::error!(
+                    addr = %binding.addr,
+                    "Failed to bind the listener again, retrying in {}: {}",
+                    humantime::format_duration(backoff),
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Whether accepting failed because of the connection being accepted, rather than the listener.
+fn is_connection_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Whether accepting failed for lack of file descriptors or memory, which are freed as connections close.
+fn is_exhausted(err: &std::io::Error) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let codes = [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+    #[cfg(windows)]
+    let codes = [
+        windows_sys::Win32::Networking::WinSock::WSAEMFILE,
+        windows_sys::Win32::Networking::WinSock::WSAENOBUFS,
+    ];
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    let codes: [i32; 0] = [];
+    err.kind() == std::io::ErrorKind::OutOfMemory
+        || err.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Waits for the active connections to close, for at most `timeout`. Connections that are still open afterwards are
+/// closed by `ConnectionTasks::close`.
+async fn drain(registry: &ConnectionRegistry, timeout: Duration) {
+    let count = registry.count();
+    if count == 0 {
+        return;
+    }
+
+    tracing::info!(count, timeout = %humantime::format_duration(timeout), "draining active connections");
+    let drained = tokio::time::timeout(timeout, async {
+        while registry.count() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            count = registry.count(),
+            "closing the connections that are still active after the drain timeout"
+        );
+    }
+}
+
+/// Reloads the dispatch addresses on SIGHUP, like `dispatch reload`.
+#[cfg(unix)]
+async fn reload_on_signal(state: ControlState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::warn!(
+                "{:?}",
+                eyre::eyre!(err).wrap_err("Failed to listen for SIGHUP")
+            );
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        if let Err(err) = control::reload_addresses(&state).await {
+            tracing::warn!(
+                "{:?}",
+                err.wrap_err("Failed to reload the dispatch addresses")
+            );
+        }
+    }
+}
+
+/// Stops the proxy like `dispatch stop` on SIGTERM, which service managers and container runtimes send to stop it, so
+/// that active connections are drained and the state is saved first. Without a handler, SIGTERM wouldn't even stop the
+/// proxy when it runs as PID 1 in a container.
+#[cfg(unix)]
+async fn stop_on_signal(shutdown: Arc<Notify>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::terminate()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::warn!(
+                "{:?}",
+                eyre::eyre!(err).wrap_err("Failed to listen for SIGTERM")
+            );
+            return;
+        }
+    };
+
+    if signals.recv().await.is_some() {
+        shutdown.notify_one();
+    }
+}
+
+/// Stops the proxy like `dispatch stop` on Ctrl+C.
+async fn stop_on_interrupt(shutdown: Arc<Notify>) {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => shutdown.notify_one(),
+        Err(err) => tracing::warn!(
+            "{:?}",
+            eyre::eyre!(err).wrap_err("Failed to listen for Ctrl+C")
+        ),
+    }
+}
+
+/// Logs the table of live connections whenever the process receives `SIGUSR1`.
+#[cfg(unix)]
+async fn report_connections_on_signal(registry: ConnectionRegistry) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::warn!(
+                "{:?}",
+                eyre::eyre!(err).wrap_err("Failed to listen for SIGUSR1")
+            );
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        let connections = registry.list();
+        tracing::info!(count = connections.len(), "active connections");
+        for connection in connections {
+            tracing::info!(
+                id = connection.id,
+                client = %redact(connection.client),
+                destination = %redact(&connection.destination),
+                address = %redact(connection.address),
+                interface = %connection.interface,
+                bytes_up = connection.bytes_up(),
+                bytes_down = connection.bytes_down(),
+                duration = ?connection.duration(),
+                "active connection"
+            );
+        }
+    }
+}
+
+/// Runs the proxy on its own runtime, dispatching to the given addresses, until it is stopped through the control
+/// socket or by a signal.
+#[instrument]
+pub fn server(options: ServerOptions, addresses: Vec<RawWeightedAddress>) -> Result<()> {
+    // Connected before the sandbox is in place, since the socket is outside of the paths it allows.
+    #[cfg(target_os = "linux")]
+    let notifier = Notifier::from_env()?.map(Arc::new);
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    raise_open_files(options.max_open_files);
+
+    // The sandbox must be in place before the runtime starts its threads, for Landlock to apply to them.
+    #[cfg(target_os = "linux")]
+    if options.sandbox {
+        sandbox(&options)?;
+    }
+
+    let mut builder = if options.single_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(workers) = options.workers {
+            builder.worker_threads(workers.get());
+        }
+        builder
+    };
+    let rt = builder.enable_all().build()?;
+
+    rt.block_on(start_server(
+        options,
+        addresses,
+        #[cfg(target_os = "linux")]
+        notifier,
+    ))
+}
+
+/// A hook called with each event of the proxy.
+type EventHook = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Builds a proxy to run inside the runtime of the program that embeds it, rather than on its own runtime like
+/// `server`. The options default to those of `dispatch start`, except that the proxy has no control socket, prints
+/// nothing and leaves the signals of the process alone. The options of the runtime, the sandbox and the limit on open
+/// files are left to the program.
+pub struct ServerBuilder {
+    options: ServerOptions,
+    addresses: Vec<RawWeightedAddress>,
+    hooks: Vec<EventHook>,
+}
+
+impl ServerBuilder {
+    pub fn new(addresses: Vec<RawWeightedAddress>) -> ServerBuilder {
+        ServerBuilder {
+            options: ServerOptions {
+                addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+                #[cfg(feature = "tls")]
+                tls: None,
+                history: None,
+                audit_log: None,
+                system_proxy: false,
+                map_port: false,
+                denial_log: None,
+                admin: None,
+                admin_token: None,
+                read_token: None,
+                #[cfg(feature = "grpc")]
+                grpc: None,
+                #[cfg(feature = "tls")]
+                admin_tls: None,
+                rules: None,
+                #[cfg(feature = "scripting")]
+                script: None,
+                #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+                dispatcher_plugin: None,
+                sniff_sni: false,
+                sniff_http: false,
+                users: None,
+                geoip: None,
+                geoip_reload: Duration::from_secs(60 * 60),
+                list_refresh: Duration::from_secs(60 * 60),
+                nameservers: vec![],
+                scoped_nameservers: vec![],
+                dns_per_interface: false,
+                dns_cache_max_ttl: Duration::from_secs(5 * 60),
+                dns_negative_ttl: Duration::from_secs(5),
+                dns_timeout: Duration::from_secs(5),
+                hosts: vec![],
+                hosts_files: vec![],
+                prefer: Prefer::Auto,
+                control: None,
+                drain_timeout: Duration::from_secs(30),
+                wait_for_port: Duration::ZERO,
+                rate_limits: vec![],
+                quotas: vec![],
+                quota_path: None,
+                quota_warnings: vec![80, 95],
+                client_quota: None,
+                over_quota_rate: None,
+                allow_from: vec![],
+                deny_from: vec![],
+                max_connections_per_client: None,
+                handshake_rate: None,
+                handshake_burst: None,
+                buffer_size: 8 * 1024,
+                tcp_nodelay: true,
+                outbound: OutboundOptions::default(),
+                #[cfg(target_os = "linux")]
+                sandbox: false,
+                workers: None,
+                single_thread: false,
+                #[cfg(target_os = "linux")]
+                acceptors: std::num::NonZeroUsize::MIN,
+                backlog: std::num::NonZeroU32::new(1024).unwrap(),
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                max_open_files: 65536,
+                #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                io_uring: false,
+                standalone: false,
+            },
+            addresses,
+            hooks: vec![],
+        }
+    }
+
+    /// Accepts connections on this address, `127.0.0.1:1080` by default. Port 0 picks a free port, which the handle
+    /// tells.
+    pub fn listen(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.options.addr = addr;
+        self
+    }
+
+    /// Also dispatches connections to this address.
+    pub fn address(mut self, address: RawWeightedAddress) -> ServerBuilder {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Listens for control requests on this socket, so that the `dispatch` commands can manage the proxy.
+    pub fn control(mut self, path: PathBuf) -> ServerBuilder {
+        self.options.control = Some(path);
+        self
+    }
+
+    /// Routes or denies destinations according to the rules in this file.
+    pub fn rules(mut self, path: PathBuf) -> ServerBuilder {
+        self.options.rules = Some(path);
+        self
+    }
+
+    /// How long to wait for active connections to close when shutting down, 30 seconds by default.
+    pub fn drain_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.options.drain_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for a domain to resolve, 5 seconds by default.
+    pub fn dns_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.options.dns_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for the address to be free when spawning, e.g. while a previous proxy stops.
+    pub fn wait_for_port(mut self, wait: Duration) -> ServerBuilder {
+        self.options.wait_for_port = wait;
+        self
+    }
+
+    /// Calls a hook with each event of the proxy, like connections opening and closing, from a task of its own. Hooks
+    /// should return quickly, since a hook that falls too far behind misses events.
+    pub fn on_event(mut self, hook: impl Fn(&Event) + Send + Sync + 'static) -> ServerBuilder {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Changes the options that have no method of their own.
+    pub fn configure(mut self, configure: impl FnOnce(&mut ServerOptions)) -> ServerBuilder {
+        configure(&mut self.options);
+        self
+    }
+
+    /// Starts the proxy on the current runtime, once everything the options refer to is loaded and it listens for
+    /// connections.
+    pub async fn spawn(self) -> Result<ServerHandle> {
+        let ServerBuilder {
+            options,
+            addresses,
+            hooks,
+        } = self;
+        let mut server = setup(
+            options,
+            addresses,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .await?;
+        let local_addr = server.local_addr()?;
+        let events = server.context.events.clone();
+        if !hooks.is_empty() {
+            let mut receiver = events.subscribe();
+            server.background.spawn(async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => hooks.iter().for_each(|hook| hook(&event)),
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!(missed, "event hooks fell behind and missed events")
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+        let shutdown = Arc::clone(&server.shutdown);
+        let task = tokio::spawn(server.run());
+        Ok(ServerHandle {
+            local_addr,
+            shutdown,
+            events,
+            task,
+        })
+    }
+}
+
+/// A proxy spawned by `ServerBuilder`, which keeps running when the handle is dropped.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Arc<Notify>,
+    events: Events,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// The address the proxy accepts connections on, with the port that was picked when listening on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Receives the events of the proxy from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Stops accepting connections, waits for the active ones to close for at most the drain timeout, closes the others,
+    /// and returns once the proxy, its connections and its background tasks have stopped.
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.notify_one();
+        self.wait().await
+    }
+
+    /// Waits for the proxy to stop, e.g. through its control socket.
+    pub async fn wait(self) -> Result<()> {
+        self.task.await?
+    }
+}
+
+/// Raises the limit on open files, warning when it stays below the target, since connections then fail once it is
+/// reached.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn raise_open_files(target: u64) {
+    match crate::rlimit::raise_open_files(target) {
+        Ok(limit) => {
+            if limit.current > limit.previous {
+                tracing::info!(
+                    previous = limit.previous,
+                    current = limit.current,
+                    "raised the open file limit"
+                );
+            }
+            if limit.current < target {
+                let message = format!(
+                    "The proxy can only open {} files at once, below the {} of `--max-open-files`, so it may fail to \
+                     accept or make connections under load. Raise the hard limit, e.g. with `ulimit -Hn` or \
+                     `LimitNOFILE=` in a systemd unit",
+                    limit.current, target
+                );
+                tracing::warn!(
+                    current = limit.current,
+                    target,
+                    "open file limit below the target"
+                );
+                println!("{}", message.yellow());
+            }
+        }
+        Err(err) => tracing::warn!("Failed to raise the open file limit: {}", err),
+    }
+}
+
+/// Sandboxes the process, allowing access to the data directory and to the files given in the options.
+#[cfg(target_os = "linux")]
+fn sandbox(options: &ServerOptions) -> Result<()> {
+    use std::path::Path;
+
+    use crate::{paths, sandbox::Sandbox};
+
+    // Files that are created, or replaced by renaming another one over them, are allowed through their directory.
+    let dir = |path: &Path| match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let mut sandbox = Sandbox::default();
+    sandbox.write(&paths::data_dir()?);
+    let written = [
+        options.history.as_ref().map(|(path, _)| path),
+        options.audit_log.as_ref(),
+        options.denial_log.as_ref().map(|(path, _)| path),
+        options.quota_path.as_ref(),
+        options.control.as_ref(),
+    ];
+    for path in written.into_iter().flatten() {
+        sandbox.write(&dir(path));
+    }
+    let read = [options.rules.as_ref(), options.users.as_ref()];
+    for path in read.into_iter().flatten().chain(&options.hosts_files) {
+        sandbox.read(path);
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &options.script {
+        sandbox.read(path);
+    }
+    #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+    if let Some(path) = &options.dispatcher_plugin {
+        sandbox.read(path);
+    }
+    // The domain lists that rules match are usually kept next to them.
+    if let Some(path) = &options.rules {
+        sandbox.read(&dir(path));
+    }
+    #[cfg(feature = "tls")]
+    for tls in [&options.tls, &options.admin_tls].into_iter().flatten() {
+        for path in [Some(&tls.cert), Some(&tls.key), tls.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            sandbox.read(path);
+        }
+    }
+    if let Some(path) = &options.geoip {
+        sandbox.read(&dir(path));
+    }
+
+    let sandboxed = sandbox.apply()?;
+    match sandboxed.landlock {
+        Some(abi) => {
+            println!(
+                "Restricting the filesystem with {} (ABI {})",
+                "Landlock".bold(),
+                abi.bold()
+            );
+            for exporter in crate::debug::exporters() {
+                tracing::warn!(
+                    "The {} exporter started before the sandbox, its thread can access the whole filesystem",
+                    exporter
+                );
+            }
+        }
+        None => tracing::warn!(
+            "Landlock isn't supported by the kernel, the filesystem isn't restricted"
+        ),
+    }
+    if sandboxed.seccomp {
+        println!("Restricting syscalls with {}", "seccomp".bold());
+    } else {
+        tracing::warn!(
+            "seccomp filters aren't supported on this architecture, syscalls aren't restricted"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,210 @@
+//! Starting the proxy when the user logs in, with the mechanism of the desktop rather than a service manager: a value
+//! of the `Run` key of the registry on Windows, a launchd agent on macOS, and an XDG autostart entry on Linux.
+
+use std::path::Path;
+
+use eyre::Result;
+
+/// Registers the proxy to be started with `dispatch start` and the given arguments whenever the user logs in,
+/// replacing the registration already made if any.
+pub fn enable(exe: &Path, args: &[String]) -> Result<()> {
+    platform::enable(exe, args)
+}
+
+/// Stops the proxy from being started when the user logs in.
+pub fn disable() -> Result<()> {
+    platform::disable()
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::{path::Path, process::Command};
+
+    use eyre::{Result, WrapErr};
+
+    const RUN: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+    const VALUE: &str = "dispatch-proxy";
+
+    pub fn enable(exe: &Path, args: &[String]) -> Result<()> {
+        // The proxy is a console program, so it is started in a minimized window rather than one in the way. The first
+        // quoted argument of `start` is the title of the window.
+        let command = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(std::iter::once("start".to_string()))
+            .chain(args.iter().cloned())
+            .map(|arg| quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let command = format!("cmd.exe /C start \"dispatch\" /MIN {}", command);
+        reg(&[
+            "add", RUN, "/v", VALUE, "/t", "REG_SZ", "/d", &command, "/f",
+        ])?;
+        println!("Registered `{}` to start when you log in", command);
+        Ok(())
+    }
+
+    pub fn disable() -> Result<()> {
+        reg(&["delete", RUN, "/v", VALUE, "/f"])?;
+        println!("The proxy won't start when you log in anymore");
+        Ok(())
+    }
+
+    /// Quotes an argument of a command line, following the rules of `CommandLineToArgvW`.
+    fn quote(arg: &str) -> String {
+        if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+            return arg.into();
+        }
+        let mut quoted = String::from('"');
+        let mut backslashes = 0;
+        for c in arg.chars() {
+            if c == '\\' {
+                backslashes += 1;
+                continue;
+            }
+            // Backslashes are only escaped before a quote.
+            let escaped = if c == '"' {
+                backslashes * 2 + 1
+            } else {
+                backslashes
+            };
+            quoted.push_str(&"\\".repeat(escaped));
+            quoted.push(c);
+            backslashes = 0;
+        }
+        quoted.push_str(&"\\".repeat(backslashes * 2));
+        quoted.push('"');
+        quoted
+    }
+
+    fn reg(args: &[&str]) -> Result<()> {
+        let output = Command::new("reg")
+            .args(args)
+            .output()
+            .wrap_err("Failed to run reg")?;
+        if !output.status.success() {
+            return Err(eyre::eyre!(
+                "`reg {}` failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::path::Path;
+
+    use eyre::Result;
+
+    use crate::service::launchd::{self, Domain};
+
+    pub fn enable(exe: &Path, args: &[String]) -> Result<()> {
+        launchd::install(Domain::User, exe, args)
+    }
+
+    pub fn disable() -> Result<()> {
+        launchd::uninstall(Domain::User)
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod platform {
+    use std::{fmt::Write, path::Path, path::PathBuf};
+
+    use color_eyre::Section;
+    use eyre::{Result, WrapErr};
+
+    /// The autostart entry, in the autostart directory of the XDG specification.
+    fn entry() -> Result<PathBuf> {
+        let dirs = directories::BaseDirs::new()
+            .ok_or_else(|| eyre::eyre!("Couldn't find the user's home directory"))?;
+        Ok(dirs.config_dir().join("autostart/dispatch-proxy.desktop"))
+    }
+
+    pub fn enable(exe: &Path, args: &[String]) -> Result<()> {
+        let entry = entry()?;
+        if let Some(dir) = entry.parent() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create `{}`", dir.display()))?;
+        }
+
+        let exec = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(std::iter::once("start".to_string()))
+            .chain(args.iter().cloned())
+            .map(|arg| quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut desktop = String::new();
+        desktop.push_str("[Desktop Entry]\n");
+        desktop.push_str("Type=Application\n");
+        desktop.push_str("Name=dispatch\n");
+        desktop
+            .push_str("Comment=A SOCKS proxy that balances traffic between network interfaces\n");
+        writeln!(desktop, "Exec={}", exec).unwrap();
+        desktop.push_str("Terminal=false\n");
+        desktop.push_str("NoDisplay=true\n");
+        desktop.push_str("X-GNOME-Autostart-enabled=true\n");
+        std::fs::write(&entry, desktop)
+            .wrap_err_with(|| format!("Failed to write `{}`", entry.display()))?;
+
+        println!("Installed {}", entry.display());
+        println!("The proxy is started whenever you log in to your desktop");
+        Ok(())
+    }
+
+    pub fn disable() -> Result<()> {
+        let entry = entry()?;
+        if !entry.exists() {
+            return Err(eyre::eyre!(
+                "No autostart entry is installed at `{}`",
+                entry.display()
+            ))
+            .suggestion("Run `dispatch autostart enable` first");
+        }
+        std::fs::remove_file(&entry)
+            .wrap_err_with(|| format!("Failed to remove `{}`", entry.display()))?;
+
+        println!("Removed {}", entry.display());
+        Ok(())
+    }
+
+    /// Quotes an argument of the `Exec` key when needed, and escapes the field codes that would be expanded.
+    fn quote(arg: &str) -> String {
+        let escaped = arg.replace('%', "%%");
+        let reserved = |c: char| {
+            c.is_whitespace()
+                || matches!(
+                    c,
+                    '"' | '\''
+                        | '\\'
+                        | '>'
+                        | '<'
+                        | '~'
+                        | '|'
+                        | '&'
+                        | ';'
+                        | '$'
+                        | '*'
+                        | '?'
+                        | '#'
+                        | '('
+                        | ')'
+                        | '`'
+                )
+        };
+        if !escaped.is_empty() && !escaped.contains(reserved) {
+            return escaped;
+        }
+        let mut quoted = String::from('"');
+        for c in escaped.chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        // Backslashes are unescaped once when the value is read, and once more when the quoted argument is.
+        quoted.replace('\\', "\\\\")
+    }
+}
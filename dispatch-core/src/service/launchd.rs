@@ -0,0 +1,177 @@
+//! Running the proxy as a launchd service on macOS: an agent started at login, or a daemon started at boot, which
+//! launchd restarts if it crashes.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+
+const LABEL: &str = "com.github.alexkirsz.dispatch";
+
+/// Which launchd domain the proxy runs in.
+#[derive(Clone, Copy, Debug)]
+pub enum Domain {
+    /// An agent of the user, started when they log in.
+    User,
+    /// A daemon of the system, started at boot as root.
+    System,
+}
+
+impl Domain {
+    pub fn new(system: bool) -> Domain {
+        if system {
+            Domain::System
+        } else {
+            Domain::User
+        }
+    }
+
+    fn plist(self) -> Result<PathBuf> {
+        let dir = match self {
+            Domain::User => home()?.join("Library/LaunchAgents"),
+            Domain::System => PathBuf::from("/Library/LaunchDaemons"),
+        };
+        Ok(dir.join(format!("{}.plist", LABEL)))
+    }
+
+    /// Where the output of the proxy goes, which Console.app shows under Log Reports.
+    fn logs(self) -> Result<PathBuf> {
+        let dir = match self {
+            Domain::User => home()?.join("Library/Logs"),
+            Domain::System => PathBuf::from("/Library/Logs"),
+        };
+        Ok(dir.join("dispatch-proxy"))
+    }
+
+    /// The domain as `launchctl` names it, e.g. `gui/501`.
+    fn target(self) -> String {
+        match self {
+            // SAFETY: getuid can't fail.
+            Domain::User => format!("gui/{}", unsafe { libc::getuid() }),
+            Domain::System => "system".into(),
+        }
+    }
+}
+
+fn home() -> Result<PathBuf> {
+    directories::BaseDirs::new()
+        .map(|dirs| dirs.home_dir().to_path_buf())
+        .ok_or_else(|| eyre::eyre!("Couldn't find the user's home directory"))
+}
+
+/// Writes the property list of a service that runs `dispatch start` with the given arguments, and loads it, replacing
+/// the one already installed if any.
+pub fn install(domain: Domain, exe: &Path, args: &[String]) -> Result<()> {
+    let plist = domain.plist()?;
+    let log = domain.logs()?.join("dispatch.log");
+    for dir in [plist.parent(), log.parent()].into_iter().flatten() {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create `{}`", dir.display()))
+            .with_suggestion(|| permission_suggestion(domain))?;
+    }
+
+    let program = [exe.to_string_lossy().into_owned(), "start".into()]
+        .into_iter()
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>();
+    std::fs::write(&plist, property_list(&program, &log))
+        .wrap_err_with(|| format!("Failed to write `{}`", plist.display()))
+        .with_suggestion(|| permission_suggestion(domain))?;
+
+    // Unloading fails when the service isn't loaded yet, which is fine.
+    let _ = launchctl(&["bootout", &format!("{}/{}", domain.target(), LABEL)]);
+    launchctl(&["bootstrap", &domain.target(), &plist.to_string_lossy()])?;
+
+    println!("Installed {}", plist.display());
+    match domain {
+        Domain::User => println!("The proxy is started now and whenever you log in"),
+        Domain::System => println!("The proxy is started now and whenever the system boots"),
+    }
+    println!("Its output is written to {}", log.display());
+    Ok(())
+}
+
+/// Unloads the service and removes its property list.
+pub fn uninstall(domain: Domain) -> Result<()> {
+    let plist = domain.plist()?;
+    if !plist.exists() {
+        return Err(eyre::eyre!(
+            "No service is installed at `{}`",
+            plist.display()
+        ))
+        .suggestion("Pass --system to uninstall the service started at boot");
+    }
+
+    // The service may already be unloaded, e.g. if it failed to start.
+    let _ = launchctl(&["bootout", &format!("{}/{}", domain.target(), LABEL)]);
+    std::fs::remove_file(&plist)
+        .wrap_err_with(|| format!("Failed to remove `{}`", plist.display()))
+        .with_suggestion(|| permission_suggestion(domain))?;
+
+    println!("Uninstalled {}", plist.display());
+    Ok(())
+}
+
+fn permission_suggestion(domain: Domain) -> &'static str {
+    match domain {
+        Domain::User => "Check the permissions of your Library directory",
+        Domain::System => "Run the command with sudo to install a service started at boot",
+    }
+}
+
+fn launchctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("launchctl")
+        .args(args)
+        .output()
+        .wrap_err("Failed to run launchctl")?;
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "`launchctl {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// The property list of the service. launchd restarts the proxy whenever it exits with an error, but not after
+/// `dispatch stop`, and throttles restarts to one every 10 seconds.
+fn property_list(program: &[String], log: &Path) -> String {
+    let mut plist = String::new();
+    plist.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    plist.push_str("<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n");
+    plist.push_str("<plist version=\"1.0\">\n<dict>\n");
+    writeln!(plist, "\t<key>Label</key>\n\t<string>{}</string>", LABEL).unwrap();
+    plist.push_str("\t<key>ProgramArguments</key>\n\t<array>\n");
+    for arg in program {
+        writeln!(plist, "\t\t<string>{}</string>", escape(arg)).unwrap();
+    }
+    plist.push_str("\t</array>\n");
+    plist.push_str("\t<key>RunAtLoad</key>\n\t<true/>\n");
+    plist.push_str("\t<key>KeepAlive</key>\n\t<dict>\n\t\t<key>SuccessfulExit</key>\n\t\t<false/>\n\t</dict>\n");
+    let log = escape(&log.to_string_lossy());
+    writeln!(
+        plist,
+        "\t<key>StandardOutPath</key>\n\t<string>{}</string>",
+        log
+    )
+    .unwrap();
+    writeln!(
+        plist,
+        "\t<key>StandardErrorPath</key>\n\t<string>{}</string>",
+        log
+    )
+    .unwrap();
+    plist.push_str("</dict>\n</plist>\n");
+    plist
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,7 @@
+//! Integration with the service managers that start the proxy at boot or login.
+
+pub mod autostart;
+#[cfg(target_os = "macos")]
+pub mod launchd;
+#[cfg(target_os = "linux")]
+pub mod systemd;
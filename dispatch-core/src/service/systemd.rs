@@ -0,0 +1,207 @@
+//! Running the proxy as a systemd service: generating its unit file, and notifying systemd of its state with the
+//! `sd_notify` protocol, so that `Type=notify` units are only started once the proxy accepts connections, and restarted
+//! if it stops responding.
+
+use std::{
+    fmt::Write,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+
+/// How long systemd waits without a watchdog ping before restarting the proxy. The proxy pings it twice as often.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to leave the proxy to exit after its drain timeout, before systemd kills it.
+const STOP_GRACE: Duration = Duration::from_secs(15);
+
+/// What goes into the unit file of the proxy.
+#[derive(Debug)]
+pub struct Unit {
+    /// The `dispatch` executable.
+    pub exe: PathBuf,
+    /// The arguments of `dispatch start`.
+    pub args: Vec<String>,
+    /// Generate a unit for the user's service manager rather than the system's.
+    pub user: bool,
+    /// The capabilities the options of the proxy need, e.g. `CAP_NET_BIND_SERVICE` to listen on a privileged port.
+    pub capabilities: Vec<&'static str>,
+    /// The directories the proxy writes to outside of its data directory.
+    pub writable: Vec<PathBuf>,
+    /// How long the proxy waits for connections to close when stopped.
+    pub drain_timeout: Duration,
+}
+
+impl Unit {
+    pub fn render(&self) -> String {
+        let mut unit = String::new();
+        let exec_start = std::iter::once(quote(&self.exe.to_string_lossy()))
+            .chain(std::iter::once("start".to_string()))
+            .chain(self.args.iter().map(|arg| quote(arg)))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        unit.push_str("[Unit]\n");
+        unit.push_str("Description=dispatch, a SOCKS proxy that balances traffic between network interfaces\n");
+        unit.push_str("Documentation=https://github.com/alexkirsz/dispatch\n");
+        if !self.user {
+            unit.push_str("Wants=network-online.target\n");
+            unit.push_str("After=network-online.target\n");
+        }
+
+        unit.push_str("\n[Service]\n");
+        unit.push_str("Type=notify\n");
+        writeln!(unit, "ExecStart={}", exec_start).unwrap();
+        unit.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+        unit.push_str("Restart=on-failure\n");
+        writeln!(unit, "WatchdogSec={}", WATCHDOG_TIMEOUT.as_secs()).unwrap();
+        writeln!(
+            unit,
+            "TimeoutStopSec={}",
+            (self.drain_timeout + STOP_GRACE).as_secs()
+        )
+        .unwrap();
+
+        if !self.user {
+            // The data directory, which holds the logs, the control socket and the quotas, is /var/lib/dispatch-proxy.
+            unit.push_str("DynamicUser=yes\n");
+            unit.push_str("StateDirectory=dispatch-proxy\n");
+            unit.push_str("Environment=XDG_DATA_HOME=%S\n");
+            writeln!(
+                unit,
+                "CapabilityBoundingSet={}",
+                self.capabilities.join(" ")
+            )
+            .unwrap();
+            if !self.capabilities.is_empty() {
+                writeln!(unit, "AmbientCapabilities={}", self.capabilities.join(" ")).unwrap();
+            }
+            unit.push_str("ProtectSystem=strict\n");
+            unit.push_str("ProtectHome=yes\n");
+            for dir in &self.writable {
+                writeln!(unit, "ReadWritePaths={}", quote(&dir.to_string_lossy())).unwrap();
+            }
+            unit.push_str("PrivateTmp=yes\n");
+            unit.push_str("PrivateDevices=yes\n");
+            unit.push_str("ProtectKernelTunables=yes\n");
+            unit.push_str("ProtectKernelModules=yes\n");
+            unit.push_str("ProtectKernelLogs=yes\n");
+            unit.push_str("ProtectControlGroups=yes\n");
+            unit.push_str("ProtectClock=yes\n");
+            unit.push_str("ProtectHostname=yes\n");
+            unit.push_str("RestrictNamespaces=yes\n");
+            unit.push_str("RestrictSUIDSGID=yes\n");
+        }
+        unit.push_str("NoNewPrivileges=yes\n");
+        unit.push_str("LockPersonality=yes\n");
+        unit.push_str("RestrictRealtime=yes\n");
+        unit.push_str("RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK\n");
+        unit.push_str("SystemCallArchitectures=native\n");
+        unit.push_str("SystemCallFilter=@system-service\n");
+        unit.push_str("SystemCallErrorNumber=EPERM\n");
+        unit.push_str("UMask=0077\n");
+
+        unit.push_str("\n[Install]\n");
+        if self.user {
+            unit.push_str("WantedBy=default.target\n");
+        } else {
+            unit.push_str("WantedBy=multi-user.target\n");
+        }
+        unit
+    }
+}
+
+/// Quotes an argument of `ExecStart` when needed, and escapes the specifiers and variables systemd would expand.
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The directory that a file is created in, which must be writable for the file to be created or replaced.
+pub fn parent(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Notifies systemd of the state of the proxy, when it runs as a `Type=notify` service.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    /// How often to ping the watchdog, when the unit has one.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connects to the socket systemd expects notifications on, if it set one.
+    pub fn from_env() -> Result<Option<Notifier>> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let socket =
+            UnixDatagram::unbound().wrap_err("Failed to create the systemd notification socket")?;
+        let res = match path.as_encoded_bytes() {
+            [b'@', name @ ..] => {
+                use std::os::linux::net::SocketAddrExt;
+
+                std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .and_then(|addr| socket.connect_addr(&addr))
+            }
+            _ => socket.connect(&path),
+        };
+        res.wrap_err_with(|| {
+            format!(
+                "Failed to connect to the systemd notification socket `{}`",
+                path.to_string_lossy()
+            )
+        })?;
+
+        // The watchdog only applies to the process it names, if any.
+        let ours = match std::env::var("WATCHDOG_PID") {
+            Ok(pid) => pid == std::process::id().to_string(),
+            Err(_) => true,
+        };
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| ours)
+            .map(|usec: u64| Duration::from_micros(usec) / 2);
+
+        Ok(Some(Notifier { socket, watchdog }))
+    }
+
+    /// Tells systemd that the proxy accepts connections.
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    /// Tells systemd that the proxy is stopping, while it drains its connections.
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=Draining active connections");
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send(state.as_bytes()) {
+            tracing::warn!("failed to notify systemd: {}", err);
+        }
+    }
+
+    /// Pings the watchdog for as long as the runtime runs, so that systemd restarts the proxy if it stops responding.
+    pub async fn watchdog(self: std::sync::Arc<Self>) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            self.notify("WATCHDOG=1");
+        }
+    }
+}
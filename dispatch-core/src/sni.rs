@@ -0,0 +1,287 @@
+//! Reading the server name that a TLS client sends in its ClientHello (SNI), so that clients which resolve domains
+//! themselves and ask the proxy for an IP address can still be matched by domain rules.
+
+/// The largest TLS record, with its header and the expansion allowed for compressed or protected records.
+pub const MAX_RECORD_LEN: usize = 5 + (1 << 14) + 2048;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// What the first bytes sent by a client tell about the server name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// The first TLS record isn't complete yet.
+    Incomplete,
+    /// The client doesn't speak TLS, or its ClientHello has no server name.
+    NoServerName,
+    /// The lowercase domain name the client asked for.
+    ServerName(String),
+}
+
+/// Parses the ClientHello in the first TLS record sent by a client. A ClientHello split over several records is only
+/// parsed as far as the first one goes.
+pub fn parse(buf: &[u8]) -> ClientHello {
+    let Some(header) = buf.get(..5) else {
+        // Reject early what can't be the start of a handshake record.
+        return match buf.first() {
+            Some(&content_type) if content_type != CONTENT_TYPE_HANDSHAKE => {
+                ClientHello::NoServerName
+            }
+            _ => ClientHello::Incomplete,
+        };
+    };
+    if header[0] != CONTENT_TYPE_HANDSHAKE || header[1] != 0x03 {
+        return ClientHello::NoServerName;
+    }
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if 5 + len > MAX_RECORD_LEN {
+        return ClientHello::NoServerName;
+    }
+    let Some(record) = buf.get(5..5 + len) else {
+        return ClientHello::Incomplete;
+    };
+    match server_name(&mut Reader(record)) {
+        Some(name) => ClientHello::ServerName(name),
+        None => ClientHello::NoServerName,
+    }
+}
+
+fn server_name(record: &mut Reader) -> Option<String> {
+    if record.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    // The length of the ClientHello, which may go beyond this record.
+    record.bytes(3)?;
+    // The legacy version and the random.
+    record.bytes(2 + 32)?;
+    let session_id_len = record.u8()? as usize;
+    record.bytes(session_id_len)?;
+    let cipher_suites_len = record.u16()? as usize;
+    record.bytes(cipher_suites_len)?;
+    let compression_methods_len = record.u8()? as usize;
+    record.bytes(compression_methods_len)?;
+
+    let extensions_len = record.u16()? as usize;
+    let mut extensions = Reader(record.bytes(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.bytes(len)?);
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let list_len = data.u16()? as usize;
+        let mut list = Reader(data.bytes(list_len)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let len = list.u16()? as usize;
+            let name = list.bytes(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return host_name(name);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Validates a host name, which must be a domain name rather than an IP address as per RFC 6066, and lowercases it.
+pub fn host_name(name: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(name).ok()?;
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let valid = !name.is_empty()
+        && name.parse::<std::net::IpAddr>().is_err()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        });
+    valid.then(|| name.to_ascii_lowercase())
+}
+
+/// Reads big-endian integers and byte strings from the front of a buffer.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TLS record holding a ClientHello with these extensions, each a type and its data.
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut extensions_data = vec![];
+        for (extension_type, data) in extensions {
+            extensions_data.extend_from_slice(&extension_type.to_be_bytes());
+            extensions_data.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions_data.extend_from_slice(data);
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        // A session ID, two cipher suites and the null compression method.
+        hello.extend_from_slice(&[4, 1, 2, 3, 4]);
+        hello.extend_from_slice(&[0, 4, 0x13, 0x01, 0x13, 0x02]);
+        hello.extend_from_slice(&[1, 0]);
+        hello.extend_from_slice(&(extensions_data.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions_data);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    /// The data of a server name extension listing these names, each a type and its bytes.
+    fn server_names(names: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut list = vec![];
+        for (name_type, name) in names {
+            list.push(*name_type);
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name);
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&list);
+        data
+    }
+
+    #[test]
+    fn reads_the_server_name() {
+        let record = client_hello(&[
+            (0x000a, vec![0, 2, 0, 0x1d]),
+            (
+                EXTENSION_SERVER_NAME,
+                server_names(&[(NAME_TYPE_HOST_NAME, b"WWW.Example.com.")]),
+            ),
+        ]);
+        assert_eq!(
+            parse(&record),
+            ClientHello::ServerName("www.example.com".to_string())
+        );
+
+        // Bytes sent after the record, like the rest of the handshake, are ignored.
+        let mut buf = record.clone();
+        buf.extend_from_slice(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        assert_eq!(
+            parse(&buf),
+            ClientHello::ServerName("www.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_names_of_other_types() {
+        let record = client_hello(&[(
+            EXTENSION_SERVER_NAME,
+            server_names(&[(0x01, b"other"), (NAME_TYPE_HOST_NAME, b"example.com")]),
+        )]);
+        assert_eq!(
+            parse(&record),
+            ClientHello::ServerName("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn waits_for_the_whole_record() {
+        let record = client_hello(&[(
+            EXTENSION_SERVER_NAME,
+            server_names(&[(NAME_TYPE_HOST_NAME, b"example.com")]),
+        )]);
+        for len in [0, 1, 4, 5, record.len() - 1] {
+            assert_eq!(
+                parse(&record[..len]),
+                ClientHello::Incomplete,
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_client_hello() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), ClientHello::NoServerName);
+        assert_eq!(parse(b"G"), ClientHello::NoServerName);
+        // An SSL 2 version.
+        assert_eq!(
+            parse(&[0x16, 0x02, 0x00, 0x00, 0x00]),
+            ClientHello::NoServerName
+        );
+        // A record longer than TLS allows.
+        assert_eq!(
+            parse(&[0x16, 0x03, 0x01, 0xff, 0xff]),
+            ClientHello::NoServerName
+        );
+
+        // A ServerHello.
+        let mut record = client_hello(&[]);
+        record[5] = 0x02;
+        assert_eq!(parse(&record), ClientHello::NoServerName);
+    }
+
+    #[test]
+    fn rejects_client_hellos_without_a_valid_server_name() {
+        assert_eq!(parse(&client_hello(&[])), ClientHello::NoServerName);
+        for name in [
+            &b""[..],
+            b"192.0.2.1",
+            b"::1",
+            b"exa mple.com",
+            b"example..com",
+            b"\xff.com",
+        ] {
+            let record = client_hello(&[(
+                EXTENSION_SERVER_NAME,
+                server_names(&[(NAME_TYPE_HOST_NAME, name)]),
+            )]);
+            assert_eq!(parse(&record), ClientHello::NoServerName, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lengths() {
+        let record = client_hello(&[(
+            EXTENSION_SERVER_NAME,
+            server_names(&[(NAME_TYPE_HOST_NAME, b"example.com")]),
+        )]);
+        // The server name, in the last bytes of the record, claims to be longer than its extension.
+        let name_len = record.len() - b"example.com".len() - 2;
+        let mut long_name = record.clone();
+        long_name[name_len + 1] += 1;
+        assert_eq!(parse(&long_name), ClientHello::NoServerName);
+
+        // The extensions claim to be longer than the record.
+        let extensions_len = 5 + 4 + 2 + 32 + 5 + 6 + 2;
+        let mut long_extensions = record.clone();
+        long_extensions[extensions_len + 1] += 1;
+        assert_eq!(parse(&long_extensions), ClientHello::NoServerName);
+
+        // The record is cut short, with a length that matches.
+        let mut truncated = record[..record.len() - 4].to_vec();
+        truncated[4] -= 4;
+        assert_eq!(parse(&truncated), ClientHello::NoServerName);
+    }
+}
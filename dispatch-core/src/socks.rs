@@ -0,0 +1,1335 @@
+//! The SOCKS4 and SOCKS5 handshakes of clients, which authenticate them, apply the rules and quotas to the destination
+//! they request, and connect to it from the local address picked by the dispatcher.
+
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::ControlFlow,
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::Section;
+use eyre::{eyre, Report, Result, WrapErr};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use socksv5::{
+    v4::SocksV4Command,
+    v5::{SocksV5Command, SocksV5Handshake},
+    SocksVersion, SocksVersionError,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+};
+use tracing::instrument;
+
+use crate::{
+    denials::Reason,
+    dispatcher::Dispatch,
+    dns::{Prefer, Resolver},
+    http_host,
+    net::{NamedInterface, OutboundOptions, SourcePorts},
+    quota::Quotas,
+    redact::redact,
+    rules::{Decision, Denied, Reply, Rules, Sticky, Verdict},
+    sni,
+    users::{User, Users},
+};
+
+const HTTP_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// The version of the username/password authentication of SOCKS5, as per RFC 1929.
+const AUTH_VERSION: u8 = 0x01;
+
+/// How long to wait for a connection attempt before starting the next one in parallel, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The port of the TLS connections whose server name is read with `--sniff-sni`.
+const HTTPS_PORT: u16 = 443;
+/// The port of the plain HTTP connections whose `Host` header is read with `--sniff-http`.
+const HTTP_PORT: u16 = 80;
+/// How long to wait for the ClientHello or the request of a client, which sends it as soon as it's told the connection
+/// succeeded.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The protocol whose first bytes are read to learn the domain of a connection to an IP address.
+#[derive(Clone, Copy, Debug)]
+enum Sniff {
+    Tls,
+    Http,
+}
+
+impl Sniff {
+    /// The most bytes to read before giving up on the domain.
+    fn max_len(self) -> usize {
+        match self {
+            Sniff::Tls => sni::MAX_RECORD_LEN,
+            Sniff::Http => http_host::MAX_HEADER_LEN,
+        }
+    }
+
+    /// Breaks with the domain once the bytes read so far tell it, or that there is none.
+    fn parse(self, buf: &[u8]) -> ControlFlow<Option<String>> {
+        match self {
+            Sniff::Tls => match sni::parse(buf) {
+                sni::ClientHello::Incomplete => ControlFlow::Continue(()),
+                sni::ClientHello::NoServerName => ControlFlow::Break(None),
+                sni::ClientHello::ServerName(name) => ControlFlow::Break(Some(name)),
+            },
+            Sniff::Http => match http_host::parse(buf) {
+                http_host::Request::Incomplete => ControlFlow::Continue(()),
+                http_host::Request::NoHost => ControlFlow::Break(None),
+                http_host::Request::Host(host) => ControlFlow::Break(Some(host)),
+            },
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+fn assert_supports_noauth(handshake: &SocksV5Handshake) -> Result<()> {
+    if !handshake
+        .methods
+        .contains(&socksv5::v5::SocksV5AuthMethod::Noauth)
+    {
+        Err(unsupported_auth_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads the username and password a client authenticates with, as per RFC 1929.
+async fn read_credentials<R>(reader: &mut R) -> Result<(String, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let version = reader.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(eyre!(
+            "Unsupported username/password authentication version {}",
+            version
+        ));
+    }
+    let mut name = vec![0; reader.read_u8().await? as usize];
+    reader.read_exact(&mut name).await?;
+    let mut password = vec![0; reader.read_u8().await? as usize];
+    reader.read_exact(&mut password).await?;
+    Ok((String::from_utf8_lossy(&name).into_owned(), password))
+}
+
+#[instrument(level = "debug")]
+fn try_bind_socket(
+    outbound: &OutboundOptions,
+    addr: IpAddr,
+    interface: Option<&NamedInterface>,
+) -> Result<TcpSocket> {
+    outbound
+        .bind(addr, interface)
+        .map_err(|err| match err.raw_os_error() {
+            // Can't assign requested address
+            Some(49) => eyre::eyre!(err).wrap_err(unaccessible_local_address_error(&addr)),
+            _ => eyre::eyre!(err),
+        })
+}
+
+#[instrument(level = "debug", skip(resolver, domain, sources), fields(host = ?redact(&(domain, port))))]
+async fn lookup(
+    resolver: &Resolver,
+    domain: &str,
+    port: u16,
+    sources: &[IpAddr],
+) -> Result<Vec<SocketAddr>> {
+    resolver
+        .lookup(domain, port, sources)
+        .await
+        .map_err(|err| err.wrap_err(resolve_host_error(&(domain, port))))
+}
+
+#[instrument(level = "debug", skip(resolver, domain), fields(host = ?redact(&(domain, port))))]
+async fn lookup_from(
+    resolver: &Resolver,
+    local_addr: IpAddr,
+    domain: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>> {
+    resolver
+        .lookup_from(local_addr, domain, port)
+        .await
+        .map_err(|err| err.wrap_err(resolve_host_error(&(domain, port))))
+}
+
+/// The destination a client asked the proxy to connect to.
+#[derive(Clone, Debug)]
+pub struct Destination {
+    /// The domain name requested by the client, if it didn't directly request an IP address.
+    pub domain: Option<String>,
+    pub addr: SocketAddr,
+}
+
+impl Destination {
+    /// The address the destination resolved to, unless it was refused before its domain was resolved, when it is
+    /// given with the unspecified address of an IP version.
+    pub fn resolved(&self) -> Option<SocketAddr> {
+        match self.domain {
+            Some(_) if self.addr.ip().is_unspecified() => None,
+            _ => Some(self.addr),
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match &self.domain {
+            Some(domain) => write!(f, "{}:{}", domain, self.addr.port()),
+            None => Display::fmt(&self.addr, f),
+        }
+    }
+}
+
+/// The error reported when a client doesn't authenticate as one of the users.
+#[derive(Debug)]
+pub enum Unauthenticated {
+    /// The client didn't offer username/password authentication.
+    MissingCredentials,
+    /// The client uses SOCKS4, which has no authentication.
+    Socks4,
+    /// The username or password is wrong.
+    Rejected(String),
+}
+
+impl Unauthenticated {
+    /// The name the client tried to authenticate as.
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Unauthenticated::Rejected(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Unauthenticated {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Unauthenticated::MissingCredentials => f.write_str(
+                "The client didn't offer to authenticate with a username and password, which the proxy requires.",
+            ),
+            Unauthenticated::Socks4 => {
+                f.write_str("SOCKS4 clients can't authenticate, which the proxy requires.")
+            }
+            Unauthenticated::Rejected(name) => {
+                write!(f, "Failed to authenticate the user `{}`", redact(name))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Unauthenticated {}
+
+/// The handshake of a client, which ends with a connection to the destination it requested.
+#[derive(Debug)]
+pub struct SocksHandshake<R, W, D>
+where
+    R: AsyncRead + Unpin + Debug,
+    W: AsyncWrite + Unpin + Debug,
+    D: Dispatch + Debug,
+{
+    reader: R,
+    writer: W,
+    /// The address of the client, which rules can apply to.
+    client: IpAddr,
+    dispatcher: D,
+    rules: Rules,
+    resolver: Resolver,
+    outbound: OutboundOptions,
+    /// The users who can connect, when clients must authenticate.
+    users: Option<Users>,
+    /// The user the client authenticated as.
+    user: Option<Arc<User>>,
+    /// The application the client connected from, when it runs on the same machine and rules match applications.
+    app: Option<String>,
+    /// The quotas that refuse the connections of clients and users who have used them up.
+    quotas: Quotas,
+    /// The local address picked to resolve the destination over, with per-interface resolution.
+    dispatched: Option<IpAddr>,
+    /// The other addresses the destination resolved to, to race against the first one when connecting.
+    fallbacks: Vec<SocketAddr>,
+    /// The decision of the rules on the address connected to.
+    decision: Option<Decision>,
+    /// The bytes read from the client to find the domain of the connection, which are relayed first.
+    sniffed: Vec<u8>,
+    /// Read the server name of TLS connections to IP addresses before connecting, to apply the domain rules.
+    sniff_sni: bool,
+    /// Read the `Host` header of plain HTTP connections to IP addresses before connecting, likewise.
+    sniff_http: bool,
+}
+
+impl<R, W, D> SocksHandshake<R, W, D>
+where
+    R: AsyncRead + Unpin + Debug,
+    W: AsyncWrite + Unpin + Debug,
+    D: Dispatch + Debug,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        reader: R,
+        writer: W,
+        client: IpAddr,
+        app: Option<String>,
+        dispatcher: D,
+        rules: Rules,
+        resolver: Resolver,
+        outbound: OutboundOptions,
+        users: Option<Users>,
+        quotas: Quotas,
+        sniff_sni: bool,
+        sniff_http: bool,
+    ) -> SocksHandshake<R, W, D> {
+        SocksHandshake {
+            reader,
+            writer,
+            client,
+            app,
+            dispatcher,
+            rules,
+            resolver,
+            outbound,
+            users,
+            user: None,
+            quotas,
+            dispatched: None,
+            fallbacks: vec![],
+            decision: None,
+            sniffed: vec![],
+            sniff_sni,
+            sniff_http,
+        }
+    }
+
+    /// The user the client authenticated as, once the handshake is done.
+    pub fn user(&self) -> Option<Arc<User>> {
+        self.user.clone()
+    }
+
+    /// The decision of the rules on the connection, once the handshake is done.
+    pub fn decision(&self) -> Option<Decision> {
+        self.decision.clone()
+    }
+
+    /// The bytes read from the client to find the domain of the connection, once the handshake is done, which must be
+    /// relayed to the destination before the rest.
+    pub fn take_sniffed(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.sniffed)
+    }
+
+    pub async fn handshake(&mut self) -> Result<(TcpStream, Destination)> {
+        match socksv5::read_version(&mut self.reader).await {
+            Err(err) => Err(self.handle_version_error(err).await),
+            Ok(version) => self.handle_handshake_with_version(version).await,
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn handle_version_error(&mut self, err: SocksVersionError) -> eyre::Report {
+        match err {
+            SocksVersionError::InvalidVersion(byte) => {
+                match byte as char {
+                    // HTTP method prefixes
+                    'C' | 'G' | 'P' | 'H' | 'D' | 'O' | 'T' => {
+                        let mut out = [0u8; 1024];
+                        out[0] = byte;
+                        match self.reader.read(&mut out[1..]).await {
+                            Ok(read) => {
+                                let out = String::from_utf8_lossy(&out[..read + 1]);
+                                if HTTP_METHODS.iter().any(|method| out.starts_with(method)) {
+                                    http_header_error(&out)
+                                } else {
+                                    err.into()
+                                }
+                            }
+                            Err(read_err) => eyre!(err).wrap_err(read_err),
+                        }
+                    }
+                    _ => err.into(),
+                }
+            }
+            err => err.into(),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn handle_handshake_with_version(
+        &mut self,
+        version: SocksVersion,
+    ) -> Result<(TcpStream, Destination)> {
+        match version {
+            socksv5::SocksVersion::V5 => {
+                let handshake = socksv5::v5::read_handshake_skip_version(&mut self.reader).await?;
+
+                self.handle_auth(&handshake).await?;
+
+                let mut destination = self.handle_request_v5().await?;
+                let sniff = match destination.addr.port() {
+                    _ if destination.domain.is_some() => None,
+                    HTTPS_PORT if self.sniff_sni => Some(Sniff::Tls),
+                    HTTP_PORT if self.sniff_http => Some(Sniff::Http),
+                    _ => None,
+                };
+                let stream = match sniff {
+                    Some(sniff) => {
+                        self.handle_connect_v5_sniffed(&mut destination, sniff)
+                            .await?
+                    }
+                    None => self.handle_connect_v5(&mut destination).await?,
+                };
+                Ok((stream, destination))
+            }
+            socksv5::SocksVersion::V4 if self.users.is_some() => {
+                socksv5::v4::read_request_skip_version(&mut self.reader).await?;
+                socksv5::v4::write_request_status(
+                    &mut self.writer,
+                    socksv5::v4::SocksV4RequestStatus::Failed,
+                    [0, 0, 0, 0],
+                    0,
+                )
+                .await?;
+                Err(socks4_auth_error())
+            }
+            socksv5::SocksVersion::V4 => {
+                let mut destination = self.handle_request_v4().await?;
+                let stream = self.handle_connect_v4(&mut destination).await?;
+                Ok((stream, destination))
+            }
+        }
+    }
+
+    /// Connects to the destination, racing the addresses its domain resolved to as per Happy Eyeballs (RFC 8305):
+    /// alternating between address families, a new attempt starts whenever the previous one fails or takes longer than
+    /// `CONNECTION_ATTEMPT_DELAY`, and the first connection established wins. The destination is updated with the
+    /// address connected to.
+    #[instrument(level = "debug", skip_all, fields(destination = %redact(&*destination)))]
+    async fn connect(&mut self, destination: &mut Destination) -> Result<TcpStream, ConnectError> {
+        if let Some(denied) = self.quota_refusal(destination) {
+            return Err(ConnectError::Denied(denied));
+        }
+
+        let mut dispatched = self.dispatched.take();
+        let fallbacks = std::mem::take(&mut self.fallbacks);
+        let mut candidates = interleave_families(destination.addr, fallbacks).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut error: Option<(ConnectError, Option<Decision>)> = None;
+        let mut next = candidates.next();
+
+        loop {
+            if let Some(addr) = next.take() {
+                let candidate = Destination {
+                    domain: destination.domain.clone(),
+                    addr,
+                };
+                let decision = self.rules.decide(
+                    self.client,
+                    self.user.as_deref(),
+                    self.app.as_deref(),
+                    &candidate,
+                );
+                let (dispatcher, outbound, quotas) =
+                    (&self.dispatcher, &self.outbound, &self.quotas);
+                let dispatched = dispatched.take();
+                attempts.push(async move {
+                    let (result, decision) = match decision {
+                        Ok(decision) => {
+                            let result = try_connect(
+                                dispatcher,
+                                outbound,
+                                quotas,
+                                &candidate,
+                                &decision.verdict,
+                                dispatched,
+                            )
+                            .await;
+                            (result, Some(decision))
+                        }
+                        Err(err) => (Err(err.into()), None),
+                    };
+                    (addr, result, decision)
+                });
+            }
+
+            tokio::select! {
+                Some((addr, result, decision)) = attempts.next() => match result {
+                    // The connection counts as a single hit of the rule that decided the address it was made to, or
+                    // else the address whose error is reported.
+                    Ok(stream) => {
+                        if let Some(decision) = &decision {
+                            decision.record_hit();
+                        }
+                        destination.addr = addr;
+                        self.decision = decision;
+                        return Ok(stream);
+                    }
+                    Err(err) => {
+                        tracing::debug!(address = %redact(addr), "failed to connect to an address of the destination: {}", err);
+                        error = Some(match error {
+                            Some((previous, previous_decision)) if previous.relevance() > err.relevance() => {
+                                (previous, previous_decision)
+                            }
+                            _ => (err, decision),
+                        });
+                        next = candidates.next();
+                        if next.is_none() && attempts.is_empty() {
+                            break;
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if !candidates.as_slice().is_empty() => {
+                    next = candidates.next();
+                }
+            }
+        }
+
+        let (error, decision) = error.expect("a destination has at least one address");
+        if let Some(decision) = &decision {
+            decision.record_hit();
+        }
+        Err(error)
+    }
+
+    /// Why the connection is refused if the client or its user is over a quota.
+    fn quota_refusal(&self, destination: &Destination) -> Option<Denied> {
+        let quota = self.quotas.refusal(self.client, self.user.as_deref())?;
+        Some(Denied {
+            destination: destination.clone(),
+            rule: quota,
+            reason: Reason::Quota,
+            reply: Reply::NotAllowed,
+        })
+    }
+
+    /// Decides a domain requested by the client before resolving it, as if it resolved to the preferred IP version, or
+    /// else the other one, e.g. when it is routed to an interface without an address of the preferred one, so that
+    /// denied domains are refused without being looked up. There is no decision when it depends on the addresses the
+    /// domain resolves to.
+    fn decide_domain(&self, domain: &str, port: u16) -> Result<Option<Decision>> {
+        let (ipv4, ipv6) = (Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into());
+        let (preferred, other) = match self.resolver.prefer() {
+            Prefer::Auto | Prefer::Ipv4 => (ipv4, ipv6),
+            Prefer::Ipv6 => (ipv6, ipv4),
+        };
+        let decide = |ip| {
+            self.rules.decide_domain(
+                self.client,
+                self.user.as_deref(),
+                self.app.as_deref(),
+                &Destination {
+                    domain: Some(domain.to_owned()),
+                    addr: SocketAddr::new(ip, port),
+                },
+            )
+        };
+        decide(preferred).or_else(|_| decide(other))
+    }
+
+    /// Resolves a domain requested by the client. With per-interface resolution, the domain is resolved over the
+    /// local address it connects from, as decided by the rules before resolving it, unless it has static addresses or
+    /// nameservers of its own: the address of the interface it is routed to, or the one the system picks when it
+    /// connects directly, or else its sticky address, or the one the dispatcher picks first, from the preferred IP
+    /// version when possible, which it is then dispatched from.
+    async fn resolve(
+        &mut self,
+        domain: &str,
+        port: u16,
+        verdict: Option<&Verdict>,
+    ) -> Result<SocketAddr> {
+        if !self.resolver.resolves_over_interface(domain) {
+            let sources = self.dispatcher.local_addresses().await;
+            let mut addrs = lookup(&self.resolver, domain, port, &sources).await?;
+            let addr = addrs.remove(0);
+            self.fallbacks = addrs;
+            return Ok(addr);
+        }
+
+        let mut addrs = match verdict {
+            Some(Verdict::Route { ip, .. }) => {
+                lookup_from(&self.resolver, *ip, domain, port).await?
+            }
+            // The system routes direct connections, and their queries likewise.
+            Some(Verdict::Direct { .. }) => lookup(&self.resolver, domain, port, &[]).await?,
+            verdict => {
+                // A sticky address is only reused while the dispatcher can still dispatch from it.
+                let sticky = match verdict {
+                    Some(Verdict::Dispatch { options }) => {
+                        options.sticky.as_ref().and_then(Sticky::get)
+                    }
+                    _ => None,
+                };
+                let local_addr = match sticky {
+                    Some(ip) if self.dispatcher.local_addresses().await.contains(&ip) => ip,
+                    _ => self.dispatch_for_lookup(port).await?,
+                };
+                self.dispatched = Some(local_addr);
+                lookup_from(&self.resolver, local_addr, domain, port).await?
+            }
+        };
+        let addr = addrs.remove(0);
+        self.fallbacks = addrs;
+        Ok(addr)
+    }
+
+    /// Picks the local address to dispatch a domain from before resolving it over that address, from the preferred IP
+    /// version when possible.
+    async fn dispatch_for_lookup(&self, port: u16) -> Result<IpAddr> {
+        let (ipv4, ipv6) = (Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into());
+        let (preferred, other) = match self.resolver.prefer() {
+            Prefer::Auto | Prefer::Ipv4 => (ipv4, ipv6),
+            Prefer::Ipv6 => (ipv6, ipv4),
+        };
+        match self
+            .dispatcher
+            .dispatch(&SocketAddr::new(preferred, port))
+            .await
+        {
+            Ok(local_addr) => Ok(local_addr),
+            Err(_) => self
+                .dispatcher
+                .dispatch(&SocketAddr::new(other, port))
+                .await
+                .wrap_err_with(dispatch_error),
+        }
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn handle_auth(&mut self, handshake: &SocksV5Handshake) -> Result<()> {
+        let Some(users) = self.users.clone() else {
+            assert_supports_noauth(handshake)?;
+
+            socksv5::v5::write_auth_method(
+                &mut self.writer,
+                socksv5::v5::SocksV5AuthMethod::Noauth,
+            )
+            .await?;
+
+            return Ok(());
+        };
+
+        if !handshake
+            .methods
+            .contains(&socksv5::v5::SocksV5AuthMethod::UsernamePassword)
+        {
+            socksv5::v5::write_auth_method(
+                &mut self.writer,
+                socksv5::v5::SocksV5AuthMethod::NoAcceptableMethod,
+            )
+            .await?;
+            return Err(missing_credentials_error());
+        }
+        socksv5::v5::write_auth_method(
+            &mut self.writer,
+            socksv5::v5::SocksV5AuthMethod::UsernamePassword,
+        )
+        .await?;
+
+        let (name, password) = read_credentials(&mut self.reader).await?;
+        match users.authenticate(&name, &password).await {
+            Some(user) => {
+                self.writer.write_all(&[AUTH_VERSION, 0x00]).await?;
+                self.user = Some(user);
+                Ok(())
+            }
+            None => {
+                self.writer.write_all(&[AUTH_VERSION, 0x01]).await?;
+                Err(Report::new(Unauthenticated::Rejected(name))
+                    .suggestion("Check the username and password the client is configured with"))
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn handle_request_v5(&mut self) -> Result<Destination> {
+        let request = socksv5::v5::read_request(&mut self.reader).await?;
+
+        match request.command {
+            socksv5::v5::SocksV5Command::Connect => {
+                let destination = match request.host {
+                    socksv5::v5::SocksV5Host::Ipv4(ip) => Destination {
+                        domain: None,
+                        addr: SocketAddr::new(IpAddr::V4(ip.into()), request.port),
+                    },
+                    socksv5::v5::SocksV5Host::Ipv6(ip) => Destination {
+                        domain: None,
+                        addr: SocketAddr::new(IpAddr::V6(ip.into()), request.port),
+                    },
+                    socksv5::v5::SocksV5Host::Domain(domain) => {
+                        let domain = String::from_utf8(domain)?;
+                        let decision = self.decide_domain(&domain, request.port)?;
+                        if let Some(denied) = refusal(decision.as_ref()) {
+                            socksv5::v5::write_request_status(
+                                &mut self.writer,
+                                denied_status(&denied),
+                                socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+                                0,
+                            )
+                            .await?;
+                            return Err(denied.into());
+                        }
+                        let mut addr = match self
+                            .resolve(
+                                &domain,
+                                request.port,
+                                decision.map(|decision| decision.verdict).as_ref(),
+                            )
+                            .await
+                        {
+                            Ok(addr) => addr,
+                            Err(err) => {
+                                socksv5::v5::write_request_status(
+                                    &mut self.writer,
+                                    socksv5::v5::SocksV5RequestStatus::HostUnreachable,
+                                    socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+                                    0,
+                                )
+                                .await?;
+                                return Err(err.note(lookup_note()).note(safe_to_ignore_note()));
+                            }
+                        };
+                        addr.set_port(request.port);
+                        Destination {
+                            domain: Some(domain),
+                            addr,
+                        }
+                    }
+                };
+
+                Ok(destination)
+            }
+            cmd => {
+                socksv5::v5::write_request_status(
+                    &mut self.writer,
+                    socksv5::v5::SocksV5RequestStatus::CommandNotSupported,
+                    socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+                    0,
+                )
+                .await?;
+                Err(unsupported_v5_command_error(&cmd))
+            }
+        }
+    }
+
+    async fn handle_connect_v5(&mut self, destination: &mut Destination) -> Result<TcpStream> {
+        let err = match self.connect(destination).await {
+            Ok(server_stream) => {
+                socksv5::v5::write_request_status(
+                    &mut self.writer,
+                    socksv5::v5::SocksV5RequestStatus::Success,
+                    socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+                    0,
+                )
+                .await?;
+                return Ok(server_stream);
+            }
+            Err(err) => err,
+        };
+
+        let status = match &err {
+            ConnectError::Other(_) => return Err(err.into()),
+            ConnectError::Denied(denied) => denied_status(denied),
+            // Unix error codes.
+            // TODO: handle Windows error codes.
+            ConnectError::Failed { err, .. } => match err.raw_os_error() {
+                // ENETUNREACH
+                Some(101) => socksv5::v5::SocksV5RequestStatus::NetworkUnreachable,
+                // ETIMEDOUT
+                Some(110) => socksv5::v5::SocksV5RequestStatus::TtlExpired,
+                // ECONNREFUSED
+                Some(111) => socksv5::v5::SocksV5RequestStatus::ConnectionRefused,
+                // EHOSTUNREACH
+                Some(113) => socksv5::v5::SocksV5RequestStatus::HostUnreachable,
+                // Unhandled error code
+                _ => socksv5::v5::SocksV5RequestStatus::ServerFailure,
+            },
+        };
+        socksv5::v5::write_request_status(
+            &mut self.writer,
+            status,
+            socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+            0,
+        )
+        .await?;
+        Err(err.into())
+    }
+
+    /// Connects to an IP address after reading the server name from the TLS ClientHello of the client, so that the rules
+    /// apply to it as if the client had requested the domain. The client is told that the connection succeeded before
+    /// it is attempted, since it only sends the ClientHello afterwards, so failing to connect closes the connection
+    /// instead. The bytes read are kept to be relayed first, so that they count as the traffic of the connection.
+    ///
+    /// The quotas and the rules are first applied to the address alone, so that the client is refused before being told
+    /// that the connection succeeded. The client could send any server name: a server name can route the connection or
+    /// deny it, but can't allow an address that is denied.
+    async fn handle_connect_v5_sniffed(
+        &mut self,
+        destination: &mut Destination,
+        sniff: Sniff,
+    ) -> Result<TcpStream> {
+        let denied = match self.quota_refusal(destination) {
+            Some(denied) => Some(denied),
+            None => {
+                let decision = self.rules.decide(
+                    self.client,
+                    self.user.as_deref(),
+                    self.app.as_deref(),
+                    destination,
+                )?;
+                // The connection only counts as a hit here when it is refused, since it is decided again once the
+                // domain is known.
+                match &decision.verdict {
+                    Verdict::Deny(denied) => {
+                        decision.record_hit();
+                        Some(denied.clone())
+                    }
+                    _ => None,
+                }
+            }
+        };
+        if let Some(denied) = denied {
+            socksv5::v5::write_request_status(
+                &mut self.writer,
+                denied_status(&denied),
+                socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+                0,
+            )
+            .await?;
+            return Err(denied.into());
+        }
+        socksv5::v5::write_request_status(
+            &mut self.writer,
+            socksv5::v5::SocksV5RequestStatus::Success,
+            socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+            0,
+        )
+        .await?;
+
+        let mut sniffed = Vec::new();
+        let read = tokio::time::timeout(SNIFF_TIMEOUT, async {
+            let mut buf = [0u8; 4096];
+            while sniffed.len() < sniff.max_len() {
+                match self.reader.read(&mut buf).await? {
+                    0 => break,
+                    read => sniffed.extend_from_slice(&buf[..read]),
+                }
+                if let ControlFlow::Break(domain) = sniff.parse(&sniffed) {
+                    destination.domain = domain;
+                    break;
+                }
+            }
+            Ok::<_, std::io::Error>(())
+        })
+        .await;
+        match read {
+            Ok(res) => res?,
+            // The client may wait for the server to speak first, and is then connected without a domain.
+            Err(_) => tracing::debug!(?sniff, "nothing was sent, connecting without a domain"),
+        }
+        if let Some(domain) = &destination.domain {
+            tracing::debug!(?sniff, domain = %redact(domain), "read the domain of the connection");
+        }
+
+        let stream = self.connect(destination).await?;
+        self.sniffed = sniffed;
+        Ok(stream)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn handle_request_v4(&mut self) -> Result<Destination> {
+        let request = socksv5::v4::read_request(&mut self.reader).await?;
+
+        match request.command {
+            socksv5::v4::SocksV4Command::Connect => Ok(match request.host {
+                socksv5::v4::SocksV4Host::Ip(ip) => Destination {
+                    domain: None,
+                    addr: SocketAddr::new(IpAddr::V4(ip.into()), request.port),
+                },
+                socksv5::v4::SocksV4Host::Domain(domain) => {
+                    let domain = String::from_utf8(domain)?;
+                    let decision = self.decide_domain(&domain, request.port)?;
+                    if let Some(denied) = refusal(decision.as_ref()) {
+                        socksv5::v4::write_request_status(
+                            &mut self.writer,
+                            socksv5::v4::SocksV4RequestStatus::Failed,
+                            [0, 0, 0, 0],
+                            0,
+                        )
+                        .await?;
+                        return Err(denied.into());
+                    }
+
+                    match self
+                        .resolve(
+                            &domain,
+                            request.port,
+                            decision.map(|decision| decision.verdict).as_ref(),
+                        )
+                        .await
+                    {
+                        Ok(addr) => Destination {
+                            domain: Some(domain),
+                            addr,
+                        },
+                        Err(err) => {
+                            socksv5::v4::write_request_status(
+                                &mut self.writer,
+                                socksv5::v4::SocksV4RequestStatus::Failed,
+                                [0, 0, 0, 0],
+                                0,
+                            )
+                            .await?;
+                            return Err(err);
+                        }
+                    }
+                }
+            }),
+            cmd => {
+                socksv5::v4::write_request_status(
+                    &mut self.writer,
+                    socksv5::v4::SocksV4RequestStatus::Failed,
+                    [0, 0, 0, 0],
+                    0,
+                )
+                .await?;
+                Err(unsupported_v4_command_error(&cmd))
+            }
+        }
+    }
+
+    async fn handle_connect_v4(&mut self, destination: &mut Destination) -> Result<TcpStream> {
+        let (status, res) = match self.connect(destination).await {
+            Ok(server_stream) => (
+                socksv5::v4::SocksV4RequestStatus::Granted,
+                Ok(server_stream),
+            ),
+            Err(ConnectError::Other(err)) => return Err(err),
+            Err(err) => (socksv5::v4::SocksV4RequestStatus::Failed, Err(err.into())),
+        };
+        socksv5::v4::write_request_status(&mut self.writer, status, [0, 0, 0, 0], 0).await?;
+        res
+    }
+}
+
+/// Connects to a single address as the rules decided, from the local address picked to resolve it over if any, or else
+/// from the one the rules or the dispatcher pick.
+async fn try_connect<D: Dispatch>(
+    dispatcher: &D,
+    outbound: &OutboundOptions,
+    quotas: &Quotas,
+    destination: &Destination,
+    verdict: &Verdict,
+    dispatched: Option<IpAddr>,
+) -> Result<TcpStream, ConnectError> {
+    let (local_addr, interface, options) = match verdict {
+        Verdict::Dispatch { options } => {
+            // A sticky address is only reused while the dispatcher can still dispatch from it.
+            let sticky = match options.sticky.as_ref().and_then(Sticky::get) {
+                Some(ip) if dispatcher.local_addresses().await.contains(&ip) => Some(ip),
+                _ => None,
+            };
+            let local_addr = match sticky.or(dispatched) {
+                Some(local_addr) => local_addr,
+                None => dispatcher
+                    .dispatch(&destination.addr)
+                    .await
+                    .wrap_err_with(dispatch_error)?,
+            };
+            (
+                local_addr,
+                dispatcher.interface_of(local_addr).await,
+                options,
+            )
+        }
+        Verdict::Route {
+            ip,
+            interface,
+            options,
+        } => {
+            // The dispatcher stops dispatching to an interface once it has used up its quota, and routes stop too.
+            if let Some(quota) = quotas.interface_refusal(interface.as_ref(), *ip) {
+                return Err(ConnectError::Denied(Denied {
+                    destination: destination.clone(),
+                    rule: quota,
+                    reason: Reason::Quota,
+                    reply: Reply::NotAllowed,
+                }));
+            }
+            (*ip, interface.clone(), options)
+        }
+        // Binding to the unspecified address leaves picking the source address, and so the interface, to the routing
+        // table of the system.
+        Verdict::Direct { options } => {
+            let local_addr = match destination.addr {
+                SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+            };
+            (local_addr, None, options)
+        }
+        Verdict::Deny(denied) => return Err(ConnectError::Denied(denied.clone())),
+    };
+
+    // With a range of source ports, the next port may already be connected to the destination, and the others are
+    // tried in turn.
+    let attempts = outbound
+        .source_ports(interface.as_ref(), local_addr)
+        .map_or(1, SourcePorts::len);
+    let mut attempt = 1;
+    loop {
+        let server_socket = try_bind_socket(outbound, local_addr, interface.as_ref())?;
+        outbound
+            .apply(&server_socket, interface.as_ref(), options.dscp)
+            .map_err(|err| outbound_options_error(err, interface.as_ref()))?;
+        let connecting = server_socket.connect(destination.addr);
+        let res = match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connecting)
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+            None => connecting.await,
+        };
+        match res {
+            Err(err)
+                if err.kind() == std::io::ErrorKind::AddrNotAvailable && attempt < attempts =>
+            {
+                attempt += 1;
+            }
+            Ok(stream) => {
+                if let Some(sticky) = &options.sticky {
+                    sticky.set(local_addr);
+                }
+                return Ok(stream);
+            }
+            Err(err) => {
+                return Err(ConnectError::Failed {
+                    err,
+                    addr: destination.addr,
+                })
+            }
+        }
+    }
+}
+
+/// Why a domain is refused before it is resolved, if it is denied whatever it resolves to. The refusal counts as the
+/// hit of the rule that denied it.
+fn refusal(decision: Option<&Decision>) -> Option<Denied> {
+    let decision = decision?;
+    match &decision.verdict {
+        Verdict::Deny(denied) => {
+            decision.record_hit();
+            Some(denied.clone())
+        }
+        _ => None,
+    }
+}
+
+fn denied_status(denied: &Denied) -> socksv5::v5::SocksV5RequestStatus {
+    match denied.reply {
+        Reply::NotAllowed => socksv5::v5::SocksV5RequestStatus::ConnectionNotAllowed,
+        Reply::NetworkUnreachable => socksv5::v5::SocksV5RequestStatus::NetworkUnreachable,
+        Reply::HostUnreachable => socksv5::v5::SocksV5RequestStatus::HostUnreachable,
+        Reply::ConnectionRefused => socksv5::v5::SocksV5RequestStatus::ConnectionRefused,
+    }
+}
+
+/// Orders the addresses of a destination for Happy Eyeballs, alternating between address families and starting with
+/// the family of the first address, while keeping the order given by the resolver within each family.
+fn interleave_families(first: SocketAddr, others: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = std::iter::once(first)
+        .chain(others)
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut addrs = Vec::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return addrs,
+            (preferred, other) => addrs.extend(preferred.into_iter().chain(other)),
+        }
+    }
+}
+
+/// Why no connection could be established to a destination.
+#[derive(Debug)]
+enum ConnectError {
+    /// A rule denied the connection.
+    Denied(Denied),
+    /// The destination couldn't be reached.
+    Failed {
+        err: std::io::Error,
+        addr: SocketAddr,
+    },
+    /// The connection couldn't be attempted, e.g. because no local address can reach the destination.
+    Other(Report),
+}
+
+impl ConnectError {
+    /// When connecting to several addresses fails, the error of the most relevant kind is reported.
+    fn relevance(&self) -> u8 {
+        match self {
+            ConnectError::Other(_) => 0,
+            ConnectError::Denied(_) => 1,
+            ConnectError::Failed { .. } => 2,
+        }
+    }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ConnectError::Denied(denied) => Display::fmt(denied, f),
+            ConnectError::Failed { err, addr } => write!(f, "`{}`: {}", redact(addr), err),
+            ConnectError::Other(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl From<Report> for ConnectError {
+    fn from(err: Report) -> ConnectError {
+        ConnectError::Other(err)
+    }
+}
+
+impl From<ConnectError> for Report {
+    fn from(err: ConnectError) -> Report {
+        match err {
+            ConnectError::Denied(denied) => denied.into(),
+            ConnectError::Failed { err, addr } => eyre::eyre!(err).wrap_err(connect_error(&addr)),
+            ConnectError::Other(err) => err,
+        }
+    }
+}
+
+fn connect_error(address: &SocketAddr) -> Report {
+    eyre::eyre!(format!(
+        "Failed to connect to address `{}`",
+        redact(address)
+    ))
+    .note("This error usually happens when the proxy fails to contact a remote host.")
+    .note(safe_to_ignore_note())
+}
+
+fn resolve_host_error<T>(host: &T) -> Report
+where
+    T: Debug,
+{
+    eyre::eyre!("Failed to resolve the host `{:?}`", redact(host))
+}
+
+fn dispatch_error() -> Report {
+    eyre::eyre!("An error occurred during dispatching")
+}
+
+fn unsupported_v4_command_error(cmd: &SocksV4Command) -> Report {
+    eyre::eyre!("Unsupported SOCKSv4 proxy command `{:?}`", cmd)
+}
+
+fn unsupported_v5_command_error(cmd: &SocksV5Command) -> Report {
+    eyre::eyre!("Unsupported SOCKSv4 proxy command `{:?}`", cmd)
+}
+
+fn unaccessible_local_address_error(addr: &IpAddr) -> Report {
+    eyre::eyre!(format!("The local address `{}` is not accessible.", addr)).suggestion(
+        "Please ensure that it matches an existing network \
+        interface on your computer by inspecting the output of `dispatch list`.",
+    )
+}
+
+fn outbound_options_error(err: std::io::Error, interface: Option<&NamedInterface>) -> Report {
+    let permission_denied = err.kind() == std::io::ErrorKind::PermissionDenied;
+    let report = eyre::eyre!(err).wrap_err(match interface {
+        Some(interface) => format!(
+            "Failed to set the socket options of a connection through `{}`",
+            interface.name
+        ),
+        None => "Failed to set the socket options of a connection".to_string(),
+    });
+    if permission_denied {
+        report.suggestion(
+            "Binding connections to a network interface requires the CAP_NET_RAW capability on Linux before 5.7, \
+            and marking them requires CAP_NET_ADMIN, e.g. with `sudo setcap cap_net_raw,cap_net_admin+ep \
+            $(which dispatch)`",
+        )
+    } else {
+        report
+    }
+}
+
+fn http_header_error(out: &str) -> Report {
+    let first_http_line = out.split("\r\n").next().unwrap();
+    eyre::eyre!(eyre!(
+        "The proxy received `{}` ({} additional bytes not shown), which looks like an HTTP \
+        request. Please ensure that you have properly configured the proxy as a SOCKS \
+        proxy and not an HTTP proxy.",
+        redact(first_http_line),
+        out.len() - first_http_line.len()
+    ))
+}
+
+fn unsupported_auth_error() -> Report {
+    eyre::eyre!("Only the NOAUTH SOCKS proxy authentication scheme is supported.").suggestion(
+        "Please ensure that you haven't provided authentication credentials to your system's \
+                proxy configuration.",
+    )
+}
+
+fn missing_credentials_error() -> Report {
+    Report::new(Unauthenticated::MissingCredentials).suggestion(
+        "Configure the client with the username and password of one of the users of `--users`.",
+    )
+}
+
+fn socks4_auth_error() -> Report {
+    Report::new(Unauthenticated::Socks4)
+        .suggestion("Configure the client to use SOCKS5, with the username and password of one of the users of `--users`.")
+}
+
+fn lookup_note() -> &'static str {
+    "This error usually happens when an application tries to contact a domain name that does not exist."
+}
+
+fn safe_to_ignore_note() -> String {
+    use owo_colors::OwoColorize;
+    format!("{} {}", "It is safe to ignore in most cases.".bold(), "However, if you notice a degradation in service because of this error, please file an issue.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dispatcher::{RawWeightedAddress, WeightedAddress, WeightedRoundRobinDispatcher},
+        dns::{Hosts, ResolverOptions},
+        rules::{Environment, RuleSet},
+    };
+
+    fn addrs(srcs: &[&str]) -> Vec<SocketAddr> {
+        srcs.iter()
+            .map(|src| SocketAddr::new(src.parse().unwrap(), 443))
+            .collect()
+    }
+
+    #[test]
+    fn interleaves_address_families() {
+        let resolved = addrs(&[
+            "2001:db8::1",
+            "2001:db8::2",
+            "2001:db8::3",
+            "192.0.2.1",
+            "192.0.2.2",
+        ]);
+        assert_eq!(
+            interleave_families(resolved[0], resolved[1..].to_vec()),
+            addrs(&[
+                "2001:db8::1",
+                "192.0.2.1",
+                "2001:db8::2",
+                "192.0.2.2",
+                "2001:db8::3"
+            ])
+        );
+
+        let resolved = addrs(&["192.0.2.1", "2001:db8::1", "2001:db8::2", "192.0.2.2"]);
+        assert_eq!(
+            interleave_families(resolved[0], resolved[1..].to_vec()),
+            addrs(&["192.0.2.1", "2001:db8::1", "192.0.2.2", "2001:db8::2"])
+        );
+    }
+
+    #[test]
+    fn keeps_single_family_addresses_in_order() {
+        let resolved = addrs(&["192.0.2.2", "192.0.2.1", "192.0.2.3"]);
+        assert_eq!(
+            interleave_families(resolved[0], resolved[1..].to_vec()),
+            resolved
+        );
+        assert_eq!(interleave_families(resolved[0], vec![]), &resolved[..1]);
+    }
+
+    /// A handshake with a client on the local host, dispatched from `127.0.0.1`.
+    fn handshake<R, W>(
+        reader: R,
+        writer: W,
+        rules: Rules,
+    ) -> SocksHandshake<R, W, WeightedRoundRobinDispatcher>
+    where
+        R: AsyncRead + Unpin + Debug,
+        W: AsyncWrite + Unpin + Debug,
+    {
+        let resolver = Resolver::new(ResolverOptions {
+            nameservers: vec![],
+            per_interface: false,
+            cache_max_ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+            timeout: Duration::ZERO,
+            scoped: vec![],
+            hosts: Hosts::default(),
+            prefer: Prefer::Auto,
+        })
+        .unwrap();
+        SocksHandshake::new(
+            reader,
+            writer,
+            Ipv4Addr::LOCALHOST.into(),
+            None,
+            WeightedRoundRobinDispatcher::new(
+                WeightedAddress::resolve(vec![RawWeightedAddress::new(
+                    "127.0.0.1".parse().unwrap(),
+                    std::num::NonZeroUsize::MIN,
+                )])
+                .unwrap(),
+            ),
+            rules,
+            resolver,
+            OutboundOptions::default(),
+            None,
+            Quotas::default(),
+            false,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn counts_one_hit_per_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // Nothing listens on the first address, so that the next one is tried.
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let rules = Rules::new(
+            RuleSet::parse("127.0.0.0/8 direct").unwrap(),
+            Environment::default(),
+        );
+        let mut handshake = handshake(tokio::io::empty(), tokio::io::sink(), rules.clone());
+        handshake.fallbacks = vec![open];
+        let mut destination = Destination {
+            domain: Some("localhost".to_string()),
+            addr: closed,
+        };
+
+        handshake.connect(&mut destination).await.unwrap();
+        assert_eq!(destination.addr, open);
+        assert_eq!(rules.current().describe()[0].hits, 1);
+    }
+    #[tokio::test]
+    async fn blocks_domains_without_resolving_them() {
+        let rules = Rules::new(
+            RuleSet::parse("ads.invalid block").unwrap(),
+            Environment::default(),
+        );
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        request.extend_from_slice(b"ads.invalid");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        let mut handshake = handshake(&request[..], vec![], rules.clone());
+
+        let err = handshake.handshake().await.unwrap_err();
+        assert!(err.downcast_ref::<Denied>().is_some());
+        // The domain would fail to resolve, and be reported as unreachable, if it was looked up.
+        assert_eq!(handshake.writer, [5, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(rules.current().describe()[0].hits, 1);
+    }
+}
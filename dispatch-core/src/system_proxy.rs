@@ -0,0 +1,224 @@
+//! Pointing the SOCKS proxy settings of the system at the proxy, so that the applications that follow them go through
+//! it: the Internet Options of Windows (WinINET), the network services of macOS, and the proxy settings of GNOME on
+//! Linux.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    process::Command,
+};
+
+use eyre::{Result, WrapErr};
+use owo_colors::OwoColorize;
+
+/// The address that applications reach a proxy listening on `addr` at, which is loopback when it listens on all
+/// addresses.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Points the SOCKS proxy of the system at a proxy listening on `listen`, printing what was configured when `announce`
+/// is set, and only logging it otherwise.
+pub fn set(listen: SocketAddr, announce: bool) -> Result<()> {
+    let addr = reachable(listen);
+    let configured = platform::set(addr).wrap_err("Failed to set the system proxy")?;
+    if announce {
+        println!(
+            "Pointed the SOCKS proxy of {} at {}",
+            configured,
+            addr.bold()
+        );
+    }
+    tracing::info!(%configured, %addr, "system proxy set");
+    Ok(())
+}
+
+/// Stops the system from using a SOCKS proxy, likewise.
+pub fn unset(announce: bool) -> Result<()> {
+    let configured = platform::unset().wrap_err("Failed to unset the system proxy")?;
+    if announce {
+        println!("Removed the SOCKS proxy of {}", configured);
+    }
+    tracing::info!(%configured, "system proxy unset");
+    Ok(())
+}
+
+/// Runs a command that configures the system, and returns its output.
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .wrap_err_with(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "`{} {}` failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::net::SocketAddr;
+
+    use eyre::Result;
+
+    use super::run;
+
+    /// The Internet Options of the user, which WinINET and the applications that follow the system settings read.
+    /// WinHTTP can't use SOCKS proxies, so its settings are left alone.
+    const INTERNET_SETTINGS: &str =
+        r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+    pub fn set(addr: SocketAddr) -> Result<String> {
+        let server = format!("socks={}", addr);
+        run(
+            "reg",
+            &[
+                "add",
+                INTERNET_SETTINGS,
+                "/v",
+                "ProxyServer",
+                "/t",
+                "REG_SZ",
+                "/d",
+                &server,
+                "/f",
+            ],
+        )?;
+        run(
+            "reg",
+            &[
+                "add",
+                INTERNET_SETTINGS,
+                "/v",
+                "ProxyEnable",
+                "/t",
+                "REG_DWORD",
+                "/d",
+                "1",
+                "/f",
+            ],
+        )?;
+        Ok("the Internet Options".into())
+    }
+
+    pub fn unset() -> Result<String> {
+        run(
+            "reg",
+            &[
+                "add",
+                INTERNET_SETTINGS,
+                "/v",
+                "ProxyEnable",
+                "/t",
+                "REG_DWORD",
+                "/d",
+                "0",
+                "/f",
+            ],
+        )?;
+        Ok("the Internet Options".into())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::net::SocketAddr;
+
+    use eyre::Result;
+
+    use super::run;
+
+    /// The enabled network services, e.g. `Wi-Fi`, whose proxy settings apply when they are in use.
+    fn services() -> Result<Vec<String>> {
+        let output = run("networksetup", &["-listallnetworkservices"])?;
+        // The first line explains that disabled services are marked with an asterisk.
+        Ok(output
+            .lines()
+            .skip(1)
+            .filter(|service| !service.is_empty() && !service.starts_with('*'))
+            .map(String::from)
+            .collect())
+    }
+
+    fn describe(services: &[String]) -> String {
+        format!("the {} network services", services.join(", "))
+    }
+
+    pub fn set(addr: SocketAddr) -> Result<String> {
+        let services = services()?;
+        for service in &services {
+            run(
+                "networksetup",
+                &[
+                    "-setsocksfirewallproxy",
+                    service,
+                    &addr.ip().to_string(),
+                    &addr.port().to_string(),
+                ],
+            )?;
+            run(
+                "networksetup",
+                &["-setsocksfirewallproxystate", service, "on"],
+            )?;
+        }
+        Ok(describe(&services))
+    }
+
+    pub fn unset() -> Result<String> {
+        let services = services()?;
+        for service in &services {
+            run(
+                "networksetup",
+                &["-setsocksfirewallproxystate", service, "off"],
+            )?;
+        }
+        Ok(describe(&services))
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod platform {
+    use std::net::SocketAddr;
+
+    use color_eyre::Section;
+    use eyre::Result;
+
+    use super::run;
+
+    fn gsettings(args: &[&str]) -> Result<String> {
+        run("gsettings", args).suggestion(
+            "Only the proxy settings of GNOME can be set on Linux. Configure other desktops and applications with a \
+             SOCKS5 proxy at the address of the proxy",
+        )
+    }
+
+    pub fn set(addr: SocketAddr) -> Result<String> {
+        gsettings(&[
+            "set",
+            "org.gnome.system.proxy.socks",
+            "host",
+            &addr.ip().to_string(),
+        ])?;
+        gsettings(&[
+            "set",
+            "org.gnome.system.proxy.socks",
+            "port",
+            &addr.port().to_string(),
+        ])?;
+        gsettings(&["set", "org.gnome.system.proxy", "mode", "manual"])?;
+        Ok("the GNOME proxy settings".into())
+    }
+
+    pub fn unset() -> Result<String> {
+        gsettings(&["set", "org.gnome.system.proxy", "mode", "none"])?;
+        Ok("the GNOME proxy settings".into())
+    }
+}
@@ -0,0 +1,248 @@
+//! Bandwidth limits, enforced by the relay with token buckets: each direction of a connection waits for the bytes it
+//! relays to be available in the buckets it is subject to, which refill at the rate of their limit.
+
+use std::{
+    fmt::{Display, Formatter},
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::Section;
+use eyre::Result;
+
+use crate::net::{self, NamedInterface};
+
+/// A rate in bytes per second, given in bits per second with an optional decimal unit, e.g. 5Mbps or 500K.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate(u64);
+
+impl Rate {
+    pub fn bytes_per_sec(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Rate {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<Rate> {
+        let invalid = || {
+            eyre::eyre!("`{}` isn't a rate", src)
+                .suggestion("Rates are given in bits per second, with K, M or G for thousands, millions or billions, e.g. 5Mbps or 500K")
+        };
+        let (value, unit) = src.split_at(
+            src.find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(src.len()),
+        );
+        let unit = unit.to_ascii_lowercase();
+        let unit = unit
+            .strip_suffix("bps")
+            .or_else(|| unit.strip_suffix("bit"))
+            .unwrap_or(&unit);
+        let multiplier = match unit {
+            "" => 1.0,
+            "k" => 1e3,
+            "m" => 1e6,
+            "g" => 1e9,
+            _ => return Err(invalid()),
+        };
+        let bits = value.parse::<f64>().map_err(|_| invalid())? * multiplier;
+        let bytes = (bits / 8.0) as u64;
+        if bytes == 0 {
+            return Err(eyre::eyre!("The rate `{}` is too low", src)
+                .suggestion("Rates must be at least 8bps"));
+        }
+        Ok(Rate(bytes))
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bits = self.0 as f64 * 8.0;
+        match bits {
+            bits if bits >= 1e9 => write!(f, "{}Gbps", bits / 1e9),
+            bits if bits >= 1e6 => write!(f, "{}Mbps", bits / 1e6),
+            bits if bits >= 1e3 => write!(f, "{}Kbps", bits / 1e3),
+            bits => write!(f, "{}bps", bits),
+        }
+    }
+}
+
+/// How long a bucket can save up for, i.e. how much can be relayed at once after being idle.
+const BURST: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct TokenBucketInner {
+    rate: f64,
+    /// The bytes that can be relayed right away, negative when waiters are due more than is available.
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits the throughput of everything that takes from it to a rate.
+#[derive(Clone, Debug)]
+pub struct TokenBucket(Arc<Mutex<TokenBucketInner>>);
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> TokenBucket {
+        let rate = rate.bytes_per_sec() as f64;
+        TokenBucket(Arc::new(Mutex::new(TokenBucketInner {
+            rate,
+            tokens: rate * BURST.as_secs_f64(),
+            updated: Instant::now(),
+        })))
+    }
+
+    /// Whether the bucket is still taken from elsewhere, i.e. by a connection.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+
+    /// Waits until some bytes can be relayed. Bytes are taken right away, and those that aren't available yet are owed,
+    /// so that concurrent takers wait in turn.
+    pub async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut inner = self.0.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(inner.updated).as_secs_f64() * inner.rate;
+            inner.tokens = (inner.tokens + refill).min(inner.rate * BURST.as_secs_f64());
+            inner.updated = now;
+            inner.tokens -= bytes as f64;
+            match inner.tokens {
+                tokens if tokens < 0.0 => Duration::from_secs_f64(-tokens / inner.rate),
+                _ => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A data quota that a direction of a connection counts the bytes it relays toward.
+#[derive(Clone, Debug)]
+struct Counter {
+    used: Arc<AtomicU64>,
+    /// The bytes that can be used, until the connection is throttled with the over-quota bucket, if any, or else
+    /// ended. There is none once the connection is throttled.
+    allowance: Option<u64>,
+    over_quota: Option<TokenBucket>,
+}
+
+/// The error reported when a direction of a connection can't relay more bytes, since a data quota it counts toward is
+/// used up.
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaUsedUp;
+
+/// The buckets a direction of a connection takes from, and the data quotas it counts toward.
+#[derive(Clone, Debug, Default)]
+pub struct Throttle {
+    buckets: Vec<TokenBucket>,
+    counters: Vec<Counter>,
+}
+
+impl Throttle {
+    pub fn push(&mut self, bucket: TokenBucket) {
+        self.buckets.push(bucket);
+    }
+
+    /// Counts the bytes relayed toward a data quota, which ends the connection once used up, or throttles it from then
+    /// on when given an over-quota bucket.
+    pub fn count(&mut self, used: Arc<AtomicU64>, allowance: u64, over_quota: Option<TokenBucket>) {
+        self.counters.push(Counter {
+            used,
+            allowance: Some(allowance),
+            over_quota,
+        });
+    }
+
+    /// Waits until some bytes can be relayed under every limit, and counts them toward the data quotas. Fails once one
+    /// of the quotas that end connections is used up.
+    pub async fn take(&mut self, bytes: usize) -> Result<(), QuotaUsedUp> {
+        for counter in &mut self.counters {
+            if counter
+                .allowance
+                .is_some_and(|allowance| counter.used.load(Ordering::Relaxed) >= allowance)
+            {
+                let bucket = counter.over_quota.take().ok_or(QuotaUsedUp)?;
+                self.buckets.push(bucket);
+                counter.allowance = None;
+            }
+        }
+        for bucket in &self.buckets {
+            bucket.take(bytes).await;
+        }
+        for counter in &self.counters {
+            counter.used.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// A bandwidth limit for the connections through a network interface, in the form of `<interface>=<rate>`, which
+/// applies to each direction.
+#[derive(Clone, Debug)]
+pub struct InterfaceLimit {
+    /// The network interface name or IP address, as given to `start`.
+    pub interface: String,
+    pub rate: Rate,
+}
+
+impl FromStr for InterfaceLimit {
+    type Err = eyre::Report;
+
+    fn from_str(src: &str) -> Result<InterfaceLimit> {
+        let Some((interface, rate)) = src.split_once('=') else {
+            return Err(eyre::eyre!("`{}` isn't a bandwidth limit", src).suggestion(
+                "Bandwidth limits are given as `<interface>=<rate>`, e.g. `wlan0=5Mbps`",
+            ));
+        };
+        Ok(InterfaceLimit {
+            interface: interface.to_string(),
+            rate: rate.parse()?,
+        })
+    }
+}
+
+/// The buckets of the bandwidth limits, shared by all connections.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// The limit of an interface, and its buckets for each direction.
+    interfaces: Vec<(InterfaceLimit, TokenBucket, TokenBucket)>,
+}
+
+impl Limits {
+    pub fn new(interfaces: Vec<InterfaceLimit>) -> Limits {
+        Limits {
+            interfaces: interfaces
+                .into_iter()
+                .map(|limit| {
+                    let (up, down) = (TokenBucket::new(limit.rate), TokenBucket::new(limit.rate));
+                    (limit, up, down)
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the throttles of each direction of a connection, from a local address which belongs to the given
+    /// network interface when it was given by name.
+    pub fn throttles(
+        &self,
+        interface: Option<&NamedInterface>,
+        local_addr: IpAddr,
+    ) -> (Throttle, Throttle) {
+        let (mut up, mut down) = (Throttle::default(), Throttle::default());
+        for (limit, up_bucket, down_bucket) in &self.interfaces {
+            if net::is_interface(&limit.interface, interface, local_addr) {
+                up.push(up_bucket.clone());
+                down.push(down_bucket.clone());
+            }
+        }
+        (up, down)
+    }
+}
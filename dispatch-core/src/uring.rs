@@ -0,0 +1,189 @@
+//! Relaying connections with io_uring instead of epoll. Each relay read and write is submitted to a ring instead of
+//! being a syscall of its own, which reduces the overhead at tens of thousands of concurrent connections.
+//!
+//! Connections are still accepted and handshaken on the main runtime, then handed to one of several threads, each
+//! running its own io_uring runtime, to be relayed.
+
+use std::{
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use color_eyre::Section;
+use eyre::Result;
+use tokio::sync::{mpsc, oneshot};
+use tokio_uring::{buf::IoBuf, net::TcpStream};
+
+use crate::{
+    connections::Traffic,
+    server::{self, CloseReason, PipeEnd},
+    throttle::Throttle,
+};
+
+/// A connection handed to a relay thread.
+struct Job {
+    client: std::net::TcpStream,
+    destination: std::net::TcpStream,
+    traffic: Arc<Traffic>,
+    throttles: (Throttle, Throttle),
+    buffer_size: usize,
+    /// Dropped by the main runtime when the connection is killed, which makes the relay thread close it.
+    done: oneshot::Sender<Result<CloseReason>>,
+}
+
+impl Job {
+    async fn run(self) {
+        let Job {
+            client,
+            destination,
+            traffic,
+            throttles: (mut up, mut down),
+            buffer_size,
+            mut done,
+        } = self;
+        let client = TcpStream::from_std(client);
+        let destination = TcpStream::from_std(destination);
+
+        let relay = server::relay(
+            pipe(&client, &destination, &traffic.up, &mut up, buffer_size),
+            pipe(&destination, &client, &traffic.down, &mut down, buffer_size),
+        );
+        let res = tokio::select! {
+            res = relay => Some(res),
+            _ = done.closed() => None,
+        };
+        // A read still in flight on either side keeps its socket open after it is dropped, until the read completes.
+        let _ = client.shutdown(Shutdown::Both);
+        let _ = destination.shutdown(Shutdown::Both);
+        if let Some(res) = res {
+            let _ = done.send(res);
+        }
+    }
+}
+
+/// Same as `server::pipe`, with buffers owned by the ring while an operation is in flight.
+async fn pipe(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    transferred: &AtomicU64,
+    throttle: &mut Throttle,
+    buffer_size: usize,
+) -> Result<PipeEnd> {
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let (res, read_buf) = reader.read(buf).await;
+        buf = read_buf;
+        let read = match res {
+            Ok(0) => {
+                return match writer.shutdown(Shutdown::Write) {
+                    Ok(()) => Ok(PipeEnd::Eof),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotConnected => Ok(PipeEnd::Eof),
+                    Err(err) if server::is_reset(&err) => Ok(PipeEnd::WriterReset),
+                    Err(err) => Err(eyre::eyre!(err)),
+                };
+            }
+            Ok(read) => read,
+            Err(err) if server::is_reset(&err) => return Ok(PipeEnd::ReaderReset),
+            Err(err) => return Err(eyre::eyre!(err)),
+        };
+        if throttle.take(read).await.is_err() {
+            return Ok(PipeEnd::QuotaUsedUp);
+        }
+        let (res, written_buf) = writer.write_all(buf.slice(..read)).await;
+        buf = written_buf.into_inner();
+        match res {
+            Ok(()) => {
+                transferred.fetch_add(read as u64, Ordering::Relaxed);
+            }
+            Err(err) if server::is_reset(&err) => return Ok(PipeEnd::WriterReset),
+            Err(err) => return Err(eyre::eyre!(err)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UringRelayInner {
+    threads: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+    buffer_size: usize,
+}
+
+/// Relays connections on io_uring threads, in turn.
+#[derive(Clone, Debug)]
+pub struct UringRelay(Arc<UringRelayInner>);
+
+impl UringRelay {
+    /// Starts a relay thread per CPU. Fails if io_uring isn't available.
+    pub fn start(buffer_size: usize) -> Result<UringRelay> {
+        let count = std::thread::available_parallelism().map_or(1, |count| count.get());
+        let mut threads = Vec::with_capacity(count);
+        for index in 0..count {
+            let (jobs, mut receiver) = mpsc::unbounded_channel::<Job>();
+            let (ready, started) = std::sync::mpsc::channel();
+            std::thread::Builder::new()
+                .name(format!("io-uring-{}", index))
+                .spawn(move || {
+                    let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                        Ok(runtime) => runtime,
+                        Err(err) => {
+                            let _ = ready.send(Err(err));
+                            return;
+                        }
+                    };
+                    let _ = ready.send(Ok(()));
+                    runtime.block_on(async move {
+                        while let Some(job) = receiver.recv().await {
+                            tokio_uring::spawn(job.run());
+                        }
+                    });
+                })?;
+            started.recv()?.map_err(|err| {
+                eyre::eyre!(err)
+                    .wrap_err("Failed to set up io_uring")
+                    .suggestion(
+                        "io_uring requires Linux 5.10 or later, and can be disabled by the kernel configuration or by \
+                        a seccomp policy, e.g. in containers. Start without `--io-uring` to relay with epoll",
+                    )
+            })?;
+            threads.push(jobs);
+        }
+
+        Ok(UringRelay(Arc::new(UringRelayInner {
+            threads,
+            next: AtomicUsize::new(0),
+            buffer_size,
+        })))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.0.threads.len()
+    }
+
+    /// Relays a connection on the next thread until both sides have closed it, or either side reset it.
+    pub async fn relay(
+        &self,
+        client: tokio::net::TcpStream,
+        destination: tokio::net::TcpStream,
+        traffic: Arc<Traffic>,
+        throttles: (Throttle, Throttle),
+    ) -> Result<CloseReason> {
+        let (done, res) = oneshot::channel();
+        let job = Job {
+            client: client.into_std()?,
+            destination: destination.into_std()?,
+            traffic,
+            throttles,
+            buffer_size: self.0.buffer_size,
+            done,
+        };
+        let index = self.0.next.fetch_add(1, Ordering::Relaxed) % self.0.threads.len();
+        if self.0.threads[index].send(job).is_err() {
+            return Err(eyre::eyre!("The io_uring relay thread {} stopped", index));
+        }
+        res.await
+            .map_err(|_| eyre::eyre!("The io_uring relay thread {} stopped", index))?
+    }
+}
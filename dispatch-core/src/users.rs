@@ -0,0 +1,330 @@
+//! Users of the proxy, who authenticate over SOCKS5 with a username and password, each with a policy of their own.
+//!
+//! Users are written one per line, as `<username> <password hash> [<option>=<value>...]`, with the hash printed by
+//! `dispatch hash-password`:
+//!
+//! ```text
+//! alice  $argon2id$v=19$m=19456,t=2,p=1$...  interface=eth0
+//! guest  $argon2id$v=19$m=19456,t=2,p=1$...  allow=example.com,example.org  port=80,443  rate=2Mbps  quota=5GiB/month
+//! bob    $argon2id$v=19$m=19456,t=2,p=1$...  deny=country:KP,country:IR
+//! ```
+//!
+//! `allow` restricts the destinations of the user to those matching one of the patterns, in the same form as routing
+//! rules, and `port` to the given destination ports, in the same form as the `port` option of routing rules. `deny`
+//! keeps the user away from the destinations matching one of the patterns, e.g. countries.
+//! `interface` connects from the given network interface name or IP address instead of dispatching, unless a
+//! routing rule routes the destination elsewhere. `rate` limits the bandwidth of all the connections of the user
+//! together, in each direction. `quota` caps the data the user can relay per day or month, in the same form as the
+//! quotas of `--quota`. Routing rules still apply to users, before their own policy.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt::{Debug, Display, Formatter},
+    hash::BuildHasher,
+    io::{BufRead, IsTerminal, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+
+use crate::{
+    dispatcher::WeightedAddress,
+    quota::Allowance,
+    rules::{Environment, Pattern, Ports, Route},
+    socks::Destination,
+    throttle::{Rate, Throttle, TokenBucket},
+};
+
+pub struct User {
+    pub name: String,
+    /// The Argon2 hash of the password, as a PHC string.
+    hash: String,
+    /// The destinations the user can connect to, or all of them.
+    allowed: Option<Vec<Pattern>>,
+    /// The destinations the user can't connect to.
+    denied: Vec<Pattern>,
+    /// The destination ports the user can connect to, or all of them.
+    ports: Option<Ports>,
+    route: Option<Route>,
+    rate: Option<Rate>,
+    quota: Option<Allowance>,
+    /// The buckets of each direction, shared by all the connections of the user.
+    buckets: Option<(TokenBucket, TokenBucket)>,
+}
+
+impl User {
+    pub fn allows(&self, destination: &Destination, environment: &Environment) -> bool {
+        self.allowed.as_ref().is_none_or(|allowed| {
+            allowed
+                .iter()
+                .any(|pattern| pattern.matches(destination, environment))
+        }) && !self
+            .denied
+            .iter()
+            .any(|pattern| pattern.matches(destination, environment))
+            && self
+                .ports
+                .as_ref()
+                .is_none_or(|ports| ports.contains(destination.addr.port()))
+    }
+
+    /// Whether the policy of the user denies a domain before it is resolved, given with the unspecified address of an
+    /// IP version, by its domain or port alone. A domain that the allowed destinations only allow by their addresses
+    /// is decided once resolved.
+    pub fn denies_domain(&self, destination: &Destination, environment: &Environment) -> bool {
+        let matches = |pattern: &Pattern| {
+            !pattern.matches_addresses() && pattern.matches(destination, environment)
+        };
+        self.allowed.as_ref().is_some_and(|allowed| {
+            !allowed.iter().any(Pattern::matches_addresses) && !allowed.iter().any(matches)
+        }) || self.denied.iter().any(matches)
+            || self
+                .ports
+                .as_ref()
+                .is_some_and(|ports| !ports.contains(destination.addr.port()))
+    }
+
+    /// Whether the policy of the user matches countries.
+    pub fn needs_geoip(&self) -> bool {
+        self.allowed
+            .iter()
+            .flatten()
+            .chain(&self.denied)
+            .any(Pattern::needs_geoip)
+    }
+
+    /// The network interface the user connects from, unless a rule routes the destination elsewhere.
+    pub fn route(&self) -> Option<&Route> {
+        self.route.as_ref()
+    }
+
+    /// The data usage quota of the user.
+    pub fn quota(&self) -> Option<&Allowance> {
+        self.quota.as_ref()
+    }
+
+    /// Adds the bandwidth limit of the user to the throttles of each direction of a connection.
+    pub fn throttle(&self, up: &mut Throttle, down: &mut Throttle) {
+        if let Some((up_bucket, down_bucket)) = &self.buckets {
+            up.push(up_bucket.clone());
+            down.push(down_bucket.clone());
+        }
+    }
+}
+
+/// The user and their options, without the password hash.
+impl Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(&self.name)?;
+        if let Some(allowed) = &self.allowed {
+            let allowed = allowed.iter().map(ToString::to_string).collect::<Vec<_>>();
+            write!(f, " allow={}", allowed.join(","))?;
+        }
+        if !self.denied.is_empty() {
+            let denied = self
+                .denied
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            write!(f, " deny={}", denied.join(","))?;
+        }
+        if let Some(ports) = &self.ports {
+            write!(f, " port={}", ports)?;
+        }
+        if let Some(route) = &self.route {
+            write!(f, " interface={}", route)?;
+        }
+        if let Some(rate) = self.rate {
+            write!(f, " rate={}", rate)?;
+        }
+        if let Some(quota) = &self.quota {
+            write!(f, " quota={}", quota)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("User").field(&self.to_string()).finish()
+    }
+}
+
+#[derive(Debug)]
+struct UsersInner {
+    users: HashMap<String, Arc<User>>,
+    /// A keyed hash of the password last verified for each user, so that only the first connection of a user pays for
+    /// the deliberately slow password hash.
+    verified: Mutex<HashMap<String, u64>>,
+    key: RandomState,
+}
+
+/// The users who can connect to the proxy.
+#[derive(Clone, Debug)]
+pub struct Users(Arc<UsersInner>);
+
+impl Users {
+    /// Parses users, and resolves the network interfaces they connect from. Fails if any user is invalid.
+    pub fn parse(src: &str) -> Result<Users> {
+        let mut resolved = HashMap::new();
+        let mut users = HashMap::new();
+
+        for (index, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let user = parse_user(line, &mut resolved)
+                .wrap_err_with(|| format!("Invalid user on line {}", index + 1))?;
+            if users.contains_key(&user.name) {
+                return Err(eyre::eyre!(
+                    "The user `{}` on line {} is already defined",
+                    user.name,
+                    index + 1
+                ));
+            }
+            users.insert(user.name.clone(), Arc::new(user));
+        }
+
+        if users.is_empty() {
+            return Err(eyre::eyre!("There are no users, so nobody could connect")
+                .suggestion("Add users as `<username> <password hash>`, with the hash printed by `dispatch hash-password`"));
+        }
+
+        Ok(Users(Arc::new(UsersInner {
+            users,
+            verified: Mutex::default(),
+            key: RandomState::new(),
+        })))
+    }
+
+    pub fn read(path: &Path) -> Result<Users> {
+        let src = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read the users file `{}`", path.display()))?;
+        Users::parse(&src)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.users.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.0.users.values().map(AsRef::as_ref)
+    }
+
+    /// Returns the user if the password is theirs.
+    pub async fn authenticate(&self, name: &str, password: &[u8]) -> Option<Arc<User>> {
+        let fingerprint = self.0.key.hash_one((name, password));
+        let user = self.0.users.get(name);
+        if user.is_some() && self.0.verified.lock().unwrap().get(name) == Some(&fingerprint) {
+            return user.cloned();
+        }
+
+        // Unknown users are checked against the hash of another user, so that they take as long to turn away as wrong
+        // passwords and usernames can't be probed.
+        let hash = user.or_else(|| self.0.users.values().next())?.hash.clone();
+        let password = password.to_vec();
+        let valid = tokio::task::spawn_blocking(move || verify(&hash, &password))
+            .await
+            .unwrap_or(false);
+        if !valid {
+            return None;
+        }
+        let user = user?;
+        self.0
+            .verified
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), fingerprint);
+        Some(Arc::clone(user))
+    }
+}
+
+fn parse_user(line: &str, resolved: &mut HashMap<String, WeightedAddress>) -> Result<User> {
+    let mut fields = line.split_whitespace();
+    let (Some(name), Some(hash)) = (fields.next(), fields.next()) else {
+        return Err(eyre::eyre!(
+            "Expected a username followed by a password hash"
+        ));
+    };
+    if name.len() > 255 {
+        return Err(eyre::eyre!(
+            "The username `{}` is longer than 255 bytes",
+            name
+        ));
+    }
+    PasswordHash::new(hash)
+        .map_err(|err| eyre::eyre!("The password hash of `{}` is invalid: {}", name, err))
+        .suggestion("Hash passwords with `dispatch hash-password`")?;
+
+    let (mut allowed, mut denied, mut ports, mut route, mut rate, mut quota) =
+        (None, vec![], None, None, None, None);
+    for option in fields {
+        match option.split_once('=') {
+            Some(("allow", value)) => {
+                allowed = Some(value.split(',').map(str::parse).collect::<Result<_>>()?)
+            }
+            Some(("deny", value)) => {
+                denied = value.split(',').map(str::parse).collect::<Result<_>>()?
+            }
+            Some(("port", value)) => ports = Some(value.parse()?),
+            Some(("interface", value)) => route = Some(Route::resolve(value, resolved)?),
+            Some(("rate", value)) => rate = Some(value.parse()?),
+            Some(("quota", value)) => quota = Some(value.parse()?),
+            _ => return Err(eyre::eyre!("Unknown user option `{}`", option).suggestion(
+                "User options are `allow=<pattern>[,<pattern>...]`, `deny=<pattern>[,<pattern>...]`, `port=<ports>`, \
+                    `interface=<interface>`, `rate=<rate>` and `quota=<size>/<period>`, e.g. `rate=2Mbps` or `quota=20GiB/month`",
+            )),
+        }
+    }
+
+    Ok(User {
+        name: name.to_string(),
+        hash: hash.to_string(),
+        allowed,
+        denied,
+        ports,
+        route,
+        rate,
+        quota,
+        buckets: rate.map(|rate| (TokenBucket::new(rate), TokenBucket::new(rate))),
+    })
+}
+
+fn verify(hash: &str, password: &[u8]) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password, &hash).is_ok())
+}
+
+/// Reads a password from stdin, and prints its hash for the users file.
+pub fn hash_password() -> Result<()> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut password = String::new();
+    stdin.lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(eyre::eyre!("The password is empty"));
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| eyre::eyre!("Failed to hash the password: {}", err))?;
+    println!("{}", hash);
+    Ok(())
+}
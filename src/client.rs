@@ -0,0 +1,510 @@
+use std::{
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::Path,
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use owo_colors::OwoColorize;
+use term_table::{
+    row::Row,
+    table_cell::{Alignment, TableCell},
+    Table, TableStyle,
+};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use dispatch_core::{
+    connections::ConnectionId,
+    control::{self, Request, Response, Route},
+    report::format_bytes,
+    rules::RuleInfo,
+};
+
+/// Sends a request to the running proxy, and waits for its response.
+fn request(path: &Path, request: Request) -> Result<Response> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(send(path, request))
+}
+
+/// Sends a request to the running proxy from within a runtime, e.g. for a client that keeps polling it.
+pub async fn send(path: &Path, request: Request) -> Result<Response> {
+    let mut stream = BufReader::new(control::connect(path).await?);
+
+    let mut request = serde_json::to_vec(&request)?;
+    request.push(b'\n');
+    stream.get_mut().write_all(&request).await?;
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    match serde_json::from_str(&line)? {
+        Response::Error { message } => Err(eyre::eyre!(message)),
+        response => Ok(response),
+    }
+}
+
+pub fn unexpected_response(response: Response) -> eyre::Report {
+    eyre::eyre!("Unexpected response from the proxy: {:?}", response)
+}
+
+fn table<const N: usize>(headers: [&str; N]) -> Table<'static> {
+    let mut table = Table::new();
+    table.style = TableStyle::extended();
+    table.add_row(Row::new(
+        headers
+            .into_iter()
+            .map(|header| TableCell::new(header.bold())),
+    ));
+    table
+}
+
+pub fn status(path: &Path) -> Result<()> {
+    let status = match request(path, Request::Status)? {
+        Response::Status(status) => status,
+        response => return Err(unexpected_response(response)),
+    };
+
+    println!(
+        "dispatch-proxy {} (pid {}), up for {}",
+        status.version,
+        status.pid,
+        humantime::format_duration(Duration::from_secs(status.uptime_secs))
+    );
+    println!(
+        "Listening on {}, {}",
+        status.listen.bold(),
+        if status.accepting {
+            "accepting connections".green().to_string()
+        } else {
+            "not accepting connections".red().to_string()
+        }
+    );
+    println!("{} active connections", status.connections.bold());
+    if let Some(log_filter) = status.log_filter {
+        println!("Log filter: {}", log_filter.bold());
+    }
+    if let Some(log_file) = status.log_file {
+        println!("Logging to {}", log_file.display().bold());
+    }
+    if let Some(external) = status.external {
+        println!("Reachable from outside the LAN on {}", external.bold());
+    }
+
+    let mut table = table(["Address", "Weight", "State", "Health"]);
+    for address in status.addresses {
+        table.add_row(Row::new(vec![
+            TableCell::new(address.address),
+            TableCell::new_with_alignment(address.weight, 1, Alignment::Right),
+            TableCell::new(if address.paused {
+                "paused".yellow().to_string()
+            } else if address.over_quota {
+                "over quota".yellow().to_string()
+            } else {
+                "active".to_string()
+            }),
+            TableCell::new(if address.healthy {
+                "usable".green().to_string()
+            } else {
+                "unusable".red().to_string()
+            }),
+        ]));
+    }
+    println!("{}", table.render());
+
+    Ok(())
+}
+
+/// The address the running proxy accepts connections on.
+pub fn listen_addr(path: &Path) -> Result<SocketAddr> {
+    match request(path, Request::Status)? {
+        Response::Status(status) => Ok(status.listen),
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn stats(path: &Path) -> Result<()> {
+    let (addresses, rules) = match request(path, Request::Stats)? {
+        Response::Stats { addresses, rules } => (addresses, rules),
+        response => return Err(unexpected_response(response)),
+    };
+
+    let mut table = table(["Address", "Active", "Total", "Up", "Down"]);
+    for address in addresses {
+        table.add_row(Row::new(vec![
+            TableCell::new(address.address),
+            TableCell::new_with_alignment(address.stats.active, 1, Alignment::Right),
+            TableCell::new_with_alignment(address.stats.total, 1, Alignment::Right),
+            TableCell::new_with_alignment(
+                format_bytes(address.stats.bytes_up),
+                1,
+                Alignment::Right,
+            ),
+            TableCell::new_with_alignment(
+                format_bytes(address.stats.bytes_down),
+                1,
+                Alignment::Right,
+            ),
+        ]));
+    }
+    println!("{}", table.render());
+    if !rules.is_empty() {
+        print_rules(rules);
+    }
+
+    Ok(())
+}
+
+pub fn reload(path: &Path) -> Result<()> {
+    match request(path, Request::Reload)? {
+        Response::Reloaded { addresses, changes } => {
+            println!(
+                "Dispatching to {}",
+                addresses
+                    .iter()
+                    .map(|address| address.bold().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            if changes.is_empty() {
+                println!("No changes");
+            }
+            for address in changes.added {
+                println!("{} {}", "+".green(), address.green());
+            }
+            for address in changes.removed {
+                println!("{} {}", "-".red(), address.red());
+            }
+            for change in changes.reweighted {
+                println!(
+                    "{} {} weight {} -> {}",
+                    "~".yellow(),
+                    change.address.yellow(),
+                    change.from,
+                    change.to
+                );
+            }
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn set_weight(path: &Path, address: &str, weight: NonZeroUsize) -> Result<()> {
+    let address = address.to_string();
+    match request(path, Request::SetWeight { address, weight })? {
+        Response::WeightSet { addresses, weight } => {
+            for address in addresses {
+                println!("Dispatching to {}/{}", address.bold(), weight);
+            }
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn pause(path: &Path, address: &str) -> Result<()> {
+    let address = address.to_string();
+    match request(path, Request::Pause { address })? {
+        Response::Paused { addresses } => {
+            for address in addresses {
+                println!("Paused {}", address.bold());
+            }
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn resume(path: &Path, address: &str) -> Result<()> {
+    let address = address.to_string();
+    match request(path, Request::Resume { address })? {
+        Response::Resumed { addresses } => {
+            for address in addresses {
+                println!("Resumed {}", address.bold());
+            }
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn connections(path: &Path) -> Result<()> {
+    let connections = match request(path, Request::Connections)? {
+        Response::Connections { connections } => connections,
+        response => return Err(unexpected_response(response)),
+    };
+
+    let mut table = table([
+        "ID",
+        "Client",
+        "Destination",
+        "Interface",
+        "Up",
+        "Down",
+        "Duration",
+    ]);
+    for connection in connections {
+        table.add_row(Row::new(vec![
+            TableCell::new_with_alignment(connection.id, 1, Alignment::Right),
+            TableCell::new(connection.client),
+            TableCell::new(connection.destination),
+            TableCell::new(connection.interface),
+            TableCell::new_with_alignment(format_bytes(connection.bytes_up), 1, Alignment::Right),
+            TableCell::new_with_alignment(format_bytes(connection.bytes_down), 1, Alignment::Right),
+            TableCell::new_with_alignment(
+                humantime::format_duration(Duration::from_secs(connection.duration_ms / 1000)),
+                1,
+                Alignment::Right,
+            ),
+        ]));
+    }
+    println!("{}", table.render());
+
+    Ok(())
+}
+
+pub fn clients(path: &Path) -> Result<()> {
+    let clients = match request(path, Request::Clients)? {
+        Response::Clients { clients } => clients,
+        response => return Err(unexpected_response(response)),
+    };
+
+    let mut table = table(["Client", "Active", "Total", "Up", "Down"]);
+    for client in clients {
+        table.add_row(Row::new(vec![
+            TableCell::new(client.client),
+            TableCell::new_with_alignment(client.stats.active, 1, Alignment::Right),
+            TableCell::new_with_alignment(client.stats.total, 1, Alignment::Right),
+            TableCell::new_with_alignment(format_bytes(client.stats.bytes_up), 1, Alignment::Right),
+            TableCell::new_with_alignment(
+                format_bytes(client.stats.bytes_down),
+                1,
+                Alignment::Right,
+            ),
+        ]));
+    }
+    println!("{}", table.render());
+
+    Ok(())
+}
+
+fn print_rules(rules: Vec<RuleInfo>) {
+    if rules.is_empty() {
+        println!("No routing rules");
+        return;
+    }
+
+    let mut table = table(["Pattern", "Action", "Hits"]);
+    for rule in rules {
+        table.add_row(Row::new(vec![
+            TableCell::new(rule.pattern),
+            TableCell::new(rule.action),
+            TableCell::new_with_alignment(rule.hits, 1, Alignment::Right),
+        ]));
+    }
+    println!("{}", table.render());
+}
+
+pub fn rules(path: &Path) -> Result<()> {
+    match request(path, Request::Rules)? {
+        Response::Rules { rules } => {
+            print_rules(rules);
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn test_route(
+    path: &Path,
+    destination: &str,
+    client: IpAddr,
+    user: Option<String>,
+    app: Option<String>,
+) -> Result<()> {
+    let (host, port) = parse_host_port(destination)?;
+    let request = Request::TestRoute {
+        host,
+        port,
+        client: Some(client),
+        user,
+        app,
+    };
+    let test = match self::request(path, request)? {
+        Response::Route(test) => test,
+        response => return Err(unexpected_response(response)),
+    };
+
+    if test.destination == test.address.to_string() {
+        println!("Destination: {}", test.destination.bold());
+    } else {
+        println!(
+            "Destination: {} ({})",
+            test.destination.bold(),
+            test.address
+        );
+    }
+    match &test.rule {
+        Some(rule) => println!("Rule: {}", rule.bold()),
+        None => println!("Rule: none, dispatched as usual"),
+    }
+    let from = |address: IpAddr, interface: Option<String>| match interface {
+        Some(interface) => format!("{} ({})", address, interface),
+        None => address.to_string(),
+    };
+    match test.route {
+        Route::Denied => println!("Route: {}", "denied".red()),
+        Route::Dispatched { address, interface } => println!(
+            "Route: dispatched from {}, next in rotation",
+            from(address, interface).bold()
+        ),
+        Route::Direct => println!("Route: {}, as the system routes it", "direct".bold()),
+        Route::Routed { address, interface } => {
+            println!("Route: from {}", from(address, interface).bold())
+        }
+    }
+    Ok(())
+}
+
+/// Splits a destination into a host and a port, e.g. `example.com`, `example.com:80`, `192.0.2.1:80` or
+/// `[2001:db8::1]:80`, with port 443 by default.
+fn parse_host_port(src: &str) -> Result<(String, u16)> {
+    const DEFAULT_PORT: u16 = 443;
+
+    if let Ok(ip) = src.parse::<IpAddr>() {
+        return Ok((ip.to_string(), DEFAULT_PORT));
+    }
+    if let Ok(addr) = src.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), addr.port()));
+    }
+    let (host, port) = match src.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .wrap_err_with(|| format!("Invalid port in `{}`", src))?;
+            (host, port)
+        }
+        None => (src, DEFAULT_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(eyre::eyre!("Expected a host, e.g. `example.com:443`"));
+    }
+    Ok((host.to_string(), port))
+}
+
+pub fn set_rules(path: &Path, file: &Path) -> Result<()> {
+    let mut rules = String::new();
+    if file == Path::new("-") {
+        std::io::stdin()
+            .read_to_string(&mut rules)
+            .wrap_err("Failed to read the rules from stdin")?;
+    } else {
+        rules = std::fs::read_to_string(file)
+            .wrap_err_with(|| format!("Failed to read the rules file `{}`", file.display()))?;
+    }
+
+    match request(path, Request::SetRules { rules })? {
+        Response::Rules { rules } => {
+            println!("Loaded {} routing rules", rules.len().bold());
+            print_rules(rules);
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn log_filter(path: &Path, filter: Option<String>, duration: Option<Duration>) -> Result<()> {
+    let request = Request::SetLogFilter {
+        filter,
+        duration_secs: duration.map(|duration| duration.as_secs()),
+    };
+    match self::request(path, request)? {
+        Response::LogFilterSet {
+            filter,
+            reset_in_secs,
+        } => {
+            print!("Log filter set to {}", filter.bold());
+            match reset_in_secs {
+                Some(secs) => println!(
+                    " for {}",
+                    humantime::format_duration(Duration::from_secs(secs))
+                ),
+                None => println!(),
+            }
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+pub fn kill_conn(path: &Path, id: ConnectionId) -> Result<()> {
+    match request(path, Request::Kill { id })? {
+        Response::Killed { id } => {
+            println!("Closed connection {}", id.bold());
+            Ok(())
+        }
+        response => Err(unexpected_response(response)),
+    }
+}
+
+/// Checks that the running proxy accepts connections and has a usable address to dispatch them to, and prints why
+/// not otherwise. The output is terse, since health checkers keep it along with the result.
+pub fn healthcheck(path: &Path) -> bool {
+    let status = match request(path, Request::Status) {
+        Ok(Response::Status(status)) => status,
+        Ok(response) => {
+            println!("{}", unexpected_response(response));
+            return false;
+        }
+        Err(err) => {
+            println!("{:#}", err);
+            return false;
+        }
+    };
+
+    if !status.accepting {
+        println!("The proxy isn't accepting connections");
+        return false;
+    }
+    let usable = status
+        .addresses
+        .iter()
+        .any(|address| !address.paused && !address.over_quota && address.healthy);
+    if !usable {
+        println!("None of the dispatch addresses are usable");
+        return false;
+    }
+    println!("ok");
+    true
+}
+
+pub fn stop(path: &Path) -> Result<()> {
+    let (connections, drain_timeout_secs) = match request(path, Request::Stop)? {
+        Response::Stopping {
+            connections,
+            drain_timeout_secs,
+        } => (connections, drain_timeout_secs),
+        response => return Err(unexpected_response(response)),
+    };
+
+    if connections > 0 {
+        println!(
+            "Waiting for {} active connections to close, for at most {}",
+            connections.bold(),
+            humantime::format_duration(Duration::from_secs(drain_timeout_secs))
+        );
+    }
+
+    // The control socket goes away once the proxy has exited.
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        while control::connect(path).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+    println!("The proxy has stopped");
+
+    Ok(())
+}
@@ -0,0 +1,475 @@
+//! `dispatch doctor`: checks the usual causes of a proxy that doesn't work, from the machine it runs on, and prints how
+//! to fix the ones it finds.
+
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::Result;
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use owo_colors::OwoColorize;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use dispatch_core::{
+    dispatcher::{RawWeightedAddress, WeightedAddress},
+    dns::{Hosts, Nameserver, Prefer, Resolver, ResolverOptions},
+    net::{bind_socket, is_local_address},
+};
+
+use crate::client;
+
+/// How long each probe waits for an answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Where connections are attempted from each address, to tell whether it reaches the internet.
+const PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 443);
+const PROBE_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
+    443,
+);
+/// The domain resolved over each interface, which has both IPv4 and IPv6 addresses.
+const PROBE_DOMAIN: &str = "example.com";
+/// The SNTP server the clock is compared with.
+const NTP_SERVER: &str = "pool.ntp.org:123";
+/// How far the clock can be off before certificates, e.g. those of DNS-over-TLS nameservers, can fail to validate.
+const MAX_CLOCK_OFFSET: Duration = Duration::from_secs(60);
+/// The seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+/// Checks that the proxy could be started with the given options and work, and prints what doesn't with how to fix
+/// it. Returns whether every check passed, warnings aside.
+pub fn doctor(
+    ip: IpAddr,
+    port: u16,
+    addresses: Vec<RawWeightedAddress>,
+    nameservers: Vec<Nameserver>,
+    control: &Path,
+) -> Result<bool> {
+    let rt = tokio::runtime::Runtime::new()?;
+    let mut report = Report::default();
+
+    report.section("Network interfaces");
+    let interfaces = interfaces(addresses, &mut report)?;
+    rt.block_on(check_interfaces(&interfaces, &mut report));
+
+    report.section("DNS");
+    rt.block_on(check_dns(&interfaces, nameservers, &mut report))?;
+
+    report.section("Listen address");
+    let listen = SocketAddr::new(ip, port);
+    // Asked before entering the runtime, since the control client runs one of its own.
+    let running = client::listen_addr(control).ok();
+    // Connections are queued by the kernel without being accepted, which is enough to tell they went through.
+    if let Some(_listening) = check_port(listen, running, &mut report) {
+        rt.block_on(check_reachability(listen, &mut report));
+    }
+
+    report.section("Clock");
+    rt.block_on(check_clock(&mut report));
+
+    println!();
+    if report.failures == 0 {
+        println!(
+            "{} ({} warnings)",
+            "No problems found".green().bold(),
+            report.warnings
+        );
+    } else {
+        println!(
+            "{} and {} warnings",
+            format!("{} problems found", report.failures).red().bold(),
+            report.warnings
+        );
+    }
+    Ok(report.failures == 0)
+}
+
+/// The outcome of the checks, printed as they run.
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn section(&self, title: &str) {
+        println!("\n{}", title.bold());
+    }
+
+    fn pass(&mut self, what: impl Display) {
+        println!("  {} {}", "✓".green(), what);
+    }
+
+    fn warn(&mut self, what: impl Display, fix: impl Display) {
+        self.warnings += 1;
+        println!("  {} {}", "!".yellow(), what);
+        println!("    {} {}", "→".dimmed(), fix);
+    }
+
+    fn fail(&mut self, what: impl Display, fix: impl Display) {
+        self.failures += 1;
+        println!("  {} {}", "✗".red(), what);
+        println!("    {} {}", "→".dimmed(), fix);
+    }
+}
+
+/// A network interface to check, by name or by IP address.
+struct Interface {
+    name: String,
+    ips: Vec<IpAddr>,
+}
+
+/// The interfaces of the given dispatch addresses, or all the interfaces with an address that could be dispatched to
+/// when none are given.
+fn interfaces(addresses: Vec<RawWeightedAddress>, report: &mut Report) -> Result<Vec<Interface>> {
+    if addresses.is_empty() {
+        let mut interfaces: Vec<Interface> = Vec::new();
+        for interface in NetworkInterface::show()? {
+            let ips = interface
+                .addr
+                .iter()
+                .map(|addr| addr.ip())
+                .filter(|ip| !is_local_address(ip));
+            // Some platforms list an interface once per address.
+            match interfaces.iter_mut().find(|i| i.name == interface.name) {
+                Some(existing) => existing.ips.extend(ips),
+                None => interfaces.push(Interface {
+                    name: interface.name,
+                    ips: ips.collect(),
+                }),
+            }
+        }
+        interfaces.retain(|interface| !interface.ips.is_empty());
+        if interfaces.is_empty() {
+            report.fail(
+                "No network interface has an address to dispatch to",
+                "Connect to a network, then check the interfaces with `dispatch list`",
+            );
+        }
+        return Ok(interfaces);
+    }
+
+    let mut interfaces = Vec::new();
+    for address in addresses {
+        let given = address.interface().to_string();
+        match WeightedAddress::resolve(vec![address]) {
+            Ok(resolved) => {
+                for address in resolved {
+                    interfaces.push(Interface {
+                        name: address
+                            .named_interface()
+                            .map(|interface| interface.name)
+                            .unwrap_or_else(|| given.clone()),
+                        ips: address.ips(),
+                    });
+                }
+            }
+            Err(err) => report.fail(
+                format!("{}: {:#}", given, err),
+                "Check the names and addresses of the interfaces with `dispatch list`, and that they are connected",
+            ),
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Checks that each address can be bound, and that connections from it reach the internet.
+async fn check_interfaces(interfaces: &[Interface], report: &mut Report) {
+    for interface in interfaces {
+        for &ip in &interface.ips {
+            let what = format!("{} ({})", interface.name, ip);
+            let socket = match bind_socket(ip) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    report.fail(
+                        format!("{} can't be used: {}", what, err),
+                        "Check that the interface is up and still has this address with `dispatch list`",
+                    );
+                    continue;
+                }
+            };
+
+            let probe = if ip.is_ipv4() { PROBE_V4 } else { PROBE_V6 };
+            match connect(socket, probe).await {
+                Ok(()) => report.pass(format!("{} reaches the internet", what)),
+                Err(err) => {
+                    let fix = if bound_to_device_reaches(&interface.name, ip, probe).await {
+                        "Connections leave through another interface: pass `--bind-interface` to `start`, or add a \
+                         routing rule for this address, e.g. `ip rule add from <address> table <table>`"
+                            .to_string()
+                    } else {
+                        format!(
+                            "Check that the interface is connected to a network with internet access{}",
+                            if ip.is_ipv6() {
+                                ", or only dispatch to its IPv4 address if the network has no IPv6 connectivity"
+                            } else {
+                                ""
+                            }
+                        )
+                    };
+                    report.fail(format!("{} doesn't reach {}: {}", what, probe, err), fix);
+                }
+            }
+        }
+    }
+}
+
+/// Whether connections from the address reach the probe when they're bound to its interface, as with
+/// `--bind-interface`.
+#[cfg(target_os = "linux")]
+async fn bound_to_device_reaches(name: &str, ip: IpAddr, probe: SocketAddr) -> bool {
+    let Ok(socket) = bind_socket(ip) else {
+        return false;
+    };
+    if socket.bind_device(Some(name.as_bytes())).is_err() {
+        return false;
+    }
+    connect(socket, probe).await.is_ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn bound_to_device_reaches(_name: &str, _ip: IpAddr, _probe: SocketAddr) -> bool {
+    false
+}
+
+async fn connect(socket: tokio::net::TcpSocket, addr: SocketAddr) -> std::io::Result<()> {
+    match timeout(PROBE_TIMEOUT, socket.connect(addr)).await {
+        Ok(res) => res.map(drop),
+        Err(_) => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+/// Checks that a domain resolves over each interface, with its own nameservers.
+async fn check_dns(
+    interfaces: &[Interface],
+    nameservers: Vec<Nameserver>,
+    report: &mut Report,
+) -> Result<()> {
+    let resolver = Resolver::new(ResolverOptions {
+        nameservers,
+        per_interface: true,
+        cache_max_ttl: Duration::ZERO,
+        negative_ttl: Duration::ZERO,
+        timeout: PROBE_TIMEOUT,
+        scoped: Vec::new(),
+        hosts: Hosts::default(),
+        prefer: Prefer::Auto,
+    })?;
+    for interface in interfaces {
+        for &ip in &interface.ips {
+            let what = format!("{} ({})", interface.name, ip);
+            match resolver.lookup_from(ip, PROBE_DOMAIN, 443).await {
+                Ok(addrs) => report.pass(format!(
+                    "{} resolves {} to {}",
+                    what,
+                    PROBE_DOMAIN,
+                    addrs[0].ip()
+                )),
+                Err(err) => report.fail(
+                    format!("{} can't resolve {}: {:#}", what, PROBE_DOMAIN, err),
+                    format!(
+                        "Pass a nameserver reachable through the interface to `start`, e.g. `--dns {}@{}`",
+                        if ip.is_ipv4() {
+                            "1.1.1.1"
+                        } else {
+                            "2606:4700:4700::1111"
+                        },
+                        interface.name
+                    ),
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What listens on the listen address while its reachability is checked.
+enum Listening {
+    Doctor(#[allow(dead_code)] std::net::TcpListener),
+    Proxy,
+}
+
+/// Checks that the proxy could listen on the address, and returns what listens on it if anything.
+fn check_port(
+    listen: SocketAddr,
+    running: Option<SocketAddr>,
+    report: &mut Report,
+) -> Option<Listening> {
+    match std::net::TcpListener::bind(listen) {
+        Ok(listener) => {
+            report.pass(format!("{} is free", listen));
+            Some(Listening::Doctor(listener))
+        }
+        Err(err) if err.kind() == ErrorKind::AddrInUse && running == Some(listen) => {
+            report.pass(format!("{} is served by the running proxy", listen));
+            Some(Listening::Proxy)
+        }
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            report.fail(
+                format!("{} is already in use", listen),
+                format!(
+                    "Stop the program listening on port {} ({}), or pass another `--port` to `start`",
+                    listen.port(),
+                    if cfg!(windows) {
+                        format!("see `netstat -ano | findstr :{}`", listen.port())
+                    } else {
+                        format!("see `lsof -nP -iTCP:{} -sTCP:LISTEN`", listen.port())
+                    }
+                ),
+            );
+            None
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            report.fail(
+                format!("{} can't be listened on: {}", listen, err),
+                "Pass a `--port` above 1023 to `start`, or run it with the privilege to bind low ports",
+            );
+            None
+        }
+        Err(err) if err.kind() == ErrorKind::AddrNotAvailable => {
+            report.fail(
+                format!("{} can't be listened on: {}", listen, err),
+                "Pass an `--ip` of this machine to `start`, e.g. 0.0.0.0 to listen on all of them",
+            );
+            None
+        }
+        Err(err) => {
+            report.fail(
+                format!("{} can't be listened on: {}", listen, err),
+                "Pass another `--ip` or `--port` to `start`",
+            );
+            None
+        }
+    }
+}
+
+/// Checks that connections to the listen address are let through, from each address of the machine it accepts them
+/// on. A firewall can still drop connections from other machines, which this can't tell.
+async fn check_reachability(listen: SocketAddr, report: &mut Report) {
+    if listen.ip().is_loopback() {
+        report.warn(
+            format!("{} only accepts connections from this machine", listen),
+            "Pass `--ip 0.0.0.0` to `start` to accept connections from other devices",
+        );
+    }
+
+    let targets = if listen.ip().is_unspecified() {
+        NetworkInterface::show()
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|interface| &interface.addr)
+            .map(|addr| addr.ip())
+            .filter(|ip| ip.is_ipv4() == listen.is_ipv4() && !is_local_address(ip))
+            .collect()
+    } else {
+        vec![listen.ip()]
+    };
+    for ip in targets {
+        let target = SocketAddr::new(ip, listen.port());
+        match timeout(PROBE_TIMEOUT, TcpStream::connect(target)).await {
+            Ok(Ok(_)) => report.pass(format!("{} accepts connections", target)),
+            Ok(Err(err)) => report.fail(
+                format!("{} refuses connections: {}", target, err),
+                firewall_fix(listen.port()),
+            ),
+            Err(_) => report.fail(
+                format!("Connections to {} time out", target),
+                firewall_fix(listen.port()),
+            ),
+        }
+    }
+}
+
+fn firewall_fix(port: u16) -> String {
+    if cfg!(windows) {
+        format!(
+            "Allow the port through Windows Defender Firewall, e.g. with `New-NetFirewallRule -DisplayName dispatch \
+             -Direction Inbound -Protocol TCP -LocalPort {} -Action Allow`",
+            port
+        )
+    } else if cfg!(target_os = "macos") {
+        "Allow incoming connections to dispatch in System Settings > Network > Firewall > Options"
+            .into()
+    } else {
+        format!(
+            "Allow the port through the firewall, e.g. with `sudo ufw allow {}/tcp` or `sudo firewall-cmd \
+             --add-port={}/tcp`",
+            port, port
+        )
+    }
+}
+
+/// Checks that the clock agrees with an NTP server.
+async fn check_clock(report: &mut Report) {
+    match clock_offset().await {
+        Ok((offset, ahead)) if offset > MAX_CLOCK_OFFSET => report.fail(
+            format!(
+                "The clock is {} {}",
+                humantime::format_duration(Duration::from_secs(offset.as_secs())),
+                if ahead { "ahead" } else { "behind" }
+            ),
+            if cfg!(windows) {
+                "Turn on \"Set time automatically\" in Settings > Time & language > Date & time"
+            } else if cfg!(target_os = "macos") {
+                "Turn on \"Set time and date automatically\" in System Settings > General > Date & Time"
+            } else {
+                "Synchronize the clock, e.g. with `sudo timedatectl set-ntp true`"
+            },
+        ),
+        Ok((offset, _)) => report.pass(format!(
+            "The clock is within {}ms of {}",
+            offset.as_millis(),
+            NTP_SERVER
+        )),
+        Err(err) => report.warn(
+            format!("The clock couldn't be checked: {:#}", err),
+            "Check that outgoing NTP, on UDP port 123, isn't blocked",
+        ),
+    }
+}
+
+/// Asks the NTP server for the time, and returns how far the clock is from it, and whether it's ahead.
+async fn clock_offset() -> Result<(Duration, bool)> {
+    let server = tokio::net::lookup_host(NTP_SERVER)
+        .await?
+        .next()
+        .ok_or_else(|| eyre::eyre!("{} didn't resolve", NTP_SERVER))?;
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+
+    // An SNTP request: no leap indicator, version 3, client mode.
+    let mut packet = [0u8; 48];
+    packet[0] = 0x1b;
+    let sent = SystemTime::now();
+    socket.send_to(&packet, server).await?;
+    let (len, _) = timeout(PROBE_TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|_| eyre::eyre!("{} didn't answer", NTP_SERVER))??;
+    let received = SystemTime::now();
+    if len < packet.len() {
+        return Err(eyre::eyre!("{} sent a truncated answer", NTP_SERVER));
+    }
+
+    // The transmit timestamp of the server, in seconds and fractions of a second since 1900.
+    let secs = u32::from_be_bytes(packet[40..44].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().unwrap()) as u64;
+    let server_time = UNIX_EPOCH
+        + Duration::from_secs(secs.saturating_sub(NTP_EPOCH_OFFSET))
+        + Duration::from_nanos((fraction * 1_000_000_000) >> 32);
+    // The server answered about halfway through the round trip.
+    let local_time = sent + received.duration_since(sent).unwrap_or_default() / 2;
+    Ok(match local_time.duration_since(server_time) {
+        Ok(ahead) => (ahead, true),
+        Err(err) => (err.duration(), false),
+    })
+}
@@ -0,0 +1,223 @@
+//! `dispatch init`: an interactive walk through the choices of `dispatch start` that most setups need, for those who
+//! don't want to learn its options first.
+
+use std::{
+    io::{BufRead, ErrorKind, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use eyre::Result;
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use owo_colors::OwoColorize;
+
+use dispatch_core::net::get_valid_addresses;
+
+/// The `start` command chosen, and how to start it.
+pub struct Setup {
+    /// The arguments of `dispatch start`.
+    pub args: Vec<String>,
+    pub install: Install,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Install {
+    /// Only print the command.
+    None,
+    /// Start the proxy when the user logs in, like `dispatch autostart enable`.
+    Autostart,
+    /// Start the proxy at boot with the service manager of the system.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    Service,
+}
+
+/// Asks for the interfaces to dispatch to with their weights, the address to listen on, and how to start the proxy.
+pub fn init() -> Result<Setup> {
+    let interfaces = interfaces()?;
+    if interfaces.is_empty() {
+        return Err(eyre::eyre!(
+            "No network interface has an address to dispatch to"
+        ));
+    }
+
+    println!("Network interfaces:");
+    for (index, (name, addrs)) in interfaces.iter().enumerate() {
+        let addrs = addrs
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("  {}) {} ({})", index + 1, name.bold(), addrs);
+    }
+    let chosen = loop {
+        let answer = prompt(
+            "Which interfaces should connections be dispatched to? (numbers separated by commas)",
+            "all",
+        )?;
+        if answer == "all" {
+            break (0..interfaces.len()).collect::<Vec<_>>();
+        }
+        match parse_choices(&answer, interfaces.len()) {
+            Some(chosen) => break chosen,
+            None => println!(
+                "Answer with numbers from 1 to {}, e.g. 1,3",
+                interfaces.len()
+            ),
+        }
+    };
+
+    // A single interface gets every connection, whatever its weight.
+    let weighted = chosen.len() > 1;
+    if weighted {
+        println!(
+            "\nConnections are dispatched to each interface in proportion to its weight, e.g. give 3 to a fiber link and \
+             1 to a 4G one."
+        );
+    }
+    let mut addresses = Vec::new();
+    for index in chosen {
+        let name = &interfaces[index].0;
+        let weight = if weighted {
+            loop {
+                match prompt(&format!("Weight of {}", name), "1")?.parse::<usize>() {
+                    Ok(weight) if weight > 0 => break weight,
+                    _ => println!("The weight is a whole number above 0"),
+                }
+            }
+        } else {
+            1
+        };
+        addresses.push(if weight == 1 {
+            name.clone()
+        } else {
+            format!("{}/{}", name, weight)
+        });
+    }
+
+    println!("\nWho should be able to connect to the proxy?");
+    println!("  1) Only this machine");
+    println!("  2) Any device on the LAN");
+    let ip = loop {
+        match prompt("Choice", "1")?.as_str() {
+            "1" => break IpAddr::V4(Ipv4Addr::LOCALHOST),
+            "2" => break IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            _ => println!("Answer with 1 or 2"),
+        }
+    };
+    let port = loop {
+        let Ok(port) = prompt("Port", "1080")?.parse::<u16>() else {
+            println!("The port is a number from 1 to 65535");
+            continue;
+        };
+        match std::net::TcpListener::bind(SocketAddr::new(ip, port)) {
+            Ok(_) => break port,
+            Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                println!("Port {} is already in use, pick another one", port)
+            }
+            Err(err) => println!("Can't listen on port {}: {}", port, err),
+        }
+    };
+    let mut args = Vec::new();
+    if !ip.is_loopback() {
+        args.extend(["--ip".to_string(), ip.to_string()]);
+        println!(
+            "{}",
+            "Any device on the LAN will be able to use the proxy. Pass `--users` to make clients authenticate."
+                .yellow()
+        );
+    }
+    if port != 1080 {
+        args.extend(["--port".to_string(), port.to_string()]);
+    }
+    args.extend(addresses);
+
+    #[allow(unused_mut)]
+    let mut installs = vec![
+        (
+            Install::None,
+            "Don't start it automatically, only print the command",
+        ),
+        (Install::Autostart, "Start it whenever I log in"),
+    ];
+    #[cfg(target_os = "linux")]
+    installs.push((
+        Install::Service,
+        "Start it at boot, as a systemd service (requires root)",
+    ));
+    #[cfg(target_os = "macos")]
+    installs.push((
+        Install::Service,
+        "Start it at boot, as a launchd daemon (requires root)",
+    ));
+    println!("\nHow should the proxy be started?");
+    for (index, (_, description)) in installs.iter().enumerate() {
+        println!("  {}) {}", index + 1, description);
+    }
+    let install = loop {
+        match prompt("Choice", "1")?.parse::<usize>() {
+            Ok(choice) if (1..=installs.len()).contains(&choice) => break installs[choice - 1].0,
+            _ => println!("Answer with a number from 1 to {}", installs.len()),
+        }
+    };
+
+    println!(
+        "\nThe proxy is started with `{}`",
+        std::iter::once("dispatch start".to_string())
+            .chain(args.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ")
+            .bold()
+    );
+    Ok(Setup { args, install })
+}
+
+/// The interfaces with addresses that connections can be dispatched from, as listed by `dispatch list`.
+fn interfaces() -> Result<Vec<(String, Vec<IpAddr>)>> {
+    let mut interfaces: Vec<(String, Vec<IpAddr>)> = Vec::new();
+    for interface in NetworkInterface::show()? {
+        let addrs = get_valid_addresses(&interface.addr);
+        // Some platforms list an interface once per address.
+        match interfaces
+            .iter_mut()
+            .find(|(name, _)| *name == interface.name)
+        {
+            Some((_, existing)) => existing.extend(addrs),
+            None => interfaces.push((interface.name, addrs)),
+        }
+    }
+    interfaces.retain(|(_, addrs)| !addrs.is_empty());
+    for (_, addrs) in &mut interfaces {
+        addrs.sort_by_key(|addr| addr.is_ipv6());
+    }
+    Ok(interfaces)
+}
+
+/// Parses 1-based numbers separated by commas into distinct indices below `len`.
+fn parse_choices(answer: &str, len: usize) -> Option<Vec<usize>> {
+    let mut chosen = Vec::new();
+    for choice in answer.split(',').map(str::trim) {
+        let index = choice.parse::<usize>().ok()?.checked_sub(1)?;
+        if index >= len {
+            return None;
+        }
+        if !chosen.contains(&index) {
+            chosen.push(index);
+        }
+    }
+    (!chosen.is_empty()).then_some(chosen)
+}
+
+/// Asks a question, and returns the trimmed answer, or the default if the answer is empty.
+fn prompt(question: &str, default: &str) -> Result<String> {
+    print!("{} [{}]: ", question, default);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(eyre::eyre!("The setup was interrupted"));
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer.to_string()
+    })
+}
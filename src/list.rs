@@ -1,4 +1,4 @@
-use crate::net::get_valid_addresses;
+use dispatch_core::net::get_valid_addresses;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use owo_colors::OwoColorize;
 use term_table::{
@@ -1,16 +1,41 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
-use clap::Parser;
-use debug::LogStrategy;
-use dispatcher::{RawWeightedAddress, WeightedAddress};
+use clap::{ArgAction, ArgGroup, Parser};
+#[cfg(unix)]
+use dispatch_core::net::Dscp;
+#[cfg(target_os = "linux")]
+use dispatch_core::net::Fwmark;
+use dispatch_core::{
+    connections::{parse_client_range, ConnectionId},
+    control::ControlArgs,
+    debug::{self, LogOptions, LogStrategy},
+    dispatcher::RawWeightedAddress,
+    dns::{HostOverride, Nameserver, Prefer, ScopedNameserver},
+    history,
+    net::{OutboundOptions, SourcePorts},
+    quota::{Allowance, Quota},
+    redact,
+    report::{self, ReportFormat},
+    server::{self, ServerOptions},
+    service, system_proxy,
+    throttle::{InterfaceLimit, Rate},
+    users,
+};
 use eyre::Result;
+use ipnet::IpNet;
 
-mod debug;
-mod dispatcher;
+mod client;
+mod doctor;
+mod init;
 mod list;
-mod net;
-mod server;
-mod socks;
+#[cfg(feature = "tray")]
+mod tray;
 
 /// A proxy that balances traffic between multiple internet connections
 #[derive(Parser, Debug)]
@@ -19,48 +44,1139 @@ struct Opt {
     /// Write debug logs to stdout instead of a file
     #[arg(short, long)]
     debug: bool,
+    /// Which spans and events to log (e.g. `debug` to also trace each step of the SOCKS handshake, or
+    /// `warn` to only log problems)
+    #[arg(
+        long,
+        value_name = "FILTER",
+        default_value = "info",
+        env = "DISPATCH_LOG"
+    )]
+    log_filter: String,
+    /// Hide client and destination addresses in logs and error reports
+    #[arg(long)]
+    redact: bool,
+    /// Also report warnings and errors to the Windows Event Log
+    #[cfg(windows)]
+    #[arg(long)]
+    event_log: bool,
+    /// Export traces to an OpenTelemetry collector at this OTLP/HTTP endpoint
+    /// (e.g. http://localhost:4318/v1/traces)
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+    /// Report panics and fatal errors to the Sentry project with this DSN
+    #[cfg(feature = "sentry")]
+    #[arg(long, value_name = "DSN", env = "SENTRY_DSN")]
+    sentry_dsn: Option<String>,
     #[command(subcommand)]
     command: Command,
 }
 
+// Parsed once at startup, so the size of `Start` doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser, Debug)]
 enum Command {
     /// Lists all available network interfaces
     List,
     /// Starts the SOCKS proxy server
+    #[command(group(ArgGroup::new("tokens").args(["admin_token", "read_token"]).multiple(true)))]
     Start {
         /// Which IP to accept connections from
-        #[arg(default_value = "127.0.0.1", long)]
+        #[arg(default_value = "127.0.0.1", long, env = "DISPATCH_IP")]
         ip: IpAddr,
         /// Which port to listen to for connections
-        #[arg(default_value = "1080", long)]
+        #[arg(default_value = "1080", long, env = "PORT")]
         port: u16,
+        /// Serve the admin endpoint (`/healthz`, `/events`, `/metrics`) on this address
+        #[arg(long, value_name = "ADDRESS", env = "DISPATCH_ADMIN")]
+        admin: Option<SocketAddr>,
+        /// Serve the admin API under `/api` on the admin endpoint, requiring this bearer token
+        #[arg(long, value_name = "TOKEN", env = "DISPATCH_ADMIN_TOKEN")]
+        admin_token: Option<String>,
+        /// Serve the read-only routes of the admin API to this bearer token, e.g. for a dashboard
+        #[arg(long, value_name = "TOKEN", env = "DISPATCH_READ_TOKEN")]
+        read_token: Option<String>,
+        /// Serve the gRPC control API on this address, requiring the admin or read-only token
+        #[cfg(feature = "grpc")]
+        #[arg(long, value_name = "ADDRESS", requires = "tokens")]
+        grpc: Option<SocketAddr>,
+        /// Accept SOCKS clients over TLS with this certificate chain, in PEM, for clients which wrap their SOCKS
+        /// connections in TLS, e.g. with stunnel
+        #[cfg(feature = "tls")]
+        #[arg(
+            long,
+            value_name = "PATH",
+            requires = "tls_key",
+            conflicts_with = "system_proxy"
+        )]
+        tls_cert: Option<PathBuf>,
+        /// The private key of the TLS certificate of the SOCKS listener, in PEM
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "PATH", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Require SOCKS clients to present a certificate signed by this CA, in PEM, which authenticates them without
+        /// passwords
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "PATH", requires = "tls_cert")]
+        tls_client_ca: Option<PathBuf>,
+        /// Serve the admin and gRPC endpoints over TLS with this certificate chain, in PEM
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "PATH", requires = "admin_tls_key")]
+        admin_tls_cert: Option<PathBuf>,
+        /// The private key of the TLS certificate, in PEM
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "PATH", requires = "admin_tls_cert")]
+        admin_tls_key: Option<PathBuf>,
+        /// Require clients of the admin and gRPC endpoints to present a certificate signed by this CA, in PEM
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "PATH", requires = "admin_tls_cert")]
+        admin_tls_client_ca: Option<PathBuf>,
+        /// Record completed connections into a SQLite database in the data directory
+        #[arg(long)]
+        history: bool,
+        /// Where to store the connection history database [default: history.sqlite3 in the data directory]
+        #[arg(long, value_name = "PATH")]
+        history_path: Option<PathBuf>,
+        /// How long to keep connections in the history database (e.g. 30d, 1year)
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "90d",
+            value_parser = humantime::parse_duration
+        )]
+        history_retention: Duration,
+        /// Record every decision on a destination requested by a client (client, user, destination, interface and
+        /// verdict) as a line of JSON appended to this file, separately from the logs
+        #[arg(long, value_name = "PATH")]
+        audit_log: Option<PathBuf>,
+        /// Record every connection the proxy refuses (denied by a rule or user policy, over quota, failing to
+        /// authenticate, or refused by --allow-from, --deny-from and the per-client limits) as a line of JSON
+        /// appended to this file, separately from the logs
+        #[arg(long, value_name = "PATH")]
+        denial_log: Option<PathBuf>,
+        /// How large the denial log can grow before it is rotated, keeping the 5 previous files
+        #[arg(
+            long,
+            value_name = "SIZE",
+            default_value = "10MiB",
+            value_parser = report::parse_bytes
+        )]
+        denial_log_size: usize,
+        /// Route or deny destinations according to the rules in this file, which can be replaced at runtime with
+        /// `dispatch set-rules`
+        #[arg(long, value_name = "PATH", env = "DISPATCH_RULES")]
+        rules: Option<PathBuf>,
+        /// Route connections with the `route` function of this Rhai script before the rules, which decide the
+        /// connections it leaves to them
+        #[cfg(feature = "scripting")]
+        #[arg(long, value_name = "PATH", env = "DISPATCH_SCRIPT")]
+        script: Option<PathBuf>,
+        /// Pick the local address of connections with this dynamic library, which implements the dispatcher plugin
+        /// interface, instead of the weighted round robin
+        #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+        #[arg(long, value_name = "PATH", env = "DISPATCH_PLUGIN")]
+        dispatcher_plugin: Option<PathBuf>,
+        /// Read the server name from the TLS ClientHello of connections to an IP address on port 443, and apply the
+        /// domain rules to it, for clients that resolve domains themselves. The client is told that the connection
+        /// succeeded before it is attempted
+        #[arg(long)]
+        sniff_sni: bool,
+        /// Read the Host header of the first request of plain HTTP connections to an IP address on port 80, and apply the
+        /// domain rules to it, like `--sniff-sni`
+        #[arg(long)]
+        sniff_http: bool,
+        /// Require SOCKS5 clients to authenticate as one of the users in this file, each with their own allowed
+        /// destinations, network interface, bandwidth limit and data quota. Hash passwords with `dispatch hash-password`
+        #[arg(long, value_name = "PATH", env = "DISPATCH_USERS")]
+        users: Option<PathBuf>,
+        /// Locate destinations with this MaxMind DB country database (e.g. GeoLite2-Country.mmdb or
+        /// dbip-country-lite.mmdb), for rules and user policies that match them with `country:<code>`
+        #[arg(long, value_name = "PATH")]
+        geoip: Option<PathBuf>,
+        /// How often to check whether the GeoIP database changed, and load it again if so. 0s disables it
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "1h",
+            value_parser = humantime::parse_duration
+        )]
+        geoip_reload: Duration,
+        /// How often to refresh the domain lists matched by rules with `list:<path or URL>`, reading files again if they
+        /// changed and downloading URLs again. 0s disables it
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "1h",
+            value_parser = humantime::parse_duration
+        )]
+        list_refresh: Duration,
+        /// Resolve the domains requested by clients with this nameserver instead of the system resolver, in the form
+        /// of [tls://|https://]<ip>[:port][#server-name][@interface]. Can be given several times
+        #[arg(long = "dns", value_name = "ADDRESS", value_parser = Nameserver::from_str)]
+        nameservers: Vec<Nameserver>,
+        /// Resolve a domain and its subdomains with this nameserver, in the form of <domain>=<nameserver>, e.g.
+        /// corp.example=10.0.0.53@eth1 to resolve internal names through a VPN interface. Can be given several times
+        #[arg(long = "dns-for", value_name = "DOMAIN=ADDRESS", value_parser = ScopedNameserver::from_str)]
+        scoped_nameservers: Vec<ScopedNameserver>,
+        /// Resolve each domain over the interface that the connection goes through, with the nameservers associated
+        /// with that interface, or else the other nameservers, or else those of the system
+        #[arg(long)]
+        dns_per_interface: bool,
+        /// How long to cache DNS answers at most, within their TTL (e.g. 30s, 10m). 0s disables the cache
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "5m",
+            value_parser = humantime::parse_duration
+        )]
+        dns_cache_max_ttl: Duration,
+        /// How long to cache failures to resolve a domain, such as nonexistent domains and timeouts. 0s disables it
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "5s",
+            value_parser = humantime::parse_duration
+        )]
+        dns_negative_ttl: Duration,
+        /// How long to wait for a domain to resolve before failing the connection. 0s disables the timeout
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "5s",
+            value_parser = humantime::parse_duration
+        )]
+        dns_timeout: Duration,
+        /// Resolve a domain to this address without querying DNS, in the form of <domain>=<ip>. Can be given several
+        /// times, including for the same domain
+        #[arg(long = "host", value_name = "DOMAIN=IP", value_parser = HostOverride::from_str)]
+        hosts: Vec<HostOverride>,
+        /// Resolve domains without querying DNS according to this file, in the format of the system hosts file. Can
+        /// be given several times
+        #[arg(long = "hosts-file", value_name = "PATH")]
+        hosts_files: Vec<PathBuf>,
+        /// Which IP version to connect over first when a destination has both, e.g. ipv4 when the IPv6 uplink is
+        /// broken. auto orders the addresses of a domain as per RFC 6724, and starts with IPv4 when picking the
+        /// interface to resolve a domain over
+        #[arg(long, value_enum, default_value = "auto")]
+        prefer: Prefer,
+        #[command(flatten)]
+        control: ControlArgs,
+        /// How long to wait for active connections to close when stopped with `dispatch stop`
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "30s",
+            value_parser = humantime::parse_duration,
+            env = "DISPATCH_DRAIN_TIMEOUT"
+        )]
+        drain_timeout: Duration,
+        /// How long to wait for the listen address and the control socket to be free when they are in use at startup,
+        /// e.g. by a previous instance that is still draining its connections as a service restarts
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "0s",
+            value_parser = humantime::parse_duration,
+            env = "DISPATCH_WAIT_FOR_PORT"
+        )]
+        wait_for_port: Duration,
+        /// Point the SOCKS proxy settings of the system at the proxy while it runs, as with `dispatch
+        /// set-system-proxy`, and unset them when it stops
+        #[arg(long)]
+        system_proxy: bool,
+        /// Map the port of the proxy on the gateway with NAT-PMP or UPnP while it runs, so that clients outside the LAN
+        /// can reach it. Requires `--users`, so that it isn't open to the internet, and an IPv4 LAN address to listen on
+        #[arg(long, requires = "users")]
+        map_port: bool,
+        /// Limit the bandwidth of the connections through a network interface, in each direction, in the form of
+        /// <interface>=<rate> with the rate in bits per second, e.g. wlan0=5Mbps. Can be given several times
+        #[arg(long = "rate-limit", value_name = "LIMIT", value_parser = InterfaceLimit::from_str)]
+        rate_limits: Vec<InterfaceLimit>,
+        /// Stop dispatching to a network interface once this much data went through it in a day or month, e.g.
+        /// wwan0=20GiB/month for a metered uplink, or wwan0=20GiB/month@15 for a billing period starting on the 15th.
+        /// Quotas reset at midnight UTC. Can be given several times
+        #[arg(long = "quota", value_name = "QUOTA", value_parser = Quota::from_str)]
+        quotas: Vec<Quota>,
+        /// Where the data usage of the quotas is saved across restarts [default: quotas.json in the data directory]
+        #[arg(long, value_name = "PATH")]
+        quota_path: Option<PathBuf>,
+        /// Log a warning once this percentage of a data quota is used. Can be given several times
+        #[arg(
+            long = "quota-warning",
+            value_name = "PERCENT",
+            default_values_t = [80, 95],
+            value_parser = clap::value_parser!(u8).range(1..100)
+        )]
+        quota_warnings: Vec<u8>,
+        /// Cap the data each client IP address can relay in a day or month, in the same form as --quota without the
+        /// interface, e.g. 50GiB/month. The connections of the client are ended and refused until the quota resets
+        #[arg(long, value_name = "QUOTA", value_parser = Allowance::from_str)]
+        client_quota: Option<Allowance>,
+        /// Throttle the connections of users and clients who have used up their data quota to this rate (e.g.
+        /// 1Mbps), instead of ending and refusing them
+        #[arg(long, value_name = "RATE", value_parser = Rate::from_str)]
+        over_quota_rate: Option<Rate>,
+        /// Only accept clients from this IP address or CIDR range, e.g. 192.168.1.0/24, so that listening on a LAN or
+        /// public address doesn't expose the proxy to everyone who can reach it. Can be given several times
+        #[arg(long, value_name = "RANGE", value_parser = parse_client_range)]
+        allow_from: Vec<IpNet>,
+        /// Refuse clients from this IP address or CIDR range, even if allowed with --allow-from. Can be given several
+        /// times
+        #[arg(long, value_name = "RANGE", value_parser = parse_client_range)]
+        deny_from: Vec<IpNet>,
+        /// How many connections each client IP address can have open at once, so that a misbehaving device can't
+        /// starve the others. Further connections from the client are closed right away
+        #[arg(long, value_name = "COUNT")]
+        max_connections_per_client: Option<NonZeroUsize>,
+        /// How many new connections each client IP address can open per second, to blunt scans and retry storms.
+        /// Further connections from the client are closed right away, before the handshake
+        #[arg(long, value_name = "COUNT")]
+        handshake_rate: Option<NonZeroU32>,
+        /// How many new connections each client IP address can open at once after being idle, when limited with
+        /// --handshake-rate. Defaults to the handshake rate
+        #[arg(long, value_name = "COUNT", requires = "handshake_rate")]
+        handshake_burst: Option<NonZeroU32>,
+        /// The size of the buffer of each direction of a relayed connection (e.g. 256KiB). Larger buffers speed up
+        /// large transfers over fast links, at the cost of memory per connection
+        #[arg(
+            long,
+            value_name = "SIZE",
+            default_value = "8KiB",
+            value_parser = report::parse_bytes
+        )]
+        buffer_size: usize,
+        /// Send small writes right away on both sides of relayed connections, instead of coalescing them with
+        /// Nagle's algorithm, for interactive protocols such as SSH, RDP or games
+        #[arg(
+            long,
+            value_name = "BOOL",
+            default_value_t = true,
+            action = ArgAction::Set
+        )]
+        tcp_nodelay: bool,
+        /// Also bind connections to the network interface of their local address with SO_BINDTODEVICE, when it was
+        /// given by name, so that they leave through it whatever the routing tables say
+        #[cfg(target_os = "linux")]
+        #[arg(long)]
+        bind_interface: bool,
+        /// Mark connections with this firewall mark (SO_MARK), for policy routing with `ip rule fwmark`, in the form of
+        /// [<interface>=]<mark>, e.g. eth0=0x10 for the connections through eth0 only. Can be given several times
+        #[cfg(target_os = "linux")]
+        #[arg(long = "fwmark", value_name = "MARK", value_parser = Fwmark::from_str)]
+        fwmarks: Vec<Fwmark>,
+        /// Abort connections when the data they sent stays unacknowledged for this long (e.g. 30s), so that transfers
+        /// stalled on a dead link fail in a bounded time instead of retransmitting for many minutes
+        #[cfg(any(target_os = "linux", windows))]
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        tcp_user_timeout: Option<Duration>,
+        /// Connect with MPTCP, so that a single connection can use several paths, as configured with `ip mptcp endpoint`.
+        /// Connections fall back to TCP when the destination doesn't support MPTCP
+        #[cfg(target_os = "linux")]
+        #[arg(long)]
+        mptcp: bool,
+        /// Connect from a source port in this range instead of an ephemeral one, e.g. when a carrier-grade NAT only
+        /// allows some ports, in the form of [<interface>=]<first>-<last>, e.g. eth0=20000-20999 for the connections
+        /// through eth0 only. Can be given several times
+        #[arg(long = "source-ports", value_name = "RANGE", value_parser = SourcePorts::from_str)]
+        source_ports: Vec<SourcePorts>,
+        /// Mark the packets of connections with this DSCP, as a number from 0 to 63 or by name (e.g. ef, af41, cs1),
+        /// so that routers can apply QoS to them. Routing rules can give their own
+        #[cfg(unix)]
+        #[arg(long, value_name = "DSCP", value_parser = Dscp::from_str)]
+        dscp: Option<Dscp>,
+        /// Restrict the filesystem to the paths the proxy uses with Landlock, and the syscalls it can make with seccomp,
+        /// to contain the damage if it were compromised
+        #[cfg(target_os = "linux")]
+        #[arg(long, conflicts_with = "system_proxy")]
+        sandbox: bool,
+        /// How many threads to handle connections on [default: one per CPU]
+        #[arg(long, value_name = "COUNT", conflicts_with = "single_thread")]
+        workers: Option<NonZeroUsize>,
+        /// Handle every connection on a single thread, e.g. to run lean on small routers
+        #[arg(long)]
+        single_thread: bool,
+        /// How many sockets to accept connections on, bound to the same address with SO_REUSEPORT, to spread a very
+        /// high rate of new connections over several accept loops
+        #[cfg(target_os = "linux")]
+        #[arg(long, value_name = "COUNT", default_value = "1")]
+        acceptors: NonZeroUsize,
+        /// How many new connections to queue until they are accepted, for bursts of connections such as a browser
+        /// loading a page. Capped by the system, e.g. by the `net.core.somaxconn` sysctl on Linux
+        #[arg(long, value_name = "COUNT", default_value = "1024")]
+        backlog: NonZeroU32,
+        /// How many files the proxy raises its limit on open files to, within the hard limit, since each connection
+        /// holds two sockets open and the usual default of 1024 is quickly reached
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        #[arg(long, value_name = "COUNT", default_value = "65536")]
+        max_open_files: u64,
+        /// Relay connections with io_uring instead of epoll, on a thread per CPU, to reduce the syscall overhead at
+        /// tens of thousands of concurrent connections. Requires Linux 5.10 or later
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        #[arg(long)]
+        io_uring: bool,
         /// The network interface IP addresses to dispatch to, in the form of <address>[/priority]
-        #[arg(required = true, value_parser = RawWeightedAddress::from_str)]
+        #[arg(
+            required = true,
+            value_parser = RawWeightedAddress::from_str,
+            env = "DISPATCH_ADDRESSES",
+            value_delimiter = ','
+        )]
         addresses: Vec<RawWeightedAddress>,
     },
+    /// Shows the state of the running proxy
+    Status {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Shows per-address usage of the running proxy since it started
+    Stats {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Makes the running proxy resolve its network interfaces again, e.g. after their IP addresses changed
+    Reload {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Changes the weight of a dispatch address of the running proxy
+    SetWeight {
+        /// The network interface name or IP address, as given to `start`
+        address: String,
+        /// The new weight of the address
+        weight: NonZeroUsize,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Stops dispatching new connections to an address of the running proxy, until it is resumed
+    Pause {
+        /// The network interface name or IP address, as given to `start`
+        address: String,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Starts dispatching to a paused address of the running proxy again
+    Resume {
+        /// The network interface name or IP address, as given to `start`
+        address: String,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Lists the live connections of the running proxy
+    Connections {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Shows per-client usage of the running proxy since it started
+    Clients {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Lists the routing rules of the running proxy, with how many connections each one decided
+    Rules {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Tells which rule of the running proxy a connection to a destination would match, and where it would go
+    TestRoute {
+        /// The destination, as `<host>[:<port>]`, with port 443 by default
+        destination: String,
+        /// The client address to test as
+        #[arg(long = "from", value_name = "IP", default_value = "127.0.0.1")]
+        client: IpAddr,
+        /// The user to test as, for rules with `user=`
+        #[arg(long)]
+        user: Option<String>,
+        /// The application to test as, for rules with `app=`
+        #[arg(long)]
+        app: Option<String>,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Replaces the routing rules of the running proxy with the ones in a file, after checking that they are all valid
+    SetRules {
+        /// The rules file, in the `--rules` format, or `-` to read it from stdin
+        file: PathBuf,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Changes the log filter of the running proxy without restarting it
+    LogFilter {
+        /// The new filter, in the `--log-filter` syntax (e.g. info,dispatch_core::socks=trace)
+        #[arg(required_unless_present = "reset")]
+        filter: Option<String>,
+        /// Restore the filter the proxy was started with after this long (e.g. 5m)
+        #[arg(
+            long = "for",
+            value_name = "DURATION",
+            value_parser = humantime::parse_duration,
+            conflicts_with = "reset"
+        )]
+        duration: Option<Duration>,
+        /// Restore the filter the proxy was started with
+        #[arg(long, conflicts_with = "filter")]
+        reset: bool,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Closes a live connection of the running proxy
+    KillConn {
+        /// The ID of the connection, as listed by `connections`
+        id: ConnectionId,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Stops the running proxy once its active connections have closed, and waits for it to exit
+    Stop {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Exits successfully if the running proxy accepts connections and can dispatch them, e.g. for a Docker
+    /// HEALTHCHECK
+    Healthcheck {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Checks the usual causes of a proxy that doesn't work (interfaces that can't reach the internet or resolve
+    /// domains, a listen port in use or blocked by a firewall, a wrong clock) and prints how to fix them
+    Doctor {
+        /// The IP that `start` would accept connections from
+        #[arg(default_value = "127.0.0.1", long, env = "DISPATCH_IP")]
+        ip: IpAddr,
+        /// The port that `start` would listen to
+        #[arg(default_value = "1080", long, env = "PORT")]
+        port: u16,
+        /// Resolve domains with this nameserver over each interface as well as those of the interface, as with the
+        /// `--dns` option of `start`. Can be given several times
+        #[arg(long = "dns", value_name = "ADDRESS", value_parser = Nameserver::from_str)]
+        nameservers: Vec<Nameserver>,
+        /// The network interface IP addresses that `start` would dispatch to [default: all of them]
+        #[arg(
+            value_parser = RawWeightedAddress::from_str,
+            env = "DISPATCH_ADDRESSES",
+            value_delimiter = ','
+        )]
+        addresses: Vec<RawWeightedAddress>,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Points the SOCKS proxy settings of the system (Windows Internet Options, macOS network services or GNOME) at the
+    /// running proxy
+    SetSystemProxy {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Stops the system from using a SOCKS proxy, as set by `set-system-proxy`
+    UnsetSystemProxy,
+    /// Shows the state and throughput of the running proxy in the system tray (the menu bar on macOS), with actions to
+    /// pause its addresses or stop it
+    #[cfg(feature = "tray")]
+    Tray {
+        /// Open this URL from the menu, e.g. a dashboard of the metrics of the admin endpoint
+        #[arg(long, value_name = "URL")]
+        dashboard: Option<String>,
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+    /// Walks you through choosing the network interfaces to dispatch to, their weights and who can connect, then
+    /// optionally starts the proxy whenever you log in or at boot
+    Init,
+    /// Hashes a password read from stdin, for the users file of `--users`
+    HashPassword,
+    /// Summarizes recorded connections per interface and per destination
+    Report {
+        /// How far back to look (e.g. 7d, 1month)
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "7d",
+            value_parser = humantime::parse_duration
+        )]
+        since: Duration,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: ReportFormat,
+        /// Where the connection history database is stored [default: history.sqlite3 in the data directory]
+        #[arg(long, value_name = "PATH")]
+        history_path: Option<PathBuf>,
+    },
+    /// Starts the proxy with the given arguments of `start` whenever you log in, with the Run key of the registry on
+    /// Windows, a launchd agent on macOS or an XDG autostart entry on Linux
+    Autostart {
+        #[command(subcommand)]
+        command: AutostartCommand,
+    },
+    /// Runs the proxy as a service of the system's service manager
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum AutostartCommand {
+    /// Starts the proxy with the given arguments of `start` whenever you log in, e.g.
+    /// `dispatch autostart enable -- --system-proxy wlan0 usb0`
+    Enable {
+        /// The arguments of `dispatch start`
+        #[arg(last = true, required = true, value_name = "START_ARGS")]
+        args: Vec<String>,
+    },
+    /// Stops starting the proxy when you log in
+    Disable,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[derive(Parser, Debug)]
+enum ServiceCommand {
+    /// Prints a hardened systemd unit that starts the proxy with the given arguments of `start`, e.g.
+    /// `dispatch service generate-systemd -- --users /etc/dispatch/users.txt eth0 wlan0`
+    #[cfg(target_os = "linux")]
+    GenerateSystemd {
+        /// Generate a unit for the user's service manager (`systemctl --user`) instead of the system's
+        #[arg(long)]
+        user: bool,
+        /// The arguments of `dispatch start`
+        #[arg(last = true, required = true, value_name = "START_ARGS")]
+        args: Vec<String>,
+    },
+    /// Installs a launchd service that starts the proxy with the given arguments of `start` at login, and restarts it
+    /// if it crashes, e.g. `dispatch service install -- en0 en1`
+    #[cfg(target_os = "macos")]
+    Install {
+        /// Install a daemon started at boot as root, in /Library/LaunchDaemons, instead of an agent started when the
+        /// user logs in
+        #[arg(long)]
+        system: bool,
+        /// The arguments of `dispatch start`
+        #[arg(last = true, required = true, value_name = "START_ARGS")]
+        args: Vec<String>,
+    },
+    /// Stops and removes the launchd service installed with `dispatch service install`
+    #[cfg(target_os = "macos")]
+    Uninstall {
+        /// Uninstall the daemon started at boot instead of the agent of the user
+        #[arg(long)]
+        system: bool,
+    },
+}
+
+fn log_strategy(debug: bool, command: &Command) -> LogStrategy {
+    // Only the server logs to the file, so that running other commands alongside it doesn't truncate its logs.
+    if debug || !matches!(command, Command::Start { .. }) {
+        return LogStrategy::Stdout;
+    }
+
+    #[cfg(target_os = "linux")]
+    if debug::is_journald_stream() {
+        return LogStrategy::Journald;
+    }
+
+    if debug::is_container() {
+        return LogStrategy::Stdout;
+    }
+
+    LogStrategy::File
 }
 
 fn main() -> Result<()> {
     let opt = Opt::parse();
 
-    let _guard = debug::install(if opt.debug {
-        LogStrategy::Stdout
-    } else {
-        LogStrategy::File
+    if opt.redact {
+        redact::enable();
+    }
+
+    let guard = debug::install(LogOptions {
+        strategy: log_strategy(opt.debug, &opt.command),
+        filter: opt.log_filter,
+        #[cfg(windows)]
+        event_log: opt.event_log,
+        #[cfg(feature = "otlp")]
+        otlp_endpoint: opt.otlp_endpoint,
+        #[cfg(feature = "sentry")]
+        sentry_dsn: opt.sentry_dsn,
     })?;
 
-    match opt.command {
+    let res = run(opt.command);
+    if let Err(err) = &res {
+        guard.report_fatal_error(err);
+    }
+    res
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
         Command::List => list::list(),
         Command::Start {
             ip,
             port,
+            admin,
+            admin_token,
+            read_token,
+            #[cfg(feature = "grpc")]
+            grpc,
+            #[cfg(feature = "tls")]
+            tls_cert,
+            #[cfg(feature = "tls")]
+            tls_key,
+            #[cfg(feature = "tls")]
+            tls_client_ca,
+            #[cfg(feature = "tls")]
+            admin_tls_cert,
+            #[cfg(feature = "tls")]
+            admin_tls_key,
+            #[cfg(feature = "tls")]
+            admin_tls_client_ca,
+            history,
+            history_path,
+            history_retention,
+            audit_log,
+            denial_log,
+            denial_log_size,
+            rules,
+            #[cfg(feature = "scripting")]
+            script,
+            #[cfg(all(feature = "plugins", any(target_os = "linux", target_os = "macos")))]
+            dispatcher_plugin,
+            sniff_sni,
+            sniff_http,
+            users,
+            geoip,
+            geoip_reload,
+            list_refresh,
+            nameservers,
+            scoped_nameservers,
+            dns_per_interface,
+            dns_cache_max_ttl,
+            dns_negative_ttl,
+            dns_timeout,
+            hosts,
+            hosts_files,
+            prefer,
+            control,
+            drain_timeout,
+            wait_for_port,
+            system_proxy,
+            map_port,
+            rate_limits,
+            quotas,
+            quota_path,
+            quota_warnings,
+            client_quota,
+            over_quota_rate,
+            allow_from,
+            deny_from,
+            max_connections_per_client,
+            handshake_rate,
+            handshake_burst,
+            buffer_size,
+            tcp_nodelay,
+            #[cfg(target_os = "linux")]
+            bind_interface,
+            #[cfg(target_os = "linux")]
+            fwmarks,
+            #[cfg(any(target_os = "linux", windows))]
+            tcp_user_timeout,
+            #[cfg(target_os = "linux")]
+            mptcp,
+            source_ports,
+            #[cfg(unix)]
+            dscp,
+            #[cfg(target_os = "linux")]
+            sandbox,
+            workers,
+            single_thread,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            io_uring,
+            #[cfg(target_os = "linux")]
+            acceptors,
+            backlog,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            max_open_files,
+            addresses,
+        } => {
+            debug::set_configuration(format!(
+                "{} dispatch address(es), history {}, admin endpoint {}",
+                addresses.len(),
+                if history || history_path.is_some() {
+                    "on"
+                } else {
+                    "off"
+                },
+                if admin.is_some() { "on" } else { "off" },
+            ));
+            let history = if history || history_path.is_some() {
+                let path = match history_path {
+                    Some(path) => path,
+                    None => history::default_path()?,
+                };
+                Some((path, history_retention))
+            } else {
+                None
+            };
+            server::server(
+                ServerOptions {
+                    addr: SocketAddr::new(ip, port),
+                    #[cfg(feature = "tls")]
+                    tls: tls_cert.zip(tls_key).map(|(cert, key)| {
+                        dispatch_core::admin::tls::TlsOptions {
+                            cert,
+                            key,
+                            client_ca: tls_client_ca,
+                        }
+                    }),
+                    history,
+                    audit_log,
+                    denial_log: denial_log.map(|path| (path, denial_log_size as u64)),
+                    system_proxy,
+                    map_port,
+                    admin,
+                    admin_token,
+                    read_token,
+                    #[cfg(feature = "grpc")]
+                    grpc,
+                    #[cfg(feature = "tls")]
+                    admin_tls: admin_tls_cert.zip(admin_tls_key).map(|(cert, key)| {
+                        dispatch_core::admin::tls::TlsOptions {
+                            cert,
+                            key,
+                            client_ca: admin_tls_client_ca,
+                        }
+                    }),
+                    rules,
+                    #[cfg(feature = "scripting")]
+                    script,
+                    #[cfg(all(
+                        feature = "plugins",
+                        any(target_os = "linux", target_os = "macos")
+                    ))]
+                    dispatcher_plugin,
+                    sniff_sni,
+                    sniff_http,
+                    users,
+                    geoip,
+                    geoip_reload,
+                    list_refresh,
+                    nameservers,
+                    scoped_nameservers,
+                    dns_per_interface,
+                    dns_cache_max_ttl,
+                    dns_negative_ttl,
+                    dns_timeout,
+                    hosts,
+                    hosts_files,
+                    prefer,
+                    control: Some(control.path()?),
+                    drain_timeout,
+                    wait_for_port,
+                    rate_limits,
+                    quotas,
+                    quota_path,
+                    quota_warnings,
+                    client_quota,
+                    over_quota_rate,
+                    allow_from,
+                    deny_from,
+                    max_connections_per_client,
+                    handshake_rate,
+                    handshake_burst,
+                    buffer_size,
+                    tcp_nodelay,
+                    outbound: OutboundOptions {
+                        #[cfg(target_os = "linux")]
+                        bind_device: bind_interface,
+                        #[cfg(target_os = "linux")]
+                        fwmarks,
+                        #[cfg(any(target_os = "linux", windows))]
+                        user_timeout: tcp_user_timeout,
+                        #[cfg(target_os = "linux")]
+                        mptcp,
+                        source_ports,
+                        #[cfg(unix)]
+                        dscp,
+                    },
+                    #[cfg(target_os = "linux")]
+                    sandbox,
+                    workers,
+                    single_thread,
+                    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                    io_uring,
+                    #[cfg(target_os = "linux")]
+                    acceptors,
+                    backlog,
+                    #[cfg(any(target_os = "linux", target_os = "macos"))]
+                    max_open_files,
+                    standalone: true,
+                },
+                addresses,
+            )?
+        }
+        Command::Status { control } => client::status(&control.path()?)?,
+        Command::Stats { control } => client::stats(&control.path()?)?,
+        Command::Reload { control } => client::reload(&control.path()?)?,
+        Command::SetWeight {
+            address,
+            weight,
+            control,
+        } => client::set_weight(&control.path()?, &address, weight)?,
+        Command::Pause { address, control } => client::pause(&control.path()?, &address)?,
+        Command::Resume { address, control } => client::resume(&control.path()?, &address)?,
+        Command::Connections { control } => client::connections(&control.path()?)?,
+        Command::Clients { control } => client::clients(&control.path()?)?,
+        Command::Rules { control } => client::rules(&control.path()?)?,
+        Command::TestRoute {
+            destination,
+            client,
+            user,
+            app,
+            control,
+        } => client::test_route(&control.path()?, &destination, client, user, app)?,
+        Command::SetRules { file, control } => client::set_rules(&control.path()?, &file)?,
+        Command::LogFilter {
+            filter,
+            duration,
+            reset: _,
+            control,
+        } => client::log_filter(&control.path()?, filter, duration)?,
+        Command::KillConn { id, control } => client::kill_conn(&control.path()?, id)?,
+        Command::Stop { control } => client::stop(&control.path()?)?,
+        Command::Healthcheck { control } => {
+            if !client::healthcheck(&control.path()?) {
+                std::process::exit(1);
+            }
+        }
+        Command::Doctor {
+            ip,
+            port,
+            nameservers,
             addresses,
+            control,
+        } => {
+            if !doctor::doctor(ip, port, addresses, nameservers, &control.path()?)? {
+                std::process::exit(1);
+            }
+        }
+        Command::SetSystemProxy { control } => {
+            system_proxy::set(client::listen_addr(&control.path()?)?, true)?
+        }
+        Command::UnsetSystemProxy => system_proxy::unset(true)?,
+        #[cfg(feature = "tray")]
+        Command::Tray { dashboard, control } => tray::run(control.path()?, dashboard)?,
+        Command::Init => {
+            let init::Setup { args, install } = init::init()?;
+            match install {
+                init::Install::None => {}
+                init::Install::Autostart => enable_autostart(&args)?,
+                #[cfg(target_os = "linux")]
+                init::Install::Service => {
+                    use color_eyre::Section;
+                    use eyre::WrapErr;
+
+                    let path = "/etc/systemd/system/dispatch.service";
+                    std::fs::write(path, systemd_unit(false, args)?.render())
+                        .wrap_err_with(|| format!("Failed to write `{}`", path))
+                        .suggestion("Run `sudo dispatch init` to install a service")?;
+                    println!("Installed {}", path);
+                    println!(
+                        "Start it now and at boot with `sudo systemctl enable --now dispatch`"
+                    );
+                }
+                #[cfg(target_os = "macos")]
+                init::Install::Service => install_launchd(true, &args)?,
+            }
+        }
+        Command::HashPassword => users::hash_password()?,
+        Command::Report {
+            since,
+            format,
+            history_path,
         } => {
-            let addresses = WeightedAddress::resolve(addresses)?;
-            server::server(ip, port, addresses)?
+            let history_path = match history_path {
+                Some(path) => path,
+                None => history::default_path()?,
+            };
+            report::report(&history_path, since, format)?
         }
+        Command::Autostart {
+            command: AutostartCommand::Enable { args },
+        } => enable_autostart(&args)?,
+        Command::Autostart {
+            command: AutostartCommand::Disable,
+        } => service::autostart::disable()?,
+        #[cfg(target_os = "linux")]
+        Command::Service {
+            command: ServiceCommand::GenerateSystemd { user, args },
+        } => print!("{}", systemd_unit(user, args)?.render()),
+        #[cfg(target_os = "macos")]
+        Command::Service {
+            command: ServiceCommand::Install { system, args },
+        } => install_launchd(system, &args)?,
+        #[cfg(target_os = "macos")]
+        Command::Service {
+            command: ServiceCommand::Uninstall { system },
+        } => service::launchd::uninstall(service::launchd::Domain::new(system))?,
     }
 
     Ok(())
 }
+
+fn enable_autostart(args: &[String]) -> Result<()> {
+    use eyre::WrapErr;
+
+    service_start(args)?;
+    let exe =
+        std::env::current_exe().wrap_err("Failed to find the path of the dispatch executable")?;
+    service::autostart::enable(&exe, args)
+}
+
+#[cfg(target_os = "macos")]
+fn install_launchd(system: bool, args: &[String]) -> Result<()> {
+    use eyre::WrapErr;
+
+    service_start(args)?;
+    let exe =
+        std::env::current_exe().wrap_err("Failed to find the path of the dispatch executable")?;
+    service::launchd::install(service::launchd::Domain::new(system), &exe, args)
+}
+
+/// Parses the arguments of `dispatch start` that a service runs the proxy with. They are checked beforehand, since a
+/// service that fails to start is harder to debug, and its paths must be absolute, since it doesn't run from the current
+/// directory.
+fn service_start(args: &[String]) -> Result<Command> {
+    use color_eyre::Section;
+    use eyre::WrapErr;
+
+    let command = ["dispatch", "start"]
+        .into_iter()
+        .map(String::from)
+        .chain(args.iter().cloned());
+    let start = Opt::try_parse_from(command)
+        .wrap_err("The arguments aren't valid for `dispatch start`")?
+        .command;
+    let Command::Start {
+        #[cfg(feature = "tls")]
+        admin_tls_cert,
+        #[cfg(feature = "tls")]
+        admin_tls_key,
+        #[cfg(feature = "tls")]
+        admin_tls_client_ca,
+        history_path,
+        audit_log,
+        denial_log,
+        rules,
+        users,
+        geoip,
+        hosts_files,
+        control,
+        quota_path,
+        ..
+    } = &start
+    else {
+        unreachable!("the arguments are parsed as `dispatch start`")
+    };
+
+    #[cfg(feature = "tls")]
+    let tls = [admin_tls_cert, admin_tls_key, admin_tls_client_ca];
+    #[cfg(not(feature = "tls"))]
+    let tls: [&Option<PathBuf>; 0] = [];
+    let paths = [
+        history_path,
+        audit_log,
+        denial_log,
+        rules,
+        users,
+        geoip,
+        &control.path,
+        quota_path,
+    ]
+    .into_iter()
+    .chain(tls)
+    .flatten()
+    .chain(hosts_files);
+    for path in paths {
+        if path.is_relative() {
+            return Err(eyre::eyre!(
+                "The path `{}` is relative, but services don't run from the current directory",
+                path.display()
+            ))
+            .suggestion("Give absolute paths to the options of `dispatch start`");
+        }
+    }
+    Ok(start)
+}
+
+/// The systemd unit that runs `dispatch start` with the given arguments.
+#[cfg(target_os = "linux")]
+fn systemd_unit(user: bool, args: Vec<String>) -> Result<service::systemd::Unit> {
+    use color_eyre::Section;
+    use eyre::WrapErr;
+    use service::systemd::{parent, Unit};
+
+    let Command::Start {
+        port,
+        admin,
+        #[cfg(feature = "grpc")]
+        grpc,
+        history_path,
+        audit_log,
+        denial_log,
+        control,
+        drain_timeout,
+        quota_path,
+        bind_interface,
+        fwmarks,
+        ..
+    } = service_start(&args)?
+    else {
+        unreachable!("the arguments are parsed as `dispatch start`")
+    };
+
+    let written = [
+        history_path,
+        audit_log,
+        denial_log,
+        quota_path,
+        control.path,
+    ];
+    let mut writable = written
+        .iter()
+        .flatten()
+        .map(|path| parent(path))
+        .collect::<Vec<_>>();
+    writable.sort();
+    writable.dedup();
+
+    #[cfg(feature = "grpc")]
+    let grpc_port = grpc.map(|addr| addr.port());
+    #[cfg(not(feature = "grpc"))]
+    let grpc_port = None;
+    let ports = [Some(port), admin.map(|addr| addr.port()), grpc_port];
+    let mut capabilities = vec![];
+    if ports.into_iter().flatten().any(|port| port < 1024) {
+        capabilities.push("CAP_NET_BIND_SERVICE");
+    }
+    if !fwmarks.is_empty() {
+        capabilities.push("CAP_NET_ADMIN");
+    }
+    if bind_interface {
+        capabilities.push("CAP_NET_RAW");
+    }
+    if user && !capabilities.is_empty() {
+        return Err(eyre::eyre!(
+            "The proxy needs {} for these options, which user services can't be granted",
+            capabilities.join(", ")
+        ))
+        .suggestion("Generate a system unit instead, without --user");
+    }
+
+    Ok(Unit {
+        exe: std::env::current_exe()
+            .wrap_err("Failed to find the path of the dispatch executable")?,
+        args,
+        user,
+        capabilities,
+        writable,
+        drain_timeout,
+    })
+}
@@ -0,0 +1,367 @@
+//! A companion that shows the state of the running proxy in the system tray, or the menu bar on macOS: whether it
+//! accepts connections, the throughput of each dispatch address, and quick actions to pause an address, open a
+//! dashboard, or stop the proxy, for those who don't keep a terminal open.
+//!
+//! The companion is a separate process that talks to the proxy over its control socket, like the other commands, so
+//! that it can be started and quit without affecting the proxy.
+
+#[cfg(any(windows, target_os = "macos"))]
+mod native;
+#[cfg(target_os = "linux")]
+mod sni;
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use eyre::Result;
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+use dispatch_core::{
+    control::{AddressStatus, Request, Response, Status},
+    report::format_bytes,
+};
+
+use crate::client;
+
+#[cfg(any(windows, target_os = "macos"))]
+use native as platform;
+#[cfg(target_os = "linux")]
+use sni as platform;
+
+/// How often the companion polls the running proxy.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shows the state of the proxy listening on the control socket at `control` in the system tray, until the companion
+/// is quit from its menu.
+pub fn run(control: PathBuf, dashboard: Option<String>) -> Result<()> {
+    platform::run(Companion::new(control, dashboard))
+}
+
+/// Polls the running proxy and performs the actions of the menu.
+struct Companion {
+    control: PathBuf,
+    dashboard: Option<String>,
+    /// The bytes relayed through each address as of the previous poll, to compute their throughput.
+    previous: Option<Sample>,
+}
+
+struct Sample {
+    at: Instant,
+    /// The bytes sent and received through each address.
+    bytes: HashMap<IpAddr, (u64, u64)>,
+}
+
+impl Companion {
+    fn new(control: PathBuf, dashboard: Option<String>) -> Companion {
+        Companion {
+            control,
+            dashboard,
+            previous: None,
+        }
+    }
+
+    async fn poll(&mut self) -> Snapshot {
+        let status = match client::send(&self.control, Request::Status).await {
+            Ok(Response::Status(status)) => status,
+            Ok(response) => return self.down(client::unexpected_response(response)),
+            Err(err) => return self.down(err),
+        };
+        // The throughput is left out rather than failing the whole poll, e.g. when the proxy is stopping.
+        let stats = match client::send(&self.control, Request::Stats).await {
+            Ok(Response::Stats { addresses, .. }) => addresses,
+            _ => Vec::new(),
+        };
+
+        let now = Instant::now();
+        let bytes = stats
+            .into_iter()
+            .map(|stats| {
+                (
+                    stats.address,
+                    (stats.stats.bytes_up, stats.stats.bytes_down),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let rates = |address: IpAddr| {
+            let previous = self.previous.as_ref()?;
+            let (up, down) = bytes.get(&address)?;
+            let (previous_up, previous_down) = previous.bytes.get(&address)?;
+            let secs = now.duration_since(previous.at).as_secs_f64();
+            Some((
+                (up.saturating_sub(*previous_up) as f64 / secs) as u64,
+                (down.saturating_sub(*previous_down) as f64 / secs) as u64,
+            ))
+        };
+
+        let names = interface_names();
+        let addresses = status
+            .addresses
+            .iter()
+            .map(|address| AddressView {
+                name: names.get(&address.address).cloned(),
+                rates: rates(address.address),
+                status: address.clone(),
+            })
+            .collect();
+        self.previous = Some(Sample { at: now, bytes });
+
+        Snapshot {
+            state: State::Running(status),
+            addresses,
+            dashboard: self.dashboard.is_some(),
+        }
+    }
+
+    fn down(&mut self, err: eyre::Report) -> Snapshot {
+        self.previous = None;
+        Snapshot {
+            state: State::Down(format!("{:#}", err)),
+            addresses: Vec::new(),
+            dashboard: self.dashboard.is_some(),
+        }
+    }
+
+    /// Performs an action of the menu, and returns whether the companion should keep running.
+    async fn perform(&self, action: Action) -> bool {
+        let res = match action {
+            Action::Pause(address) => client::send(
+                &self.control,
+                Request::Pause {
+                    address: address.to_string(),
+                },
+            )
+            .await
+            .map(drop),
+            Action::Resume(address) => client::send(
+                &self.control,
+                Request::Resume {
+                    address: address.to_string(),
+                },
+            )
+            .await
+            .map(drop),
+            Action::OpenDashboard => match &self.dashboard {
+                Some(url) => open(url),
+                None => Ok(()),
+            },
+            Action::Stop => client::send(&self.control, Request::Stop).await.map(drop),
+            Action::Quit => return false,
+        };
+        if let Err(err) = res {
+            tracing::warn!("failed to {}: {:#}", action, err);
+        }
+        true
+    }
+}
+
+/// An action of the menu of the companion.
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Pause(IpAddr),
+    Resume(IpAddr),
+    OpenDashboard,
+    /// Stops the proxy, like `dispatch stop`.
+    Stop,
+    /// Quits the companion, leaving the proxy running.
+    Quit,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Pause(address) => write!(f, "pause {}", address),
+            Action::Resume(address) => write!(f, "resume {}", address),
+            Action::OpenDashboard => write!(f, "open the dashboard"),
+            Action::Stop => write!(f, "stop the proxy"),
+            Action::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+/// What the companion shows, as of the last time it polled the proxy.
+#[derive(Clone, Debug)]
+struct Snapshot {
+    state: State,
+    addresses: Vec<AddressView>,
+    /// Whether a dashboard can be opened from the menu.
+    dashboard: bool,
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    /// The proxy hasn't been polled yet.
+    Starting,
+    Running(Status),
+    /// The proxy couldn't be queried, e.g. because it isn't running.
+    Down(String),
+}
+
+impl Snapshot {
+    fn new(dashboard: bool) -> Snapshot {
+        Snapshot {
+            state: State::Starting,
+            addresses: Vec::new(),
+            dashboard,
+        }
+    }
+
+    fn health(&self) -> Health {
+        match &self.state {
+            State::Running(status) if status.accepting => {
+                let usable = self
+                    .addresses
+                    .iter()
+                    .filter(|address| address.usable())
+                    .count();
+                if usable == self.addresses.len() {
+                    Health::Healthy
+                } else if usable > 0 {
+                    Health::Degraded
+                } else {
+                    Health::Down
+                }
+            }
+            State::Running(_) | State::Down(_) => Health::Down,
+            State::Starting => Health::Unknown,
+        }
+    }
+
+    /// A line summing up the state of the proxy, at the top of the menu and in the tooltip.
+    fn summary(&self) -> String {
+        match &self.state {
+            State::Starting => "Connecting to the proxy…".into(),
+            State::Running(status) if !status.accepting => {
+                format!("Stopping, {} active connections", status.connections)
+            }
+            State::Running(status) => format!(
+                "Listening on {}, {} active connections",
+                status.listen, status.connections
+            ),
+            State::Down(err) => format!("Not running: {}", err),
+        }
+    }
+}
+
+/// How the icon of the companion is colored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Health {
+    Unknown,
+    /// Every dispatch address is usable.
+    Healthy,
+    /// Some dispatch addresses are paused, unhealthy or over their quota.
+    Degraded,
+    /// The proxy isn't running, or can't dispatch connections.
+    Down,
+}
+
+impl Health {
+    /// A 32x32 icon of a dot of the color of the health, in RGBA.
+    fn icon(self) -> (Vec<u8>, u32) {
+        const SIZE: u32 = 32;
+        let color = match self {
+            Health::Unknown => [0x9e, 0x9e, 0x9e],
+            Health::Healthy => [0x2e, 0xb8, 0x4f],
+            Health::Degraded => [0xf0, 0xa0, 0x20],
+            Health::Down => [0xd9, 0x3a, 0x3a],
+        };
+        let center = SIZE as f32 / 2.0;
+        let mut rgba = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let distance =
+                    ((x as f32 + 0.5 - center).powi(2) + (y as f32 + 0.5 - center).powi(2)).sqrt();
+                // Antialias the edge of the dot over a pixel.
+                let alpha = (center - 2.0 - distance + 0.5).clamp(0.0, 1.0);
+                rgba.extend_from_slice(&color);
+                rgba.push((alpha * 255.0) as u8);
+            }
+        }
+        (rgba, SIZE)
+    }
+}
+
+/// A dispatch address, as shown in the menu.
+#[derive(Clone, Debug)]
+struct AddressView {
+    status: AddressStatus,
+    /// The network interface the address belongs to.
+    name: Option<String>,
+    /// The bytes per second sent and received through the address since the previous poll.
+    rates: Option<(u64, u64)>,
+}
+
+impl AddressView {
+    fn usable(&self) -> bool {
+        !self.status.paused && !self.status.over_quota && self.status.healthy
+    }
+
+    /// The label of the item of the address, which is checked while connections are dispatched to it.
+    fn label(&self) -> String {
+        let mut label = match &self.name {
+            Some(name) => format!("{} ({})", name, self.status.address),
+            None => self.status.address.to_string(),
+        };
+        if self.status.paused {
+            label.push_str(", paused");
+        } else if self.status.over_quota {
+            label.push_str(", over quota");
+        } else if !self.status.healthy {
+            label.push_str(", unhealthy");
+        } else if let Some((up, down)) = self.rates {
+            label.push_str(&format!(
+                ": ↓ {}/s ↑ {}/s",
+                format_bytes(down),
+                format_bytes(up)
+            ));
+        }
+        label
+    }
+
+    /// The action of clicking the item of the address.
+    fn toggle(&self) -> Action {
+        if self.status.paused {
+            Action::Resume(self.status.address)
+        } else {
+            Action::Pause(self.status.address)
+        }
+    }
+}
+
+/// The names of the network interfaces of the local addresses, to tell e.g. Wi-Fi from tethering.
+fn interface_names() -> HashMap<IpAddr, String> {
+    NetworkInterface::show()
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|interface| {
+            interface
+                .addr
+                .iter()
+                .map(|addr| (addr.ip(), interface.name.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Opens a URL in the default browser.
+fn open(url: &str) -> Result<()> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        // The empty argument is the title of the window `start` would open for a console program.
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+    let status = command.arg(url).status()?;
+    if !status.success() {
+        return Err(eyre::eyre!("failed to open {}: {}", url, status));
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! The companion as a notification area icon on Windows, or a menu bar extra on macOS.
+//!
+//! The icon lives on the event loop of the main thread, while the proxy is polled on a runtime of its own.
+
+use std::net::IpAddr;
+
+use eyre::{Result, WrapErr};
+use tao::{
+    event::{Event, StartCause},
+    event_loop::{ControlFlow, EventLoopBuilder},
+};
+use tokio::sync::mpsc;
+use tray_icon::{
+    menu::{CheckMenuItem, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem},
+    Icon, TrayIcon, TrayIconBuilder,
+};
+
+use super::{Action, Companion, Snapshot, POLL_INTERVAL};
+
+enum UserEvent {
+    Snapshot(Snapshot),
+    Menu(MenuEvent),
+    Quit,
+}
+
+/// The menu of the icon, whose items are updated in place so that it stays open across polls.
+struct TrayMenu {
+    menu: Menu,
+    summary: MenuItem,
+    addresses: Vec<(IpAddr, CheckMenuItem)>,
+    dashboard: Option<MenuItem>,
+    stop: MenuItem,
+    quit: MenuItem,
+}
+
+impl TrayMenu {
+    fn new(snapshot: &Snapshot) -> Result<TrayMenu> {
+        let menu = Menu::new();
+        let summary = MenuItem::new(snapshot.summary(), false, None);
+        menu.append(&summary)?;
+        menu.append(&PredefinedMenuItem::separator())?;
+
+        let addresses = snapshot
+            .addresses
+            .iter()
+            .map(|address| -> Result<_> {
+                let item = CheckMenuItem::new(address.label(), true, !address.status.paused, None);
+                menu.append(&item)?;
+                Ok((address.status.address, item))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if !addresses.is_empty() {
+            menu.append(&PredefinedMenuItem::separator())?;
+        }
+
+        let dashboard = if snapshot.dashboard {
+            let item = MenuItem::new("Open dashboard", true, None);
+            menu.append(&item)?;
+            Some(item)
+        } else {
+            None
+        };
+        let stop = MenuItem::new("Stop proxy", true, None);
+        menu.append(&stop)?;
+        let quit = MenuItem::new("Quit", true, None);
+        menu.append(&quit)?;
+
+        Ok(TrayMenu {
+            menu,
+            summary,
+            addresses,
+            dashboard,
+            stop,
+            quit,
+        })
+    }
+
+    /// Updates the menu to show the snapshot, and returns whether it must be rebuilt because the addresses changed.
+    fn update(&self, snapshot: &Snapshot) -> bool {
+        let unchanged = self.addresses.len() == snapshot.addresses.len()
+            && self
+                .addresses
+                .iter()
+                .zip(&snapshot.addresses)
+                .all(|((address, _), view)| *address == view.status.address);
+        if !unchanged {
+            return true;
+        }
+
+        self.summary.set_text(snapshot.summary());
+        for ((_, item), address) in self.addresses.iter().zip(&snapshot.addresses) {
+            item.set_text(address.label());
+            item.set_checked(!address.status.paused);
+        }
+        false
+    }
+
+    fn action(&self, id: &MenuId, snapshot: &Snapshot) -> Option<Action> {
+        if id == self.stop.id() {
+            return Some(Action::Stop);
+        }
+        if id == self.quit.id() {
+            return Some(Action::Quit);
+        }
+        if self.dashboard.as_ref().is_some_and(|item| id == item.id()) {
+            return Some(Action::OpenDashboard);
+        }
+        let (address, _) = self.addresses.iter().find(|(_, item)| id == item.id())?;
+        snapshot
+            .addresses
+            .iter()
+            .find(|view| view.status.address == *address)
+            .map(|view| view.toggle())
+    }
+}
+
+fn icon(snapshot: &Snapshot) -> Result<Icon> {
+    let (rgba, size) = snapshot.health().icon();
+    Icon::from_rgba(rgba, size, size).wrap_err("Failed to create the tray icon")
+}
+
+pub fn run(mut companion: Companion) -> Result<()> {
+    #[allow(unused_mut)]
+    let mut event_loop = EventLoopBuilder::<UserEvent>::with_user_event().build();
+    // Only show the companion in the menu bar, not in the Dock.
+    #[cfg(target_os = "macos")]
+    {
+        use tao::platform::macos::{ActivationPolicy, EventLoopExtMacOS};
+
+        event_loop.set_activation_policy(ActivationPolicy::Accessory);
+    }
+
+    let proxy = event_loop.create_proxy();
+    MenuEvent::set_event_handler(Some(move |event| {
+        let _ = proxy.send_event(UserEvent::Menu(event));
+    }));
+
+    let mut snapshot = Snapshot::new(companion.dashboard.is_some());
+    let (actions, mut actions_rx) = mpsc::unbounded_channel();
+    let proxy = event_loop.create_proxy();
+    let rt = tokio::runtime::Runtime::new()?;
+    std::thread::Builder::new()
+        .name("tray".into())
+        .spawn(move || {
+            rt.block_on(async move {
+                let mut interval = tokio::time::interval(POLL_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            let snapshot = companion.poll().await;
+                            if proxy.send_event(UserEvent::Snapshot(snapshot)).is_err() {
+                                break;
+                            }
+                        }
+                        Some(action) = actions_rx.recv() => {
+                            if !companion.perform(action).await {
+                                let _ = proxy.send_event(UserEvent::Quit);
+                                break;
+                            }
+                            // Show the effect of the action right away.
+                            interval.reset_immediately();
+                        }
+                    }
+                }
+            })
+        })
+        .wrap_err("Failed to spawn the tray thread")?;
+
+    let mut state: Option<(TrayIcon, TrayMenu)> = None;
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::Wait;
+
+        let res = match event {
+            // On macOS, the icon can only be created once the event loop runs.
+            Event::NewEvents(StartCause::Init) => TrayMenu::new(&snapshot).and_then(|menu| {
+                let tray = TrayIconBuilder::new()
+                    .with_menu(Box::new(menu.menu.clone()))
+                    .with_tooltip(snapshot.summary())
+                    .with_icon(icon(&snapshot)?)
+                    .build()
+                    .wrap_err("Failed to show the tray icon")?;
+                state = Some((tray, menu));
+                Ok(())
+            }),
+            Event::UserEvent(UserEvent::Snapshot(next)) => {
+                snapshot = next;
+                match &mut state {
+                    Some((tray, menu)) => (|| -> Result<()> {
+                        if menu.update(&snapshot) {
+                            *menu = TrayMenu::new(&snapshot)?;
+                            tray.set_menu(Some(Box::new(menu.menu.clone())));
+                        }
+                        tray.set_tooltip(Some(snapshot.summary()))?;
+                        tray.set_icon(Some(icon(&snapshot)?))?;
+                        Ok(())
+                    })(),
+                    None => Ok(()),
+                }
+            }
+            Event::UserEvent(UserEvent::Menu(event)) => {
+                if let Some(action) = state
+                    .as_ref()
+                    .and_then(|(_, menu)| menu.action(&event.id, &snapshot))
+                {
+                    let _ = actions.send(action);
+                }
+                Ok(())
+            }
+            Event::UserEvent(UserEvent::Quit) => {
+                *control_flow = ControlFlow::Exit;
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(err) = res {
+            tracing::error!("{:#}", err);
+            eprintln!("{:?}", err);
+            *control_flow = ControlFlow::Exit;
+        }
+    })
+}
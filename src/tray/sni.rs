@@ -0,0 +1,139 @@
+//! The companion as a StatusNotifierItem, which KDE, Xfce and GNOME (with the AppIndicator extension) show in their
+//! tray, over D-Bus rather than through GTK.
+
+use color_eyre::Section;
+use eyre::{Result, WrapErr};
+use ksni::{
+    menu::{CheckmarkItem, StandardItem},
+    MenuItem, TrayMethods,
+};
+use tokio::sync::mpsc;
+
+use super::{Action, Companion, Snapshot, POLL_INTERVAL};
+
+struct Tray {
+    snapshot: Snapshot,
+    actions: mpsc::UnboundedSender<Action>,
+}
+
+impl Tray {
+    fn item(&self, label: String, action: Action) -> MenuItem<Tray> {
+        let actions = self.actions.clone();
+        StandardItem {
+            label,
+            activate: Box::new(move |_| {
+                let _ = actions.send(action);
+            }),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl ksni::Tray for Tray {
+    const MENU_ON_ACTIVATE: bool = true;
+
+    fn id(&self) -> String {
+        "dispatch-proxy".into()
+    }
+
+    fn title(&self) -> String {
+        "dispatch".into()
+    }
+
+    fn icon_pixmap(&self) -> Vec<ksni::Icon> {
+        let (mut data, size) = self.snapshot.health().icon();
+        // StatusNotifierItem icons are in ARGB.
+        for pixel in data.chunks_exact_mut(4) {
+            pixel.rotate_right(1);
+        }
+        vec![ksni::Icon {
+            width: size as i32,
+            height: size as i32,
+            data,
+        }]
+    }
+
+    fn tool_tip(&self) -> ksni::ToolTip {
+        ksni::ToolTip {
+            title: "dispatch".into(),
+            description: self.snapshot.summary(),
+            ..Default::default()
+        }
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Tray>> {
+        let mut menu = vec![
+            StandardItem {
+                label: self.snapshot.summary(),
+                enabled: false,
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+        ];
+        for address in &self.snapshot.addresses {
+            let actions = self.actions.clone();
+            let action = address.toggle();
+            menu.push(
+                CheckmarkItem {
+                    label: address.label(),
+                    checked: !address.status.paused,
+                    activate: Box::new(move |_| {
+                        let _ = actions.send(action);
+                    }),
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+        if !self.snapshot.addresses.is_empty() {
+            menu.push(MenuItem::Separator);
+        }
+        if self.snapshot.dashboard {
+            menu.push(self.item("Open dashboard".into(), Action::OpenDashboard));
+        }
+        menu.push(self.item("Stop proxy".into(), Action::Stop));
+        menu.push(self.item("Quit".into(), Action::Quit));
+        menu
+    }
+}
+
+pub fn run(mut companion: Companion) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async move {
+        let (actions, mut actions_rx) = mpsc::unbounded_channel();
+        let tray = Tray {
+            snapshot: Snapshot::new(companion.dashboard.is_some()),
+            actions,
+        };
+        let handle = tray
+            .spawn()
+            .await
+            .wrap_err("Failed to show the tray icon")
+            .suggestion(
+                "The tray icon needs a desktop that shows StatusNotifierItems, e.g. KDE, or GNOME with the \
+                 AppIndicator extension",
+            )?;
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let snapshot = companion.poll().await;
+                    handle.update(|tray| tray.snapshot = snapshot).await;
+                }
+                Some(action) = actions_rx.recv() => {
+                    if !companion.perform(action).await {
+                        break;
+                    }
+                    // Show the effect of the action right away.
+                    interval.reset_immediately();
+                }
+            }
+        }
+
+        handle.shutdown().await;
+        Ok(())
+    })
+}